@@ -0,0 +1,85 @@
+// Fills a full eventq with events the way process_queue does, taking avail ring
+// heads one at a time and in prefetched batches of increasing size (see
+// --prefetch).
+//
+// 256 events is what an 8 kHz mouse produces in about 10 ms.
+
+use std::num::Wrapping;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use vhost_user_input::config::VirtioInputEvent;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::Queue;
+
+const QUEUE_SIZE: u16 = 256;
+
+const DESC_TABLE: u64 = 0x1000;
+const AVAIL_RING: u64 = 0x2000;
+const USED_RING: u64 = 0x3000;
+const BUFFERS: u64 = 0x4000;
+
+// VIRTQ_DESC_F_WRITE
+const DESC_F_WRITE: u16 = 2;
+
+// A queue whose every descriptor is an 8-byte device-writable buffer, all of
+// them on the avail ring.
+fn eventq() -> Queue<GuestMemoryAtomic<GuestMemoryMmap>> {
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    for i in 0..QUEUE_SIZE {
+        let desc = GuestAddress(DESC_TABLE + u64::from(i) * 16);
+        mem.write_obj(BUFFERS + u64::from(i) * 8, desc).unwrap();
+        mem.write_obj(8u32, GuestAddress(desc.0 + 8)).unwrap();
+        mem.write_obj(DESC_F_WRITE, GuestAddress(desc.0 + 12))
+            .unwrap();
+        mem.write_obj(i, GuestAddress(AVAIL_RING + 4 + u64::from(i) * 2))
+            .unwrap();
+    }
+    mem.write_obj(QUEUE_SIZE, GuestAddress(AVAIL_RING + 2))
+        .unwrap();
+
+    let mut queue = Queue::new(GuestMemoryAtomic::new(mem), QUEUE_SIZE);
+    queue.size = QUEUE_SIZE;
+    queue.ready = true;
+    queue.desc_table = GuestAddress(DESC_TABLE);
+    queue.avail_ring = GuestAddress(AVAIL_RING);
+    queue.used_ring = GuestAddress(USED_RING);
+    queue
+}
+
+fn fill(queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>, prefetch: usize) {
+    queue.next_avail = Wrapping(0);
+    queue.next_used = Wrapping(0);
+
+    let event = VirtioInputEvent {
+        event_type: 2,
+        code: 0,
+        value: 1,
+    };
+    let mut pending = QUEUE_SIZE as usize;
+    while pending > 0 {
+        let desc_chains: Vec<_> = queue.iter().unwrap().take(prefetch.min(pending)).collect();
+        for mut desc_chain in desc_chains {
+            let desc = desc_chain.next().unwrap();
+            desc_chain.memory().write_obj(event, desc.addr()).unwrap();
+            queue.add_used(desc_chain.head_index(), 8).unwrap();
+            pending -= 1;
+        }
+    }
+}
+
+fn descriptor_prefetch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("descriptor_prefetch");
+    group.throughput(Throughput::Elements(u64::from(QUEUE_SIZE)));
+    let mut queue = eventq();
+    for prefetch in [1, 4, 16, 64].iter() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(prefetch),
+            prefetch,
+            |b, &prefetch| b.iter(|| fill(&mut queue, prefetch)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, descriptor_prefetch);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+// Prints the virtio-input config space the daemon would expose for an evdev
+// device, without a VM: every select/subsel pair the guest driver asks about,
+// answered by the same DeviceConfig the backend serves.
+//
+//     cargo run --example probe_device -- /dev/input/event3
+
+use std::{env, process};
+
+use vhost_user_input::codes;
+use vhost_user_input::config::*;
+use vhost_user_input::evdev::{self, test_bit, EvdevDevice};
+
+fn bits(payload: &[u8]) -> Vec<u16> {
+    (0..(payload.len() * 8) as u16)
+        .filter(|bit| test_bit(payload, *bit))
+        .collect()
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: probe_device <evdev path>");
+            process::exit(1);
+        }
+    };
+    let config = match EvdevDevice::open(&path)
+        .map_err(Error::QueryEvdev)
+        .and_then(|device| DeviceConfig::from_evdev(&device))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to probe {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut payload = [0u8; VIRTIO_INPUT_PAYLOAD_SIZE];
+    let mut query = |select: u32, subsel: u8| -> Vec<u8> {
+        payload = [0; VIRTIO_INPUT_PAYLOAD_SIZE];
+        let size = config.query(select as u8, subsel, &mut payload) as usize;
+        payload[..size].to_vec()
+    };
+
+    println!(
+        "name:    {}",
+        String::from_utf8_lossy(&query(VIRTIO_INPUT_CFG_ID_NAME, 0))
+    );
+    println!(
+        "serial:  {}",
+        String::from_utf8_lossy(&query(VIRTIO_INPUT_CFG_ID_SERIAL, 0))
+    );
+    let ids = query(VIRTIO_INPUT_CFG_ID_DEVIDS, 0);
+    if ids.len() == 8 {
+        let id = |i: usize| u16::from_le_bytes([ids[i], ids[i + 1]]);
+        println!(
+            "devids:  bustype {:#06x} vendor {:#06x} product {:#06x} version {:#06x}",
+            id(0),
+            id(2),
+            id(4),
+            id(6)
+        );
+    } else {
+        println!("devids:  none");
+    }
+
+    if let Some(battery) = evdev::battery(&path) {
+        println!("battery: {}", battery);
+    }
+
+    let props: Vec<String> = bits(&query(VIRTIO_INPUT_CFG_PROP_BITS, 0))
+        .into_iter()
+        .map(|prop| codes::prop_name(prop).map_or(prop.to_string(), str::to_string))
+        .collect();
+    println!("props:   {}", props.join(" "));
+
+    for ev_type in 0..=0x1f {
+        let codes: Vec<String> = bits(&query(VIRTIO_INPUT_CFG_EV_BITS, ev_type))
+            .into_iter()
+            .map(|code| {
+                codes::code_name(u16::from(ev_type), code).map_or(code.to_string(), str::to_string)
+            })
+            .collect();
+        if !codes.is_empty() {
+            let name =
+                codes::type_name(u16::from(ev_type)).map_or(ev_type.to_string(), str::to_string);
+            println!("{}: {}", name, codes.join(" "));
+        }
+    }
+
+    for axis in 0..=0x3f {
+        let info = query(VIRTIO_INPUT_CFG_ABS_INFO, axis);
+        if info.len() == 20 {
+            let field = |i: usize| {
+                u32::from_le_bytes([info[i], info[i + 1], info[i + 2], info[i + 3]]) as i32
+            };
+            let name = codes::code_name(codes::EV_ABS, u16::from(axis))
+                .map_or(axis.to_string(), str::to_string);
+            println!(
+                "{}: min {} max {} fuzz {} flat {} res {}",
+                name,
+                field(0),
+                field(4),
+                field(8),
+                field(12),
+                field(16)
+            );
+        }
+    }
+}
@@ -0,0 +1,3233 @@
+// The vhost-user backend of the input device: the worker that feeds the guest
+// the events of the host evdev devices and of the other sources, and the
+// builder it's configured with.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::{convert, error, fmt, io, result};
+
+use libc::EFD_NONBLOCK;
+use log::*;
+use vhost::vhost_user::message::*;
+use vhost_user_backend::{VhostUserBackend, Vring, VringWorker};
+use virtio_bindings::bindings::virtio_blk::VIRTIO_F_VERSION_1;
+use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
+use vm_virtio::{Descriptor, Queue};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::coalesce::{coalesce_rel, is_motion, merge_motion};
+use crate::codes::{
+    self, EV_ABS, EV_FF, EV_KEY, EV_SYN, FF_GAIN, FF_RUMBLE, SYN_DROPPED, SYN_REPORT,
+};
+use crate::config::*;
+use crate::evdev::{self, test_bit, EvdevDevice};
+use crate::event_log::EventLog;
+use crate::event_queue::EventQueue;
+use crate::filter::{CodeMask, FilterRules};
+use crate::held_keys::HeldKeys;
+use crate::hotkey::Hotkey;
+use crate::hotplug::{Monitor, Selector};
+use crate::inject::Injected;
+use crate::listeners::{Listeners, Source};
+use crate::logger;
+use crate::logind::Session;
+use crate::overflow::{self, Missed, OverflowPolicy};
+use crate::profiles;
+use crate::rate_limit::RateLimiter;
+use crate::recording::{Recorder, Replay};
+use crate::resync::{DeviceState, SynDropped};
+use crate::stats::{Counters, DropCause, DropCounters};
+use crate::watchdog::Heartbeat;
+
+pub type Result<T> = std::result::Result<T, Error>;
+type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create kill eventfd
+    CreateKillEventFd(io::Error),
+    /// Failed to handle event other than input event.
+    HandleEventNotEpollIn,
+    /// Failed to handle unknown event.
+    HandleEventUnknownEvent,
+    /// Failed to read from the synthetic input eventfd.
+    ReadInputEventFd(io::Error),
+    /// Failed to create the input poll timer.
+    CreatePollTimer(vmm_sys_util::errno::Error),
+    /// Failed to arm the input poll timer.
+    ArmPollTimer(vmm_sys_util::errno::Error),
+    /// Failed to read the input poll timer.
+    ReadPollTimer(vmm_sys_util::errno::Error),
+    /// Failed to open the evdev device at the path.
+    OpenEvdev(String, io::Error),
+    /// Failed to grab the evdev device at the path.
+    GrabEvdev(String, io::Error),
+    /// Failed to query the evdev device at the path for its configuration.
+    ConfigureEvdev(String, crate::config::Error),
+    /// Failed to query the evdev device.
+    QueryEvdev(io::Error),
+    /// Failed to read events from the evdev device.
+    ReadEvdev(io::Error),
+    /// Failed to read udev's announcements of plugged in devices.
+    ReadHotplugMonitor(io::Error),
+    /// Failed to listen to a plugged in evdev device.
+    RegisterEvdev(io::Error),
+    /// Failed to register the backend's fds with the vring workers.
+    RegisterVringWorker(io::Error),
+    /// Failed to read from a source added to the backend.
+    ReadSource(io::Error),
+    /// Failed to create the recording file.
+    CreateRecording(io::Error),
+    /// Failed to write to the recording file.
+    WriteRecording(io::Error),
+    /// Failed to create, arm or read the replay timer.
+    ReplayTimer(io::Error),
+    /// Failed to create, arm or read the --max-events-per-sec timer.
+    RateLimitTimer(io::Error),
+    /// The number of queues is not one the device can have.
+    InvalidNumQueues(usize),
+    /// The queue size is zero, too large or not a power of two.
+    InvalidQueueSize(usize),
+    /// The descriptor prefetch is zero.
+    InvalidPrefetch,
+    /// The event buffer is too small to hold a frame.
+    InvalidEventBufferSize(usize),
+    /// The device type is not one of the built-in profiles.
+    UnknownDeviceType(String),
+    /// Failed to open the event log.
+    CreateEventLog(io::Error),
+    /// Failed to create or arm the dropped events summary timer.
+    CreateDropLogTimer(vmm_sys_util::errno::Error),
+    /// Failed to read the dropped events summary timer.
+    ReadDropLogTimer(vmm_sys_util::errno::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vhost_user_input_error: {:?}", self)
+    }
+}
+
+impl error::Error for Error {}
+
+impl convert::From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+// virtio-input has an eventq and a statusq.
+const MAX_QUEUES: usize = 2;
+
+// Largest size of a split virtqueue allowed by the virtio spec.
+const MAX_QUEUE_SIZE: usize = 32768;
+
+// select and subsel, the only config fields a driver writes.
+const WRITABLE_CONFIG_SIZE: usize = 2;
+
+// Number of events buffered while the guest has no eventq buffers posted.
+const EVENT_BUFFER_SIZE: usize = 1024;
+// Smallest event buffer --event-buffer-size takes, the events of a frame have
+// to fit in there together.
+const MIN_EVENT_BUFFER_SIZE: usize = 64;
+
+// How often --log-dropped-events summarizes.
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// Strength of both motors of a host device's rumble, out of u16::MAX. The guest
+// can only start and stop it.
+const RUMBLE_MAGNITUDE: u16 = 0xc000;
+
+// Index of the event virtqueue. vhost-user-backend hands us the queue index as
+// the device_event when the corresponding kick fd fires.
+const EVENTQ: u16 = 0;
+// Index of the status virtqueue, on which the driver sends events to the
+// device, e.g. LED changes.
+const STATUSQ: u16 = 1;
+
+struct VhostUserInputThread {
+    input_fd: EventFd,
+    // What the frontend's vring worker waits on besides the kicks.
+    listeners: Listeners,
+    event_idx: bool,
+    kill_evt: EventFd,
+    filter: Option<FilterRules>,
+    // The events the guest gets to see, see --only and --block.
+    mask: Option<CodeMask>,
+    // Range of each axis to invert, see --invert-abs.
+    invert_abs: BTreeMap<u16, (i32, i32)>,
+    poll_timer: Option<TimerFd>,
+    // Translated input that hasn't been terminated by a SYN_REPORT yet, from
+    // sources other than the evdev devices (replays).
+    frame: Vec<VirtioInputEvent>,
+    // Complete frames handed from the input side to process_queue(), which
+    // waits for the guest to post eventq buffers.
+    events: EventQueue<VirtioInputEvent>,
+    // Keys down as far as the guest knows, from the frames queued for it.
+    held_keys: HeldKeys,
+    // Whether a driver took any events yet. Until then, what is queued is
+    // input from before the guest's driver came up, e.g. typed during boot.
+    delivered: bool,
+    overflow_policy: OverflowPolicy,
+    // Set while drop-newest drops frames because the event buffer is full,
+    // with the state they changed, which the guest gets after a SYN_DROPPED
+    // once there is room again.
+    missed: Option<Missed>,
+    // Frames waiting for room in the event buffer with block-source, while the
+    // sources aren't read.
+    backlog: VecDeque<Vec<VirtioInputEvent>>,
+    // Guest memory, None until the frontend has sent its memory table.
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    warned_no_memory: bool,
+    // eventq chains skipped because their descriptors don't form a chain.
+    malformed_descriptors: u64,
+    // eventq chains taken off the avail ring, head index and descriptors, that
+    // wait for enough others to hold the next frame.
+    spare: VecDeque<(u16, Vec<Descriptor>)>,
+    // Most avail ring heads process_queue() takes at once, see --prefetch.
+    prefetch: usize,
+    // Sum consecutive relative motion in a frame, see --coalesce-rel.
+    coalesce_rel: bool,
+    // With coalesce_rel, the last frame of motion queued for a guest out of
+    // eventq buffers, held back so that later motion is summed into it.
+    motion: Vec<VirtioInputEvent>,
+    // See --max-events-per-sec.
+    rate_limiter: Option<RateLimiter>,
+    dropped: DropCounters,
+    counters: Counters,
+    // Fires every DROP_LOG_INTERVAL with --log-dropped-events.
+    drop_log_timer: Option<TimerFd>,
+    // Host devices forwarded into this one, usually one, more for a group.
+    evdevs: Vec<EvdevSource>,
+    syn_dropped: SynDropped,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    // Audit trail of the events delivered, see --event-log.
+    event_log: Option<EventLog>,
+    // Events from --inject-socket clients, signalled through input_fd.
+    injected: Option<Injected>,
+    // Host input is dropped while set, see --control-socket.
+    paused: bool,
+    // Take the evdev devices' events away from the host, see --no-grab. The
+    // --grab-toggle hotkey flips it.
+    grab: bool,
+    grab_hotkey: Option<Hotkey>,
+    // Moves the host devices on to the next --switch-socket guest.
+    switch_hotkey: Option<(Hotkey, Sender<Focus>)>,
+    // The guests of --mirror-socket, which get every frame this one does.
+    mirrors: Vec<Injected>,
+    // As a guest of --mirror-socket, the frames of the first guest.
+    mirrored: Option<Injected>,
+    // Input added by an embedder, see listeners::Source.
+    sources: Vec<Box<dyn Source>>,
+    // epoll token of sources[0], the others following.
+    source_event_id: u16,
+    // epoll token of evdevs[0], the others following.
+    evdev_event_id: u16,
+    hotplug: Option<Hotplug>,
+    // Opens the evdev devices on our behalf, see --logind.
+    logind: Option<Arc<Mutex<Session>>>,
+}
+
+// Following the host device as it comes and goes, see --hotplug. There is only
+// the one device then, which is evdevs[0] while plugged in.
+struct Hotplug {
+    monitor: Monitor,
+    selector: Selector,
+}
+
+pub struct EvdevSource {
+    device: EvdevDevice,
+    // Where it was opened, for the log.
+    path: String,
+    // State of the device as implied by the frames read so far.
+    state: DeviceState,
+    // Input from this device in the frame being read, which only makes it to
+    // state with the SYN_REPORT ending it, as a broken frame never reaches
+    // the guest.
+    unreported: Vec<VirtioInputEvent>,
+    // Set between a SYN_DROPPED and the SYN_REPORT ending the broken frame.
+    dropping: bool,
+    // Translated input from this device not terminated by a SYN_REPORT yet.
+    // Each device has its own, so that frames of merged devices are queued
+    // whole and never interleave.
+    frame: Vec<VirtioInputEvent>,
+    // Id of the rumble effect uploaded to the device, None if it has no rumble
+    // motors.
+    rumble: Option<i16>,
+}
+
+impl EvdevSource {
+    fn new(device: EvdevDevice, path: &str) -> Self {
+        EvdevSource {
+            rumble: upload_rumble(&device),
+            device,
+            path: path.to_string(),
+            state: DeviceState::default(),
+            unreported: Vec::new(),
+            dropping: false,
+            frame: Vec::new(),
+        }
+    }
+}
+
+// What VhostUserInputBackendBuilder::build() hands VhostUserInputThread::new().
+struct ThreadSettings {
+    input_fd: EventFd,
+    filter: Option<FilterRules>,
+    mask: Option<CodeMask>,
+    invert_abs: BTreeMap<u16, (i32, i32)>,
+    poll_interval: Option<Duration>,
+    evdevs: Vec<(EvdevDevice, String)>,
+    syn_dropped: SynDropped,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    log_dropped: bool,
+    prefetch: usize,
+    event_buffer_size: usize,
+    overflow_policy: OverflowPolicy,
+    coalesce_rel: bool,
+    rate_limiter: Option<RateLimiter>,
+    event_log: Option<EventLog>,
+    injected: Option<Injected>,
+    grab: bool,
+    grab_hotkey: Option<Hotkey>,
+    switch_hotkey: Option<(Hotkey, Sender<Focus>)>,
+    mirrors: Vec<Injected>,
+    mirrored: Option<Injected>,
+    sources: Vec<Box<dyn Source>>,
+    source_event_id: u16,
+    evdev_event_id: u16,
+    hotplug: Option<Hotplug>,
+    logind: Option<Arc<Mutex<Session>>>,
+}
+
+impl VhostUserInputThread {
+    // Create a new virtio input device
+    fn new(settings: ThreadSettings) -> Result<Self> {
+        info!("new VhostUserInputThread");
+
+        let ThreadSettings {
+            input_fd,
+            filter,
+            mask,
+            invert_abs,
+            poll_interval,
+            evdevs,
+            syn_dropped,
+            recorder,
+            replay,
+            log_dropped,
+            prefetch,
+            event_buffer_size,
+            overflow_policy,
+            coalesce_rel,
+            rate_limiter,
+            event_log,
+            injected,
+            grab,
+            grab_hotkey,
+            switch_hotkey,
+            mirrors,
+            mirrored,
+            sources,
+            source_event_id,
+            evdev_event_id,
+            hotplug,
+            logind,
+        } = settings;
+
+        let events = EventQueue::new(event_buffer_size);
+
+        let poll_timer = match poll_interval {
+            Some(interval) => {
+                let mut timer = TimerFd::new().map_err(Error::CreatePollTimer)?;
+                timer
+                    .reset(interval, Some(interval))
+                    .map_err(Error::ArmPollTimer)?;
+                Some(timer)
+            }
+            None => None,
+        };
+
+        let drop_log_timer = if log_dropped {
+            let mut timer = TimerFd::new().map_err(Error::CreateDropLogTimer)?;
+            timer
+                .reset(DROP_LOG_INTERVAL, Some(DROP_LOG_INTERVAL))
+                .map_err(Error::CreateDropLogTimer)?;
+            Some(timer)
+        } else {
+            None
+        };
+
+        let mut thread = VhostUserInputThread {
+            input_fd,
+            listeners: Listeners::default(),
+            event_idx: false,
+            kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
+            filter,
+            mask,
+            invert_abs,
+            poll_timer,
+            frame: Vec::new(),
+            events,
+            held_keys: HeldKeys::default(),
+            delivered: false,
+            overflow_policy,
+            missed: None,
+            backlog: VecDeque::new(),
+            mem: None,
+            warned_no_memory: false,
+            malformed_descriptors: 0,
+            spare: VecDeque::new(),
+            prefetch,
+            coalesce_rel,
+            motion: Vec::new(),
+            rate_limiter,
+            dropped: DropCounters::default(),
+            counters: Counters::default(),
+            drop_log_timer,
+            evdevs: evdevs
+                .into_iter()
+                .map(|(device, path)| EvdevSource::new(device, &path))
+                .collect(),
+            syn_dropped,
+            recorder,
+            replay,
+            event_log,
+            injected,
+            paused: false,
+            grab,
+            grab_hotkey,
+            switch_hotkey,
+            mirrors,
+            mirrored,
+            sources,
+            source_event_id,
+            evdev_event_id,
+            hotplug,
+            logind,
+        };
+        // Registered with the worker once a frontend connects.
+        thread.relisten();
+
+        Ok(thread)
+    }
+
+    // Drain whatever the input source has for us. The fd is non-blocking, and
+    // when polling on a timer there is usually nothing to read.
+    fn read_input(&mut self) -> Result<()> {
+        match self.input_fd.read() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(Error::ReadInputEventFd(e)),
+        }
+
+        // Left waiting while blocked, flush_backlog() wakes us up again.
+        if !self.backlog.is_empty() {
+            return Ok(());
+        }
+        // Translated by the first guest already.
+        if let Some(mirrored) = self.mirrored.as_ref() {
+            for event in mirrored.take() {
+                self.frame.push(event);
+                if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                    let frame = std::mem::take(&mut self.frame);
+                    self.forward(frame);
+                }
+            }
+            return Ok(());
+        }
+
+        let events = match self.injected.as_ref() {
+            Some(injected) => injected.take(),
+            None => return Ok(()),
+        };
+        for event in events {
+            let events = self.translate(event);
+            self.queue_events(None, events);
+        }
+
+        Ok(())
+    }
+
+    // Read and queue whatever the index-th evdev device has for us.
+    fn read_evdev(&mut self, index: usize) -> Result<()> {
+        let _span = tracing::trace_span!("read_evdev", device = %self.evdevs[index].path).entered();
+        let events = match self.evdevs[index].device.read_events() {
+            Ok(events) => events,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                self.detach_evdev(index);
+                return Ok(());
+            }
+            Err(e) => {
+                self.release_source(index);
+                return Err(Error::ReadEvdev(e));
+            }
+        };
+        for event in events {
+            let host_event = VirtioInputEvent {
+                event_type: event.type_,
+                code: event.code,
+                value: event.value as u32,
+            };
+            if let Some(recorder) = self.recorder.as_mut() {
+                let time =
+                    Duration::new(event.time.tv_sec as u64, event.time.tv_usec as u32 * 1000);
+                recorder
+                    .record(time, &host_event)
+                    .map_err(Error::WriteRecording)?;
+            }
+            self.host_event(index, host_event)?;
+        }
+
+        Ok(())
+    }
+
+    // Read and queue whatever the index-th added source has for us, which goes
+    // the way of injected events.
+    fn read_source(&mut self, index: usize) -> Result<()> {
+        let events = self.sources[index].read().map_err(Error::ReadSource)?;
+        for event in events {
+            let events = self.translate(event);
+            self.queue_events(None, events);
+        }
+
+        Ok(())
+    }
+
+    fn read_evdevs(&mut self) -> Result<()> {
+        if !self.backlog.is_empty() {
+            return Ok(());
+        }
+        // Backwards, as removed devices are detached on the way.
+        for index in (0..self.evdevs.len()).rev() {
+            self.read_evdev(index)?;
+        }
+
+        Ok(())
+    }
+
+    // Release the keys and buttons down on the index-th evdev device, which
+    // is going away, so that none are left stuck in the guest. Its unfinished
+    // frame is dropped. While paused the guest has them released already, so
+    // they are only taken off the ledger resume() presses again.
+    fn release_source(&mut self, index: usize) {
+        let source = &mut self.evdevs[index];
+        source.frame.clear();
+        source.unreported.clear();
+        source.dropping = false;
+        let releases = source.state.key_releases();
+        source.state = DeviceState::default();
+        for event in releases {
+            let events = self.translate(event);
+            if self.paused {
+                for event in events.iter() {
+                    self.held_keys.update(event);
+                }
+            } else {
+                self.queue_events(Some(index), events);
+            }
+        }
+    }
+
+    // Forward the evdev device at path instead of the host devices forwarded so
+    // far, releasing what the guest thinks is held and pressing what is held on
+    // the new one. The old ones stay if it can't be opened or grabbed.
+    fn swap_evdev(&mut self, path: &str) -> result::Result<(), String> {
+        let device = open_evdev(self.logind.as_deref(), path)
+            .map_err(|e| format!("can't open {}: {}", path, e))?;
+        if self.grab {
+            if let Err(e) = device.grab() {
+                if e.raw_os_error() == Some(libc::EBUSY) {
+                    log_grab_holders(path);
+                }
+                return Err(format!("can't grab {}: {}", path, e));
+            }
+        }
+
+        // Closing the old devices releases their grabs and takes them out of
+        // the worker's epoll.
+        for index in 0..self.evdevs.len() {
+            self.release_source(index);
+            self.listeners.forget(self.evdev_event_id + index as u16);
+        }
+        self.evdevs.clear();
+        self.evdevs.push(EvdevSource::new(device, path));
+        if !self.paused {
+            self.resync(0).map_err(|e| e.to_string())?;
+        }
+        info!("now forwarding {}", path);
+
+        Ok(())
+    }
+
+    // Have the worker listen to the index-th evdev device, right away if a
+    // frontend is there for one, or else once the next one connects.
+    fn listen_evdev(&mut self, index: usize) -> io::Result<()> {
+        let fd = self.evdevs[index].device.as_raw_fd();
+        self.listeners.add(fd, self.evdev_event_id + index as u16)
+    }
+
+    fn unlisten_evdev(&mut self, index: usize) -> io::Result<()> {
+        self.listeners.remove(self.evdev_event_id + index as u16)
+    }
+
+    // Listen to the sources and evdev devices, from the start and again after
+    // block_sources().
+    fn relisten(&mut self) {
+        for index in 0..self.sources.len() {
+            let fd = self.sources[index].as_raw_fd();
+            if let Err(e) = self.listeners.add(fd, self.source_event_id + index as u16) {
+                warn!("Failed to read source {} again: {}", index, e);
+            }
+        }
+        for index in 0..self.evdevs.len() {
+            if let Err(e) = self.listen_evdev(index) {
+                warn!("Failed to read evdev device {} again: {}", index, e);
+            }
+        }
+    }
+
+    // Let go of the removed index-th evdev device, releasing what is held on
+    // it. Closing it takes it out of the worker's epoll, and the devices after
+    // it move up to the tokens of their new index.
+    fn detach_evdev(&mut self, index: usize) {
+        self.release_source(index);
+        self.evdevs.remove(index);
+        self.listeners.forget(self.evdev_event_id + index as u16);
+        for moved in index..self.evdevs.len() {
+            let old_id = self.evdev_event_id + moved as u16 + 1;
+            if let Err(e) = self
+                .listeners
+                .remove(old_id)
+                .and_then(|_| self.listen_evdev(moved))
+            {
+                warn!("Failed to listen to evdev device {}: {}", moved, e);
+            }
+        }
+        if self.hotplug.is_some() {
+            info!("host device unplugged, waiting for it to be plugged in again");
+        } else {
+            info!("host device removed, {} left", self.evdevs.len());
+        }
+    }
+
+    // Open the device matching the --hotplug selector if one was plugged in
+    // while none is attached, and send the guest what is already pressed on
+    // it. True if one was attached, for the caller to listen to it.
+    fn attach_evdev(&mut self) -> Result<bool> {
+        let hotplug = match self.hotplug.as_mut() {
+            Some(hotplug) => hotplug,
+            None => return Ok(false),
+        };
+        let added = hotplug.monitor.added().map_err(Error::ReadHotplugMonitor)?;
+        if !self.evdevs.is_empty() {
+            return Ok(false);
+        }
+        let node = match added.iter().find(|node| hotplug.selector.matches(node)) {
+            Some(node) => node,
+            None => return Ok(false),
+        };
+
+        let device = match open_evdev(self.logind.as_deref(), node) {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("Failed to open plugged in {}: {}", node, e);
+                return Ok(false);
+            }
+        };
+        if self.grab {
+            if let Err(e) = device.grab() {
+                warn!("Failed to grab plugged in {}: {}", node, e);
+            }
+        }
+        info!("host device plugged in at {}", node);
+        self.evdevs.push(EvdevSource::new(device, node));
+        if !self.paused {
+            self.resync(0)?;
+        }
+
+        Ok(true)
+    }
+
+    fn host_event(&mut self, index: usize, event: VirtioInputEvent) -> Result<()> {
+        // Seen even while paused, as that's how the host gets its devices back.
+        if let Some(grab_hotkey) = self.grab_hotkey.as_mut() {
+            if grab_hotkey.update(&event) {
+                return self.toggle_grab();
+            }
+        }
+        if let Some((switch_hotkey, focus_tx)) = self.switch_hotkey.as_mut() {
+            if switch_hotkey.update(&event) {
+                // The switch takes the devices away from us, so it can only
+                // do that once we're done here.
+                if focus_tx.send(Focus::Next).is_err() {
+                    warn!("The switch between guests is gone");
+                }
+                return Ok(());
+            }
+        }
+
+        // The device state is left as it was at pause(), for resume() to catch
+        // up on whatever changed since.
+        if self.paused {
+            self.dropped.add(DropCause::Paused, 1);
+            return Ok(());
+        }
+
+        let source = &mut self.evdevs[index];
+        if source.dropping {
+            self.dropped.add(DropCause::HostOverflow, 1);
+            if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                source.dropping = false;
+                if self.syn_dropped == SynDropped::Resync {
+                    self.resync(index)?;
+                }
+            }
+            return Ok(());
+        }
+
+        if event.event_type == EV_SYN
+            && event.code == SYN_DROPPED
+            && self.syn_dropped != SynDropped::Forward
+        {
+            warn!("Host dropped input events, discarding the broken frame");
+            source.dropping = true;
+            self.dropped
+                .add(DropCause::HostOverflow, source.frame.len() + 1);
+            source.frame.clear();
+            source.unreported.clear();
+            return Ok(());
+        }
+
+        if event.event_type == EV_SYN && event.code == SYN_REPORT {
+            for event in source.unreported.drain(..) {
+                source.state.update(&event);
+            }
+        } else {
+            source.unreported.push(event);
+        }
+        let events = self.translate(event);
+        self.queue_events(Some(index), events);
+
+        Ok(())
+    }
+
+    // Query the index-th evdev device after a SYN_DROPPED and send the guest a
+    // frame with whatever changed in the events we lost.
+    fn resync(&mut self, index: usize) -> Result<()> {
+        let current = DeviceState::query(&self.evdevs[index].device).map_err(Error::QueryEvdev)?;
+        self.resync_to(index, current);
+
+        Ok(())
+    }
+
+    // Send the guest what it takes to get the index-th evdev device to current.
+    fn resync_to(&mut self, index: usize, current: DeviceState) {
+        let source = &mut self.evdevs[index];
+        let burst = source.state.diff(&current);
+        source.state = current;
+        info!("resynced {} events", burst.len());
+        for event in burst {
+            let events = self.translate(event);
+            self.queue_events(Some(index), events);
+        }
+    }
+
+    // Start replaying once the driver is there to receive the events, that is
+    // on the first eventq kick.
+    fn start_replay(&mut self) -> Result<()> {
+        match self.replay.as_mut() {
+            Some(replay) if !replay.is_started() => {
+                info!("starting replay");
+                replay.start().map_err(Error::ReplayTimer)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Play the next frame of a stepping replay, see --replay-step.
+    fn step_replay(&mut self) -> result::Result<(), String> {
+        let events = match self.replay.as_mut() {
+            Some(replay) if !replay.is_stepping() => {
+                return Err("the replay isn't stepping, see --replay-step".to_string())
+            }
+            Some(replay) if !replay.is_started() => {
+                return Err("the replay starts with the guest driver".to_string())
+            }
+            Some(replay) => replay.step(),
+            None => return Err("nothing is being replayed".to_string()),
+        };
+        if events.is_empty() {
+            return Err("the replay is over".to_string());
+        }
+        for event in events {
+            let events = self.translate(event);
+            self.queue_events(None, events);
+        }
+
+        Ok(())
+    }
+
+    fn replay_due(&mut self) -> Result<()> {
+        let events = match self.replay.as_mut() {
+            Some(replay) => replay.take_due().map_err(Error::ReplayTimer)?,
+            None => return Ok(()),
+        };
+        for event in events {
+            let events = self.translate(event);
+            self.queue_events(None, events);
+        }
+
+        Ok(())
+    }
+
+    // Turn an event read from the host into the events the guest should see.
+    fn translate(&mut self, mut event: VirtioInputEvent) -> Vec<VirtioInputEvent> {
+        if event.event_type == EV_ABS {
+            if let Some(&(min, max)) = self.invert_abs.get(&event.code) {
+                let value = max as i64 - (event.value as i32 as i64 - min as i64);
+                event.value = value as i32 as u32;
+            }
+        }
+
+        let mut events = Vec::new();
+        match self.filter.as_mut() {
+            Some(filter) => {
+                filter.apply(event, &mut events);
+                if events.is_empty() {
+                    self.dropped.add(DropCause::Filtered, 1);
+                }
+            }
+            None => events.push(event),
+        }
+        if let Some(mask) = self.mask.as_ref() {
+            let before = events.len();
+            events.retain(|event| mask.allows(event.event_type, event.code));
+            self.dropped.add(DropCause::Filtered, before - events.len());
+        }
+
+        events
+    }
+
+    // Queue translated events from the given evdev device, or from elsewhere
+    // if None, for the guest. They only become visible to process_queue() a
+    // whole SYN_REPORT frame at a time.
+    fn queue_events(&mut self, source: Option<usize>, events: Vec<VirtioInputEvent>) {
+        for event in events {
+            let frame = match source {
+                Some(index) => &mut self.evdevs[index].frame,
+                None => &mut self.frame,
+            };
+            frame.push(event);
+            if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                let mut frame = std::mem::take(frame);
+                if self.coalesce_rel {
+                    coalesce_rel(&mut frame);
+                }
+                self.forward(frame);
+            }
+        }
+    }
+
+    // Hand a complete frame to push_frame(), once --max-events-per-sec allows.
+    fn forward(&mut self, frame: Vec<VirtioInputEvent>) {
+        self.mirror(&frame);
+        let frames = match self.rate_limiter.as_mut() {
+            Some(rate_limiter) => match rate_limiter.admit(frame) {
+                Ok(frames) => frames,
+                // The motion held back goes along with the next frame.
+                Err(e) => {
+                    warn!("Failed to arm the rate limit timer: {}", e);
+                    return;
+                }
+            },
+            None => vec![frame],
+        };
+        for frame in frames {
+            self.push_frame(frame);
+        }
+    }
+
+    // Hand events on to the guests of --mirror-socket. One too far behind
+    // loses them.
+    fn mirror(&self, events: &[VirtioInputEvent]) {
+        for (index, mirror) in self.mirrors.iter().enumerate() {
+            if !mirror.push(events) {
+                warn!(
+                    "Mirrored guest {} too far behind, dropping {} events",
+                    index + 1,
+                    events.len()
+                );
+            }
+        }
+    }
+
+    // Hand the motion held back by --max-events-per-sec to push_frame() once
+    // its timer fired.
+    fn release_motion(&mut self) -> Result<()> {
+        let frame = match self.rate_limiter.as_mut() {
+            Some(rate_limiter) => rate_limiter.take_due().map_err(Error::RateLimitTimer)?,
+            None => return Ok(()),
+        };
+        if let Some(frame) = frame {
+            self.push_frame(frame);
+        }
+
+        Ok(())
+    }
+
+    // Hand a complete frame to process_queue(), or deal with the event buffer
+    // being full as the overflow policy says.
+    fn push_frame(&mut self, frame: Vec<VirtioInputEvent>) {
+        self.counters.frames += 1;
+        if self.missed.is_some() && !self.catch_up() {
+            self.miss(&frame);
+            return;
+        }
+        let motion = self.coalesce_rel && is_motion(&frame);
+        if !self.backlog.is_empty() {
+            // Motion waiting for the guest right behind other motion goes
+            // along with it.
+            if let Some(last) = self.backlog.back_mut() {
+                if motion && is_motion(last) && merge_motion(last, &frame) {
+                    return;
+                }
+            }
+            self.backlog.push_back(frame);
+            return;
+        }
+
+        // Events still queued mean the guest has no eventq buffers left for
+        // them, so motion is held back rather than take buffers of its own.
+        if motion && (!self.motion.is_empty() || !self.events.is_empty()) {
+            if self.motion.is_empty() {
+                self.motion = frame;
+                return;
+            }
+            if merge_motion(&mut self.motion, &frame) {
+                return;
+            }
+        }
+        // Whatever motion was held back goes first.
+        let frame = if self.motion.is_empty() {
+            frame
+        } else {
+            let mut held = std::mem::take(&mut self.motion);
+            held.extend(frame);
+            held
+        };
+
+        if self.events.push_batch(&frame) {
+            for event in frame.iter() {
+                self.held_keys.update(event);
+            }
+            return;
+        }
+        match self.overflow_policy {
+            OverflowPolicy::DropNewest => {
+                warn!("Event buffer full, dropping frames until the guest catches up");
+                self.missed = Some(Missed::new());
+                self.miss(&frame);
+            }
+            OverflowPolicy::DropOldest => self.drop_oldest(frame),
+            OverflowPolicy::BlockSource => {
+                warn!("Event buffer full, not reading input until the guest catches up");
+                self.backlog.push_back(frame);
+                self.block_sources();
+            }
+        }
+    }
+
+    // Drop a frame while the event buffer is full, remembering the state it
+    // changed.
+    fn miss(&mut self, frame: &[VirtioInputEvent]) {
+        self.dropped.add(DropCause::Overflow, frame.len());
+        overflow::miss(self.missed.get_or_insert_with(Missed::new), frame);
+    }
+
+    // Send the guest a SYN_DROPPED and the state it missed, if there is room
+    // now. Keys the guest already knows in that state are left out.
+    fn catch_up(&mut self) -> bool {
+        let missed = match self.missed.as_ref() {
+            Some(missed) => missed,
+            None => return true,
+        };
+
+        let mut changes = overflow::changes(missed);
+        changes.retain(|event| {
+            event.event_type != EV_KEY || self.held_keys.contains(event.code) != (event.value != 0)
+        });
+        let resynced = changes.len();
+        let events = overflow::resync_frames(changes);
+        if !self.events.push_batch(&events) {
+            return false;
+        }
+
+        for event in events.iter() {
+            self.held_keys.update(event);
+        }
+        info!("guest caught up, resynced {} events", resynced);
+        self.missed = None;
+        true
+    }
+
+    // Make room for frame by dropping the oldest frames waiting for the guest,
+    // which gets a SYN_DROPPED and the state they changed before frame. The
+    // ledger already has the keys of the frames dropped, and keeps them.
+    fn drop_oldest(&mut self, frame: Vec<VirtioInputEvent>) {
+        let mut queued = Vec::with_capacity(self.events.len());
+        while let Some(event) = self.events.pop() {
+            queued.push(event);
+        }
+        let capacity = self.events.capacity();
+        let (dropped, resync) = overflow::drop_oldest(&mut queued, frame.len(), capacity);
+        self.dropped.add(DropCause::Overflow, dropped);
+
+        queued.extend_from_slice(&resync);
+        if queued.len() + frame.len() <= capacity {
+            queued.extend_from_slice(&frame);
+            for event in resync.iter().chain(frame.iter()) {
+                self.held_keys.update(event);
+            }
+        } else {
+            // Bigger than the buffer all by itself.
+            self.dropped.add(DropCause::Overflow, frame.len());
+        }
+        // Whatever was taken out fits back in.
+        self.events.push_batch(&queued);
+    }
+
+    // Stop reading input with block-source until the backlog went to the guest:
+    // the host devices and added sources are taken out of the worker's epoll,
+    // and read_input() leaves injected events waiting.
+    fn block_sources(&mut self) {
+        for index in 0..self.sources.len() {
+            if let Err(e) = self.listeners.remove(self.source_event_id + index as u16) {
+                warn!("Failed to stop reading source {}: {}", index, e);
+            }
+        }
+        for index in 0..self.evdevs.len() {
+            if let Err(e) = self.unlisten_evdev(index) {
+                warn!("Failed to stop reading evdev device {}: {}", index, e);
+            }
+        }
+    }
+
+    // Queue the motion held back, once the guest has posted eventq buffers.
+    fn flush_motion(&mut self) {
+        if !self.motion.is_empty() && self.events.push_batch(&self.motion) {
+            self.motion.clear();
+        }
+    }
+
+    // Hand over what the backlog has room for, and read input again once it's
+    // all gone.
+    fn flush_backlog(&mut self) {
+        if self.backlog.is_empty() {
+            return;
+        }
+        while let Some(frame) = self.backlog.front() {
+            if !self.events.push_batch(frame) {
+                return;
+            }
+            for event in frame.iter() {
+                self.held_keys.update(event);
+            }
+            self.backlog.pop_front();
+        }
+
+        info!("guest caught up, reading input again");
+        self.relisten();
+        if let Some(injected) = self.injected.as_ref() {
+            injected.wake();
+        }
+    }
+
+    fn log_dropped(&mut self) -> Result<()> {
+        if let Some(timer) = self.drop_log_timer.as_mut() {
+            timer.wait().map_err(Error::ReadDropLogTimer)?;
+        }
+        if let Some(summary) = self.dropped.summary() {
+            warn!("{} in the last {}s", summary, DROP_LOG_INTERVAL.as_secs());
+        }
+
+        Ok(())
+    }
+
+    // Forget everything queued for, or known about, the guest before it reset
+    // the device. Nothing is released: the reset driver starts from scratch.
+    // The first driver coming up resets the device too, and gets the input
+    // queued before it instead.
+    fn reset(&mut self) {
+        // The chains belong to the queue being reset.
+        self.spare.clear();
+        if !self.delivered {
+            info!(
+                "device reset before any driver took events, keeping {} pending events",
+                self.events.len()
+            );
+            return;
+        }
+
+        let mut dropped = self.frame.len() + self.motion.len();
+        self.frame.clear();
+        self.motion.clear();
+        for source in self.evdevs.iter_mut() {
+            dropped += source.frame.len();
+            source.frame.clear();
+        }
+        while self.events.pop().is_some() {
+            dropped += 1;
+        }
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            dropped += rate_limiter.clear();
+        }
+        self.held_keys.clear();
+        self.missed = None;
+        if !self.backlog.is_empty() {
+            dropped += self.backlog.iter().map(Vec::len).sum::<usize>();
+            self.backlog.clear();
+            self.relisten();
+            if let Some(injected) = self.injected.as_ref() {
+                injected.wake();
+            }
+        }
+        info!("device reset, dropped {} pending events", dropped);
+    }
+
+    // Forget the frontend that went away, so that the next one finds the device
+    // as if freshly started. Its workers were told to exit through kill_evt,
+    // the next frontend's get a new one.
+    fn disconnected(&mut self) -> Result<()> {
+        // Its worker's epoll is gone already.
+        self.listeners.detach();
+        self.reset();
+        self.kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?;
+        self.event_idx = false;
+        self.mem = None;
+        self.warned_no_memory = false;
+        Ok(())
+    }
+
+    // Take the evdev devices back once the next frontend connected, dropping
+    // what the host got from them in the meantime.
+    fn reconnected(&mut self) {
+        for source in self.evdevs.iter_mut() {
+            if self.grab {
+                if let Err(e) = source.device.grab() {
+                    warn!("Failed to grab evdev device: {}", e);
+                }
+            }
+            if let Err(e) = source.device.read_events() {
+                warn!("Failed to discard stale evdev events: {}", e);
+            }
+            source.state = DeviceState::default();
+            source.unreported.clear();
+            source.dropping = false;
+        }
+    }
+
+    // Release everything the guest thinks is held, before it stops getting
+    // input for a while. The ledger remembers the keys for restore_held_keys().
+    fn release_held_keys(&mut self) {
+        let releases = self.held_keys.releases();
+        self.mirror(&releases);
+        if !releases.is_empty() && !self.events.push_batch(&releases) {
+            warn!("Event buffer full, can't release {:?}", self.held_keys);
+        }
+    }
+
+    // Press the held keys again once input flows to the guest again.
+    fn restore_held_keys(&mut self) {
+        let presses = self.held_keys.presses();
+        self.mirror(&presses);
+        if !presses.is_empty() && !self.events.push_batch(&presses) {
+            warn!("Event buffer full, can't restore {:?}", self.held_keys);
+        }
+    }
+
+    // Stop forwarding host input, releasing whatever the guest thinks is held.
+    // Injected and replayed input still goes through.
+    fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+        for source in self.evdevs.iter_mut() {
+            self.dropped.add(DropCause::Paused, source.frame.len());
+            source.frame.clear();
+            source.unreported.clear();
+            source.dropping = false;
+        }
+        self.release_held_keys();
+        self.paused = true;
+        info!("paused host input");
+    }
+
+    // Hand the host devices over to another --switch-socket guest, releasing
+    // whatever they have down for this one.
+    fn take_evdevs(&mut self) -> Vec<EvdevSource> {
+        for index in 0..self.evdevs.len() {
+            self.release_source(index);
+            if let Err(e) = self.unlisten_evdev(index) {
+                warn!("Failed to stop reading evdev device {}: {}", index, e);
+            }
+        }
+        self.kick();
+
+        std::mem::take(&mut self.evdevs)
+    }
+
+    // Take over the host devices from another --switch-socket guest, pressing
+    // whatever is down on them. With no frontend yet, serve() registers them
+    // once one connects.
+    fn give_evdevs(&mut self, evdevs: Vec<EvdevSource>) -> Result<()> {
+        self.evdevs = evdevs;
+        for index in 0..self.evdevs.len() {
+            self.listen_evdev(index).map_err(Error::RegisterEvdev)?;
+            if !self.paused {
+                self.resync(index)?;
+            }
+        }
+        self.kick();
+
+        Ok(())
+    }
+
+    // Have the worker hand what was queued from outside of it to the guest.
+    fn kick(&self) {
+        if let Err(e) = self.input_fd.write(1) {
+            warn!("Failed to signal the worker: {}", e);
+        }
+    }
+
+    // Give the host devices to the host, pausing host input to the guest, or
+    // take them back and resume it, on the --grab-toggle hotkey. The event
+    // completing the hotkey goes to neither.
+    fn toggle_grab(&mut self) -> Result<()> {
+        if self.grab {
+            for source in self.evdevs.iter() {
+                if let Err(e) = source.device.ungrab() {
+                    warn!("Failed to release evdev device grab: {}", e);
+                }
+            }
+            self.grab = false;
+            self.pause();
+            info!("host devices released to the host");
+            return Ok(());
+        }
+
+        for source in self.evdevs.iter() {
+            if let Err(e) = source.device.grab() {
+                warn!("Failed to grab evdev device: {}", e);
+            }
+        }
+        self.grab = true;
+        info!("host devices grabbed for the guest");
+        self.resume()
+    }
+
+    // Forward host input again, first pressing the keys released by pause()
+    // and then sending what changed on the host devices in the meantime.
+    fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        self.restore_held_keys();
+        for index in 0..self.evdevs.len() {
+            self.resync(index)?;
+        }
+        info!("resumed host input");
+
+        Ok(())
+    }
+
+    // Number of events up to and including the next SYN_REPORT.
+    fn next_frame_len(&self) -> usize {
+        let pending = self.events.len();
+        (0..pending)
+            .find(|&offset| {
+                self.events.peek_at(offset).map_or(false, |event| {
+                    event.event_type == EV_SYN && event.code == SYN_REPORT
+                })
+            })
+            .map_or(pending, |end| end + 1)
+    }
+
+    // Hand the guest as many whole frames as there are eventq chains for.
+    // Returns whether any chain was used, which the caller signals.
+    fn process_queue(&mut self, queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>) -> bool {
+        // What the guest took last time may have made room for what it missed,
+        // or for the backlog.
+        self.catch_up();
+        self.flush_backlog();
+        self.flush_motion();
+
+        // A kick can race with the memory table at startup. Without memory the
+        // rings can't even be read, so leave the events queued until it shows up.
+        if self.mem.is_none() {
+            if !self.warned_no_memory {
+                warn!("eventq kicked before guest memory was set, deferring events");
+                self.warned_no_memory = true;
+            }
+            return false;
+        }
+
+        // Only a whole frame goes to the guest, once there are chains for all
+        // of its events, so that it never sees one torn. A frame longer than
+        // the queue could never be held, so that one goes out in parts.
+        let queue_size = usize::from(queue.actual_size());
+        let mut used_any: bool = false;
+        'fetch: loop {
+            let needed = self.next_frame_len().min(queue_size);
+            if needed == 0 {
+                break;
+            }
+
+            if self.spare.len() < needed {
+                // Take as many heads off the avail ring at once as we may need,
+                // rather than going back to it for every event.
+                let batch = self
+                    .prefetch
+                    .min(self.events.len())
+                    .max(needed - self.spare.len());
+                let desc_chains: Vec<_> = match queue.iter() {
+                    Ok(avail) => avail.take(batch).collect(),
+                    Err(e) => {
+                        self.malformed_descriptors += 1;
+                        warn!("Failed to read the eventq avail ring: {:?}", e);
+                        break;
+                    }
+                };
+                if desc_chains.is_empty() {
+                    break;
+                }
+
+                for mut desc_chain in desc_chains {
+                    let head_index = desc_chain.head_index();
+
+                    // The chain iterator stops at a next index out of bounds and
+                    // after queue size descriptors, which is where a loop ends up.
+                    // Either way the last descriptor still claims a successor.
+                    let descs: Vec<Descriptor> = desc_chain.by_ref().collect();
+                    if descs.last().map_or(true, |desc| desc.has_next()) {
+                        self.malformed_descriptors += 1;
+                        warn!(
+                            "Skipping malformed eventq chain at {} ({} so far)",
+                            head_index, self.malformed_descriptors
+                        );
+                    } else if descs.iter().all(|desc| desc.is_write_only())
+                        && descs.iter().map(|desc| desc.len() as usize).sum::<usize>()
+                            >= size_of::<VirtioInputEvent>()
+                    {
+                        // The spec doesn't tie the event to one descriptor, so
+                        // it may span several.
+                        self.spare.push_back((head_index, descs));
+                        continue;
+                    } else if let Some(desc) = descs.first() {
+                        // The eventq is device-writable only. A buffer the guest
+                        // can't receive into is handed straight back, empty.
+                        warn!(
+                            "Returning unusable eventq descriptor {} (flags {:#x}, len {})",
+                            head_index,
+                            desc.flags(),
+                            desc.len()
+                        );
+                    }
+
+                    if let Err(e) = queue.add_used(head_index, 0) {
+                        warn!("Failed to return eventq chain {}: {:?}", head_index, e);
+                        break 'fetch;
+                    }
+                    self.counters.used_ring_adds += 1;
+                    used_any = true;
+                }
+                continue;
+            }
+
+            // Checked above. The whole frame is written before any of it is
+            // used, so that a chain that can't be written to leaves nothing
+            // of the frame with the guest.
+            let mem = self.mem.as_ref().unwrap().memory();
+            let chains: Vec<_> = self.spare.drain(..needed).collect();
+            let failed = chains
+                .iter()
+                .enumerate()
+                .find_map(|(offset, (head_index, descs))| {
+                    let event = self.events.peek_at(offset).unwrap();
+                    let e = write_event(&*mem, descs, &event).err()?;
+                    Some((offset, *head_index, e))
+                });
+            if let Some((offset, head_index, e)) = failed {
+                // Returning the chain would have the driver read whatever was
+                // in it before as an event, so it is dropped. The chains ahead
+                // of it take the frame again, with the next ones fetched.
+                self.malformed_descriptors += 1;
+                warn!(
+                    "Dropping eventq chain {}, failed to write event to guest memory: {:?}",
+                    head_index, e
+                );
+                for chain in chains.into_iter().take(offset).rev() {
+                    self.spare.push_front(chain);
+                }
+                continue;
+            }
+
+            let mut chains = chains.into_iter();
+            while let Some((head_index, _)) = chains.next() {
+                let event = self.events.pop().unwrap();
+                self.counters.events_forwarded += 1;
+                if let Some(event_log) = self.event_log.as_mut() {
+                    event_log.log(&event);
+                }
+                let len = size_of::<VirtioInputEvent>() as u32;
+                if let Err(e) = queue.add_used(head_index, len) {
+                    warn!("Failed to return eventq chain {}: {:?}", head_index, e);
+                    for chain in chains.rev() {
+                        self.spare.push_front(chain);
+                    }
+                    break 'fetch;
+                }
+                self.counters.used_ring_adds += 1;
+                used_any = true;
+            }
+        }
+
+        if used_any {
+            self.delivered = true;
+        }
+
+        used_any
+    }
+
+    // Take the events the driver sent on the statusq and pass them on to the
+    // host devices. Returns whether any buffer was used, which the caller
+    // signals.
+    fn process_status_queue(
+        &mut self,
+        queue: &mut Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
+    ) -> bool {
+        if self.mem.is_none() {
+            return false;
+        }
+
+        let mut used_any = false;
+        loop {
+            let desc_chain = match queue.iter() {
+                Ok(mut avail) => avail.next(),
+                Err(e) => {
+                    warn!("Failed to read the statusq avail ring: {:?}", e);
+                    break;
+                }
+            };
+            let mut desc_chain = match desc_chain {
+                Some(desc_chain) => desc_chain,
+                None => break,
+            };
+            let head_index = desc_chain.head_index();
+
+            // The statusq is driver-writable only, and like on the eventq an
+            // event may span several buffers.
+            let descs: Vec<Descriptor> = desc_chain.by_ref().collect();
+            let capacity: usize = descs.iter().map(|desc| desc.len() as usize).sum();
+            if descs.last().map_or(true, |desc| desc.has_next())
+                || descs.iter().any(|desc| desc.is_write_only())
+                || capacity < size_of::<VirtioInputEvent>()
+            {
+                warn!("Skipping unusable statusq chain at {}", head_index);
+            } else {
+                match read_event(desc_chain.memory(), &descs) {
+                    Ok(event) => {
+                        self.counters.statusq_events += 1;
+                        self.status_event(event)
+                    }
+                    Err(e) => warn!("Failed to read status event from guest memory: {:?}", e),
+                }
+            }
+
+            // The device writes nothing into statusq buffers.
+            if let Err(e) = queue.add_used(head_index, 0) {
+                warn!("Failed to return statusq chain {}: {:?}", head_index, e);
+                break;
+            }
+            self.counters.used_ring_adds += 1;
+            used_any = true;
+        }
+
+        used_any
+    }
+
+    // Interrupt the driver for the buffers just used, unless with
+    // VIRTIO_RING_F_EVENT_IDX it asked to hear about them later.
+    fn signal_used(&mut self, vring: &mut Vring, queue: &str) {
+        if self.event_idx {
+            match vring.mut_queue().needs_notification() {
+                Ok(false) => {
+                    self.counters.notifications_suppressed += 1;
+                    return;
+                }
+                Ok(true) => {}
+                // Better a spurious interrupt than a driver left waiting.
+                Err(e) => warn!(
+                    "Failed to check whether the {} needs signalling: {:?}",
+                    queue, e
+                ),
+            }
+        }
+        match vring.signal_used_queue() {
+            Ok(()) => self.counters.notifications_sent += 1,
+            Err(e) => warn!("Failed to signal the {}: {:?}", queue, e),
+        }
+    }
+
+    // process_queue(), and with VIRTIO_RING_F_EVENT_IDX, the avail ring checked
+    // again once kicks are back on: the driver doesn't kick for buffers it
+    // made available while they were off.
+    fn process_eventq(&mut self, vring: &mut Vring) {
+        let _span = tracing::trace_span!("process_eventq", queue = "eventq").entered();
+        if !self.event_idx {
+            if self.process_queue(vring.mut_queue()) {
+                self.signal_used(vring, "eventq");
+            }
+            return;
+        }
+
+        let mut stuck = false;
+        loop {
+            if let Err(e) = vring.mut_queue().disable_notification() {
+                warn!("Failed to disable eventq kicks: {:?}", e);
+            }
+            let spare = self.spare.len();
+            let used = self.process_queue(vring.mut_queue());
+            if used {
+                self.signal_used(vring, "eventq");
+            }
+            let progress = used || self.spare.len() != spare;
+            let more = match vring.mut_queue().enable_notification() {
+                Ok(more) => more,
+                Err(e) => {
+                    warn!("Failed to enable eventq kicks: {:?}", e);
+                    false
+                }
+            };
+            // Buffers are only worth another look with events for them, and
+            // not when two looks in a row got nowhere with them.
+            if !more || self.events.is_empty() || (stuck && !progress) {
+                break;
+            }
+            stuck = !progress;
+        }
+    }
+
+    // process_status_queue(), with the avail ring checked again the same way.
+    fn process_statusq(&mut self, vring: &mut Vring) {
+        let _span = tracing::trace_span!("process_statusq", queue = "statusq").entered();
+        if !self.event_idx {
+            if self.process_status_queue(vring.mut_queue()) {
+                self.signal_used(vring, "statusq");
+            }
+            return;
+        }
+
+        let mut stuck = false;
+        loop {
+            if let Err(e) = vring.mut_queue().disable_notification() {
+                warn!("Failed to disable statusq kicks: {:?}", e);
+            }
+            let progress = self.process_status_queue(vring.mut_queue());
+            if progress {
+                self.signal_used(vring, "statusq");
+            }
+            let more = match vring.mut_queue().enable_notification() {
+                Ok(more) => more,
+                Err(e) => {
+                    warn!("Failed to enable statusq kicks: {:?}", e);
+                    false
+                }
+            };
+            if !more || (stuck && !progress) {
+                break;
+            }
+            stuck = !progress;
+        }
+    }
+
+    // Sink for the events of the statusq: every host device gets them, and
+    // the kernel drops those a device doesn't support.
+    fn status_event(&mut self, event: VirtioInputEvent) {
+        let _event_type = logger::field(
+            "EVENT_TYPE",
+            codes::type_name(event.event_type).unwrap_or("unknown"),
+        );
+        trace!(
+            "status event: type {} code {} value {}",
+            event.event_type,
+            event.code,
+            event.value as i32
+        );
+        for source in self.evdevs.iter_mut() {
+            let value = event.value as i32;
+            let code = match (event.event_type, source.rumble) {
+                // The guest driver can't upload effects, so whatever effect
+                // it plays or stops is the device's rumble.
+                (EV_FF, Some(rumble)) if event.code < FF_GAIN => rumble as u16,
+                (EV_FF, None) if event.code < FF_GAIN => continue,
+                _ => event.code,
+            };
+            if let Err(e) = source.device.write_event(event.event_type, code, value) {
+                warn!("Failed to pass status event on to the evdev device: {}", e);
+            }
+        }
+    }
+}
+
+// What the backend is up to, for the control socket, the metrics and the
+// SIGUSR1 report.
+pub struct State {
+    // The name presented to the guest.
+    pub name: String,
+    pub connected: bool,
+    // Whether the driver negotiated VIRTIO_F_VERSION_1, i.e. gets events.
+    pub driver_ready: bool,
+    // Those offered and those the driver accepted.
+    pub features: u64,
+    pub acked_features: u64,
+    pub num_queues: usize,
+    pub queue_size: usize,
+    pub counters: Counters,
+    pub events_dropped: u64,
+    // Events of complete frames waiting for the guest to post eventq buffers.
+    pub pending_events: usize,
+    // Those along with the motion held back and the frames waiting for room
+    // in the event buffer.
+    pub buffered_events: usize,
+    // eventq chains taken that wait for enough others to hold the next frame.
+    pub eventq_buffers: usize,
+    // Host devices forwarded, and whether they're taken away from the host.
+    pub evdevs: usize,
+    pub grabbed: bool,
+    pub paused: bool,
+    // Events of the recording handed to the guest so far, out of all of them.
+    pub replay: Option<(usize, usize)>,
+}
+
+pub struct VhostUserInputBackend {
+    thread: Mutex<VhostUserInputThread>,
+    config: VirtioInputConfig,
+    device_config: DeviceConfig,
+    num_queues: usize,
+    // Sources added by an embedder, with the epoll tokens between the
+    // backend's own fds and the evdev devices.
+    num_sources: usize,
+    queue_size: usize,
+    // Upper bound on the size of a get_config reply, see --max-config-size.
+    max_config_size: usize,
+    // Stamped around every handle_event, see --worker-watchdog-ms.
+    heartbeat: Arc<Heartbeat>,
+    // Features negotiated with the driver, those offered by features() it
+    // accepted. 0 until the frontend sets them.
+    acked_features: u64,
+    // Whether a frontend is connected, from start() until wait() returns.
+    connected: bool,
+}
+
+// Configuration of a VhostUserInputBackend. Only the synthetic input eventfd
+// is required, everything else defaults to an eventq and a statusq of 1024
+// entries each, with input from that eventfd alone.
+pub struct VhostUserInputBackendBuilder {
+    input_fd: EventFd,
+    num_queues: usize,
+    queue_size: usize,
+    evdev_paths: Vec<String>,
+    // Take the evdev devices' events away from the host, see --no-grab.
+    grab: bool,
+    // Presented to the guest instead of what the evdev devices report.
+    device_config: Option<DeviceConfig>,
+    // One of profiles::NAMES, presented unless there's a device_config.
+    device_type: Option<String>,
+    name: Option<String>,
+    name_suffix: Option<String>,
+    filter: Option<FilterRules>,
+    mask: Option<CodeMask>,
+    invert_abs: Vec<u16>,
+    poll_interval: Option<Duration>,
+    syn_dropped: SynDropped,
+    max_config_size: usize,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    log_dropped: bool,
+    prefetch: usize,
+    event_buffer_size: usize,
+    overflow_policy: OverflowPolicy,
+    coalesce_rel: bool,
+    max_events_per_sec: Option<u32>,
+    // Path and rotation size of the event log.
+    event_log: Option<(String, u64)>,
+    injected: Option<Injected>,
+    hotplug: Option<(Monitor, Selector)>,
+    logind: Option<Arc<Mutex<Session>>>,
+    grab_hotkey: Option<(Vec<u16>, u32)>,
+    switch_hotkey: Option<(Vec<u16>, u32, Sender<Focus>)>,
+    mirrors: Vec<Injected>,
+    mirrored: Option<Injected>,
+    sources: Vec<Box<dyn Source>>,
+}
+
+impl VhostUserInputBackendBuilder {
+    pub fn new(input_fd: EventFd) -> Self {
+        VhostUserInputBackendBuilder {
+            input_fd,
+            num_queues: 2,
+            queue_size: 1024,
+            evdev_paths: Vec::new(),
+            grab: true,
+            device_config: None,
+            device_type: None,
+            name: None,
+            name_suffix: None,
+            filter: None,
+            mask: None,
+            invert_abs: Vec::new(),
+            poll_interval: None,
+            syn_dropped: SynDropped::Resync,
+            max_config_size: size_of::<VirtioInputConfig>(),
+            recorder: None,
+            replay: None,
+            log_dropped: false,
+            prefetch: 16,
+            event_buffer_size: EVENT_BUFFER_SIZE,
+            overflow_policy: OverflowPolicy::DropNewest,
+            coalesce_rel: false,
+            max_events_per_sec: None,
+            event_log: None,
+            injected: None,
+            hotplug: None,
+            logind: None,
+            grab_hotkey: None,
+            switch_hotkey: None,
+            mirrors: Vec::new(),
+            mirrored: None,
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn num_queues(mut self, num_queues: usize) -> Self {
+        self.num_queues = num_queues;
+        self
+    }
+
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    // Forward the host evdev device at path, opened when building. Several
+    // devices are merged into one.
+    pub fn evdev_path(mut self, path: &str) -> Self {
+        self.evdev_paths.push(path.to_string());
+        self
+    }
+
+    pub fn grab(mut self, grab: bool) -> Self {
+        self.grab = grab;
+        self
+    }
+
+    pub fn device_config(mut self, device_config: DeviceConfig) -> Self {
+        self.device_config = Some(device_config);
+        self
+    }
+
+    // Present the built-in device of the profile name, one of profiles::NAMES,
+    // see --synthetic.
+    pub fn device_type(mut self, name: &str) -> Self {
+        self.device_type = Some(name.to_string());
+        self
+    }
+
+    // Name advertised to the guest instead of the device's own, see --name.
+    pub fn name_override(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn name_suffix(mut self, suffix: &str) -> Self {
+        self.name_suffix = Some(suffix.to_string());
+        self
+    }
+
+    pub fn filter(mut self, filter: FilterRules) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Present only the events allowed by only, in the input and in the
+    // capabilities alike.
+    pub fn mask(mut self, mask: CodeMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    // Invert the absolute axis, over the range the device reports for it.
+    pub fn invert_abs(mut self, axis: u16) -> Self {
+        self.invert_abs.push(axis);
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    pub fn syn_dropped(mut self, policy: SynDropped) -> Self {
+        self.syn_dropped = policy;
+        self
+    }
+
+    pub fn max_config_size(mut self, size: usize) -> Self {
+        self.max_config_size = size;
+        self
+    }
+
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn replay(mut self, replay: Replay) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    pub fn log_dropped(mut self, enable: bool) -> Self {
+        self.log_dropped = enable;
+        self
+    }
+
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    // Hold up to size events for the guest while it takes none, early in boot
+    // in particular.
+    pub fn event_buffer_size(mut self, size: usize) -> Self {
+        self.event_buffer_size = size;
+        self
+    }
+
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    pub fn coalesce_rel(mut self, enable: bool) -> Self {
+        self.coalesce_rel = enable;
+        self
+    }
+
+    pub fn max_events_per_sec(mut self, rate: u32) -> Self {
+        self.max_events_per_sec = Some(rate);
+        self
+    }
+
+    // Log every event delivered to the guest to path, which is rotated once
+    // it reaches max_size bytes.
+    pub fn event_log(mut self, path: &str, max_size: u64) -> Self {
+        self.event_log = Some((path.to_string(), max_size));
+        self
+    }
+
+    // Also forward the events injected into injected, which signals them
+    // through the input eventfd.
+    pub fn injected(mut self, injected: Injected) -> Self {
+        self.injected = Some(injected);
+        self
+    }
+
+    // Also forward the events of source, read by the worker whenever its fd
+    // is readable, such as the libei receiver of --libei-socket.
+    pub fn source(mut self, source: Box<dyn Source>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    // Follow the device selector matches through monitor as it is unplugged
+    // and plugged in again. With no evdev path the device isn't there yet.
+    pub fn hotplug(mut self, monitor: Monitor, selector: Selector) -> Self {
+        self.hotplug = Some((monitor, selector));
+        self
+    }
+
+    // Have logind open the evdev devices, those swapped to and plugged in
+    // included, rather than opening them ourselves.
+    pub fn logind(mut self, session: Arc<Mutex<Session>>) -> Self {
+        self.logind = Some(session);
+        self
+    }
+
+    // Toggle the grab when keys are pressed together taps times in a row.
+    pub fn grab_hotkey(mut self, keys: Vec<u16>, taps: u32) -> Self {
+        self.grab_hotkey = Some((keys, taps));
+        self
+    }
+
+    // Have focus_tx move the host devices on to the next guest when keys are
+    // pressed together taps times in a row, see --switch-socket.
+    pub fn switch_hotkey(mut self, keys: Vec<u16>, taps: u32, focus_tx: Sender<Focus>) -> Self {
+        self.switch_hotkey = Some((keys, taps, focus_tx));
+        self
+    }
+
+    // Also hand every frame to the --mirror-socket guest that takes from
+    // mirror.
+    pub fn mirror(mut self, mirror: Injected) -> Self {
+        self.mirrors.push(mirror);
+        self
+    }
+
+    // As a --mirror-socket guest, take the frames of the first guest from
+    // mirrored.
+    pub fn mirrored(mut self, mirrored: Injected) -> Self {
+        self.mirrored = Some(mirrored);
+        self
+    }
+
+    // A further guest of --switch-socket or --mirror-socket, presenting the
+    // host devices exactly as the first one does, as device_config.
+    pub fn same_device(mut self, device_config: DeviceConfig) -> Self {
+        self.device_config = Some(device_config);
+        self.device_type = None;
+        self.name = None;
+        self.name_suffix = None;
+        self
+    }
+
+    pub fn build(self) -> Result<VhostUserInputBackend> {
+        if self.num_queues == 0 || self.num_queues > MAX_QUEUES {
+            return Err(Error::InvalidNumQueues(self.num_queues));
+        }
+        if !self.queue_size.is_power_of_two() || self.queue_size > MAX_QUEUE_SIZE {
+            return Err(Error::InvalidQueueSize(self.queue_size));
+        }
+        if self.prefetch == 0 {
+            return Err(Error::InvalidPrefetch);
+        }
+        if self.event_buffer_size < MIN_EVENT_BUFFER_SIZE {
+            return Err(Error::InvalidEventBufferSize(self.event_buffer_size));
+        }
+        if let Some(name) = self.device_type.as_ref() {
+            if !profiles::NAMES.contains(&name.as_str()) {
+                return Err(Error::UnknownDeviceType(name.clone()));
+            }
+        }
+
+        let mut evdevs = Vec::new();
+        for path in self.evdev_paths.iter() {
+            let device = open_evdev(self.logind.as_deref(), path)
+                .map_err(|e| Error::OpenEvdev(path.clone(), e))?;
+            if self.grab {
+                if let Err(e) = device.grab() {
+                    if e.raw_os_error() == Some(libc::EBUSY) {
+                        log_grab_holders(path);
+                    }
+                    return Err(Error::GrabEvdev(path.clone(), e));
+                }
+            }
+            evdevs.push(device);
+        }
+
+        // A device description, when given, is presented to the guest as is
+        // and takes precedence over the device type and over what the evdev
+        // devices report.
+        let mut device_config = match (self.device_config, self.device_type) {
+            (Some(device_config), _) => device_config,
+            (None, Some(name)) => profiles::by_name(&name).unwrap(),
+            (None, None) => {
+                let mut merged: Option<DeviceConfig> = None;
+                for (device, path) in evdevs.iter().zip(self.evdev_paths.iter()) {
+                    let config = DeviceConfig::from_evdev(device)
+                        .map_err(|e| Error::ConfigureEvdev(path.clone(), e))?;
+                    match merged.as_mut() {
+                        Some(merged) => merged.merge(config),
+                        None => merged = Some(config),
+                    }
+                }
+                merged.unwrap_or_default()
+            }
+        };
+        if let Some(name) = self.name.as_ref() {
+            device_config.set_name(name);
+        }
+        if let Some(suffix) = self.name_suffix.as_ref() {
+            device_config.add_name_suffix(suffix);
+        }
+        if let Some(mask) = self.mask.as_ref() {
+            device_config.retain_codes(|ev_type, code| mask.allows(ev_type, code));
+        }
+
+        let mut invert_abs = BTreeMap::new();
+        for axis in self.invert_abs {
+            match abs_range(&evdevs, &device_config, axis) {
+                Some(range) => {
+                    invert_abs.insert(axis, range);
+                }
+                None => warn!("No range known for axis {}, not inverting it", axis),
+            }
+        }
+
+        let rate_limiter = match self.max_events_per_sec {
+            Some(rate) => Some(RateLimiter::new(rate).map_err(Error::RateLimitTimer)?),
+            None => None,
+        };
+
+        let event_log = match self.event_log {
+            Some((path, max_size)) => Some(
+                EventLog::create(&path, &device_config.name, max_size)
+                    .map_err(Error::CreateEventLog)?,
+            ),
+            None => None,
+        };
+
+        let hotplug = self
+            .hotplug
+            .map(|(monitor, selector)| Hotplug { monitor, selector });
+        let num_sources = self.sources.len();
+        let thread = Mutex::new(VhostUserInputThread::new(ThreadSettings {
+            input_fd: self.input_fd,
+            filter: self.filter,
+            mask: self.mask,
+            invert_abs,
+            poll_interval: self.poll_interval,
+            evdevs: evdevs.into_iter().zip(self.evdev_paths).collect(),
+            syn_dropped: self.syn_dropped,
+            recorder: self.recorder,
+            replay: self.replay,
+            log_dropped: self.log_dropped,
+            prefetch: self.prefetch,
+            event_buffer_size: self.event_buffer_size,
+            overflow_policy: self.overflow_policy,
+            coalesce_rel: self.coalesce_rel,
+            rate_limiter,
+            event_log,
+            injected: self.injected,
+            grab: self.grab,
+            grab_hotkey: self.grab_hotkey.map(|(keys, taps)| Hotkey::new(keys, taps)),
+            switch_hotkey: self
+                .switch_hotkey
+                .map(|(keys, taps, focus_tx)| (Hotkey::new(keys, taps), focus_tx)),
+            mirrors: self.mirrors,
+            mirrored: self.mirrored,
+            sources: self.sources,
+            source_event_id: first_source_event_id(self.num_queues),
+            evdev_event_id: first_evdev_event_id(self.num_queues, num_sources),
+            hotplug,
+            logind: self.logind,
+        })?);
+
+        Ok(VhostUserInputBackend {
+            thread,
+            config: VirtioInputConfig::default(),
+            device_config,
+            num_queues: self.num_queues,
+            num_sources,
+            queue_size: self.queue_size,
+            max_config_size: self.max_config_size.min(size_of::<VirtioInputConfig>()),
+            heartbeat: Arc::new(Heartbeat::new()),
+            acked_features: 0,
+            connected: false,
+        })
+    }
+}
+
+// Added sources take one epoll token each from here on, after those of the
+// vrings and of the backend's other fds, and the evdev devices after them.
+fn first_source_event_id(num_queues: usize) -> u16 {
+    (num_queues + 7) as u16
+}
+
+fn first_evdev_event_id(num_queues: usize, num_sources: usize) -> u16 {
+    first_source_event_id(num_queues) + num_sources as u16
+}
+
+impl VhostUserInputBackend {
+    pub fn state(&self) -> State {
+        let thread = self.thread.lock().unwrap();
+        State {
+            name: self.device_config.name.clone(),
+            connected: self.connected,
+            driver_ready: self.features_ok(),
+            features: self.features(),
+            acked_features: self.acked_features,
+            num_queues: self.num_queues,
+            queue_size: self.queue_size,
+            counters: thread.counters,
+            events_dropped: thread.dropped.total(),
+            pending_events: thread.events.len(),
+            buffered_events: thread.events.len()
+                + thread.motion.len()
+                + thread.backlog.iter().map(Vec::len).sum::<usize>(),
+            eventq_buffers: thread.spare.len(),
+            evdevs: thread.evdevs.len(),
+            grabbed: thread.grab,
+            paused: thread.paused,
+            replay: thread.replay.as_ref().map(Replay::progress),
+        }
+    }
+
+    // What the guest is presented with, for further guests presenting the same
+    // device.
+    pub fn device_config(&self) -> &DeviceConfig {
+        &self.device_config
+    }
+
+    pub fn heartbeat(&self) -> Arc<Heartbeat> {
+        self.heartbeat.clone()
+    }
+
+    // Stops the worker when written to.
+    pub fn kill_evt(&self) -> io::Result<EventFd> {
+        self.thread.lock().unwrap().kill_evt.try_clone()
+    }
+
+    // A frontend connected through the daemon with vring_workers, the first or,
+    // with reconnecting, another after the last one left. Registers the
+    // synthetic input EventFd and the timers on the vring workers, along with
+    // the sources and the evdev devices the thread listens to now, which with
+    // hotplug may not be those of the last frontend.
+    pub fn started(
+        &mut self,
+        vring_workers: Vec<Arc<VringWorker>>,
+        reconnecting: bool,
+    ) -> Result<()> {
+        self.connected = true;
+        let mut thread = self.thread.lock().unwrap();
+        if reconnecting {
+            thread.reconnected();
+        }
+
+        let mut listeners = vec![(thread.input_fd.as_raw_fd(), self.input_event_id())];
+        if let Some(timer) = thread.poll_timer.as_ref() {
+            listeners.push((timer.as_raw_fd(), self.poll_event_id()));
+        }
+        if let Some(timer) = thread.drop_log_timer.as_ref() {
+            listeners.push((timer.as_raw_fd(), self.drop_log_event_id()));
+        }
+        if let Some(replay) = thread.replay.as_ref() {
+            listeners.push((replay.as_raw_fd(), self.replay_event_id()));
+        }
+        if let Some(hotplug) = thread.hotplug.as_ref() {
+            listeners.push((hotplug.monitor.as_raw_fd(), self.hotplug_event_id()));
+        }
+        if let Some(rate_limiter) = thread.rate_limiter.as_ref() {
+            listeners.push((rate_limiter.as_raw_fd(), self.rate_limit_event_id()));
+        }
+        for (fd, event_id) in listeners {
+            thread
+                .listeners
+                .add(fd, event_id)
+                .map_err(Error::RegisterVringWorker)?;
+        }
+
+        for vring_worker in vring_workers {
+            // Devices plugged in later are registered through it.
+            thread
+                .listeners
+                .attach(vring_worker)
+                .map_err(Error::RegisterVringWorker)?;
+        }
+
+        Ok(())
+    }
+
+    // The frontend is gone. Gives the host its devices back right away rather
+    // than whenever our fds get closed, unless the hotkey gave them to the
+    // host already, and stops the worker.
+    pub fn stopped(&mut self) {
+        self.connected = false;
+        let thread = self.thread.lock().unwrap();
+        if thread.grab {
+            for source in thread.evdevs.iter() {
+                if let Err(e) = source.device.ungrab() {
+                    warn!("Failed to release evdev device grab: {}", e);
+                }
+            }
+        }
+
+        if let Err(e) = thread.kill_evt.write(1) {
+            error!("Error shutting down worker thread: {:?}", e)
+        }
+    }
+
+    // Write out what is left of the recording, see --record.
+    pub fn flush_recording(&self) -> io::Result<()> {
+        match self.thread.lock().unwrap().recorder.as_mut() {
+            Some(recorder) => recorder.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.thread.lock().unwrap().pause();
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.thread.lock().unwrap().resume()
+    }
+
+    pub fn step_replay(&self) -> result::Result<(), String> {
+        self.thread.lock().unwrap().step_replay()
+    }
+
+    // Forward the evdev device at path instead of the host devices, see
+    // VhostUserInputThread::swap_evdev().
+    pub fn swap_evdev(&self, path: &str) -> result::Result<(), String> {
+        let mut thread = self.thread.lock().unwrap();
+        thread.swap_evdev(path)?;
+        thread
+            .listen_evdev(0)
+            .map_err(|e| format!("can't listen to {}: {}", path, e))
+    }
+
+    // Hand the host devices over to another --switch-socket guest.
+    pub fn take_evdevs(&self) -> Vec<EvdevSource> {
+        self.thread.lock().unwrap().take_evdevs()
+    }
+
+    // Take over the host devices of another --switch-socket guest.
+    pub fn give_evdevs(&self, evdevs: Vec<EvdevSource>) -> Result<()> {
+        self.thread.lock().unwrap().give_evdevs(evdevs)
+    }
+
+    // Undo what the frontend negotiated before it disconnected, see
+    // --persistent.
+    pub fn disconnected(&mut self) -> Result<()> {
+        self.acked_features = 0;
+        self.config = VirtioInputConfig::default();
+        self.thread.lock().unwrap().disconnected()
+    }
+
+    // epoll token for kill_evt, right after the vring kicks below num_queues.
+    fn kill_event_id(&self) -> u16 {
+        self.num_queues as u16
+    }
+
+    // epoll token for the synthetic input EventFd. Tokens up to num_queues are
+    // the kicks and kill_evt, so fds owned by the backend have to be
+    // registered after that.
+    fn input_event_id(&self) -> u16 {
+        self.num_queues as u16 + 1
+    }
+
+    fn poll_event_id(&self) -> u16 {
+        self.num_queues as u16 + 2
+    }
+
+    fn replay_event_id(&self) -> u16 {
+        self.num_queues as u16 + 3
+    }
+
+    fn drop_log_event_id(&self) -> u16 {
+        self.num_queues as u16 + 4
+    }
+
+    fn hotplug_event_id(&self) -> u16 {
+        self.num_queues as u16 + 5
+    }
+
+    fn rate_limit_event_id(&self) -> u16 {
+        self.num_queues as u16 + 6
+    }
+
+    fn source_event_id(&self, index: usize) -> u16 {
+        first_source_event_id(self.num_queues) + index as u16
+    }
+
+    fn evdev_event_id(&self, index: usize) -> u16 {
+        first_evdev_event_id(self.num_queues, self.num_sources) + index as u16
+    }
+
+    // Back to the state of a freshly initialized device, for a guest that
+    // reset it.
+    fn reset(&mut self) {
+        self.thread.lock().unwrap().reset();
+        self.config = VirtioInputConfig::default();
+    }
+
+    // A legacy driver would read the eventq with the wrong layout, so the
+    // device only comes up once VIRTIO_F_VERSION_1 is negotiated.
+    fn features_ok(&self) -> bool {
+        self.acked_features & (1 << VIRTIO_F_VERSION_1) != 0
+    }
+
+    // Refresh size and payload after the driver wrote select/subsel.
+    fn update_config(&mut self) {
+        let (select, subsel) = (self.config.select, self.config.subsel);
+        self.config.payload = [0; VIRTIO_INPUT_PAYLOAD_SIZE];
+        self.config.size = self
+            .device_config
+            .query(select, subsel, &mut self.config.payload);
+    }
+}
+
+impl VhostUserBackend for VhostUserInputBackend {
+    fn num_queues(&self) -> usize {
+        debug!("num_queues");
+
+        self.num_queues
+    }
+
+    fn max_queue_size(&self) -> usize {
+        debug!("max_queue_size");
+
+        self.queue_size
+    }
+
+    fn features(&self) -> u64 {
+        debug!("features");
+
+        // virtio-input has no feature bits of its own, the VIRTIO_INPUT_CFG_*
+        // values are config selectors.
+        1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    // The frontend sets the features on every driver initialization, which
+    // follows a device reset, and vhost-user has no message for the reset
+    // itself.
+    fn acked_features(&mut self, features: u64) {
+        debug!("acked_features");
+
+        // Whatever the frontend claims, only what we offered is in effect.
+        self.acked_features = features & self.features();
+        info!("acked features {:#x}", self.acked_features);
+        if !self.features_ok() {
+            error!(
+                "Driver didn't accept VIRTIO_F_VERSION_1 (features {:#x}), not delivering events",
+                features
+            );
+        }
+
+        self.reset();
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        debug!("protocol_features");
+
+        // REPLY_ACK lets the frontend see set_config failures instead of
+        // assuming every write succeeded.
+        VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::REPLY_ACK
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        debug!("set_event_idx");
+
+        self.thread.lock().unwrap().event_idx = enabled;
+    }
+
+    fn update_memory(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    ) -> VhostUserBackendResult<()> {
+        debug!("update_memory");
+        self.thread.lock().unwrap().mem = Some(mem);
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        evset: epoll::Events,
+        vrings: &[Arc<RwLock<Vring>>],
+        _thread_id: usize,
+    ) -> VhostUserBackendResult<bool> {
+        let _beat = self.heartbeat.beat();
+        trace!("handle event");
+
+        trace!("event received: {:#?}", device_event);
+        // Whatever else is pending, and without waiting for the thread lock:
+        // the frontend is gone, or the watchdog gave up on us.
+        if device_event == self.kill_event_id() {
+            debug!("worker exiting");
+            return Ok(true);
+        }
+        let mut thread = self.thread.lock().unwrap();
+        let source_ids = self.source_event_id(0)..self.source_event_id(self.num_sources);
+        let evdev_ids = self.evdev_event_id(0)..self.evdev_event_id(thread.evdevs.len());
+        // A removed evdev device hangs up, which read_evdev() takes care of.
+        if evset != epoll::Events::EPOLLIN && !evdev_ids.contains(&device_event) {
+            return Err(Error::HandleEventNotEpollIn.into());
+        }
+
+        match device_event {
+            EVENTQ if !self.features_ok() => {
+                warn!("eventq kicked without VIRTIO_F_VERSION_1 negotiated, ignoring it");
+
+                Ok(false)
+            }
+            EVENTQ => {
+                let _queue = logger::field("QUEUE", "eventq");
+                thread.start_replay()?;
+                thread.process_eventq(&mut vrings[0].write().unwrap());
+
+                Ok(false)
+            }
+            STATUSQ if (STATUSQ as usize) < self.num_queues => {
+                let _queue = logger::field("QUEUE", "statusq");
+                thread.process_statusq(&mut vrings[STATUSQ as usize].write().unwrap());
+
+                Ok(false)
+            }
+            id if id == self.input_event_id() => {
+                // Unlike the kick fds, nobody else drains this one for us and
+                // it is level-triggered.
+                thread.read_input()?;
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+
+                Ok(false)
+            }
+            id if id == self.poll_event_id() => {
+                if let Some(timer) = thread.poll_timer.as_mut() {
+                    timer.wait().map_err(Error::ReadPollTimer)?;
+                }
+                thread.read_input()?;
+                // A device that failed has its keys released, which the guest
+                // should get before the worker gives up.
+                let read = thread.read_evdevs();
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+                read?;
+
+                Ok(false)
+            }
+            id if id == self.drop_log_event_id() => {
+                thread.log_dropped()?;
+
+                Ok(false)
+            }
+            id if id == self.replay_event_id() => {
+                thread.replay_due()?;
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+
+                Ok(false)
+            }
+            id if id == self.rate_limit_event_id() => {
+                thread.release_motion()?;
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+
+                Ok(false)
+            }
+            id if id == self.hotplug_event_id() => {
+                if thread.attach_evdev()? {
+                    thread.listen_evdev(0).map_err(Error::RegisterEvdev)?;
+                    if self.features_ok() {
+                        thread.process_eventq(&mut vrings[0].write().unwrap());
+                    }
+                }
+
+                Ok(false)
+            }
+            id if source_ids.contains(&id) => {
+                thread.read_source((id - self.source_event_id(0)) as usize)?;
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+
+                Ok(false)
+            }
+            id if evdev_ids.contains(&id) => {
+                let index = (id - self.evdev_event_id(0)) as usize;
+                let _device = logger::field("DEVICE", &thread.evdevs[index].path);
+                let read = thread.read_evdev(index);
+                if self.features_ok() {
+                    thread.process_eventq(&mut vrings[0].write().unwrap());
+                }
+                read?;
+
+                Ok(false)
+            }
+            _ => Err(Error::HandleEventUnknownEvent.into()),
+        }
+    }
+
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        let _span = tracing::trace_span!("get_config", offset, size).entered();
+        debug!("get config");
+
+        // Never trust size for the allocation: a frontend asking for u32::MAX
+        // bytes gets at most what's left of the config space past offset.
+        let config_slice = self.config.as_slice();
+        let start = (offset as usize).min(config_slice.len());
+        let len = (size as usize)
+            .min(self.max_config_size)
+            .min(config_slice.len() - start);
+        if len < size as usize {
+            warn!(
+                "Clamping config read of {} bytes at offset {} to {}",
+                size, offset, len
+            );
+        }
+
+        config_slice[start..start + len].to_vec()
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> result::Result<(), io::Error> {
+        let _span = tracing::trace_span!("set_config", offset, size = buf.len()).entered();
+        debug!("set_config");
+
+        let config_slice = self.config.as_mut_slice();
+        // Both values come straight from the frontend, so the end of the write
+        // can overflow a u32 (or even a usize on 32-bit hosts).
+        let start = offset as usize;
+        let end = match start.checked_add(buf.len()) {
+            Some(end) if end <= config_slice.len() => end,
+            _ => {
+                error!(
+                    "Failed to write config space: offset {} len {} exceeds {} bytes",
+                    offset,
+                    buf.len(),
+                    config_slice.len()
+                );
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+        };
+
+        // Only select and subsel are writable, the rest is the device's answer.
+        if end > WRITABLE_CONFIG_SIZE {
+            error!(
+                "Failed to write config space: offset {} len {} touches read-only fields",
+                offset,
+                buf.len()
+            );
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        match buf.first() {
+            Some(&select) if start == 0 && !is_supported_select(select) => {
+                error!(
+                    "Failed to write config space: unsupported select {:#x}",
+                    select
+                );
+                return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+            }
+            _ => {}
+        }
+
+        config_slice[start..end].copy_from_slice(buf);
+        self.update_config();
+
+        Ok(())
+    }
+
+    // Registered with the worker under kill_event_id(), writing kill_evt makes
+    // handle_event() tell it to exit.
+    fn exit_event(&self, _thread_index: usize) -> Option<(EventFd, Option<u16>)> {
+        debug!("exit_event");
+
+        let kill_evt = self.thread.lock().unwrap().kill_evt.try_clone();
+        match kill_evt {
+            Ok(kill_evt) => Some((kill_evt, Some(self.kill_event_id()))),
+            Err(e) => {
+                error!("Failed to clone kill eventfd: {}", e);
+                None
+            }
+        }
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        debug!("queues_per_thread");
+
+        // A single worker owns every queue. vhost-user-backend only registers a
+        // kick fd (on SET_VRING_KICK, closing the previous one) with the worker
+        // whose mask contains that queue, so an empty list means kicks are
+        // never heard at all.
+        vec![(1 << self.num_queues) - 1]
+    }
+}
+
+// Write event across the buffers of an eventq chain, in order, which have room
+// for it between them.
+fn write_event<M: Bytes<GuestAddress>>(
+    mem: &M,
+    descs: &[Descriptor],
+    event: &VirtioInputEvent,
+) -> result::Result<(), M::E> {
+    let mut bytes = event.as_slice();
+    for desc in descs {
+        if bytes.is_empty() {
+            break;
+        }
+        let len = bytes.len().min(desc.len() as usize);
+        mem.write_slice(&bytes[..len], desc.addr())?;
+        bytes = &bytes[len..];
+    }
+
+    Ok(())
+}
+
+// Read an event the driver sent on the statusq out of the buffers of its chain.
+fn read_event<M: Bytes<GuestAddress>>(
+    mem: &M,
+    descs: &[Descriptor],
+) -> result::Result<VirtioInputEvent, M::E> {
+    let mut event = VirtioInputEvent::default();
+    let mut bytes = event.as_mut_slice();
+    for desc in descs {
+        if bytes.is_empty() {
+            break;
+        }
+        let len = bytes.len().min(desc.len() as usize);
+        mem.read_slice(&mut bytes[..len], desc.addr())?;
+        bytes = &mut bytes[len..];
+    }
+
+    Ok(event)
+}
+
+// Range of axis as reported by the first host device that has it, or as
+// presented to the guest if no host device does.
+fn abs_range(
+    evdevs: &[EvdevDevice],
+    device_config: &DeviceConfig,
+    axis: u16,
+) -> Option<(i32, i32)> {
+    for device in evdevs {
+        let has_axis = device
+            .ev_bits(EV_ABS)
+            .map_or(false, |bits| test_bit(&bits, axis));
+        if has_axis {
+            if let Ok(info) = device.abs_info(axis) {
+                return Some((info.minimum, info.maximum));
+            }
+        }
+    }
+
+    device_config
+        .abs_info
+        .get(&axis)
+        .map(|info| (info.min as i32, info.max as i32))
+}
+
+// Open the evdev device at path, through logind if there is a session.
+fn open_evdev(logind: Option<&Mutex<Session>>, path: &str) -> io::Result<EvdevDevice> {
+    match logind {
+        Some(session) => session.lock().unwrap().take_device(path),
+        None => EvdevDevice::open(path),
+    }
+}
+
+// Point the user at whoever holds the grab on the evdev device at path, most
+// likely another instance of this daemon.
+fn log_grab_holders(path: &str) {
+    let holders = evdev::holders(path);
+    if holders.is_empty() {
+        error!(
+            "{} is grabbed by another process, which couldn't be identified",
+            path
+        );
+    }
+    for (pid, comm) in holders {
+        error!(
+            "{} is grabbed by another process, held open by pid {} ({})",
+            path, pid, comm
+        );
+    }
+}
+
+// Upload the effect the guest's force feedback requests play on device, if it
+// has rumble motors.
+fn upload_rumble(device: &EvdevDevice) -> Option<i16> {
+    match device.ev_bits(EV_FF) {
+        Ok(bits) if test_bit(&bits, FF_RUMBLE) => {}
+        _ => return None,
+    }
+    match device.upload_rumble(RUMBLE_MAGNITUDE, RUMBLE_MAGNITUDE) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!(
+                "Failed to upload rumble effect, force feedback disabled: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Which --switch-socket guest gets the host devices.
+pub enum Focus {
+    // The one after the guest that has them, the first after the last.
+    Next,
+    // By index, 0 being the guest of --socket-path.
+    Guest(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codes::{EV_REL, REL_X};
+
+    const KEY_A: u16 = 30;
+    const KEY_B: u16 = 48;
+
+    const QUEUE_SIZE: u16 = 16;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const BUFFERS: u64 = 0x4000;
+
+    // VIRTQ_DESC_F_WRITE
+    const DESC_F_NEXT: u16 = 1;
+    const DESC_F_WRITE: u16 = 2;
+
+    type Memory = GuestMemoryAtomic<GuestMemoryMmap>;
+
+    fn frame(events: &[(u16, u16, i32)]) -> Vec<VirtioInputEvent> {
+        events
+            .iter()
+            .map(|&(event_type, code, value)| VirtioInputEvent {
+                event_type,
+                code,
+                value: value as u32,
+            })
+            .collect()
+    }
+
+    fn memory() -> Memory {
+        GuestMemoryAtomic::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap())
+    }
+
+    // An eventq in mem with nothing on its avail ring yet.
+    fn eventq(mem: &Memory) -> Queue<Memory> {
+        let mut queue = Queue::new(mem.clone(), QUEUE_SIZE);
+        queue.size = QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(DESC_TABLE);
+        queue.avail_ring = GuestAddress(AVAIL_RING);
+        queue.used_ring = GuestAddress(USED_RING);
+        queue
+    }
+
+    // Descriptor index, over an 8-byte buffer of its own.
+    fn set_desc(mem: &Memory, index: u16, flags: u16, next: u16) {
+        let mem = mem.memory();
+        let desc = DESC_TABLE + u64::from(index) * 16;
+        mem.write_obj(BUFFERS + u64::from(index) * 8, GuestAddress(desc))
+            .unwrap();
+        mem.write_obj(8u32, GuestAddress(desc + 8)).unwrap();
+        mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+        mem.write_obj(next, GuestAddress(desc + 14)).unwrap();
+    }
+
+    // Put the chains starting at heads on the avail ring, after those there.
+    fn make_avail(mem: &Memory, heads: &[u16]) {
+        let mem = mem.memory();
+        let mut idx: u16 = mem.read_obj(GuestAddress(AVAIL_RING + 2)).unwrap();
+        for &head in heads {
+            let slot = AVAIL_RING + 4 + u64::from(idx % QUEUE_SIZE) * 2;
+            mem.write_obj(head, GuestAddress(slot)).unwrap();
+            idx = idx.wrapping_add(1);
+        }
+        mem.write_obj(idx, GuestAddress(AVAIL_RING + 2)).unwrap();
+    }
+
+    // The chains used so far, head index and length written.
+    fn used(mem: &Memory) -> Vec<(u32, u32)> {
+        let mem = mem.memory();
+        let idx: u16 = mem.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        (0..u64::from(idx))
+            .map(|i| {
+                let elem = USED_RING + 4 + i * 8;
+                (
+                    mem.read_obj(GuestAddress(elem)).unwrap(),
+                    mem.read_obj(GuestAddress(elem + 4)).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    // The event in the buffer of descriptor index.
+    fn written(mem: &Memory, index: u16) -> (u16, u16, i32) {
+        let buffer = GuestAddress(BUFFERS + u64::from(index) * 8);
+        let event: VirtioInputEvent = mem.memory().read_obj(buffer).unwrap();
+        (event.event_type, event.code, event.value as i32)
+    }
+
+    fn builder() -> VhostUserInputBackendBuilder {
+        VhostUserInputBackendBuilder::new(EventFd::new(EFD_NONBLOCK).unwrap())
+            .device_type("keyboard")
+    }
+
+    fn backend() -> VhostUserInputBackend {
+        builder().build().unwrap()
+    }
+
+    // Take what the guest would get next off the event buffer.
+    fn pending(thread: &mut VhostUserInputThread) -> Vec<(u16, u16, i32)> {
+        let mut events = Vec::new();
+        while let Some(event) = thread.events.pop() {
+            events.push((event.event_type, event.code, event.value as i32));
+        }
+        events
+    }
+
+    fn config(backend: &VhostUserInputBackend) -> Vec<u8> {
+        backend.get_config(0, size_of::<VirtioInputConfig>() as u32)
+    }
+
+    #[test]
+    fn set_config_out_of_bounds() {
+        let mut backend = backend();
+        let before = config(&backend);
+        let big = vec![0xff; 1 << 20];
+        let writes: &[(u32, &[u8])] = &[
+            (u32::MAX, &[VIRTIO_INPUT_CFG_ID_NAME as u8]),
+            (u32::MAX, &big),
+            (u32::MAX - 1, &[0, 0]),
+            (size_of::<VirtioInputConfig>() as u32, &[0]),
+            (0, &big),
+            (0, &big[..size_of::<VirtioInputConfig>() + 1]),
+        ];
+        for &(offset, buf) in writes {
+            assert!(backend.set_config(offset, buf).is_err());
+            assert_eq!(config(&backend), before);
+        }
+    }
+
+    #[test]
+    fn read_only_eventq_descriptor() {
+        let mut backend = backend();
+        let mem = memory();
+        backend.update_memory(mem.clone()).unwrap();
+        let mut queue = eventq(&mem);
+        set_desc(&mem, 0, 0, 0);
+        set_desc(&mem, 1, DESC_F_WRITE, 0);
+        set_desc(&mem, 2, DESC_F_WRITE, 0);
+        mem.memory()
+            .write_obj(u64::MAX, GuestAddress(BUFFERS))
+            .unwrap();
+        make_avail(&mem, &[0, 1, 2]);
+
+        let mut thread = backend.thread.lock().unwrap();
+        thread.queue_events(None, frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]));
+        assert!(thread.process_queue(&mut queue));
+        // Handed back empty, and left alone.
+        assert_eq!(used(&mem), vec![(0, 0), (1, 8), (2, 8)]);
+        let buffer: u64 = mem.memory().read_obj(GuestAddress(BUFFERS)).unwrap();
+        assert_eq!(buffer, u64::MAX);
+        assert_eq!(written(&mem, 1), (EV_KEY, KEY_A, 1));
+        assert_eq!(written(&mem, 2), (EV_SYN, SYN_REPORT, 0));
+    }
+
+    #[test]
+    fn pause_resume() {
+        let backend = backend();
+        let mut thread = backend.thread.lock().unwrap();
+        let press = frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]);
+        let release = frame(&[(EV_KEY, KEY_A, 0), (EV_SYN, SYN_REPORT, 0)]);
+
+        thread.queue_events(None, press.clone());
+        thread.pause();
+        thread.resume().unwrap();
+        assert_eq!(
+            pending(&mut thread),
+            vec![
+                (EV_KEY, KEY_A, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 0),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 1),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        // Let go of while paused, so not pressed again.
+        thread.pause();
+        thread.queue_events(None, release);
+        thread.resume().unwrap();
+        assert_eq!(
+            pending(&mut thread),
+            vec![
+                (EV_KEY, KEY_A, 0),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+        assert!(thread.held_keys.is_empty());
+    }
+
+    #[test]
+    fn disconnect_reconnect() {
+        let mut backend = builder()
+            .overflow_policy(OverflowPolicy::BlockSource)
+            .event_buffer_size(MIN_EVENT_BUFFER_SIZE)
+            .build()
+            .unwrap();
+        let mem = memory();
+        backend.update_memory(mem.clone()).unwrap();
+        let mut queue = eventq(&mem);
+        set_desc(&mem, 0, DESC_F_WRITE, 0);
+        set_desc(&mem, 1, DESC_F_WRITE, 0);
+        make_avail(&mem, &[0, 1]);
+
+        {
+            let mut thread = backend.thread.lock().unwrap();
+            thread.queue_events(None, frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]));
+            assert!(thread.process_queue(&mut queue));
+            // More than the event buffer holds, with the guest out of buffers.
+            for _ in 0..MIN_EVENT_BUFFER_SIZE {
+                thread.queue_events(None, frame(&[(EV_REL, REL_X, 1), (EV_SYN, SYN_REPORT, 0)]));
+            }
+            assert!(!thread.backlog.is_empty());
+        }
+
+        backend.disconnected().unwrap();
+        let mut thread = backend.thread.lock().unwrap();
+        assert!(thread.backlog.is_empty());
+        assert!(pending(&mut thread).is_empty());
+        assert!(thread.held_keys.is_empty());
+
+        // The next frontend's driver isn't handed the key held for the last.
+        thread.reconnected();
+        thread.pause();
+        thread.resume().unwrap();
+        assert!(pending(&mut thread).is_empty());
+    }
+
+    #[test]
+    fn kicked_before_memory() {
+        let mut backend = backend();
+        // Without the rings there's nothing it may touch: not acked yet, or
+        // not a kick at all.
+        assert!(!backend
+            .handle_event(EVENTQ, epoll::Events::EPOLLIN, &[], 0)
+            .unwrap());
+        assert!(backend
+            .handle_event(EVENTQ, epoll::Events::EPOLLOUT, &[], 0)
+            .is_err());
+
+        let mem = memory();
+        let mut queue = eventq(&mem);
+        set_desc(&mem, 0, DESC_F_WRITE, 0);
+        set_desc(&mem, 1, DESC_F_WRITE, 0);
+        make_avail(&mem, &[0, 1]);
+        {
+            let mut thread = backend.thread.lock().unwrap();
+            thread.queue_events(None, frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]));
+            assert!(!thread.process_queue(&mut queue));
+            assert!(!thread.process_queue(&mut queue));
+            assert!(thread.warned_no_memory);
+            assert!(used(&mem).is_empty());
+        }
+
+        // Deferred, not lost.
+        backend.update_memory(mem.clone()).unwrap();
+        assert!(backend.thread.lock().unwrap().process_queue(&mut queue));
+        assert_eq!(used(&mem), vec![(0, 8), (1, 8)]);
+        assert_eq!(written(&mem, 0), (EV_KEY, KEY_A, 1));
+    }
+
+    #[test]
+    fn syn_dropped() {
+        // The resync query needs a real device, so it's left out here and
+        // the state it would find handed to resync_to() instead.
+        let backend = builder()
+            .evdev_path("/dev/null")
+            .grab(false)
+            .syn_dropped(SynDropped::Drop)
+            .build()
+            .unwrap();
+        let mut thread = backend.thread.lock().unwrap();
+        let event = |event_type, code, value: i32| VirtioInputEvent {
+            event_type,
+            code,
+            value: value as u32,
+        };
+
+        thread.host_event(0, event(EV_KEY, KEY_A, 1)).unwrap();
+        thread.host_event(0, event(EV_SYN, SYN_REPORT, 0)).unwrap();
+        // Lost with the broken frame, up to and including its SYN_REPORT.
+        thread.host_event(0, event(EV_KEY, KEY_B, 1)).unwrap();
+        thread.host_event(0, event(EV_SYN, SYN_DROPPED, 0)).unwrap();
+        thread.host_event(0, event(EV_KEY, KEY_A, 0)).unwrap();
+        thread.host_event(0, event(EV_SYN, SYN_REPORT, 0)).unwrap();
+        assert_eq!(
+            pending(&mut thread),
+            vec![(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]
+        );
+
+        let mut current = DeviceState::default();
+        current.update(&event(EV_KEY, KEY_B, 1));
+        thread.resync_to(0, current);
+        assert_eq!(
+            pending(&mut thread),
+            vec![
+                (EV_KEY, KEY_A, 0),
+                (EV_KEY, KEY_B, 1),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        // Back in sync, so passed through again.
+        thread.host_event(0, event(EV_KEY, KEY_B, 0)).unwrap();
+        thread.host_event(0, event(EV_SYN, SYN_REPORT, 0)).unwrap();
+        assert_eq!(
+            pending(&mut thread),
+            vec![(EV_KEY, KEY_B, 0), (EV_SYN, SYN_REPORT, 0)]
+        );
+    }
+
+    #[test]
+    fn get_config_size_max() {
+        let size = size_of::<VirtioInputConfig>();
+        let backend = backend();
+        assert_eq!(backend.get_config(0, u32::MAX), config(&backend));
+        assert_eq!(backend.get_config(8, u32::MAX).len(), size - 8);
+        assert!(backend.get_config(u32::MAX, u32::MAX).is_empty());
+
+        let backend = builder().max_config_size(8).build().unwrap();
+        assert_eq!(backend.get_config(0, u32::MAX).len(), 8);
+    }
+
+    #[test]
+    fn invalid_builder() {
+        let cases = [
+            (builder().num_queues(0), "InvalidNumQueues(0)"),
+            (builder().num_queues(MAX_QUEUES + 1), "InvalidNumQueues(3)"),
+            (builder().queue_size(0), "InvalidQueueSize(0)"),
+            (builder().queue_size(100), "InvalidQueueSize(100)"),
+            (
+                builder().queue_size(MAX_QUEUE_SIZE * 2),
+                "InvalidQueueSize(65536)",
+            ),
+            (builder().prefetch(0), "InvalidPrefetch"),
+            (
+                builder().event_buffer_size(MIN_EVENT_BUFFER_SIZE - 1),
+                "InvalidEventBufferSize(63)",
+            ),
+            (
+                builder().device_type("toaster"),
+                "UnknownDeviceType(\"toaster\")",
+            ),
+        ];
+        for (builder, expected) in cases {
+            match builder.build() {
+                Err(e) => assert_eq!(format!("{:?}", e), expected),
+                Ok(_) => panic!("built an invalid backend"),
+            }
+        }
+
+        // The limits themselves are fine.
+        assert!(builder().num_queues(1).build().is_ok());
+        assert!(builder().num_queues(MAX_QUEUES).build().is_ok());
+        assert!(builder().queue_size(1).build().is_ok());
+        assert!(builder().queue_size(MAX_QUEUE_SIZE).build().is_ok());
+        assert!(builder()
+            .event_buffer_size(MIN_EVENT_BUFFER_SIZE)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn cyclic_eventq_chain() {
+        let mut backend = backend();
+        let mem = memory();
+        backend.update_memory(mem.clone()).unwrap();
+        let mut queue = eventq(&mem);
+        set_desc(&mem, 0, DESC_F_WRITE | DESC_F_NEXT, 1);
+        set_desc(&mem, 1, DESC_F_WRITE | DESC_F_NEXT, 0);
+        set_desc(&mem, 2, DESC_F_WRITE, 0);
+        set_desc(&mem, 3, DESC_F_WRITE, 0);
+        make_avail(&mem, &[0, 2, 3]);
+
+        let mut thread = backend.thread.lock().unwrap();
+        thread.queue_events(None, frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]));
+        assert!(thread.process_queue(&mut queue));
+        // The loop is handed back empty, and the chains past it still used.
+        assert_eq!(used(&mem), vec![(0, 0), (2, 8), (3, 8)]);
+        assert_eq!(thread.malformed_descriptors, 1);
+        assert_eq!(written(&mem, 2), (EV_KEY, KEY_A, 1));
+        assert_eq!(written(&mem, 3), (EV_SYN, SYN_REPORT, 0));
+    }
+
+    #[test]
+    fn write_failure_mid_frame() {
+        let mut backend = backend();
+        let mem = memory();
+        backend.update_memory(mem.clone()).unwrap();
+        let mut queue = eventq(&mem);
+        for index in 0..3 {
+            set_desc(&mem, index, DESC_F_WRITE, 0);
+        }
+        // A buffer past the end of guest memory.
+        mem.memory()
+            .write_obj(0x10_0000u64, GuestAddress(DESC_TABLE + 16))
+            .unwrap();
+        make_avail(&mem, &[0, 1]);
+
+        let mut thread = backend.thread.lock().unwrap();
+        thread.queue_events(None, frame(&[(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]));
+        // Nothing of the frame is used, and the bad chain isn't handed back.
+        assert!(!thread.process_queue(&mut queue));
+        assert!(used(&mem).is_empty());
+        assert_eq!(thread.malformed_descriptors, 1);
+        assert_eq!(thread.events.len(), 2);
+
+        make_avail(&mem, &[2]);
+        assert!(thread.process_queue(&mut queue));
+        assert_eq!(used(&mem), vec![(0, 8), (2, 8)]);
+        assert_eq!(written(&mem, 0), (EV_KEY, KEY_A, 1));
+        assert_eq!(written(&mem, 2), (EV_SYN, SYN_REPORT, 0));
+        assert!(thread.events.is_empty());
+    }
+
+    #[test]
+    fn get_config_after_reset() {
+        let mut backend = backend();
+        backend
+            .set_config(0, &[VIRTIO_INPUT_CFG_ID_NAME as u8, 0])
+            .unwrap();
+        let selected = config(&backend);
+        assert_eq!(selected[0], VIRTIO_INPUT_CFG_ID_NAME as u8);
+        assert_ne!(selected[2], 0);
+
+        // Acking the features is where a driver that reset the device starts.
+        backend.acked_features(backend.features());
+        let unset = config(&backend);
+        assert_eq!(unset[..3], [VIRTIO_INPUT_CFG_UNSET as u8, 0, 0]);
+        assert_eq!(unset, vec![0; size_of::<VirtioInputConfig>()]);
+    }
+
+    #[test]
+    fn merged_evdev_frames() {
+        let mut backend = builder()
+            .evdev_path("/dev/null")
+            .evdev_path("/dev/null")
+            .grab(false)
+            .build()
+            .unwrap();
+        let mem = memory();
+        backend.update_memory(mem.clone()).unwrap();
+        let mut queue = eventq(&mem);
+        for index in 0..5 {
+            set_desc(&mem, index, DESC_F_WRITE, 0);
+        }
+        make_avail(&mem, &[0, 1, 2]);
+
+        let mut thread = backend.thread.lock().unwrap();
+        let events = [
+            (0, EV_KEY, KEY_A, 1),
+            (1, EV_KEY, KEY_B, 1),
+            (0, EV_KEY, KEY_A, 0),
+            (1, EV_SYN, SYN_REPORT, 0),
+            (0, EV_SYN, SYN_REPORT, 0),
+        ];
+        for &(index, event_type, code, value) in events.iter() {
+            let event = VirtioInputEvent {
+                event_type,
+                code,
+                value,
+            };
+            thread.host_event(index, event).unwrap();
+        }
+
+        // Not enough chains for the second frame, which waits for more rather
+        // than going out torn.
+        assert!(thread.process_queue(&mut queue));
+        assert_eq!(used(&mem), vec![(0, 8), (1, 8)]);
+        make_avail(&mem, &[3, 4]);
+        assert!(thread.process_queue(&mut queue));
+        assert_eq!(used(&mem), vec![(0, 8), (1, 8), (2, 8), (3, 8), (4, 8)]);
+        let written: Vec<_> = (0..5).map(|index| written(&mem, index)).collect();
+        assert_eq!(
+            written,
+            vec![
+                (EV_KEY, KEY_B, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 1),
+                (EV_KEY, KEY_A, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_config_errno() {
+        let mut backend = backend();
+        let writes: &[(u32, &[u8], Option<i32>)] = &[
+            (u32::MAX, &[0], Some(libc::EINVAL)),
+            (
+                size_of::<VirtioInputConfig>() as u32,
+                &[0],
+                Some(libc::EINVAL),
+            ),
+            // size, and the payload past it, are the device's.
+            (2, &[0], Some(libc::EINVAL)),
+            (
+                0,
+                &[VIRTIO_INPUT_CFG_ID_NAME as u8, 0, 0],
+                Some(libc::EINVAL),
+            ),
+            (8, &[0; 4], Some(libc::EINVAL)),
+            (0, &[0x55], Some(libc::ENOTSUP)),
+            (0, &[0x55, 0], Some(libc::ENOTSUP)),
+            (0, &[VIRTIO_INPUT_CFG_ID_NAME as u8, 0], None),
+            (1, &[0], None),
+        ];
+        for &(offset, buf, errno) in writes {
+            let result = backend.set_config(offset, buf);
+            assert_eq!(
+                result.map_err(|e| e.raw_os_error()).err(),
+                errno.map(Some),
+                "offset {} buf {:?}",
+                offset,
+                buf
+            );
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut backend = backend();
+        let offered = backend.features();
+        let version_1 = 1 << VIRTIO_F_VERSION_1;
+        let event_idx = 1 << VIRTIO_RING_F_EVENT_IDX;
+        assert_eq!(offered & (version_1 | event_idx), version_1 | event_idx);
+
+        // Not offered, so never acked.
+        let packed = 1 << 34;
+        assert_eq!(offered & packed, 0);
+        let cases = [
+            (0, false),
+            (event_idx, false),
+            (version_1, true),
+            (version_1 | event_idx, true),
+            (version_1 | event_idx | packed, true),
+            (u64::MAX, true),
+        ];
+        for &(requested, ok) in cases.iter() {
+            backend.acked_features(requested);
+            assert_eq!(backend.acked_features, requested & offered);
+            assert_eq!(backend.features_ok(), ok, "requested {:#x}", requested);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Merging of relative motion, see --coalesce-rel.
+
+use crate::codes::{EV_REL, EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+// Sum runs of consecutive EV_REL events on the same axis in frame into one
+// event each, e.g. three REL_X +1 into one REL_X +3. The guest ends up at the
+// same place with fewer eventq buffers. Anything else, the SYN_REPORT ending
+// the frame included, is left where it is, and so are sums that would
+// overflow.
+pub fn coalesce_rel(frame: &mut Vec<VirtioInputEvent>) {
+    let mut coalesced: Vec<VirtioInputEvent> = Vec::with_capacity(frame.len());
+    for event in frame.drain(..) {
+        if let Some(last) = coalesced.last_mut() {
+            if event.event_type == EV_REL && last.event_type == EV_REL && last.code == event.code {
+                if let Some(sum) = (last.value as i32).checked_add(event.value as i32) {
+                    last.value = sum as u32;
+                    continue;
+                }
+            }
+        }
+        coalesced.push(event);
+    }
+    *frame = coalesced;
+}
+
+// Whether frame holds relative motion and nothing else but its SYN_REPORT.
+pub fn is_motion(frame: &[VirtioInputEvent]) -> bool {
+    frame.iter().any(|event| event.event_type == EV_REL)
+        && frame.iter().all(|event| {
+            event.event_type == EV_REL || (event.event_type == EV_SYN && event.code == SYN_REPORT)
+        })
+}
+
+// Add the motion of frame to that of into, axis by axis, for two frames of
+// motion that would otherwise wait for the guest one after the other. Axes new
+// to into go before its SYN_REPORT. Returns false, leaving into as it was, if
+// a sum would overflow.
+pub fn merge_motion(into: &mut Vec<VirtioInputEvent>, frame: &[VirtioInputEvent]) -> bool {
+    let mut merged = into.clone();
+    for event in frame.iter().filter(|event| event.event_type == EV_REL) {
+        match merged
+            .iter_mut()
+            .find(|merged| merged.event_type == EV_REL && merged.code == event.code)
+        {
+            Some(merged) => match (merged.value as i32).checked_add(event.value as i32) {
+                Some(sum) => merged.value = sum as u32,
+                None => return false,
+            },
+            None => {
+                let end = merged
+                    .iter()
+                    .rposition(|event| event.event_type == EV_SYN && event.code == SYN_REPORT)
+                    .unwrap_or(merged.len());
+                merged.insert(end, *event);
+            }
+        }
+    }
+    *into = merged;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codes::EV_KEY;
+
+    const REL_X: u16 = 0;
+    const REL_Y: u16 = 1;
+    const REL_WHEEL: u16 = 8;
+    const BTN_LEFT: u16 = 0x110;
+
+    fn frame(events: &[(u16, u16, i32)]) -> Vec<VirtioInputEvent> {
+        events
+            .iter()
+            .map(|&(event_type, code, value)| VirtioInputEvent {
+                event_type,
+                code,
+                value: value as u32,
+            })
+            .collect()
+    }
+
+    fn unpack(frame: &[VirtioInputEvent]) -> Vec<(u16, u16, i32)> {
+        frame
+            .iter()
+            .map(|event| (event.event_type, event.code, event.value as i32))
+            .collect()
+    }
+
+    #[test]
+    fn motion() {
+        assert!(is_motion(&frame(&[
+            (EV_REL, REL_X, 1),
+            (EV_SYN, SYN_REPORT, 0)
+        ])));
+        assert!(!is_motion(&frame(&[(EV_SYN, SYN_REPORT, 0)])));
+        assert!(!is_motion(&frame(&[
+            (EV_REL, REL_X, 1),
+            (EV_KEY, BTN_LEFT, 1),
+            (EV_SYN, SYN_REPORT, 0)
+        ])));
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut events = frame(&[
+            (EV_REL, REL_X, 1),
+            (EV_REL, REL_X, 2),
+            (EV_REL, REL_X, -4),
+            (EV_REL, REL_X, 5),
+            (EV_SYN, SYN_REPORT, 0),
+        ]);
+        coalesce_rel(&mut events);
+        assert_eq!(
+            unpack(&events),
+            vec![(EV_REL, REL_X, 4), (EV_SYN, SYN_REPORT, 0)]
+        );
+
+        // Only runs on one axis are summed, in place.
+        let mut events = frame(&[
+            (EV_REL, REL_X, 1),
+            (EV_REL, REL_X, 1),
+            (EV_KEY, BTN_LEFT, 1),
+            (EV_REL, REL_X, 1),
+            (EV_REL, REL_Y, 1),
+            (EV_REL, REL_Y, 1),
+            (EV_REL, REL_X, i32::MAX),
+            (EV_REL, REL_X, 1),
+            (EV_SYN, SYN_REPORT, 0),
+        ]);
+        coalesce_rel(&mut events);
+        assert_eq!(
+            unpack(&events),
+            vec![
+                (EV_REL, REL_X, 2),
+                (EV_KEY, BTN_LEFT, 1),
+                (EV_REL, REL_X, 1),
+                (EV_REL, REL_Y, 2),
+                (EV_REL, REL_X, i32::MAX),
+                (EV_REL, REL_X, 1),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let mut held = frame(&[(EV_REL, REL_X, 3), (EV_SYN, SYN_REPORT, 0)]);
+        assert!(merge_motion(
+            &mut held,
+            &frame(&[
+                (EV_REL, REL_X, -1),
+                (EV_REL, REL_Y, 2),
+                (EV_SYN, SYN_REPORT, 0)
+            ])
+        ));
+        assert!(merge_motion(
+            &mut held,
+            &frame(&[
+                (EV_REL, REL_WHEEL, 1),
+                (EV_REL, REL_Y, 2),
+                (EV_SYN, SYN_REPORT, 0)
+            ])
+        ));
+        assert_eq!(
+            unpack(&held),
+            vec![
+                (EV_REL, REL_X, 2),
+                (EV_REL, REL_Y, 4),
+                (EV_REL, REL_WHEEL, 1),
+                (EV_SYN, SYN_REPORT, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_overflow() {
+        let mut held = frame(&[
+            (EV_REL, REL_Y, 1),
+            (EV_REL, REL_X, i32::MAX),
+            (EV_SYN, SYN_REPORT, 0),
+        ]);
+        let before = unpack(&held);
+        assert!(!merge_motion(
+            &mut held,
+            &frame(&[
+                (EV_REL, REL_Y, 1),
+                (EV_REL, REL_X, 1),
+                (EV_SYN, SYN_REPORT, 0)
+            ])
+        ));
+        assert_eq!(unpack(&held), before);
+    }
+}
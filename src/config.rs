@@ -0,0 +1,293 @@
+// The virtio-input config space and what the daemon fills it with, see
+// section 5.8 of the virtio spec.
+
+use std::collections::BTreeMap;
+use std::{error, fmt, fs, io};
+
+use log::info;
+use vm_memory::ByteValued;
+
+use crate::codes::{ABS_MT_SLOT, EV_ABS, EV_CNT};
+use crate::description::DeviceDescription;
+use crate::evdev::{test_bit, EvdevDevice};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to query the evdev device.
+    QueryEvdev(io::Error),
+    /// Failed to read the device description file.
+    ReadDeviceDescription(io::Error),
+    /// Failed to parse the device description file.
+    ParseDeviceDescription(serde_json::Error),
+    /// The device description doesn't describe a usable device.
+    InvalidDeviceDescription(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vhost_user_input_config_error: {:?}", self)
+    }
+}
+
+impl error::Error for Error {}
+
+pub const VIRTIO_INPUT_CFG_UNSET: u32 = 0x00;
+pub const VIRTIO_INPUT_CFG_ID_NAME: u32 = 0x01;
+pub const VIRTIO_INPUT_CFG_ID_SERIAL: u32 = 0x02;
+pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u32 = 0x03;
+pub const VIRTIO_INPUT_CFG_PROP_BITS: u32 = 0x10;
+pub const VIRTIO_INPUT_CFG_EV_BITS: u32 = 0x11;
+pub const VIRTIO_INPUT_CFG_ABS_INFO: u32 = 0x12;
+
+// Selectors a driver may write to select, see query.
+pub fn is_supported_select(select: u8) -> bool {
+    matches!(
+        u32::from(select),
+        VIRTIO_INPUT_CFG_UNSET
+            | VIRTIO_INPUT_CFG_ID_NAME
+            | VIRTIO_INPUT_CFG_ID_SERIAL
+            | VIRTIO_INPUT_CFG_ID_DEVIDS
+            | VIRTIO_INPUT_CFG_PROP_BITS
+            | VIRTIO_INPUT_CFG_EV_BITS
+            | VIRTIO_INPUT_CFG_ABS_INFO
+    )
+}
+
+// Size of the payload union, and so the longest name or bitmap we can report.
+pub const VIRTIO_INPUT_PAYLOAD_SIZE: usize = 128;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct VirtioInputAbsInfo {
+    pub min: u32,
+    pub max: u32,
+    pub fuzz: u32,
+    pub flat: u32,
+    pub res: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct VirtioInputDevIDs {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+unsafe impl ByteValued for VirtioInputAbsInfo {}
+unsafe impl ByteValued for VirtioInputDevIDs {}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct VirtioInputConfig {
+    pub select: u8,
+    pub subsel: u8,
+    pub size: u8,
+    pub reserved: [u8; 5],
+    // A union of string, bitmap, abs and ids in the spec, which member is
+    // valid depends on select and subsel.
+    pub payload: [u8; VIRTIO_INPUT_PAYLOAD_SIZE],
+}
+
+impl Default for VirtioInputConfig {
+    fn default() -> Self {
+        VirtioInputConfig {
+            select: 0,
+            subsel: 0,
+            size: 0,
+            reserved: [0; 5],
+            payload: [0; VIRTIO_INPUT_PAYLOAD_SIZE],
+        }
+    }
+}
+
+unsafe impl ByteValued for VirtioInputConfig {}
+
+// What the guest gets to see of the device, gathered once at startup and
+// served through the select/subsel window of the config space.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub serial: String,
+    pub ids: VirtioInputDevIDs,
+    pub props: Vec<u8>,
+    // Code bitmap for each supported event type.
+    pub ev_bits: BTreeMap<u8, Vec<u8>>,
+    pub abs_info: BTreeMap<u16, VirtioInputAbsInfo>,
+}
+
+fn or_bitmap(into: &mut Vec<u8>, bits: &[u8]) {
+    if into.len() < bits.len() {
+        into.resize(bits.len(), 0);
+    }
+    for (byte, bits) in into.iter_mut().zip(bits) {
+        *byte |= bits;
+    }
+}
+
+// The longest prefix of s that fits in max bytes without splitting a char.
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// Bitmaps are reported up to their last set bit.
+fn trim_bitmap(bits: &[u8]) -> &[u8] {
+    let len = bits.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    &bits[..len]
+}
+
+impl DeviceConfig {
+    pub fn from_evdev(device: &EvdevDevice) -> Result<Self> {
+        let mut config = DeviceConfig::default();
+
+        config.set_name(&device.name().map_err(Error::QueryEvdev)?);
+        let serial = device.uniq().map_err(Error::QueryEvdev)?;
+        config.serial = truncate_utf8(&serial, VIRTIO_INPUT_PAYLOAD_SIZE).to_string();
+        let ids = device.ids().map_err(Error::QueryEvdev)?;
+        config.ids = VirtioInputDevIDs {
+            bustype: ids.bustype,
+            vendor: ids.vendor,
+            product: ids.product,
+            version: ids.version,
+        };
+        config.props = device.props().map_err(Error::QueryEvdev)?;
+
+        // Type 0 gets the bitmap of the supported types rather than of codes.
+        let types = device.ev_bits(0).map_err(Error::QueryEvdev)?;
+        for ev_type in 1..EV_CNT {
+            if !test_bit(&types, ev_type) {
+                continue;
+            }
+            let bits = device.ev_bits(ev_type).map_err(Error::QueryEvdev)?;
+            if bits.iter().any(|b| *b != 0) {
+                config.ev_bits.insert(ev_type as u8, bits);
+            }
+        }
+
+        let abs_bits = config
+            .ev_bits
+            .get(&(EV_ABS as u8))
+            .cloned()
+            .unwrap_or_default();
+        for axis in 0..(abs_bits.len() * 8) as u16 {
+            if test_bit(&abs_bits, axis) && axis != ABS_MT_SLOT {
+                let info = device.abs_info(axis).map_err(Error::QueryEvdev)?;
+                config.abs_info.insert(
+                    axis,
+                    VirtioInputAbsInfo {
+                        min: info.minimum as u32,
+                        max: info.maximum as u32,
+                        fuzz: info.fuzz as u32,
+                        flat: info.flat as u32,
+                        res: info.resolution as u32,
+                    },
+                );
+            }
+        }
+        if test_bit(&abs_bits, ABS_MT_SLOT) {
+            // The guest sizes its slot array from max, so report the slots as
+            // numbered from 0 whatever the driver put in the minimum.
+            let info = device.abs_info(ABS_MT_SLOT).map_err(Error::QueryEvdev)?;
+            let max = info.maximum.max(0) as u32;
+            info!("multitouch device with {} slots", max + 1);
+            config.abs_info.insert(
+                ABS_MT_SLOT,
+                VirtioInputAbsInfo {
+                    min: 0,
+                    max,
+                    ..Default::default()
+                },
+            );
+        }
+
+        Ok(config)
+    }
+
+    // Fold in the capabilities of another device forwarded through the same
+    // virtio device. Event codes and properties are united; identity and the
+    // range of an axis both devices report stay those of the first device.
+    pub fn merge(&mut self, other: DeviceConfig) {
+        or_bitmap(&mut self.props, &other.props);
+        for (ev_type, bits) in other.ev_bits {
+            or_bitmap(self.ev_bits.entry(ev_type).or_default(), &bits);
+        }
+        for (axis, info) in other.abs_info {
+            self.abs_info.entry(axis).or_insert(info);
+        }
+    }
+
+    // Mask the event codes allows() rejects out of what the guest is told the
+    // device supports, dropping types that are left with no codes at all.
+    pub fn retain_codes<F: Fn(u16, u16) -> bool>(&mut self, allows: F) {
+        for (&ev_type, bits) in self.ev_bits.iter_mut() {
+            for code in 0..(bits.len() * 8) as u16 {
+                if test_bit(bits, code) && !allows(u16::from(ev_type), code) {
+                    bits[code as usize / 8] &= !(1 << (code % 8));
+                }
+            }
+        }
+        self.ev_bits.retain(|_, bits| bits.iter().any(|b| *b != 0));
+        self.abs_info.retain(|&axis, _| allows(EV_ABS, axis));
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = truncate_utf8(name, VIRTIO_INPUT_PAYLOAD_SIZE).to_string();
+    }
+
+    // Append " suffix" to the name, shortening the name rather than the suffix
+    // if both don't fit in the config space.
+    pub fn add_name_suffix(&mut self, suffix: &str) {
+        let suffix = truncate_utf8(suffix, VIRTIO_INPUT_PAYLOAD_SIZE - 1);
+        let name = truncate_utf8(&self.name, VIRTIO_INPUT_PAYLOAD_SIZE - 1 - suffix.len());
+        self.name = format!("{} {}", name, suffix);
+    }
+
+    pub fn from_description(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(Error::ReadDeviceDescription)?;
+        let description: DeviceDescription =
+            serde_json::from_str(&text).map_err(Error::ParseDeviceDescription)?;
+        description
+            .into_device_config()
+            .map_err(Error::InvalidDeviceDescription)
+    }
+
+    // Fill payload with the answer to (select, subsel), returning its size.
+    pub fn query(&self, select: u8, subsel: u8, payload: &mut [u8]) -> u8 {
+        let data = match u32::from(select) {
+            VIRTIO_INPUT_CFG_ID_NAME => self.name.as_bytes(),
+            VIRTIO_INPUT_CFG_ID_SERIAL => self.serial.as_bytes(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS => self.ids.as_slice(),
+            VIRTIO_INPUT_CFG_PROP_BITS => trim_bitmap(&self.props),
+            VIRTIO_INPUT_CFG_EV_BITS => match self.ev_bits.get(&subsel) {
+                Some(bits) => trim_bitmap(bits),
+                None => return 0,
+            },
+            VIRTIO_INPUT_CFG_ABS_INFO => match self.abs_info.get(&u16::from(subsel)) {
+                Some(info) => info.as_slice(),
+                None => return 0,
+            },
+            _ => return 0,
+        };
+        let len = data.len().min(payload.len());
+        payload[..len].copy_from_slice(&data[..len]);
+
+        len as u8
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct VirtioInputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+unsafe impl ByteValued for VirtioInputEvent {}
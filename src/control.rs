@@ -0,0 +1,332 @@
+// Runtime control of the daemon over a unix socket, see --control-socket.
+//
+// Clients write one JSON command per line and get a JSON reply line for each,
+// {"ok": true} with whatever the command returns, or {"ok": false, "error":
+// "..."} if it failed:
+//
+//     {"cmd": "key", "code": "KEY_A", "value": 1}
+//     {"cmd": "rel", "code": "REL_X", "value": -5}
+//     {"cmd": "abs", "code": "ABS_X", "value": 16384}
+//     {"cmd": "syn"}
+//     {"cmd": "type", "text": "Hello, world!\n"}
+//     {"cmd": "status"}
+//     {"cmd": "query-stats"}
+//     {"cmd": "pause"}
+//     {"cmd": "resume"}
+//     {"cmd": "step"}
+//     {"cmd": "swap", "path": "/dev/input/event7"}
+//     {"cmd": "focus", "guest": 1}
+//
+// Codes are names or numbers. As with host input, key, rel and abs events
+// only reach the guest with the syn ending their frame.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::codes::{self, EV_ABS, EV_KEY, EV_REL, EV_SYN, SYN_REPORT};
+use crate::description::{resolve, Code};
+use crate::VirtioInputEvent;
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Command {
+    Key {
+        code: Code,
+        value: i32,
+    },
+    Rel {
+        code: Code,
+        value: i32,
+    },
+    Abs {
+        code: Code,
+        value: i32,
+    },
+    Syn,
+    // Type ASCII text on a US layout, a frame per key press and release.
+    Type {
+        text: String,
+    },
+    // Reply with the Status of the device.
+    Status,
+    // Reply with the Stats of the device.
+    #[serde(rename = "query-stats")]
+    QueryStats,
+    // Stop forwarding host input to the guest, until resumed.
+    Pause,
+    Resume,
+    // Play the next frame of the replay, see --replay-step.
+    Step,
+    // Forward the evdev device at path instead of the current host devices.
+    Swap {
+        path: String,
+    },
+    // Hand the host devices to the guest-th guest, see --switch-socket.
+    Focus {
+        guest: usize,
+    },
+}
+
+// The key typing c on a US layout, and whether it takes shift.
+fn typed_key(c: char) -> Option<(&'static str, bool)> {
+    let key = match c.to_ascii_lowercase() {
+        'a' => "KEY_A",
+        'b' => "KEY_B",
+        'c' => "KEY_C",
+        'd' => "KEY_D",
+        'e' => "KEY_E",
+        'f' => "KEY_F",
+        'g' => "KEY_G",
+        'h' => "KEY_H",
+        'i' => "KEY_I",
+        'j' => "KEY_J",
+        'k' => "KEY_K",
+        'l' => "KEY_L",
+        'm' => "KEY_M",
+        'n' => "KEY_N",
+        'o' => "KEY_O",
+        'p' => "KEY_P",
+        'q' => "KEY_Q",
+        'r' => "KEY_R",
+        's' => "KEY_S",
+        't' => "KEY_T",
+        'u' => "KEY_U",
+        'v' => "KEY_V",
+        'w' => "KEY_W",
+        'x' => "KEY_X",
+        'y' => "KEY_Y",
+        'z' => "KEY_Z",
+        '1' | '!' => "KEY_1",
+        '2' | '@' => "KEY_2",
+        '3' | '#' => "KEY_3",
+        '4' | '$' => "KEY_4",
+        '5' | '%' => "KEY_5",
+        '6' | '^' => "KEY_6",
+        '7' | '&' => "KEY_7",
+        '8' | '*' => "KEY_8",
+        '9' | '(' => "KEY_9",
+        '0' | ')' => "KEY_0",
+        '-' | '_' => "KEY_MINUS",
+        '=' | '+' => "KEY_EQUAL",
+        '[' | '{' => "KEY_LEFTBRACE",
+        ']' | '}' => "KEY_RIGHTBRACE",
+        '\\' | '|' => "KEY_BACKSLASH",
+        ';' | ':' => "KEY_SEMICOLON",
+        '\'' | '"' => "KEY_APOSTROPHE",
+        '`' | '~' => "KEY_GRAVE",
+        ',' | '<' => "KEY_COMMA",
+        '.' | '>' => "KEY_DOT",
+        '/' | '?' => "KEY_SLASH",
+        ' ' => "KEY_SPACE",
+        '\t' => "KEY_TAB",
+        '\n' => "KEY_ENTER",
+        _ => return None,
+    };
+    let shift = c.is_ascii_uppercase() || "!@#$%^&*()_+{}|:\"~<>?".contains(c);
+
+    Some((key, shift))
+}
+
+fn key(name: &str, value: u32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type: EV_KEY,
+        code: codes::code_by_name(name).unwrap().1,
+        value,
+    }
+}
+
+const SYN: VirtioInputEvent = VirtioInputEvent {
+    event_type: EV_SYN,
+    code: SYN_REPORT,
+    value: 0,
+};
+
+// Press and release the keys typing text, holding shift where needed.
+fn type_text(text: &str) -> Result<Vec<VirtioInputEvent>, String> {
+    let mut events = Vec::new();
+    for c in text.chars() {
+        let (name, shift) = typed_key(c).ok_or_else(|| format!("can't type {:?}", c))?;
+        if shift {
+            events.push(key("KEY_LEFTSHIFT", 1));
+        }
+        events.extend_from_slice(&[key(name, 1), SYN, key(name, 0)]);
+        if shift {
+            events.push(key("KEY_LEFTSHIFT", 0));
+        }
+        events.push(SYN);
+    }
+
+    Ok(events)
+}
+
+fn event(event_type: u16, code: &Code, value: i32) -> Result<VirtioInputEvent, String> {
+    Ok(VirtioInputEvent {
+        event_type,
+        code: resolve(event_type, code)?,
+        value: value as u32,
+    })
+}
+
+impl Command {
+    // The events to inject for key, rel, abs, syn and type, None for the
+    // others.
+    pub fn events(&self) -> Result<Option<Vec<VirtioInputEvent>>, String> {
+        let events = match self {
+            Command::Key { code, value } => vec![event(EV_KEY, code, *value)?],
+            Command::Rel { code, value } => vec![event(EV_REL, code, *value)?],
+            Command::Abs { code, value } => vec![event(EV_ABS, code, *value)?],
+            Command::Syn => vec![SYN],
+            Command::Type { text } => type_text(text)?,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(events))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    pub name: String,
+    // Whether host input is paused.
+    pub paused: bool,
+    // Whether the guest driver negotiated features, i.e. gets events.
+    pub driver_ready: bool,
+    // Host devices forwarded.
+    pub evdevs: usize,
+    // Events waiting for the guest to post eventq buffers.
+    pub pending_events: usize,
+    // Events dropped since startup, for any reason.
+    pub dropped_events: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay: Option<ReplayStatus>,
+}
+
+#[derive(Serialize)]
+pub struct ReplayStatus {
+    // Events of the recording handed to the guest so far.
+    pub played: usize,
+    pub events: usize,
+}
+
+// Counters of the device since startup.
+#[derive(Serialize)]
+pub struct Stats {
+    pub events_forwarded: u64,
+    pub events_dropped: u64,
+    pub frames: u64,
+    pub used_ring_adds: u64,
+    pub notifications_sent: u64,
+    pub notifications_suppressed: u64,
+    pub statusq_events: u64,
+}
+
+// What a command replies with, besides whether it went well.
+pub enum Answer {
+    Status(Status),
+    Stats(Stats),
+}
+
+#[derive(Serialize)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
+}
+
+// Carries out a command, returning the answer for the ones replying with one.
+pub type Handler = dyn Fn(Command) -> Result<Option<Answer>, String> + Send + Sync;
+
+// Answer the commands of one client until it hangs up.
+fn serve(stream: UnixStream, handler: Arc<Handler>) {
+    let mut replies = match stream.try_clone() {
+        Ok(replies) => replies,
+        Err(e) => {
+            warn!("Failed to set up control client: {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = serde_json::from_str(&line)
+            .map_err(|e| format!("invalid command: {}", e))
+            .and_then(|command| handler(command));
+        let reply = match result {
+            Ok(Some(Answer::Status(status))) => Reply {
+                ok: true,
+                error: None,
+                status: Some(status),
+                stats: None,
+            },
+            Ok(Some(Answer::Stats(stats))) => Reply {
+                ok: true,
+                error: None,
+                status: None,
+                stats: Some(stats),
+            },
+            Ok(None) => Reply {
+                ok: true,
+                error: None,
+                status: None,
+                stats: None,
+            },
+            Err(error) => Reply {
+                ok: false,
+                error: Some(error),
+                status: None,
+                stats: None,
+            },
+        };
+        let reply = match serde_json::to_string(&reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("Failed to serialize control reply: {}", e);
+                break;
+            }
+        };
+        if writeln!(replies, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
+
+// Accept clients on the socket at path, each served by a thread of its own.
+pub fn listen(path: &str, handler: Arc<Handler>) -> io::Result<()> {
+    // Replace a socket left behind by an earlier run, but nothing else.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler = handler.clone();
+                    thread::spawn(move || serve(stream, handler));
+                }
+                Err(e) => warn!("Failed to accept control client: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+// Detaching from the terminal, see --daemon.
+//
+// The fork has to come before any thread is started, as only the forking
+// thread lives on in the child. The parent waits until the child is ready for
+// a frontend, or fails, and exits with a status to match, so that whoever
+// started us can tell, and startup errors still show on the terminal. Only
+// once ready does the child redirect its output to the log and let the parent
+// go. The working directory stays, for paths given later, e.g. to swap.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process;
+
+// The child's end of the pipe to the parent, and where its output goes.
+pub struct Detached {
+    parent: File,
+    log: Option<String>,
+}
+
+// Fork into the background, the parent not returning. log, if any, gets the
+// output once the child is ready, which otherwise goes nowhere.
+pub fn detach(log: Option<&str>) -> io::Result<Detached> {
+    let mut fds = [0; 2];
+    // pipe2() writes the two fds, and nothing else, into fds.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Both were just created, each File is the sole owner of its fd.
+    let (mut child, parent) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // No thread has been started yet, so the child is left with nothing
+    // half-done by another one.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => {
+            drop(parent);
+            // The child writes a byte once ready, or exits without.
+            let mut ready = [0; 1];
+            let status = match child.read(&mut ready) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            process::exit(status);
+        }
+    }
+    drop(child);
+
+    // Out of the terminal's session, so that hanging it up leaves us alone.
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    redirect(&File::open("/dev/null")?, libc::STDIN_FILENO)?;
+
+    Ok(Detached {
+        parent,
+        log: log.map(str::to_string),
+    })
+}
+
+impl Detached {
+    // Send the output to the log, and let the parent exit successfully.
+    pub fn ready(mut self) -> io::Result<()> {
+        let log = match self.log.as_ref() {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+            None => OpenOptions::new().write(true).open("/dev/null")?,
+        };
+        io::stdout().flush()?;
+        redirect(&log, libc::STDOUT_FILENO)?;
+        redirect(&log, libc::STDERR_FILENO)?;
+        self.parent.write_all(&[0])
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()> {
+    // dup2() only replaces fd, which the standard streams keep using.
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Record our pid at path for init scripts, replacing whatever is there.
+pub fn write_pidfile(path: &str) -> io::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+}
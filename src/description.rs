@@ -0,0 +1,229 @@
+// Device descriptions loaded with --device-description: a JSON file saying
+// exactly what the guest is told about the device, instead of asking a host
+// evdev node. Useful to reproduce a device on a machine that doesn't have it.
+//
+//     {
+//         "name": "Wacom Intuos S Pen",
+//         "serial": "8CQ00L1034960",
+//         "ids": { "bustype": 3, "vendor": 1386, "product": 884, "version": 256 },
+//         "props": ["INPUT_PROP_POINTER"],
+//         "events": {
+//             "EV_KEY": ["BTN_TOOL_PEN", "BTN_TOUCH", "BTN_STYLUS"],
+//             "EV_ABS": ["ABS_X", "ABS_Y", "ABS_PRESSURE"],
+//             "EV_MSC": [4]
+//         },
+//         "abs": {
+//             "ABS_X": { "min": 0, "max": 15200, "res": 100 },
+//             "ABS_Y": { "min": 0, "max": 9500, "res": 100 },
+//             "ABS_PRESSURE": { "max": 4095 }
+//         }
+//     }
+//
+// Everything but events is optional. Codes are given by name or number, and
+// every axis listed under EV_ABS needs its range in abs. --print-capabilities
+// writes the description of an evdev device in this format.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codes::{self, EV_ABS, EV_SYN};
+use crate::config::{DeviceConfig, VirtioInputAbsInfo, VirtioInputDevIDs};
+use crate::evdev::{set_bit, test_bit};
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Code {
+    Number(u16),
+    Name(String),
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Ids {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Abs {
+    min: i32,
+    max: i32,
+    fuzz: i32,
+    flat: i32,
+    res: i32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DeviceDescription {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    serial: String,
+    #[serde(default)]
+    ids: Ids,
+    #[serde(default)]
+    props: Vec<Code>,
+    events: BTreeMap<String, Vec<Code>>,
+    #[serde(default)]
+    abs: BTreeMap<String, Abs>,
+}
+
+pub fn resolve(ev_type: u16, code: &Code) -> Result<u16, String> {
+    match code {
+        Code::Number(code) => Ok(*code),
+        Code::Name(name) => match codes::code_by_name(name) {
+            Some((t, code)) if t == ev_type => Ok(code),
+            Some(_) => Err(format!(
+                "{} is not a {} code",
+                name,
+                codes::type_name(ev_type).unwrap_or("?")
+            )),
+            None => Err(format!("unknown event code {}", name)),
+        },
+    }
+}
+
+// A code by name if it has one, by number otherwise.
+fn code(ev_type: u16, code: u16) -> Code {
+    match codes::code_name(ev_type, code) {
+        Some(name) => Code::Name(name.to_string()),
+        None => Code::Number(code),
+    }
+}
+
+fn set_bits(bits: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    (0..(bits.len() * 8) as u16).filter(move |&bit| test_bit(bits, bit))
+}
+
+impl DeviceDescription {
+    pub fn from_device_config(config: &DeviceConfig) -> Self {
+        let props = set_bits(&config.props)
+            .map(|prop| match codes::prop_name(prop) {
+                Some(name) => Code::Name(name.to_string()),
+                None => Code::Number(prop),
+            })
+            .collect();
+
+        // Types without a name can't be described, but no device has any.
+        let events = config
+            .ev_bits
+            .iter()
+            .filter_map(|(&ev_type, bits)| {
+                let ev_type = u16::from(ev_type);
+                let name = codes::type_name(ev_type)?;
+                Some((
+                    name.to_string(),
+                    set_bits(bits).map(|c| code(ev_type, c)).collect(),
+                ))
+            })
+            .collect();
+
+        let abs = config
+            .abs_info
+            .iter()
+            .map(|(&axis, info)| {
+                let name = match codes::code_name(EV_ABS, axis) {
+                    Some(name) => name.to_string(),
+                    None => axis.to_string(),
+                };
+                let abs = Abs {
+                    min: info.min as i32,
+                    max: info.max as i32,
+                    fuzz: info.fuzz as i32,
+                    flat: info.flat as i32,
+                    res: info.res as i32,
+                };
+                (name, abs)
+            })
+            .collect();
+
+        DeviceDescription {
+            name: config.name.clone(),
+            serial: config.serial.clone(),
+            ids: Ids {
+                bustype: config.ids.bustype,
+                vendor: config.ids.vendor,
+                product: config.ids.product,
+                version: config.ids.version,
+            },
+            props,
+            events,
+            abs,
+        }
+    }
+
+    pub fn into_device_config(self) -> Result<DeviceConfig, String> {
+        let mut config = DeviceConfig {
+            name: self.name,
+            serial: self.serial,
+            ids: VirtioInputDevIDs {
+                bustype: self.ids.bustype,
+                vendor: self.ids.vendor,
+                product: self.ids.product,
+                version: self.ids.version,
+            },
+            ..Default::default()
+        };
+
+        for prop in self.props.iter() {
+            let prop = match prop {
+                Code::Number(prop) => *prop,
+                Code::Name(name) => codes::prop_by_name(name)
+                    .ok_or_else(|| format!("unknown input property {}", name))?,
+            };
+            set_bit(&mut config.props, prop);
+        }
+
+        for (type_name, codes) in self.events.iter() {
+            let ev_type = codes::type_by_name(type_name)
+                .ok_or_else(|| format!("unknown event type {}", type_name))?;
+            let bits = config.ev_bits.entry(ev_type as u8).or_default();
+            for code in codes.iter() {
+                set_bit(bits, resolve(ev_type, code)?);
+            }
+        }
+
+        for (axis_name, abs) in self.abs.iter() {
+            let axis = match axis_name.parse() {
+                Ok(axis) => axis,
+                Err(_) => resolve(EV_ABS, &Code::Name(axis_name.clone()))?,
+            };
+            config.abs_info.insert(
+                axis,
+                VirtioInputAbsInfo {
+                    min: abs.min as u32,
+                    max: abs.max as u32,
+                    fuzz: abs.fuzz as u32,
+                    flat: abs.flat as u32,
+                    res: abs.res as u32,
+                },
+            );
+        }
+
+        if let Some(abs_bits) = config.ev_bits.get(&(EV_ABS as u8)) {
+            for axis in 0..(abs_bits.len() * 8) as u16 {
+                if test_bit(abs_bits, axis) && !config.abs_info.contains_key(&axis) {
+                    return Err(format!(
+                        "no abs range for {}",
+                        codes::code_name(EV_ABS, axis).unwrap_or("axis")
+                    ));
+                }
+            }
+        }
+
+        // A guest driver ignores a device it can't get a single event out of.
+        let usable = config
+            .ev_bits
+            .iter()
+            .any(|(ev_type, bits)| u16::from(*ev_type) != EV_SYN && bits.iter().any(|b| *b != 0));
+        if !usable {
+            return Err("no event type with any codes".to_string());
+        }
+
+        Ok(config)
+    }
+}
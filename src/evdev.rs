@@ -0,0 +1,600 @@
+// Thin wrapper around a host evdev node (/dev/input/eventN) and the ioctls
+// used to query it, see include/uapi/linux/input.h.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::debug;
+
+use crate::codes::{self, EV_ABS, FF_RUMBLE};
+
+pub const INPUT_DIR: &str = "/dev/input";
+const BY_ID_DIR: &str = "/dev/input/by-id";
+const SYSFS_INPUT_DIR: &str = "/sys/class/input";
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+// Set by --strace-ioctls.
+static TRACE_IOCTLS: AtomicBool = AtomicBool::new(false);
+
+pub fn trace_ioctls(enable: bool) {
+    TRACE_IOCTLS.store(enable, Ordering::Relaxed);
+}
+
+// Size of struct input_event as the kernel hands it out. Its time is a pair of
+// __kernel_ulong_t, so that it stays 16 bytes for 32-bit userspace even where
+// libc has a 64-bit time_t.
+const KERNEL_EVENT_SIZE: usize = 2 * size_of::<libc::c_ulong>() + 8;
+
+// The most event_size() can be, a timeval of two 64-bit fields.
+const MAX_EVENT_SIZE: usize = 24;
+
+// Size of the events read from the host, libc's unless forced to the kernel's
+// with --time64.
+static EVENT_SIZE: AtomicUsize = AtomicUsize::new(size_of::<libc::input_event>());
+
+pub fn force_kernel_layout(enable: bool) {
+    if enable {
+        EVENT_SIZE.store(KERNEL_EVENT_SIZE, Ordering::Relaxed);
+    }
+}
+
+pub fn event_size() -> usize {
+    EVENT_SIZE.load(Ordering::Relaxed)
+}
+
+// Decode one event of event_size() bytes: a timeval of two longs, which are
+// either both 32 or both 64-bit, then type, code and value.
+fn decode_event(raw: &[u8]) -> libc::input_event {
+    let long = (raw.len() - 8) / 2;
+    let field = |at: usize| -> i64 {
+        if long == 8 {
+            i64::from_ne_bytes(raw[at..at + 8].try_into().unwrap())
+        } else {
+            i64::from(i32::from_ne_bytes(raw[at..at + 4].try_into().unwrap()))
+        }
+    };
+    let rest = 2 * long;
+
+    libc::input_event {
+        time: libc::timeval {
+            tv_sec: field(0) as _,
+            tv_usec: field(long) as _,
+        },
+        type_: u16::from_ne_bytes(raw[rest..rest + 2].try_into().unwrap()),
+        code: u16::from_ne_bytes(raw[rest + 2..rest + 4].try_into().unwrap()),
+        value: i32::from_ne_bytes(raw[rest + 4..rest + 8].try_into().unwrap()),
+    }
+}
+
+// Builds the request number of an evdev ioctl, the equivalent of the kernel's
+// _IOC(dir, 'E', nr, size).
+const fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+const EVIOCGID: u32 = ioc(IOC_READ, 0x02, size_of::<libc::input_id>());
+
+const fn eviocgname(len: usize) -> u32 {
+    ioc(IOC_READ, 0x06, len)
+}
+
+const fn eviocguniq(len: usize) -> u32 {
+    ioc(IOC_READ, 0x08, len)
+}
+
+const fn eviocgprop(len: usize) -> u32 {
+    ioc(IOC_READ, 0x09, len)
+}
+
+// EVIOCGKEY, EVIOCGLED and EVIOCGSW, the current state bitmaps of keys, LEDs
+// and switches.
+const fn eviocgstate(nr: u32, len: usize) -> u32 {
+    ioc(IOC_READ, nr, len)
+}
+
+const EVIOCGKEY_NR: u32 = 0x18;
+const EVIOCGLED_NR: u32 = 0x19;
+const EVIOCGSW_NR: u32 = 0x1b;
+
+// EVIOCGRAB, exclusive access to the device's events.
+const EVIOCGRAB: u32 = ioc(IOC_WRITE, 0x90, size_of::<libc::c_int>());
+
+// EVIOCSFF, upload a force feedback effect.
+const EVIOCSFF: u32 = ioc(IOC_WRITE, 0x80, size_of::<libc::ff_effect>());
+
+const fn eviocgbit(ev_type: u16, len: usize) -> u32 {
+    ioc(IOC_READ, 0x20 + ev_type as u32, len)
+}
+
+const fn eviocgabs(axis: u16) -> u32 {
+    ioc(
+        IOC_READ,
+        0x40 + axis as u32,
+        size_of::<libc::input_absinfo>(),
+    )
+}
+
+// Human readable form of a request built by the functions above.
+fn request_name(request: u32) -> String {
+    let nr = request & 0xff;
+    let size = (request >> 16) & 0x3fff;
+    match nr {
+        0x02 => "EVIOCGID".to_string(),
+        0x06 => format!("EVIOCGNAME({})", size),
+        0x08 => format!("EVIOCGUNIQ({})", size),
+        0x09 => format!("EVIOCGPROP({})", size),
+        0x80 => "EVIOCSFF".to_string(),
+        0x90 => "EVIOCGRAB".to_string(),
+        EVIOCGKEY_NR => format!("EVIOCGKEY({})", size),
+        EVIOCGLED_NR => format!("EVIOCGLED({})", size),
+        EVIOCGSW_NR => format!("EVIOCGSW({})", size),
+        0x20..=0x3f => {
+            let ev_type = (nr - 0x20) as u16;
+            match codes::type_name(ev_type) {
+                Some(name) => format!("EVIOCGBIT({}, {})", name, size),
+                None => format!("EVIOCGBIT({}, {})", ev_type, size),
+            }
+        }
+        0x40..=0x7f => {
+            let axis = (nr - 0x40) as u16;
+            match codes::code_name(EV_ABS, axis) {
+                Some(name) => format!("EVIOCGABS({})", name),
+                None => format!("EVIOCGABS({})", axis),
+            }
+        }
+        _ => format!("{:#x}", request),
+    }
+}
+
+pub struct EvdevDevice {
+    file: File,
+}
+
+impl EvdevDevice {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        Ok(EvdevDevice { file })
+    }
+
+    // A device opened elsewhere, e.g. handed to us by logind. As with open(),
+    // reads are expected not to block.
+    pub fn from_file(file: File) -> Self {
+        EvdevDevice { file }
+    }
+
+    // Issue an ioctl that fills in arg, returning the kernel's return value.
+    fn ioctl<T>(&self, request: u32, arg: *mut T) -> io::Result<i32> {
+        self.ioctl_raw(request, arg as libc::c_ulong)
+    }
+
+    // Issue an ioctl whose argument is either a pointer to a buffer matching
+    // the size encoded in request, or a plain value.
+    fn ioctl_raw(&self, request: u32, arg: libc::c_ulong) -> io::Result<i32> {
+        // Safe because every request we issue only writes within the size
+        // encoded in it, which always matches the buffer behind arg.
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg) };
+        let result = if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        };
+
+        if TRACE_IOCTLS.load(Ordering::Relaxed) {
+            debug!(
+                "ioctl(fd {}, {}, {:#x}) = {:?}",
+                self.file.as_raw_fd(),
+                request_name(request),
+                arg,
+                result
+            );
+        }
+
+        result
+    }
+
+    pub fn name(&self) -> io::Result<String> {
+        self.string(eviocgname)
+    }
+
+    // Unique identifier of the device, usually its serial number. Empty if the
+    // driver doesn't report one.
+    pub fn uniq(&self) -> io::Result<String> {
+        match self.string(eviocguniq) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(String::new()),
+            result => result,
+        }
+    }
+
+    // Issue one of the ioctls returning a NUL-terminated string.
+    fn string(&self, request: fn(usize) -> u32) -> io::Result<String> {
+        let mut buf = vec![0u8; 256];
+        let len = self.ioctl(request(buf.len()), buf.as_mut_ptr())? as usize;
+        // The kernel counts the terminating NUL in len.
+        buf.truncate(len);
+        if let Some(nul) = buf.iter().position(|&b| b == 0) {
+            buf.truncate(nul);
+        }
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    pub fn ids(&self) -> io::Result<libc::input_id> {
+        let mut ids = libc::input_id {
+            bustype: 0,
+            vendor: 0,
+            product: 0,
+            version: 0,
+        };
+        self.ioctl(EVIOCGID, &mut ids)?;
+
+        Ok(ids)
+    }
+
+    // Bitmap of the INPUT_PROP_* properties of the device.
+    pub fn props(&self) -> io::Result<Vec<u8>> {
+        let mut bits = vec![0u8; 128];
+        let len = self.ioctl(eviocgprop(bits.len()), bits.as_mut_ptr())?;
+        bits.truncate(len as usize);
+
+        Ok(bits)
+    }
+
+    // Bitmap of the codes supported for ev_type, or of the supported event
+    // types themselves when ev_type is 0.
+    pub fn ev_bits(&self, ev_type: u16) -> io::Result<Vec<u8>> {
+        let mut bits = vec![0u8; 128];
+        let len = self.ioctl(eviocgbit(ev_type, bits.len()), bits.as_mut_ptr())?;
+        bits.truncate(len as usize);
+
+        Ok(bits)
+    }
+
+    fn state_bits(&self, nr: u32) -> io::Result<Vec<u8>> {
+        let mut bits = vec![0u8; 128];
+        let len = self.ioctl(eviocgstate(nr, bits.len()), bits.as_mut_ptr())?;
+        bits.truncate(len as usize);
+
+        Ok(bits)
+    }
+
+    // Bitmaps of the keys currently down, LEDs lit and switches set.
+    pub fn key_state(&self) -> io::Result<Vec<u8>> {
+        self.state_bits(EVIOCGKEY_NR)
+    }
+
+    pub fn led_state(&self) -> io::Result<Vec<u8>> {
+        self.state_bits(EVIOCGLED_NR)
+    }
+
+    pub fn switch_state(&self) -> io::Result<Vec<u8>> {
+        self.state_bits(EVIOCGSW_NR)
+    }
+
+    // Read whatever events are pending, nothing if the device has none.
+    pub fn read_events(&mut self) -> io::Result<Vec<libc::input_event>> {
+        let mut events = Vec::new();
+        let event_size = event_size();
+        let mut buf = [0u8; 64 * MAX_EVENT_SIZE];
+        let buf = &mut buf[..64 * event_size];
+        loop {
+            let len = match self.file.read(buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            // evdev only ever returns whole events.
+            for chunk in buf[..len].chunks_exact(event_size) {
+                events.push(decode_event(chunk));
+            }
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        Ok(events)
+    }
+
+    // Take the device's events for ourselves, so the host stops seeing them.
+    // Fails with EBUSY if someone else grabbed it first.
+    pub fn grab(&self) -> io::Result<()> {
+        // EVIOCGRAB takes its argument by value, non-zero grabs.
+        self.ioctl_raw(EVIOCGRAB, 1)?;
+
+        Ok(())
+    }
+
+    // Hand the device's events back to the host.
+    pub fn ungrab(&self) -> io::Result<()> {
+        self.ioctl_raw(EVIOCGRAB, 0)?;
+
+        Ok(())
+    }
+
+    // Send an event to the device, e.g. to light an LED. The kernel ignores
+    // the ones the device doesn't support, and fills in the time.
+    pub fn write_event(&mut self, ev_type: u16, code: u16, value: i32) -> io::Result<()> {
+        let event_size = event_size();
+        let mut buf = [0u8; MAX_EVENT_SIZE];
+        let rest = event_size - 8;
+        buf[rest..rest + 2].copy_from_slice(&ev_type.to_ne_bytes());
+        buf[rest + 2..rest + 4].copy_from_slice(&code.to_ne_bytes());
+        buf[rest + 4..rest + 8].copy_from_slice(&value.to_ne_bytes());
+        self.file.write_all(&buf[..event_size])
+    }
+
+    // Upload a rumble effect with the magnitudes of the strong and weak motors,
+    // playing until stopped, and return the id to play it by.
+    pub fn upload_rumble(&self, strong: u16, weak: u16) -> io::Result<i16> {
+        // Safe because ff_effect is plain old data, for which all zeroes is a
+        // valid value.
+        let mut effect: libc::ff_effect = unsafe { std::mem::zeroed() };
+        effect.type_ = FF_RUMBLE;
+        // Asks for a new effect, the kernel fills in its id.
+        effect.id = -1;
+        // Safe because u is the union of the effect parameters, which starts
+        // with the two magnitudes of struct ff_rumble_effect for FF_RUMBLE.
+        unsafe {
+            std::ptr::write_unaligned(effect.u.as_mut_ptr() as *mut [u16; 2], [strong, weak])
+        };
+        self.ioctl(EVIOCSFF, &mut effect)?;
+
+        Ok(effect.id)
+    }
+
+    pub fn abs_info(&self, axis: u16) -> io::Result<libc::input_absinfo> {
+        let mut info = libc::input_absinfo {
+            value: 0,
+            minimum: 0,
+            maximum: 0,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        self.ioctl(eviocgabs(axis), &mut info)?;
+
+        Ok(info)
+    }
+}
+
+impl AsRawFd for EvdevDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Paths of the evdev nodes on the host, /dev/input/eventN, ordered by N.
+pub fn list_devices() -> io::Result<Vec<String>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(INPUT_DIR)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(n) = name
+            .strip_prefix("event")
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            devices.push((n, format!("{}/{}", INPUT_DIR, name)));
+        }
+    }
+    devices.sort();
+
+    Ok(devices.into_iter().map(|(_, path)| path).collect())
+}
+
+// The evdev node a /dev/input/by-id link points to, given by its name in there
+// (e.g. "usb-Logitech_USB_Receiver-event-mouse") or its path. Unlike eventN,
+// these names stay the same across reboots, as udev derives them from the
+// device's bus, vendor, model and serial.
+pub fn by_id(name: &str) -> io::Result<String> {
+    let link = by_id_link(name);
+    let node = node_name(&link)?;
+    // The same device has -mouse or -joystick links to its legacy nodes.
+    if !node.starts_with("event") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is {}, not an evdev node", link, node),
+        ));
+    }
+
+    Ok(format!("{}/{}", INPUT_DIR, node))
+}
+
+// The path of the /dev/input/by-id link given by name or path.
+pub fn by_id_link(name: &str) -> String {
+    if name.contains('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", BY_ID_DIR, name)
+    }
+}
+
+// Resolve /dev/input/by-id and friends to the eventN node name.
+fn node_name(path: &str) -> io::Result<String> {
+    Ok(fs::canonicalize(path)?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default())
+}
+
+// Processes other than us that have the evdev node at path open, as (pid,
+// command name), for telling the user who holds a grab. Best effort: processes
+// whose fds we can't read, or all of them without /proc, are left out.
+pub fn holders(path: &str) -> Vec<(u32, String)> {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return Vec::new(),
+    };
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut holders = Vec::new();
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) if pid != std::process::id() => pid,
+            _ => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).map_or(false, |link| link == target));
+        if holds {
+            let comm = fs::read_to_string(entry.path().join("comm"))
+                .map(|comm| comm.trim().to_string())
+                .unwrap_or_default();
+            holders.push((pid, comm));
+        }
+    }
+
+    holders
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Battery {
+    // Charge in percent.
+    pub capacity: Option<u8>,
+    // Charging, Discharging, Full, ...
+    pub status: Option<String>,
+}
+
+// Battery of the device behind an evdev node, None if it has none. Drivers of
+// wireless devices (HID ones in particular) register it as a power_supply class
+// device under the same parent as the input device.
+pub fn battery(path: &str) -> Option<Battery> {
+    let node = node_name(path).ok()?;
+    let dir = format!("{}/{}/device/device/power_supply", SYSFS_INPUT_DIR, node);
+    let supply = fs::read_dir(dir).ok()?.next()?.ok()?.path();
+    let read = |attr: &str| {
+        fs::read_to_string(supply.join(attr))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+
+    Some(Battery {
+        capacity: read("capacity").and_then(|capacity| capacity.parse().ok()),
+        status: read("status"),
+    })
+}
+
+impl fmt::Display for Battery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.capacity {
+            Some(capacity) => write!(f, "{}%", capacity)?,
+            None => write!(f, "unknown level")?,
+        }
+        if let Some(status) = self.status.as_ref() {
+            write!(f, " ({})", status)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Physical path of an evdev node as the driver reports it, with the part
+// naming the interface (e.g. the "/input1" of "usb-0000:00:14.0-2/input1")
+// cut off, or None if the driver doesn't report one.
+fn phys_prefix(node: &str) -> io::Result<Option<String>> {
+    let phys = fs::read_to_string(format!("{}/{}/device/phys", SYSFS_INPUT_DIR, node))?;
+    let phys = phys.trim();
+    if phys.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(match phys.rfind('/') {
+        Some(slash) => phys[..slash].to_string(),
+        None => phys.to_string(),
+    }))
+}
+
+// The evdev nodes of the physical device path belongs to, path's own included,
+// ordered by N. Siblings are the nodes whose phys only differs in the trailing
+// interface, which is how e.g. a USB keyboard's consumer control interface
+// shows up next to the keyboard.
+pub fn siblings(path: &str) -> io::Result<Vec<String>> {
+    let node = node_name(path)?;
+    let prefix = match phys_prefix(&node)? {
+        Some(prefix) => prefix,
+        None => return Ok(vec![format!("{}/{}", INPUT_DIR, node)]),
+    };
+
+    let mut group = Vec::new();
+    for device in list_devices()? {
+        let name = device.trim_start_matches(INPUT_DIR).trim_start_matches('/');
+        if phys_prefix(name)?.as_ref() == Some(&prefix) {
+            group.push(device);
+        }
+    }
+
+    Ok(group)
+}
+
+pub fn set_bit(bits: &mut Vec<u8>, bit: u16) {
+    let index = bit as usize / 8;
+    if bits.len() <= index {
+        bits.resize(index + 1, 0);
+    }
+    bits[index] |= 1 << (bit % 8);
+}
+
+pub fn test_bit(bits: &[u8], bit: u16) -> bool {
+    bits.get(bit as usize / 8)
+        .map_or(false, |byte| byte & (1 << (bit % 8)) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(long: usize, event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut raw = vec![0xaa; 2 * long];
+        raw.extend_from_slice(&event_type.to_ne_bytes());
+        raw.extend_from_slice(&code.to_ne_bytes());
+        raw.extend_from_slice(&value.to_ne_bytes());
+        raw
+    }
+
+    #[test]
+    fn native_event_size() {
+        assert_eq!(event_size(), size_of::<libc::input_event>());
+        assert_eq!(raw(size_of::<libc::c_long>(), 0, 0, 0).len(), event_size());
+    }
+
+    #[test]
+    fn kernel_event_size() {
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(KERNEL_EVENT_SIZE, 16);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(KERNEL_EVENT_SIZE, 24);
+        assert_eq!(
+            raw(size_of::<libc::c_ulong>(), 0, 0, 0).len(),
+            KERNEL_EVENT_SIZE
+        );
+    }
+
+    #[test]
+    fn decode() {
+        for &long in [4, 8].iter() {
+            let event = decode_event(&raw(long, codes::EV_KEY, 30, -1));
+            assert_eq!(
+                (event.type_, event.code, event.value),
+                (codes::EV_KEY, 30, -1)
+            );
+        }
+    }
+}
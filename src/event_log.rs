@@ -0,0 +1,133 @@
+// Audit trail of the events delivered to the guest, written with --event-log.
+//
+// The log is a text file with one tab-separated record per event:
+//
+//     <time_us>\t<device>\t<type>\t<code>\t<value>\t<name>
+//
+// time_us is the wall-clock time of delivery in microseconds since the Unix
+// epoch, device the name advertised to the guest, type, code and value the
+// event as the guest received it (value signed) and name the symbolic name of
+// the code, e.g. REL_X, or "-" if it has none. Once the file grows past its
+// size limit it is renamed to <file>.1, replacing the previous one, and a new
+// file is started.
+//
+// Records are handed to a writer thread, so the worker never waits on the
+// disk. If the writer falls behind by more than RECORD_BUFFER_SIZE records,
+// further records are dropped and counted rather than stall delivery.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::codes;
+use crate::config::VirtioInputEvent;
+use log::*;
+
+const RECORD_BUFFER_SIZE: usize = 4096;
+
+pub struct EventLog {
+    tx: SyncSender<(SystemTime, VirtioInputEvent)>,
+    // Records dropped because the writer fell behind, since the last warning.
+    dropped: u64,
+}
+
+struct Writer {
+    path: String,
+    device: String,
+    max_size: u64,
+    out: BufWriter<File>,
+    size: u64,
+}
+
+fn open(path: &str) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok((BufWriter::new(file), size))
+}
+
+impl EventLog {
+    // Append to the log at path, rotating it once it reaches max_size bytes.
+    // device is the name the guest knows the device by.
+    pub fn create(path: &str, device: &str, max_size: u64) -> io::Result<Self> {
+        let (out, size) = open(path)?;
+        let mut writer = Writer {
+            path: path.to_string(),
+            // Keep the records parseable whatever the name holds.
+            device: device.replace(|c: char| c.is_control(), " "),
+            max_size,
+            out,
+            size,
+        };
+        let (tx, rx) = mpsc::sync_channel(RECORD_BUFFER_SIZE);
+        thread::spawn(move || {
+            if let Err(e) = writer.run(rx) {
+                error!("Failed to write event log {}: {}", writer.path, e);
+            }
+        });
+
+        Ok(EventLog { tx, dropped: 0 })
+    }
+
+    // Log an event the guest just received.
+    pub fn log(&mut self, event: &VirtioInputEvent) {
+        match self.tx.try_send((SystemTime::now(), *event)) {
+            Ok(()) if self.dropped > 0 => {
+                warn!("Event log fell behind, {} records lost", self.dropped);
+                self.dropped = 0;
+            }
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            // The writer gave up and already said why.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Writer {
+    fn run(&mut self, rx: Receiver<(SystemTime, VirtioInputEvent)>) -> io::Result<()> {
+        while let Ok(record) = rx.recv() {
+            self.write(record)?;
+            // Flush once we've caught up rather than after every record.
+            while let Ok(record) = rx.try_recv() {
+                self.write(record)?;
+            }
+            self.out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, (time, event): (SystemTime, VirtioInputEvent)) -> io::Result<()> {
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let record = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            time.as_micros(),
+            self.device,
+            event.event_type,
+            event.code,
+            event.value as i32,
+            codes::code_name(event.event_type, event.code).unwrap_or("-")
+        );
+        self.out.write_all(record.as_bytes())?;
+        self.size += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        let (out, size) = open(&self.path)?;
+        self.out = out;
+        self.size = size;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+// Bounded queue of the events waiting for the guest to post eventq buffers.
+//
+// Both the input side and process_queue() run on the worker thread, so this is
+// a plain VecDeque with a fixed capacity rather than anything shared.
+
+use std::collections::VecDeque;
+
+pub struct EventQueue<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Copy> EventQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "event queue needs a non-zero capacity");
+        EventQueue {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Append all of batch or nothing, so that a SYN_REPORT frame is never
+    // queued only in part.
+    pub fn push_batch(&mut self, batch: &[T]) -> bool {
+        if self.capacity - self.items.len() < batch.len() {
+            return false;
+        }
+
+        self.items.extend(batch.iter().copied());
+        true
+    }
+
+    pub fn push(&mut self, item: T) -> bool {
+        self.push_batch(&[item])
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        self.peek_at(0)
+    }
+
+    // The item offset places after the oldest, without taking any.
+    pub fn peek_at(&self, offset: usize) -> Option<T> {
+        self.items.get(offset).copied()
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_batch_when_full() {
+        let mut queue = EventQueue::new(4);
+        assert!(queue.push_batch(&[1, 2, 3]));
+        assert!(!queue.push_batch(&[4, 5]));
+        assert_eq!(queue.len(), 3);
+        assert!(queue.push_batch(&[4]));
+        assert!(!queue.push(5));
+        assert!(queue.push_batch(&[]));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(5));
+        assert_eq!(queue.len(), queue.capacity());
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut queue = EventQueue::new(3);
+        for round in 0..10 {
+            assert!(queue.push_batch(&[round, round + 1]));
+            assert_eq!(queue.pop(), Some(round));
+            assert_eq!(queue.pop(), Some(round + 1));
+            assert!(queue.is_empty());
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn peek_at() {
+        let mut queue = EventQueue::new(4);
+        assert_eq!(queue.peek(), None);
+        queue.push_batch(&[1, 2, 3]);
+        queue.pop();
+        assert_eq!(queue.peek(), Some(2));
+        assert_eq!(queue.peek_at(1), Some(3));
+        assert_eq!(queue.peek_at(2), None);
+        assert_eq!(queue.peek_at(usize::MAX), None);
+        assert_eq!(queue.len(), 2);
+    }
+}
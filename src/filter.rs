@@ -0,0 +1,532 @@
+// Per-event filter rules, loaded with --filter-rules.
+//
+// Rules are tried in order and the first one whose pattern (and guard, if
+// any) matches decides the fate of the event. The syntax is described in the
+// README, under Filter rules.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::codes::{self, EV_KEY, EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+#[derive(Debug)]
+pub struct ParseError {
+    line: usize,
+    reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pattern {
+    ev_type: Option<u16>,
+    code: Option<u16>,
+    value: Option<i32>,
+}
+
+impl Pattern {
+    fn matches(&self, event: &VirtioInputEvent) -> bool {
+        self.ev_type.map_or(true, |t| t == event.event_type)
+            && self.code.map_or(true, |c| c == event.code)
+            && self.value.map_or(true, |v| v == event.value as i32)
+    }
+}
+
+#[derive(Debug)]
+struct Guard {
+    key: u16,
+    held: bool,
+}
+
+#[derive(Debug)]
+enum Action {
+    Drop,
+    Pass,
+    Remap(u16, u16),
+    Emit(Vec<(u16, u16)>),
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    guard: Option<Guard>,
+    action: Action,
+}
+
+#[derive(Debug, Default)]
+pub struct FilterRules {
+    rules: Vec<Rule>,
+    held: HashSet<u16>,
+}
+
+fn parse_code(token: &str) -> Result<(u16, u16), String> {
+    codes::code_by_name(token).ok_or_else(|| format!("unknown event code {}", token))
+}
+
+fn parse_pattern(text: &str) -> Result<Pattern, String> {
+    let fields: Vec<&str> = text.split(':').map(str::trim).collect();
+    if fields.len() > 3 {
+        return Err(format!("too many fields in pattern {}", text));
+    }
+
+    let mut pattern = Pattern::default();
+    let rest;
+    if fields[0] == "*" || fields[0].starts_with("EV_") {
+        if fields[0] != "*" {
+            pattern.ev_type = Some(
+                codes::type_by_name(fields[0])
+                    .ok_or_else(|| format!("unknown event type {}", fields[0]))?,
+            );
+        }
+        if let Some(code) = fields.get(1).filter(|code| **code != "*") {
+            pattern.code = Some(match code.parse::<u16>() {
+                Ok(n) => n,
+                Err(_) => {
+                    let (ev_type, n) = parse_code(code)?;
+                    if pattern.ev_type.map_or(false, |t| t != ev_type) {
+                        return Err(format!("{} does not belong to {}", code, fields[0]));
+                    }
+                    n
+                }
+            });
+        }
+        rest = fields.get(2..).unwrap_or(&[]);
+    } else {
+        if fields.len() > 2 {
+            return Err(format!("too many fields in pattern {}", text));
+        }
+        let (ev_type, code) = parse_code(fields[0])?;
+        pattern.ev_type = Some(ev_type);
+        pattern.code = Some(code);
+        rest = &fields[1..];
+    }
+
+    if let Some(value) = rest.first() {
+        if *value != "*" {
+            pattern.value = Some(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid event value {}", value))?,
+            );
+        }
+    }
+
+    Ok(pattern)
+}
+
+fn parse_guard(text: &str) -> Result<Guard, String> {
+    let (held, text) = match text.strip_prefix('!') {
+        Some(rest) => (false, rest.trim_start()),
+        None => (true, text),
+    };
+    let mut words = text.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("held"), Some(key), None) => match parse_code(key)? {
+            (EV_KEY, key) => Ok(Guard { key, held }),
+            _ => Err(format!("{} is not a key", key)),
+        },
+        _ => Err(format!("invalid condition {}", text)),
+    }
+}
+
+fn parse_action(text: &str) -> Result<Action, String> {
+    let mut words = text.split_whitespace();
+    let action = match (words.next(), words.next()) {
+        (Some("drop"), None) => return Ok(Action::Drop),
+        (Some("pass"), None) => return Ok(Action::Pass),
+        (Some("remap"), Some(code)) => {
+            let (ev_type, code) = parse_code(code)?;
+            Action::Remap(ev_type, code)
+        }
+        (Some("emit"), Some(sequence)) => Action::Emit(
+            sequence
+                .split('+')
+                .map(|code| parse_code(code.trim()))
+                .collect::<Result<_, _>>()?,
+        ),
+        _ => return Err(format!("invalid action {}", text)),
+    };
+    match words.next() {
+        None => Ok(action),
+        Some(extra) => Err(format!("unexpected {} after action", extra)),
+    }
+}
+
+fn parse_rule(text: &str) -> Result<Rule, String> {
+    let arrow = text.find("=>").ok_or("missing =>")?;
+    let (lhs, action) = (text[..arrow].trim(), text[arrow + 2..].trim());
+    let (pattern, guard) = match lhs.find(" if ") {
+        Some(pos) => (&lhs[..pos], Some(parse_guard(lhs[pos + 4..].trim())?)),
+        None => (lhs, None),
+    };
+
+    Ok(Rule {
+        pattern: parse_pattern(pattern)?,
+        guard,
+        action: parse_action(action)?,
+    })
+}
+
+impl FilterRules {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut rules = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(line).map_err(|reason| ParseError {
+                line: index + 1,
+                reason,
+            })?);
+        }
+
+        Ok(FilterRules {
+            rules,
+            held: HashSet::new(),
+        })
+    }
+
+    // Run an event through the rules, appending whatever it turns into to out.
+    pub fn apply(&mut self, event: VirtioInputEvent, out: &mut Vec<VirtioInputEvent>) {
+        if event.event_type == EV_KEY {
+            if event.value == 0 {
+                self.held.remove(&event.code);
+            } else {
+                self.held.insert(event.code);
+            }
+        }
+
+        let held = &self.held;
+        let rule = self.rules.iter().find(|rule| {
+            rule.pattern.matches(&event)
+                && rule
+                    .guard
+                    .as_ref()
+                    .map_or(true, |g| held.contains(&g.key) == g.held)
+        });
+
+        match rule.map(|rule| &rule.action) {
+            None | Some(Action::Pass) => out.push(event),
+            Some(Action::Drop) => {}
+            Some(Action::Remap(event_type, code)) => out.push(VirtioInputEvent {
+                event_type: *event_type,
+                code: *code,
+                value: event.value,
+            }),
+            Some(Action::Emit(sequence)) => {
+                let emit = |value, out: &mut Vec<VirtioInputEvent>| {
+                    let codes: Box<dyn Iterator<Item = &(u16, u16)>> = if value == 0 {
+                        Box::new(sequence.iter().rev())
+                    } else {
+                        Box::new(sequence.iter())
+                    };
+                    out.extend(codes.map(|&(event_type, code)| VirtioInputEvent {
+                        event_type,
+                        code,
+                        value,
+                    }));
+                };
+                if event.event_type == EV_KEY {
+                    if event.value != 2 {
+                        emit(event.value, out);
+                    }
+                } else if event.value != 0 {
+                    emit(1, out);
+                    out.push(VirtioInputEvent {
+                        event_type: EV_SYN,
+                        code: SYN_REPORT,
+                        value: 0,
+                    });
+                    emit(0, out);
+                }
+            }
+        }
+    }
+}
+
+// Event types and codes masked out of both the input and the capabilities
+// advertised to the guest, built from --only and --block. With any --only,
+// only what it lists passes, and whatever --block lists never does, e.g. to
+// keep KEY_POWER and KEY_SLEEP away from the guest. SYN events always pass,
+// the guest can't make sense of input without them.
+//
+//     spec  := TYPE [ ":" CODES { "," CODES } ]
+//     CODES := CODE [ "-" CODE ]
+//
+// A TYPE on its own stands for all its codes, e.g. `EV_REL:REL_WHEEL,REL_HWHEEL`
+// leaves only the scroll wheels of a mouse and `EV_KEY:BTN_LEFT,BTN_RIGHT`
+// only two of its buttons. A range takes in the codes between its ends, both
+// included, e.g. `EV_KEY:KEY_F1-KEY_F10`.
+#[derive(Debug, Default)]
+pub struct CodeMask {
+    // Allowed codes by type, None for all codes of the type.
+    allowed: Vec<(u16, Option<HashSet<u16>>)>,
+    // Blocked codes by type, the same way.
+    blocked: Vec<(u16, Option<HashSet<u16>>)>,
+}
+
+fn parse_type_code(ev_type: u16, code: &str, spec: &str) -> Result<u16, String> {
+    match code.parse::<u16>() {
+        Ok(n) => Ok(n),
+        Err(_) => match parse_code(code)? {
+            (t, n) if t == ev_type => Ok(n),
+            _ => Err(format!("{} does not belong to {}", code, spec)),
+        },
+    }
+}
+
+fn parse_spec(spec: &str) -> Result<(u16, Option<HashSet<u16>>), String> {
+    let (ev_type, codes) = match spec.find(':') {
+        Some(colon) => (spec[..colon].trim(), Some(&spec[colon + 1..])),
+        None => (spec.trim(), None),
+    };
+    let ev_type =
+        codes::type_by_name(ev_type).ok_or_else(|| format!("unknown event type {}", ev_type))?;
+
+    let codes = match codes {
+        Some(codes) => {
+            let mut listed = HashSet::new();
+            for codes in codes.split(',').map(str::trim) {
+                let (first, last) = match codes.find('-') {
+                    Some(dash) => (codes[..dash].trim(), codes[dash + 1..].trim()),
+                    None => (codes, codes),
+                };
+                let first = parse_type_code(ev_type, first, spec)?;
+                let last = parse_type_code(ev_type, last, spec)?;
+                if first > last {
+                    return Err(format!("empty range {}", codes));
+                }
+                listed.extend(first..=last);
+            }
+            Some(listed)
+        }
+        None => None,
+    };
+
+    Ok((ev_type, codes))
+}
+
+fn lists(list: &[(u16, Option<HashSet<u16>>)], ev_type: u16, code: u16) -> bool {
+    list.iter().any(|(t, codes)| {
+        *t == ev_type && codes.as_ref().map_or(true, |codes| codes.contains(&code))
+    })
+}
+
+impl CodeMask {
+    // Add the types or codes allowed by spec, from --only.
+    pub fn allow(&mut self, spec: &str) -> Result<(), String> {
+        self.allowed.push(parse_spec(spec)?);
+        Ok(())
+    }
+
+    // Add the types or codes blocked by spec, from --block.
+    pub fn block(&mut self, spec: &str) -> Result<(), String> {
+        self.blocked.push(parse_spec(spec)?);
+        Ok(())
+    }
+
+    pub fn allows(&self, ev_type: u16, code: u16) -> bool {
+        ev_type == EV_SYN
+            || ((self.allowed.is_empty() || lists(&self.allowed, ev_type, code))
+                && !lists(&self.blocked, ev_type, code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codes::{EV_REL, EV_SYN};
+
+    const KEY_TAB: u16 = 15;
+    const KEY_LEFTCTRL: u16 = 29;
+    const KEY_LEFTMETA: u16 = 125;
+    const KEY_POWER: u16 = 116;
+    const BTN_SIDE: u16 = 0x113;
+    const REL_WHEEL: u16 = 8;
+    const REL_HWHEEL: u16 = 6;
+
+    fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+        VirtioInputEvent {
+            event_type,
+            code,
+            value: value as u32,
+        }
+    }
+
+    fn run(rules: &str, input: &[(u16, u16, i32)]) -> Vec<(u16, u16, i32)> {
+        let mut rules = FilterRules::parse(rules).unwrap();
+        let mut out = Vec::new();
+        for &(event_type, code, value) in input {
+            rules.apply(event(event_type, code, value), &mut out);
+        }
+        out.iter()
+            .map(|e| (e.event_type, e.code, e.value as i32))
+            .collect()
+    }
+
+    #[test]
+    fn pattern() {
+        let p = parse_pattern("EV_KEY").unwrap();
+        assert_eq!((p.ev_type, p.code, p.value), (Some(EV_KEY), None, None));
+        let p = parse_pattern("EV_KEY:KEY_TAB:1").unwrap();
+        assert_eq!(
+            (p.ev_type, p.code, p.value),
+            (Some(EV_KEY), Some(KEY_TAB), Some(1))
+        );
+        let p = parse_pattern("KEY_TAB:*").unwrap();
+        assert_eq!(
+            (p.ev_type, p.code, p.value),
+            (Some(EV_KEY), Some(KEY_TAB), None)
+        );
+        let p = parse_pattern("* : 8 : -1").unwrap();
+        assert_eq!((p.ev_type, p.code, p.value), (None, Some(8), Some(-1)));
+        let p = parse_pattern("EV_REL:*").unwrap();
+        assert_eq!((p.ev_type, p.code, p.value), (Some(EV_REL), None, None));
+
+        assert!(parse_pattern("EV_NOPE").is_err());
+        assert!(parse_pattern("KEY_NOPE").is_err());
+        assert!(parse_pattern("EV_REL:KEY_TAB").is_err());
+        assert!(parse_pattern("EV_KEY:KEY_TAB:1:2").is_err());
+        assert!(parse_pattern("KEY_TAB:1:2").is_err());
+        assert!(parse_pattern("KEY_TAB:x").is_err());
+    }
+
+    #[test]
+    fn guard() {
+        let g = parse_guard("held KEY_LEFTCTRL").unwrap();
+        assert_eq!((g.key, g.held), (KEY_LEFTCTRL, true));
+        let g = parse_guard("! held KEY_LEFTCTRL").unwrap();
+        assert_eq!((g.key, g.held), (KEY_LEFTCTRL, false));
+
+        assert!(parse_guard("held REL_X").is_err());
+        assert!(parse_guard("held").is_err());
+        assert!(parse_guard("held KEY_A KEY_B").is_err());
+        assert!(parse_guard("pressed KEY_A").is_err());
+    }
+
+    #[test]
+    fn action() {
+        assert!(matches!(parse_action("drop"), Ok(Action::Drop)));
+        assert!(matches!(parse_action("pass"), Ok(Action::Pass)));
+        assert!(matches!(
+            parse_action("remap REL_WHEEL"),
+            Ok(Action::Remap(EV_REL, REL_WHEEL))
+        ));
+        match parse_action("emit KEY_LEFTMETA+KEY_TAB") {
+            Ok(Action::Emit(sequence)) => {
+                assert_eq!(sequence, vec![(EV_KEY, KEY_LEFTMETA), (EV_KEY, KEY_TAB)])
+            }
+            other => panic!("{:?}", other),
+        }
+
+        assert!(parse_action("drop KEY_A").is_err());
+        assert!(parse_action("remap").is_err());
+        assert!(parse_action("remap KEY_NOPE").is_err());
+        assert!(parse_action("emit KEY_A+").is_err());
+        assert!(parse_action("explode").is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let rules = FilterRules::parse(
+            "# comment\n\nKEY_POWER => drop # trailing\nEV_KEY if !held KEY_LEFTCTRL => pass\n",
+        )
+        .unwrap();
+        assert_eq!(rules.rules.len(), 2);
+
+        let e = FilterRules::parse("KEY_POWER => drop\n\nKEY_POWER drop\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 3: missing =>");
+        let e = FilterRules::parse("KEY_POWER => shutdown").unwrap_err();
+        assert_eq!(e.line, 1);
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let rules = "KEY_POWER => pass\nEV_KEY => drop\nREL_HWHEEL => remap REL_WHEEL\n";
+        assert_eq!(
+            run(
+                rules,
+                &[
+                    (EV_KEY, KEY_POWER, 1),
+                    (EV_KEY, KEY_TAB, 1),
+                    (EV_REL, REL_HWHEEL, -2),
+                    (EV_SYN, 0, 0),
+                ]
+            ),
+            vec![
+                (EV_KEY, KEY_POWER, 1),
+                (EV_REL, REL_WHEEL, -2),
+                (EV_SYN, 0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn emit_key() {
+        // Presses in order, releases in reverse, autorepeats swallowed.
+        assert_eq!(
+            run(
+                "BTN_SIDE => emit KEY_LEFTMETA+KEY_TAB",
+                &[
+                    (EV_KEY, BTN_SIDE, 1),
+                    (EV_KEY, BTN_SIDE, 2),
+                    (EV_KEY, BTN_SIDE, 0)
+                ]
+            ),
+            vec![
+                (EV_KEY, KEY_LEFTMETA, 1),
+                (EV_KEY, KEY_TAB, 1),
+                (EV_KEY, KEY_TAB, 0),
+                (EV_KEY, KEY_LEFTMETA, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn emit_tap() {
+        assert_eq!(
+            run(
+                "REL_WHEEL => emit KEY_LEFTMETA+KEY_TAB",
+                &[(EV_REL, REL_WHEEL, 1), (EV_REL, REL_WHEEL, 0)]
+            ),
+            vec![
+                (EV_KEY, KEY_LEFTMETA, 1),
+                (EV_KEY, KEY_TAB, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_TAB, 0),
+                (EV_KEY, KEY_LEFTMETA, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn held_guard() {
+        let rules = "EV_KEY:KEY_TAB if !held KEY_LEFTCTRL => drop";
+        assert_eq!(
+            run(
+                rules,
+                &[
+                    (EV_KEY, KEY_TAB, 1),
+                    (EV_KEY, KEY_TAB, 0),
+                    (EV_KEY, KEY_LEFTCTRL, 1),
+                    (EV_KEY, KEY_TAB, 1),
+                    (EV_KEY, KEY_TAB, 0),
+                    (EV_KEY, KEY_LEFTCTRL, 0),
+                    (EV_KEY, KEY_TAB, 1),
+                ]
+            ),
+            vec![
+                (EV_KEY, KEY_LEFTCTRL, 1),
+                (EV_KEY, KEY_TAB, 1),
+                (EV_KEY, KEY_TAB, 0),
+                (EV_KEY, KEY_LEFTCTRL, 0),
+            ]
+        );
+    }
+}
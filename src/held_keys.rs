@@ -0,0 +1,76 @@
+// Ledger of the keys and buttons the guest has been told are down.
+//
+// Every path that cuts the guest off from the input stream for a while
+// (pause, disconnect, regrab, shutdown, switching devices) goes through here to
+// release them, so nothing is left stuck down in the guest, and to press them
+// again when input resumes. Autorepeat (value 2) counts as held.
+
+use std::collections::BTreeSet;
+
+use crate::codes::{EV_KEY, EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+#[derive(Debug, Default)]
+pub struct HeldKeys {
+    keys: BTreeSet<u16>,
+}
+
+impl HeldKeys {
+    pub fn update(&mut self, event: &VirtioInputEvent) {
+        if event.event_type != EV_KEY {
+            return;
+        }
+
+        if event.value == 0 {
+            self.keys.remove(&event.code);
+        } else {
+            self.keys.insert(event.code);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, code: u16) -> bool {
+        self.keys.contains(&code)
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    // A frame setting every held key to value, or nothing if none are held.
+    fn frame(&self, value: u32) -> Vec<VirtioInputEvent> {
+        if self.keys.is_empty() {
+            return Vec::new();
+        }
+
+        let mut events: Vec<VirtioInputEvent> = self
+            .keys
+            .iter()
+            .map(|&code| VirtioInputEvent {
+                event_type: EV_KEY,
+                code,
+                value,
+            })
+            .collect();
+        events.push(VirtioInputEvent {
+            event_type: EV_SYN,
+            code: SYN_REPORT,
+            value: 0,
+        });
+
+        events
+    }
+
+    // Frame releasing every held key. The ledger keeps them, so presses()
+    // can restore them later.
+    pub fn releases(&self) -> Vec<VirtioInputEvent> {
+        self.frame(0)
+    }
+
+    pub fn presses(&self) -> Vec<VirtioInputEvent> {
+        self.frame(1)
+    }
+}
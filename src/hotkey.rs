@@ -0,0 +1,78 @@
+// Hotkeys typed on the host devices, for --grab-toggle and --switch-socket.
+//
+// A hotkey is a chord, its keys pressed together, tapped a number of times in
+// a row, each tap no more than TAP_INTERVAL after the one before and no other
+// key pressed in between, e.g. both Ctrl keys twice, which hardly ever happens
+// by accident. Autorepeat doesn't count as a tap.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::codes::EV_KEY;
+use crate::config::VirtioInputEvent;
+
+// Switches the host devices between host and guest.
+pub const GRAB_HOTKEY: &str = "KEY_LEFTCTRL+KEY_RIGHTCTRL";
+pub const GRAB_TAPS: &str = "2";
+// Moves the host devices on to the next guest, as on a hardware KVM switch.
+pub const SWITCH_HOTKEY: &str = "KEY_SCROLLLOCK";
+pub const SWITCH_TAPS: &str = "2";
+
+const TAP_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct Hotkey {
+    keys: Vec<u16>,
+    taps: u32,
+    // Keys down on the host devices.
+    down: HashSet<u16>,
+    tapped: u32,
+    last_tap: Option<Instant>,
+}
+
+impl Hotkey {
+    pub fn new(keys: Vec<u16>, taps: u32) -> Self {
+        Hotkey {
+            keys,
+            taps,
+            down: HashSet::new(),
+            tapped: 0,
+            last_tap: None,
+        }
+    }
+
+    // Follow a host event, true once it completes the hotkey.
+    pub fn update(&mut self, event: &VirtioInputEvent) -> bool {
+        if event.event_type != EV_KEY || event.value == 2 {
+            return false;
+        }
+        if event.value == 0 {
+            self.down.remove(&event.code);
+            return false;
+        }
+
+        self.down.insert(event.code);
+        if !self.keys.contains(&event.code) {
+            self.tapped = 0;
+            return false;
+        }
+        if !self.keys.iter().all(|key| self.down.contains(key)) {
+            return false;
+        }
+
+        let now = Instant::now();
+        if self
+            .last_tap
+            .map_or(true, |last| now.duration_since(last) > TAP_INTERVAL)
+        {
+            self.tapped = 0;
+        }
+        self.tapped += 1;
+        self.last_tap = Some(now);
+        if self.tapped < self.taps {
+            return false;
+        }
+
+        self.tapped = 0;
+        true
+    }
+}
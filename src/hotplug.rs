@@ -0,0 +1,190 @@
+// Following a host device as it comes and goes, see --hotplug.
+//
+// udev announces the devices it has set up, device nodes and /dev/input/by-id
+// links included, on the udev group of the NETLINK_KOBJECT_UEVENT socket. Each
+// message starts with a header of its own, pointing at the device's properties
+// as NUL separated KEY=VALUE strings, from which ACTION, SUBSYSTEM and DEVNAME
+// tell us an input device node was added. The kernel's own announcements on
+// the other group come before udev is done with the device, so we leave them
+// alone.
+//
+// Only processes with CAP_NET_ADMIN, udevd that is, can send to the group, so
+// unlike libudev we don't check the sender's credentials.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::{self, size_of};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use regex::Regex;
+
+use crate::evdev::{self, EvdevDevice};
+
+// The multicast group of udev's announcements, 1 is the kernel's.
+const UDEV_GROUP: u32 = 2;
+const UDEV_PREFIX: &[u8] = b"libudev\0";
+const UDEV_MAGIC: u32 = 0xfeed_cafe;
+const HEADER_SIZE: usize = 40;
+// Largest message udev sends.
+const MAX_MESSAGE: usize = 8192;
+
+// The host device to forward, as given on the command line.
+pub enum Selector {
+    // --evdev-path or --evdev-by-id, matched by the node the path resolves to.
+    Path(String),
+    // --evdev-name.
+    Name(Regex),
+    // --evdev-vid-pid.
+    Ids(u16, u16),
+}
+
+impl Selector {
+    // Whether the evdev node at node is the device to forward.
+    pub fn matches(&self, node: &str) -> bool {
+        match self {
+            Selector::Path(path) => match (fs::canonicalize(path), fs::canonicalize(node)) {
+                (Ok(path), Ok(node)) => path == node,
+                _ => false,
+            },
+            Selector::Name(pattern) => EvdevDevice::open(node)
+                .and_then(|device| device.name())
+                .map_or(false, |name| pattern.is_match(&name)),
+            Selector::Ids(vendor, product) => EvdevDevice::open(node)
+                .and_then(|device| device.ids())
+                .map_or(false, |id| id.vendor == *vendor && id.product == *product),
+        }
+    }
+
+    // The first device present that matches, in eventN order.
+    pub fn find(&self) -> io::Result<Option<String>> {
+        Ok(evdev::list_devices()?
+            .into_iter()
+            .find(|node| self.matches(node)))
+    }
+}
+
+// udev's announcements of new input device nodes.
+pub struct Monitor {
+    socket: File,
+}
+
+impl Monitor {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The socket was just created, socket is its sole owner from here on.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        // sockaddr_nl is plain old data, for which all zeroes is a valid value.
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = UDEV_GROUP;
+        // bind() reads no more than the size given of addr.
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Monitor { socket })
+    }
+
+    // The nodes of the input devices added since the last call, without
+    // waiting for any.
+    pub fn added(&mut self) -> io::Result<Vec<String>> {
+        let mut nodes = Vec::new();
+        let mut message = [0; MAX_MESSAGE];
+        loop {
+            let len = match self.socket.read(&mut message) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(nodes),
+                Err(e) => return Err(e),
+            };
+            nodes.extend(added_node(&message[..len]));
+        }
+    }
+
+    // Wait for a device matching selector to be added, and return its node.
+    pub fn wait_for(&mut self, selector: &Selector) -> io::Result<String> {
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // poll() only writes the revents of the one pollfd it is given.
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            if let Some(node) = self
+                .added()?
+                .into_iter()
+                .find(|node| selector.matches(node))
+            {
+                return Ok(node);
+            }
+        }
+    }
+}
+
+impl AsRawFd for Monitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+// The header's fields are in host byte order, bar the magic.
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+// The evdev node a udev message announces as added, if that's what it is.
+fn added_node(message: &[u8]) -> Option<String> {
+    if message.len() < HEADER_SIZE || !message.starts_with(UDEV_PREFIX) {
+        return None;
+    }
+    if message[8..12] != UDEV_MAGIC.to_be_bytes() {
+        return None;
+    }
+    let offset = u32_at(message, 16) as usize;
+    let len = u32_at(message, 20) as usize;
+    let properties = message.get(offset..offset.checked_add(len)?)?;
+
+    let (mut action, mut subsystem, mut node) = (None, None, None);
+    for property in properties.split(|&byte| byte == 0) {
+        let property = String::from_utf8_lossy(property);
+        if let Some(value) = property.strip_prefix("ACTION=") {
+            action = Some(value.to_string());
+        } else if let Some(value) = property.strip_prefix("SUBSYSTEM=") {
+            subsystem = Some(value.to_string());
+        } else if let Some(value) = property.strip_prefix("DEVNAME=") {
+            node = Some(value.to_string());
+        }
+    }
+    if action.as_deref() != Some("add") || subsystem.as_deref() != Some("input") {
+        return None;
+    }
+    // The inputN parent and legacy mouseN and jsN nodes are announced as well.
+    node.filter(|node| node.starts_with("/dev/input/event"))
+}
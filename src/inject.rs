@@ -0,0 +1,271 @@
+// Events injected by other programs through a unix socket, see
+// --inject-socket. This is the only input of a --synthetic device.
+//
+// Clients write one event per line, by name or number:
+//
+//     <type> <code> <value>
+//     <code> <value>
+//
+// e.g. `EV_KEY KEY_A 1` or `KEY_A 1`, and `SYN_REPORT 0` to end the frame.
+// As with host input, the guest sees nothing of a frame until its SYN_REPORT.
+//
+// Contacts of a touchscreen are easier to inject as touches, which expand to
+// the multitouch events of the slot and the single touch ones guests fall
+// back on:
+//
+//     touch down <slot> <x> <y>
+//     touch move <slot> <x> <y>
+//     touch up <slot>
+//
+// Several touches can go into one frame, which again ends with SYN_REPORT.
+// A line that doesn't parse is answered with the reason, the others aren't
+// answered at all.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::*;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::codes::{
+    self, ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_MT_TRACKING_ID, ABS_X, ABS_Y,
+    BTN_TOUCH, EV_ABS, EV_KEY, EV_SYN, SYN_REPORT,
+};
+use crate::config::VirtioInputEvent;
+use crate::profiles::MAX_TRACKING_ID;
+
+// Most events waiting for the worker before further ones are refused, so that
+// a client can't make us buffer without bounds while the guest isn't reading.
+const MAX_PENDING: usize = 4096;
+
+struct Queue {
+    // Injected so far and not yet taken by the worker, in order.
+    pending: Mutex<Vec<VirtioInputEvent>>,
+    // Tells the worker to take them.
+    input_evt: EventFd,
+}
+
+// Events injected from other threads for the worker, shared by all of them.
+#[derive(Clone)]
+pub struct Injected(Arc<Queue>);
+
+impl Injected {
+    pub fn new(input_evt: EventFd) -> Self {
+        Injected(Arc::new(Queue {
+            pending: Mutex::new(Vec::new()),
+            input_evt,
+        }))
+    }
+
+    pub fn take(&self) -> Vec<VirtioInputEvent> {
+        std::mem::take(&mut *self.0.pending.lock().unwrap())
+    }
+
+    // Queue events for the worker, and wake it up if they end a frame. False,
+    // with nothing queued, if too many are pending already.
+    pub fn push(&self, events: &[VirtioInputEvent]) -> bool {
+        {
+            let mut pending = self.0.pending.lock().unwrap();
+            if pending.len() + events.len() > MAX_PENDING {
+                return false;
+            }
+            pending.extend_from_slice(events);
+        }
+        if events
+            .iter()
+            .any(|event| event.event_type == EV_SYN && event.code == SYN_REPORT)
+        {
+            self.wake();
+        }
+        true
+    }
+
+    // Have the worker look at the queues for the guest, e.g. after frames
+    // were queued behind its back.
+    pub fn wake(&self) {
+        if let Err(e) = self.0.input_evt.write(1) {
+            warn!("Failed to signal injected events: {}", e);
+        }
+    }
+}
+
+// The contacts a client has down, to expand its touches.
+#[derive(Default)]
+struct Touches {
+    down: BTreeSet<u32>,
+    next_tracking_id: u32,
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type,
+        code,
+        value: value as u32,
+    }
+}
+
+impl Touches {
+    // The events for one touch command, given the fields after `touch`.
+    fn expand(&mut self, fields: &[&str]) -> Result<Vec<VirtioInputEvent>, String> {
+        let number = |i: usize| -> Result<i32, String> {
+            match parse_number(fields[i])? {
+                n if n >= 0 && n <= i32::MAX as i64 => Ok(n as i32),
+                n => Err(format!("{} out of range", n)),
+            }
+        };
+        let expected = match fields.first() {
+            Some(&"down") | Some(&"move") => 4,
+            Some(&"up") => 2,
+            _ => return Err("expected touch down, move or up".to_string()),
+        };
+        if fields.len() != expected {
+            return Err(format!("expected {} fields after touch", expected));
+        }
+        let slot = number(1)? as u32;
+        let position = match expected {
+            4 => Some((number(2)?, number(3)?)),
+            _ => None,
+        };
+
+        let mut events = vec![event(EV_ABS, ABS_MT_SLOT, slot as i32)];
+        match fields[0] {
+            "down" if self.down.contains(&slot) => {
+                return Err(format!("slot {} is already down", slot))
+            }
+            "down" => {
+                if self.down.is_empty() {
+                    events.push(event(EV_KEY, BTN_TOUCH, 1));
+                }
+                self.down.insert(slot);
+                let tracking_id = self.next_tracking_id;
+                self.next_tracking_id = (tracking_id + 1) % (MAX_TRACKING_ID + 1);
+                events.push(event(EV_ABS, ABS_MT_TRACKING_ID, tracking_id as i32));
+            }
+            "move" if self.down.contains(&slot) => {}
+            "up" if self.down.remove(&slot) => {
+                events.push(event(EV_ABS, ABS_MT_TRACKING_ID, -1));
+                if self.down.is_empty() {
+                    events.push(event(EV_KEY, BTN_TOUCH, 0));
+                }
+            }
+            _ => return Err(format!("slot {} is not down", slot)),
+        }
+
+        if let Some((x, y)) = position {
+            events.push(event(EV_ABS, ABS_MT_POSITION_X, x));
+            events.push(event(EV_ABS, ABS_MT_POSITION_Y, y));
+            // The single touch axes follow the contact in the lowest slot.
+            if self.down.iter().next() == Some(&slot) {
+                events.push(event(EV_ABS, ABS_X, x));
+                events.push(event(EV_ABS, ABS_Y, y));
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+fn parse_number(field: &str) -> Result<i64, String> {
+    field
+        .parse()
+        .map_err(|_| format!("invalid number {}", field))
+}
+
+pub fn parse_event(line: &str) -> Result<VirtioInputEvent, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (event_type, code) = match fields.len() {
+        2 => codes::code_by_name(fields[0])
+            .ok_or_else(|| format!("unknown event code {}", fields[0]))?,
+        3 => {
+            let event_type = match codes::type_by_name(fields[0]) {
+                Some(event_type) => event_type,
+                None => parse_number(fields[0])? as u16,
+            };
+            let code = match codes::code_by_name(fields[1]) {
+                Some((code_type, code)) if code_type == event_type => code,
+                Some(_) => return Err(format!("{} is not a {} code", fields[1], fields[0])),
+                None => parse_number(fields[1])? as u16,
+            };
+            (event_type, code)
+        }
+        n => return Err(format!("expected 2 or 3 fields, got {}", n)),
+    };
+    let value = parse_number(fields[fields.len() - 1])?;
+
+    Ok(VirtioInputEvent {
+        event_type,
+        code,
+        value: value as i32 as u32,
+    })
+}
+
+// The events for one line from a client.
+fn parse_line(line: &str, touches: &mut Touches) -> Result<Vec<VirtioInputEvent>, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.first() {
+        Some(&"touch") => touches.expand(&fields[1..]),
+        _ => parse_event(line).map(|event| vec![event]),
+    }
+}
+
+// Read events from one client until it hangs up.
+fn serve(stream: UnixStream, injected: Injected) {
+    let mut replies = match stream.try_clone() {
+        Ok(replies) => replies,
+        Err(e) => {
+            warn!("Failed to set up injection client: {}", e);
+            return;
+        }
+    };
+    let mut touches = Touches::default();
+    for (index, line) in BufReader::new(stream).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let reply = match parse_line(line, &mut touches) {
+            Ok(events) if !injected.push(&events) => "too many pending events".to_string(),
+            Ok(_) => continue,
+            Err(reason) => reason,
+        };
+        if writeln!(replies, "line {}: {}", index + 1, reply).is_err() {
+            break;
+        }
+    }
+}
+
+// Accept clients on the socket at path, each served by a thread of its own.
+pub fn listen(path: &str, injected: Injected) -> io::Result<()> {
+    // Replace a socket left behind by an earlier run, but nothing else.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept injection client: {}", e);
+                    continue;
+                }
+            };
+            let injected = injected.clone();
+            thread::spawn(move || serve(stream, injected));
+        }
+    });
+
+    Ok(())
+}
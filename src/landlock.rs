@@ -0,0 +1,231 @@
+// A Landlock sandbox of the filesystem, see --landlock.
+//
+// Unlike the seccomp filter, a Landlock domain can't be put on threads that are
+// already running, only on the calling thread and those it starts later, so it
+// goes on once the devices are found, before the first thread is started. The
+// rules, made from the arguments, cover what the rest of startup opens as well
+// as what's opened later: the evdev nodes, the directories of the sockets, for
+// stale ones to be replaced, and the files
+// given to record, replay, log and the like. Anything else, e.g. a node named
+// to the control socket's swap that isn't one of ours, is refused with EACCES.
+// Connecting to a unix socket isn't something Landlock restricts.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::ptr;
+
+// From asm-generic/unistd.h, the same on every architecture.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+// From linux/landlock.h.
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// Since version 2 and 3 of the ABI.
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const ACCESS_FS_V1: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_READ_DIR
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+// The paths the process keeps access to, and what access.
+#[derive(Default)]
+pub struct Sandbox {
+    rules: Vec<(String, u64)>,
+}
+
+// The directory path is in, which for a bare name is the working directory.
+fn parent(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    }
+}
+
+impl Sandbox {
+    // A file only read, e.g. --replay's recording.
+    pub fn read(&mut self, path: &str) {
+        self.rules.push((path.to_string(), ACCESS_FS_READ_FILE));
+    }
+
+    // A file read and written, e.g. an evdev node.
+    pub fn read_write(&mut self, path: &str) {
+        self.rules
+            .push((path.to_string(), ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE));
+    }
+
+    // A tree only read, e.g. /sys.
+    pub fn read_tree(&mut self, dir: &str) {
+        self.rules
+            .push((dir.to_string(), ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR));
+    }
+
+    // A directory of devices that come and go, listed, read and written.
+    pub fn devices(&mut self, dir: &str) {
+        self.rules.push((
+            dir.to_string(),
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_DIR,
+        ));
+    }
+
+    // A file created, appended to or replaced, rotated and removed, e.g.
+    // --event-log.
+    pub fn create(&mut self, path: &str) {
+        self.rules.push((
+            parent(path),
+            ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE,
+        ));
+    }
+
+    // A socket bound at path, whatever stale one is there replaced. With
+    // --socket-mode and the like it's bound in a directory of its own first,
+    // and moved from there.
+    pub fn bind(&mut self, path: &str) {
+        // An abstract name is no file.
+        if path.starts_with('@') {
+            return;
+        }
+        self.rules.push((
+            parent(path),
+            ACCESS_FS_MAKE_SOCK
+                | ACCESS_FS_REMOVE_FILE
+                | ACCESS_FS_MAKE_DIR
+                | ACCESS_FS_REMOVE_DIR
+                | ACCESS_FS_REFER,
+        ));
+    }
+
+    // Restrict the calling thread, and the threads it starts from now on, to
+    // the rules. Paths that don't exist are left out, opening them fails
+    // anyway.
+    pub fn apply(&self) -> io::Result<()> {
+        // With the version flag, create_ruleset() only returns the ABI version.
+        let abi = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut handled = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // create_ruleset() only reads the size given of attr.
+        let fd = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The fd was just created, the File is its sole owner.
+        let ruleset = unsafe { File::from_raw_fd(fd as libc::c_int) };
+
+        for (path, access) in self.rules.iter() {
+            let beneath = match open_path(path) {
+                Ok(beneath) => beneath,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path, e))),
+            };
+            let rule = PathBeneathAttr {
+                allowed_access: access & handled,
+                parent_fd: beneath.as_raw_fd(),
+            };
+            // add_rule() only reads rule.
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_LANDLOCK_ADD_RULE,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                return Err(io::Error::new(e.kind(), format!("{}: {}", path, e)));
+            }
+        }
+
+        // As with seccomp, unprivileged processes may only restrict themselves
+        // once they can't gain privileges the domain would stay on.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // restrict_self() takes nothing but the ruleset's fd.
+        if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+// An O_PATH fd of path, following symlinks, e.g. of /dev/input/by-id.
+fn open_path(path: &str) -> io::Result<File> {
+    let path = CString::new(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in the path"))?;
+    // open() only reads the NUL terminated path.
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fd was just opened, the File is its sole owner.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
@@ -0,0 +1,28 @@
+pub mod backend;
+pub mod coalesce;
+pub mod codes;
+pub mod config;
+pub mod description;
+pub mod evdev;
+pub mod event_log;
+pub mod event_queue;
+pub mod filter;
+pub mod held_keys;
+pub mod hotkey;
+pub mod hotplug;
+pub mod inject;
+#[cfg(feature = "libei")]
+pub mod libei;
+pub mod listeners;
+pub mod logger;
+pub mod logind;
+pub mod overflow;
+pub mod profiles;
+pub mod rate_limit;
+pub mod recording;
+pub mod resync;
+pub mod stats;
+pub mod watchdog;
+
+pub use backend::{VhostUserInputBackend, VhostUserInputBackendBuilder};
+pub use listeners::Source;
@@ -0,0 +1,768 @@
+// Keyboard and pointer input of a Wayland session, received from the
+// compositor's EIS server with libei's protocol, see --libei-socket.
+//
+// Wayland compositors hand out the session's input to libei clients rather
+// than letting unprivileged users at evdev. We connect as a receiver context,
+// bind the pointer, button, scroll and keyboard capabilities of the seats the
+// server announces, and turn what its devices emulate into evdev events, each
+// of the server's frames ending in a SYN_REPORT. Keys and buttons are evdev
+// codes on the wire already. Relative motion comes in fractions of a logical
+// pixel, the remainder carried over to the next motion, and discrete scrolling
+// in 120ths of a wheel notch. Smooth scrolling, absolute pointers and touch
+// aren't bound. The keys held through a device are released when it stops
+// emulating, is paused or goes away, and all of them when the server
+// disconnects, after which the next read fails.
+//
+// Only the bits of the protocol needed for this are spoken, version 1 of every
+// interface, over the socket named as in $LIBEI_SOCKET. A socket from the
+// InputCapture portal, which takes D-Bus to ask for, can be handed to
+// Receiver::new() by an embedder instead.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::env;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+
+use log::*;
+
+use crate::codes::{EV_KEY, EV_REL, EV_SYN, REL_HWHEEL, REL_WHEEL, REL_X, REL_Y, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+use crate::held_keys::HeldKeys;
+use crate::listeners::Source;
+
+const CONTEXT_NAME: &str = "vhost-user-input";
+const CONTEXT_TYPE_RECEIVER: u32 = 1;
+// The interfaces we speak, all in version 1.
+const INTERFACES: &[&str] = &[
+    "ei_connection",
+    "ei_callback",
+    "ei_pingpong",
+    "ei_seat",
+    "ei_device",
+    "ei_pointer",
+    "ei_button",
+    "ei_scroll",
+    "ei_keyboard",
+];
+// Seat capabilities bound, named after the device interfaces they bring.
+const CAPABILITIES: &[&str] = &["ei_pointer", "ei_button", "ei_scroll", "ei_keyboard"];
+
+// Every connection starts with this object.
+const HANDSHAKE: u64 = 0;
+// ei_handshake requests, and events with the same opcodes.
+const HANDSHAKE_VERSION: u32 = 0;
+const HANDSHAKE_FINISH: u32 = 1;
+const HANDSHAKE_CONTEXT_TYPE: u32 = 2;
+const HANDSHAKE_NAME: u32 = 3;
+const HANDSHAKE_INTERFACE_VERSION: u32 = 4;
+const HANDSHAKE_CONNECTION: u32 = 2;
+// ei_connection events.
+const CONNECTION_DISCONNECTED: u32 = 0;
+const CONNECTION_SEAT: u32 = 1;
+const CONNECTION_PING: u32 = 3;
+// ei_pingpong request.
+const PINGPONG_DONE: u32 = 0;
+// ei_seat request and events.
+const SEAT_BIND: u32 = 1;
+const SEAT_CAPABILITY: u32 = 2;
+const SEAT_DONE: u32 = 3;
+const SEAT_DEVICE: u32 = 4;
+// ei_device events.
+const DEVICE_NAME: u32 = 1;
+const DEVICE_INTERFACE: u32 = 5;
+const DEVICE_PAUSED: u32 = 8;
+const DEVICE_STOP_EMULATING: u32 = 10;
+const DEVICE_FRAME: u32 = 11;
+// Event of the seat, the device and the device interfaces.
+const DESTROYED: u32 = 0;
+const POINTER_MOTION_RELATIVE: u32 = 1;
+const BUTTON_BUTTON: u32 = 1;
+const SCROLL_DISCRETE: u32 = 2;
+const KEYBOARD_KEY: u32 = 2;
+
+// Object, length and opcode.
+const HEADER_SIZE: usize = 16;
+// Largest message taken from the server.
+const MAX_MESSAGE: usize = 1 << 16;
+// How long the server gets to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const WHEEL_NOTCH: i32 = 120;
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32 + 1).to_ne_bytes());
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    out.resize(out.len() + pad(s.len() + 1), 0);
+}
+
+// A message with its header filled in, in the host's byte order as all of the
+// protocol is.
+fn message(object: u64, opcode: u32, args: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + args.len());
+    message.extend_from_slice(&object.to_ne_bytes());
+    message.extend_from_slice(&((HEADER_SIZE + args.len()) as u32).to_ne_bytes());
+    message.extend_from_slice(&opcode.to_ne_bytes());
+    message.extend_from_slice(args);
+    message
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type,
+        code,
+        value: value as u32,
+    }
+}
+
+// Where the EIS server named name listens: name itself if absolute, under
+// $XDG_RUNTIME_DIR otherwise, as with $LIBEI_SOCKET.
+pub fn socket_path(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if path.is_relative() => Path::new(&dir).join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+// The arguments of a message, taken in order.
+struct Args<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or_else(|| invalid("truncated EIS message".to_string()))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    // Its length counts the NUL, and is 0 for none at all.
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        if len == 0 {
+            return Ok(String::new());
+        }
+        let data = self.take(len + pad(len))?;
+        Ok(String::from_utf8_lossy(&data[..len - 1]).into_owned())
+    }
+}
+
+// What the objects the server created are, those we don't care about left out.
+// Messages to those are skipped.
+#[derive(Clone, Copy)]
+enum Object {
+    Connection,
+    Seat { capabilities: u64 },
+    Device,
+    // The device interfaces, those with keys with the device holding them.
+    Pointer,
+    Button { device: u64 },
+    Scroll,
+    Keyboard { device: u64 },
+}
+
+pub struct Receiver {
+    stream: UnixStream,
+    // Bytes read that don't make up a whole message yet.
+    buffer: Vec<u8>,
+    objects: BTreeMap<u64, Object>,
+    // The keys and buttons held through each device.
+    held: BTreeMap<u64, HeldKeys>,
+    connected: bool,
+    disconnected: bool,
+    // Whether events went out since the last SYN_REPORT.
+    framing: bool,
+    // Motion short of a whole pixel, and scrolling short of a notch.
+    motion: (f32, f32),
+    scroll: (i32, i32),
+}
+
+impl Receiver {
+    // Connect to the EIS server listening at path.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Self::new(UnixStream::connect(path)?)
+    }
+
+    // Do the handshake with the EIS server on the other end of stream.
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut receiver = Receiver {
+            stream,
+            buffer: Vec::new(),
+            objects: BTreeMap::new(),
+            held: BTreeMap::new(),
+            connected: false,
+            disconnected: false,
+            framing: false,
+            motion: (0.0, 0.0),
+            scroll: (0, 0),
+        };
+        // Whatever came along with the connection is handled too, as the fd
+        // won't be readable for it again. Input only follows a bind, so
+        // there's none yet.
+        let mut events = Vec::new();
+        while !receiver.connected {
+            match receiver.receive() {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "EIS server hung up during the handshake",
+                    ))
+                }
+                Ok(_) => receiver.dispatch_all(&mut events)?,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "EIS server didn't finish the handshake",
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        receiver.stream.set_read_timeout(None)?;
+        receiver.stream.set_nonblocking(true)?;
+
+        Ok(receiver)
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    // Read what there is into the buffer. The fds the server sends along,
+    // such as a keyboard's keymap, are of no use to us and closed.
+    fn receive(&mut self) -> io::Result<usize> {
+        let mut data = [0u8; 4096];
+        // u64s, to be aligned for the cmsghdrs.
+        let mut control = [0u64; 16];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        // msghdr is plain old data, for which all zeroes is a valid value.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        // recvmsg() writes no more than the sizes given of data and control.
+        let len =
+            unsafe { libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the kernel filled in msg_controllen bytes of control
+        // with whole cmsghdrs, and the fds of SCM_RIGHTS are ours to close.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let fds = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                        / mem::size_of::<RawFd>();
+                    for index in 0..count {
+                        libc::close(ptr::read_unaligned(fds.add(index)));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        self.buffer.extend_from_slice(&data[..len as usize]);
+
+        Ok(len as usize)
+    }
+
+    // Handle the whole messages in the buffer.
+    fn dispatch_all(&mut self, events: &mut Vec<VirtioInputEvent>) -> io::Result<()> {
+        while self.buffer.len() >= HEADER_SIZE {
+            let object = u64::from_ne_bytes(self.buffer[0..8].try_into().unwrap());
+            let len = u32::from_ne_bytes(self.buffer[8..12].try_into().unwrap()) as usize;
+            let opcode = u32::from_ne_bytes(self.buffer[12..16].try_into().unwrap());
+            if !(HEADER_SIZE..=MAX_MESSAGE).contains(&len) {
+                return Err(invalid(format!("EIS message of {} bytes", len)));
+            }
+            if self.buffer.len() < len {
+                break;
+            }
+
+            let args: Vec<u8> = self.buffer.drain(..len).skip(HEADER_SIZE).collect();
+            let mut args = Args {
+                data: &args,
+                pos: 0,
+            };
+            self.dispatch(object, opcode, &mut args, events)?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(
+        &mut self,
+        object: u64,
+        opcode: u32,
+        args: &mut Args,
+        events: &mut Vec<VirtioInputEvent>,
+    ) -> io::Result<()> {
+        if object == HANDSHAKE {
+            return self.handshake(opcode, args);
+        }
+        let kind = match self.objects.get(&object) {
+            Some(&kind) => kind,
+            None => return Ok(()),
+        };
+
+        match (kind, opcode) {
+            (Object::Connection, CONNECTION_DISCONNECTED) => {
+                let _last_serial = args.u32()?;
+                let reason = args.u32()?;
+                let explanation = args.string()?;
+                warn!(
+                    "EIS server disconnected us (reason {}): {}",
+                    reason, explanation
+                );
+                self.disconnect(events);
+            }
+            (Object::Connection, CONNECTION_SEAT) => {
+                self.objects
+                    .insert(args.u64()?, Object::Seat { capabilities: 0 });
+            }
+            (Object::Connection, CONNECTION_PING) => {
+                let ping = args.u64()?;
+                self.send(&message(ping, PINGPONG_DONE, &0u64.to_ne_bytes()))?;
+            }
+            (Object::Seat { .. }, SEAT_CAPABILITY) => {
+                let mask = args.u64()?;
+                let interface = args.string()?;
+                if let Some(Object::Seat { capabilities }) = self.objects.get_mut(&object) {
+                    if CAPABILITIES.contains(&interface.as_str()) {
+                        *capabilities |= mask;
+                    }
+                }
+            }
+            (Object::Seat { capabilities }, SEAT_DONE) => {
+                debug!("binding EIS seat capabilities {:#x}", capabilities);
+                self.send(&message(object, SEAT_BIND, &capabilities.to_ne_bytes()))?;
+            }
+            (Object::Seat { .. }, SEAT_DEVICE) => {
+                let device = args.u64()?;
+                self.objects.insert(device, Object::Device);
+                self.held.insert(device, HeldKeys::default());
+            }
+            (Object::Device, DEVICE_NAME) => {
+                info!("receiving input of EIS device {}", args.string()?);
+            }
+            (Object::Device, DEVICE_INTERFACE) => {
+                let id = args.u64()?;
+                let interface = match args.string()?.as_str() {
+                    "ei_pointer" => Object::Pointer,
+                    "ei_button" => Object::Button { device: object },
+                    "ei_scroll" => Object::Scroll,
+                    "ei_keyboard" => Object::Keyboard { device: object },
+                    _ => return Ok(()),
+                };
+                self.objects.insert(id, interface);
+            }
+            (Object::Device, DEVICE_PAUSED) | (Object::Device, DEVICE_STOP_EMULATING) => {
+                self.release(object, events);
+            }
+            (Object::Device, DEVICE_FRAME) => {
+                if self.framing {
+                    events.push(event(EV_SYN, SYN_REPORT, 0));
+                    self.framing = false;
+                }
+            }
+            (Object::Device, DESTROYED) => {
+                self.release(object, events);
+                self.held.remove(&object);
+                self.objects.remove(&object);
+            }
+            (Object::Pointer, POINTER_MOTION_RELATIVE) => {
+                let (x, y) = (args.f32()?, args.f32()?);
+                self.move_pointer(x, y, events);
+            }
+            (Object::Button { device }, BUTTON_BUTTON)
+            | (Object::Keyboard { device }, KEYBOARD_KEY) => {
+                let (code, state) = (args.u32()?, args.u32()?);
+                self.press(device, code, state, events);
+            }
+            (Object::Scroll, SCROLL_DISCRETE) => {
+                let (x, y) = (args.i32()?, args.i32()?);
+                self.turn_wheel(x, y, events);
+            }
+            (_, DESTROYED) => {
+                self.objects.remove(&object);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handshake(&mut self, opcode: u32, args: &mut Args) -> io::Result<()> {
+        match opcode {
+            HANDSHAKE_VERSION => {
+                let version = args.u32()?;
+                if version == 0 {
+                    return Err(invalid(
+                        "EIS server offered handshake version 0".to_string(),
+                    ));
+                }
+                let mut requests = message(HANDSHAKE, HANDSHAKE_VERSION, &1u32.to_ne_bytes());
+                requests.extend(message(
+                    HANDSHAKE,
+                    HANDSHAKE_CONTEXT_TYPE,
+                    &CONTEXT_TYPE_RECEIVER.to_ne_bytes(),
+                ));
+                let mut name = Vec::new();
+                push_string(&mut name, CONTEXT_NAME);
+                requests.extend(message(HANDSHAKE, HANDSHAKE_NAME, &name));
+                for interface in INTERFACES {
+                    let mut args = Vec::new();
+                    push_string(&mut args, interface);
+                    args.extend_from_slice(&1u32.to_ne_bytes());
+                    requests.extend(message(HANDSHAKE, HANDSHAKE_INTERFACE_VERSION, &args));
+                }
+                requests.extend(message(HANDSHAKE, HANDSHAKE_FINISH, &[]));
+                self.send(&requests)
+            }
+            HANDSHAKE_CONNECTION => {
+                let _serial = args.u32()?;
+                self.objects.insert(args.u64()?, Object::Connection);
+                self.connected = true;
+                Ok(())
+            }
+            // The server's interface versions: we only speak 1 anyway.
+            _ => Ok(()),
+        }
+    }
+
+    fn press(&mut self, device: u64, code: u32, state: u32, events: &mut Vec<VirtioInputEvent>) {
+        let code = match code.try_into() {
+            Ok(code) => code,
+            Err(_) => return,
+        };
+        let event = event(EV_KEY, code, (state != 0) as i32);
+        if let Some(held) = self.held.get_mut(&device) {
+            held.update(&event);
+        }
+        events.push(event);
+        self.framing = true;
+    }
+
+    fn move_pointer(&mut self, x: f32, y: f32, events: &mut Vec<VirtioInputEvent>) {
+        let (x, y) = (self.motion.0 + x, self.motion.1 + y);
+        let (dx, dy) = (x.trunc(), y.trunc());
+        self.motion = (x - dx, y - dy);
+        for &(code, delta) in [(REL_X, dx), (REL_Y, dy)].iter() {
+            if delta != 0.0 {
+                events.push(event(EV_REL, code, delta as i32));
+                self.framing = true;
+            }
+        }
+    }
+
+    // Scrolling down is positive here, up is on evdev's wheel.
+    fn turn_wheel(&mut self, x: i32, y: i32, events: &mut Vec<VirtioInputEvent>) {
+        let (x, y) = (
+            self.scroll.0.saturating_add(x),
+            self.scroll.1.saturating_add(y),
+        );
+        let (dx, dy) = (x / WHEEL_NOTCH, y / WHEEL_NOTCH);
+        self.scroll = (x % WHEEL_NOTCH, y % WHEEL_NOTCH);
+        for &(code, notches) in [(REL_HWHEEL, dx), (REL_WHEEL, -dy)].iter() {
+            if notches != 0 {
+                events.push(event(EV_REL, code, notches));
+                self.framing = true;
+            }
+        }
+    }
+
+    // Release the keys held through device, in a frame of their own.
+    fn release(&mut self, device: u64, events: &mut Vec<VirtioInputEvent>) {
+        if let Some(held) = self.held.get_mut(&device) {
+            let releases = held.releases();
+            held.clear();
+            if !releases.is_empty() {
+                events.extend(releases);
+                self.framing = false;
+            }
+        }
+    }
+
+    fn disconnect(&mut self, events: &mut Vec<VirtioInputEvent>) {
+        let devices: Vec<u64> = self.held.keys().copied().collect();
+        for device in devices {
+            self.release(device, events);
+        }
+        self.disconnected = true;
+    }
+}
+
+impl AsRawFd for Receiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Source for Receiver {
+    fn read(&mut self) -> io::Result<Vec<VirtioInputEvent>> {
+        if self.disconnected {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "EIS server disconnected",
+            ));
+        }
+
+        let mut events = Vec::new();
+        loop {
+            match self.receive() {
+                Ok(0) => {
+                    if !self.disconnected {
+                        warn!("EIS server hung up");
+                        self.disconnect(&mut events);
+                    }
+                    break;
+                }
+                Ok(_) => self.dispatch_all(&mut events)?,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const KEY_A: u16 = 30;
+    const BTN_LEFT: u16 = 0x110;
+    // Ids of the server's objects.
+    const CONNECTION: u64 = 0xff00_0000_0000_0000;
+    const SEAT: u64 = CONNECTION + 1;
+    const DEVICE: u64 = CONNECTION + 2;
+    const POINTER: u64 = CONNECTION + 3;
+    const BUTTON: u64 = CONNECTION + 4;
+    const SCROLL: u64 = CONNECTION + 5;
+    const KEYBOARD: u64 = CONNECTION + 6;
+    const PING: u64 = CONNECTION + 7;
+
+    enum Arg {
+        U32(u32),
+        U64(u64),
+        F32(f32),
+        Str(&'static str),
+    }
+
+    fn args(values: &[Arg]) -> Vec<u8> {
+        let mut args = Vec::new();
+        for value in values {
+            match *value {
+                Arg::U32(value) => args.extend_from_slice(&value.to_ne_bytes()),
+                Arg::U64(value) => args.extend_from_slice(&value.to_ne_bytes()),
+                Arg::F32(value) => args.extend_from_slice(&value.to_ne_bytes()),
+                Arg::Str(value) => push_string(&mut args, value),
+            }
+        }
+        args
+    }
+
+    // The messages the receiver sent, as object, opcode and arguments.
+    fn sent(server: &mut UnixStream) -> Vec<(u64, u32, Vec<u8>)> {
+        let mut data = Vec::new();
+        server.set_nonblocking(true).unwrap();
+        let _ = server.read_to_end(&mut data);
+        let mut messages = Vec::new();
+        while !data.is_empty() {
+            let len = u32::from_ne_bytes(data[8..12].try_into().unwrap()) as usize;
+            let message: Vec<u8> = data.drain(..len).collect();
+            messages.push((
+                u64::from_ne_bytes(message[0..8].try_into().unwrap()),
+                u32::from_ne_bytes(message[12..16].try_into().unwrap()),
+                message[HEADER_SIZE..].to_vec(),
+            ));
+        }
+        messages
+    }
+
+    #[test]
+    fn receive() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut handshake = message(HANDSHAKE, HANDSHAKE_VERSION, &args(&[Arg::U32(1)]));
+        handshake.extend(message(
+            HANDSHAKE,
+            HANDSHAKE_CONNECTION,
+            &args(&[Arg::U32(1), Arg::U64(CONNECTION), Arg::U32(1)]),
+        ));
+        server.write_all(&handshake).unwrap();
+        let mut receiver = Receiver::new(client).unwrap();
+        let requests = sent(&mut server);
+        assert_eq!(requests.first().unwrap().1, HANDSHAKE_VERSION);
+        assert!(requests.contains(&(
+            HANDSHAKE,
+            HANDSHAKE_CONTEXT_TYPE,
+            CONTEXT_TYPE_RECEIVER.to_ne_bytes().to_vec()
+        )));
+        assert_eq!(requests.last().unwrap().1, HANDSHAKE_FINISH);
+
+        let script = [
+            message(
+                CONNECTION,
+                CONNECTION_SEAT,
+                &args(&[Arg::U64(SEAT), Arg::U32(1)]),
+            ),
+            message(
+                SEAT,
+                SEAT_CAPABILITY,
+                &args(&[Arg::U64(1 << 1), Arg::Str("ei_pointer")]),
+            ),
+            message(
+                SEAT,
+                SEAT_CAPABILITY,
+                &args(&[Arg::U64(1 << 2), Arg::Str("ei_touchscreen")]),
+            ),
+            message(
+                SEAT,
+                SEAT_CAPABILITY,
+                &args(&[Arg::U64(1 << 3), Arg::Str("ei_keyboard")]),
+            ),
+            message(SEAT, SEAT_DONE, &[]),
+            message(
+                CONNECTION,
+                CONNECTION_PING,
+                &args(&[Arg::U64(PING), Arg::U32(1)]),
+            ),
+            message(SEAT, SEAT_DEVICE, &args(&[Arg::U64(DEVICE), Arg::U32(1)])),
+            message(DEVICE, DEVICE_NAME, &args(&[Arg::Str("virtual")])),
+            message(
+                DEVICE,
+                DEVICE_INTERFACE,
+                &args(&[Arg::U64(POINTER), Arg::Str("ei_pointer"), Arg::U32(1)]),
+            ),
+            message(
+                DEVICE,
+                DEVICE_INTERFACE,
+                &args(&[Arg::U64(BUTTON), Arg::Str("ei_button"), Arg::U32(1)]),
+            ),
+            message(
+                DEVICE,
+                DEVICE_INTERFACE,
+                &args(&[Arg::U64(SCROLL), Arg::Str("ei_scroll"), Arg::U32(1)]),
+            ),
+            message(
+                DEVICE,
+                DEVICE_INTERFACE,
+                &args(&[Arg::U64(KEYBOARD), Arg::Str("ei_keyboard"), Arg::U32(1)]),
+            ),
+            message(
+                KEYBOARD,
+                KEYBOARD_KEY,
+                &args(&[Arg::U32(KEY_A as u32), Arg::U32(1)]),
+            ),
+            message(
+                POINTER,
+                POINTER_MOTION_RELATIVE,
+                &args(&[Arg::F32(1.5), Arg::F32(-0.5)]),
+            ),
+            message(DEVICE, DEVICE_FRAME, &args(&[Arg::U32(2), Arg::U64(0)])),
+            message(
+                POINTER,
+                POINTER_MOTION_RELATIVE,
+                &args(&[Arg::F32(0.75), Arg::F32(-0.75)]),
+            ),
+            message(
+                BUTTON,
+                BUTTON_BUTTON,
+                &args(&[Arg::U32(BTN_LEFT as u32), Arg::U32(1)]),
+            ),
+            message(SCROLL, SCROLL_DISCRETE, &args(&[Arg::U32(0), Arg::U32(60)])),
+            message(DEVICE, DEVICE_FRAME, &args(&[Arg::U32(3), Arg::U64(0)])),
+            message(SCROLL, SCROLL_DISCRETE, &args(&[Arg::U32(0), Arg::U32(60)])),
+            message(DEVICE, DEVICE_FRAME, &args(&[Arg::U32(4), Arg::U64(0)])),
+            // Nothing since the last one.
+            message(DEVICE, DEVICE_FRAME, &args(&[Arg::U32(5), Arg::U64(0)])),
+            message(DEVICE, DEVICE_STOP_EMULATING, &args(&[Arg::U32(6)])),
+        ];
+        for message in script.iter() {
+            server.write_all(message).unwrap();
+        }
+        let events: Vec<_> = receiver
+            .read()
+            .unwrap()
+            .iter()
+            .map(|event| (event.event_type, event.code, event.value as i32))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (EV_KEY, KEY_A, 1),
+                (EV_REL, REL_X, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_X, 1),
+                (EV_REL, REL_Y, -1),
+                (EV_KEY, BTN_LEFT, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_WHEEL, -1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 0),
+                (EV_KEY, BTN_LEFT, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        // Only the capabilities we forward are bound, and pings answered.
+        let requests = sent(&mut server);
+        assert_eq!(
+            requests,
+            vec![
+                (SEAT, SEAT_BIND, (1u64 << 1 | 1 << 3).to_ne_bytes().to_vec()),
+                (PING, PINGPONG_DONE, 0u64.to_ne_bytes().to_vec()),
+            ]
+        );
+
+        drop(server);
+        assert!(receiver.read().unwrap().is_empty());
+        assert!(receiver.read().is_err());
+    }
+}
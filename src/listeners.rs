@@ -0,0 +1,80 @@
+// The fds the vring worker waits on besides the kick fds, and the epoll token
+// each comes back to handle_event() as.
+//
+// A frontend's vring worker only exists once it's connected, and goes away
+// with it, so the fds are kept here and registered with each worker in turn:
+// those added before a frontend connects are registered as it does, those added
+// while one is there right away. An fd that gets closed leaves the worker's
+// epoll by itself, it only has to be forgotten here.
+//
+// Sources of input an embedder adds to the backend get tokens of their own the
+// same way, after those of the backend's own fds, see Source.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use vhost_user_backend::VringWorker;
+
+use crate::config::VirtioInputEvent;
+
+// Input of an embedder's, read by the worker whenever its fd is readable. Its
+// events go to the guest as injected ones do, through the filter rules, mask
+// and the rest, and like those the guest sees nothing of a frame until its
+// SYN_REPORT.
+pub trait Source: AsRawFd + Send {
+    // The events there are, without waiting for any. The fd is level
+    // triggered, so whatever makes it readable has to be drained.
+    fn read(&mut self) -> io::Result<Vec<VirtioInputEvent>>;
+}
+
+#[derive(Default)]
+pub struct Listeners {
+    vring_worker: Option<Arc<VringWorker>>,
+    fds: BTreeMap<u16, RawFd>,
+}
+
+impl Listeners {
+    // Wait for fd to be readable, as token. It replaces whatever had the token.
+    pub fn add(&mut self, fd: RawFd, token: u16) -> io::Result<()> {
+        self.remove(token)?;
+        if let Some(vring_worker) = self.vring_worker.as_ref() {
+            vring_worker.register_listener(fd, epoll::Events::EPOLLIN, u64::from(token))?;
+        }
+        self.fds.insert(token, fd);
+
+        Ok(())
+    }
+
+    // Stop waiting for the fd of token, which is still open.
+    pub fn remove(&mut self, token: u16) -> io::Result<()> {
+        match (self.fds.remove(&token), self.vring_worker.as_ref()) {
+            (Some(fd), Some(vring_worker)) => {
+                vring_worker.unregister_listener(fd, epoll::Events::EPOLLIN, u64::from(token))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Forget the fd of token, which was closed.
+    pub fn forget(&mut self, token: u16) {
+        self.fds.remove(&token);
+    }
+
+    // Register everything with the vring worker of the frontend that just
+    // connected.
+    pub fn attach(&mut self, vring_worker: Arc<VringWorker>) -> io::Result<()> {
+        for (&token, &fd) in self.fds.iter() {
+            vring_worker.register_listener(fd, epoll::Events::EPOLLIN, u64::from(token))?;
+        }
+        self.vring_worker = Some(vring_worker);
+
+        Ok(())
+    }
+
+    // The frontend went away, and with it the worker and its epoll.
+    pub fn detach(&mut self) {
+        self.vring_worker = None;
+    }
+}
@@ -0,0 +1,206 @@
+// Where the log records go, see --log-level, --log-file and --log-target.
+//
+// Status messages are info, the frontend's vhost-user requests debug, and what
+// happens for every event trace, so that at the default level the event path
+// costs no more than a comparison of levels. Records go to stderr, which
+// --daemon sends to the log once ready, or with --log-file alone straight to
+// the file, along with the time.
+//
+// On hosts where nothing captures stderr they can go to journald instead, over
+// its native protocol, with the fields set on the logging thread at the time,
+// e.g. the DEVICE an event was read from or the QUEUE being processed, or to
+// syslog, as plain messages. Both sockets are connected at startup, so neither
+// --chroot nor --landlock gets in the way later.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = "vhost-user-input";
+// The daemon facility of syslog priorities.
+const LOG_DAEMON: u8 = 3 << 3;
+
+// Set with journald, the only target fields are kept for.
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Stderr,
+    Journald,
+    Syslog,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Target::Stderr),
+            "journald" => Ok(Target::Journald),
+            "syslog" => Ok(Target::Syslog),
+            _ => Err(format!("unknown log target {}", s)),
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(Mutex<File>),
+    Journald(UnixDatagram),
+    Syslog(UnixDatagram),
+}
+
+struct Logger {
+    level: LevelFilter,
+    sink: Sink,
+}
+
+// A field journald gets along with the records logged on this thread for as
+// long as the guard lives. With any other target it's never formatted.
+pub fn field(name: &'static str, value: impl fmt::Display) -> Field {
+    if !STRUCTURED.load(Ordering::Relaxed) {
+        return Field(false);
+    }
+    FIELDS.with(|fields| fields.borrow_mut().push((name, value.to_string())));
+    Field(true)
+}
+
+pub struct Field(bool);
+
+impl Drop for Field {
+    fn drop(&mut self) {
+        if self.0 {
+            FIELDS.with(|fields| fields.borrow_mut().pop());
+        }
+    }
+}
+
+// syslog's severity of level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// A field of journald's native protocol. Values with a newline are sent as
+// their length and bytes instead.
+fn push_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Nowhere left to complain to if writing fails.
+        match &self.sink {
+            Sink::Stderr => {
+                let _ = writeln!(io::stderr(), "{:<5} {}", record.level(), record.args());
+            }
+            Sink::File(file) => {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let _ = writeln!(
+                    file.lock().unwrap(),
+                    "{}.{:03} {:<5} {}",
+                    time.as_secs(),
+                    time.subsec_millis(),
+                    record.level(),
+                    record.args()
+                );
+            }
+            Sink::Journald(socket) => {
+                let mut entry = Vec::new();
+                push_field(&mut entry, "MESSAGE", &record.args().to_string());
+                push_field(
+                    &mut entry,
+                    "PRIORITY",
+                    &severity(record.level()).to_string(),
+                );
+                push_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+                push_field(&mut entry, "CODE_MODULE", record.target());
+                FIELDS.with(|fields| {
+                    for (name, value) in fields.borrow().iter() {
+                        push_field(&mut entry, name, value);
+                    }
+                });
+                let _ = socket.send(&entry);
+            }
+            Sink::Syslog(socket) => {
+                let message = format!(
+                    "<{}>{}[{}]: {}",
+                    LOG_DAEMON | severity(record.level()),
+                    IDENTIFIER,
+                    process::id(),
+                    record.args()
+                );
+                let _ = socket.send(message.as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::File(file) = &self.sink {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    Ok(socket)
+}
+
+// Log records up to level to target, with stderr to the file at path if any,
+// for the rest of the process.
+pub fn init(level: LevelFilter, target: Target, path: Option<&str>) -> io::Result<()> {
+    let sink = match (target, path) {
+        (Target::Journald, _) => Sink::Journald(connect(JOURNALD_SOCKET)?),
+        (Target::Syslog, _) => Sink::Syslog(connect(SYSLOG_SOCKET)?),
+        (Target::Stderr, Some(path)) => Sink::File(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        (Target::Stderr, None) => Sink::Stderr,
+    };
+    STRUCTURED.store(target == Target::Journald, Ordering::Relaxed);
+    let logger = Box::leak(Box::new(Logger { level, sink }));
+    log::set_logger(logger).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
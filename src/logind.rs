@@ -0,0 +1,471 @@
+// The evdev devices through systemd-logind, see --logind.
+//
+// A session's controller may have logind open the devices of its seat on its
+// behalf, with TakeDevice(major, minor), and gets the fd back over D-Bus, so
+// the daemon can run as the user of the session without being in the input
+// group or needing udev rules of its own. Control is taken once, of the session
+// the daemon runs in or else of the user's graphical one, and is held for as
+// long as the connection to the system bus is open, that is until we exit.
+//
+// Only the bits of D-Bus needed for this are spoken, as with X11: EXTERNAL
+// authentication with fd passing, then method calls and their replies, in
+// little-endian. Signals logind sends the controller, e.g. PauseDevice as the
+// seat switches to another session, are skipped; logind revokes the evdev fds
+// then, which the daemon sees as the devices being unplugged.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::{self, size_of};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+
+use crate::evdev::EvdevDevice;
+
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+const LOGIND: &str = "org.freedesktop.login1";
+// The caller's session, or the user's graphical one if it's in none.
+const SESSION_PATH: &str = "/org/freedesktop/login1/session/auto";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+// Message types.
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+// Header fields.
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+// Largest message we take, well past any reply of logind's.
+const MAX_MESSAGE: usize = 1 << 20;
+// Most fds a message comes with that we make room for.
+const MAX_FDS: usize = 16;
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// A message being marshalled. Offsets count from the start of the buffer,
+// which is 8-aligned in the message as both header and body are.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, to: usize) {
+        let padding = (to - self.buf.len() % to) % to;
+        self.buf.resize(self.buf.len() + padding, 0);
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Strings and object paths.
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.byte(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    // A header field, a (yv) struct, of a string-like type.
+    fn field(&mut self, code: u8, kind: &str, value: &str) {
+        self.align(8);
+        self.byte(code);
+        self.signature(kind);
+        match kind {
+            "g" => self.signature(value),
+            _ => self.string(value),
+        }
+    }
+}
+
+// A message being unmarshalled, the header or the body.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, to: usize) {
+        self.pos += (to - self.pos % to) % to;
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("truncated D-Bus message".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    fn signature(&mut self) -> io::Result<String> {
+        let len = self.byte()? as usize;
+        let bytes = self.bytes(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+// What we look at of a message off the bus.
+struct Message {
+    kind: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    fds: Vec<File>,
+}
+
+// Our connection to the system bus, as the controller of our session.
+pub struct Session {
+    stream: UnixStream,
+    serial: u32,
+    // Received with the bytes read so far, and not yet handed to a message.
+    fds: Vec<File>,
+}
+
+impl Session {
+    // Connect to the system bus and take control of our session.
+    pub fn take_control() -> io::Result<Self> {
+        let path = match env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+            Ok(address) => address
+                .split(';')
+                .find_map(|address| address.strip_prefix("unix:path="))
+                .map(str::to_string)
+                .ok_or_else(|| invalid(format!("unsupported bus address {}", address)))?,
+            Err(_) => SYSTEM_BUS.to_string(),
+        };
+        let mut stream = UnixStream::connect(path)?;
+
+        // Who we are goes along with the connection, EXTERNAL only has to name
+        // the uid, in hex of its decimal digits. getuid() can't fail.
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|digit| format!("{:02x}", digit)).collect();
+        stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+        expect_line(&mut stream, "OK ")?;
+        stream.write_all(b"NEGOTIATE_UNIX_FD\r\n")?;
+        expect_line(&mut stream, "AGREE_UNIX_FD")?;
+        stream.write_all(b"BEGIN\r\n")?;
+
+        let mut session = Session {
+            stream,
+            serial: 0,
+            fds: Vec::new(),
+        };
+        session.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            "",
+            &[],
+        )?;
+        // Not forcing it away from another controller.
+        let mut body = Writer::default();
+        body.u32(0);
+        session.call(
+            LOGIND,
+            SESSION_PATH,
+            SESSION_INTERFACE,
+            "TakeControl",
+            "b",
+            &body.buf,
+        )?;
+
+        Ok(session)
+    }
+
+    // Have logind open the device node at path, which has to be one of our
+    // seat's.
+    pub fn take_device(&mut self, path: &str) -> io::Result<EvdevDevice> {
+        let rdev = fs::metadata(path)?.rdev();
+        // As glibc's gnu_dev_major() and gnu_dev_minor().
+        let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+        let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+        let mut body = Writer::default();
+        body.u32(major as u32);
+        body.u32(minor as u32);
+        let mut reply = self.call(
+            LOGIND,
+            SESSION_PATH,
+            SESSION_INTERFACE,
+            "TakeDevice",
+            "uu",
+            &body.buf,
+        )?;
+        if reply.signature != "hb" {
+            return Err(invalid(format!(
+                "unexpected TakeDevice reply ({})",
+                reply.signature
+            )));
+        }
+
+        let mut reader = Reader {
+            buf: &reply.body,
+            pos: 0,
+        };
+        let index = reader.u32()? as usize;
+        let inactive = reader.u32()? != 0;
+        if index >= reply.fds.len() {
+            return Err(invalid("TakeDevice reply without the fd".to_string()));
+        }
+        let file = reply.fds.swap_remove(index);
+        if inactive {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the session isn't in the foreground of its seat",
+            ));
+        }
+        // logind opens it non-blocking already, make sure of it.
+        let fd = file.as_raw_fd();
+        // fcntl() only reads and sets the flags of the fd we own.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(EvdevDevice::from_file(file))
+    }
+
+    // Call member and wait for its reply, skipping whatever else comes first.
+    fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: &[u8],
+    ) -> io::Result<Message> {
+        self.serial += 1;
+        let serial = self.serial;
+
+        let mut header = Writer::default();
+        header.byte(b'l');
+        header.byte(METHOD_CALL);
+        header.byte(0);
+        header.byte(1);
+        header.u32(body.len() as u32);
+        header.u32(serial);
+        // The length of the fields, filled in once they're there.
+        header.u32(0);
+        header.field(FIELD_PATH, "o", path);
+        header.field(FIELD_INTERFACE, "s", interface);
+        header.field(FIELD_MEMBER, "s", member);
+        header.field(FIELD_DESTINATION, "s", destination);
+        if !signature.is_empty() {
+            header.field(FIELD_SIGNATURE, "g", signature);
+        }
+        let fields_len = (header.buf.len() - 16) as u32;
+        header.buf[12..16].copy_from_slice(&fields_len.to_le_bytes());
+        header.align(8);
+        header.buf.extend_from_slice(body);
+        self.stream.write_all(&header.buf)?;
+
+        loop {
+            let message = self.read_message()?;
+            if message.reply_serial != Some(serial) {
+                continue;
+            }
+            match message.kind {
+                METHOD_RETURN => return Ok(message),
+                ERROR => {
+                    let mut reader = Reader {
+                        buf: &message.body,
+                        pos: 0,
+                    };
+                    let text = if message.signature.starts_with('s') {
+                        reader.string()?
+                    } else {
+                        String::new()
+                    };
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "{} failed: {} {}",
+                            member,
+                            message.error_name.unwrap_or_default(),
+                            text
+                        ),
+                    ));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> io::Result<Message> {
+        let mut fixed = [0u8; 16];
+        self.read_exact(&mut fixed)?;
+        if fixed[0] != b'l' {
+            return Err(invalid("big-endian D-Bus message".to_string()));
+        }
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                fixed[offset],
+                fixed[offset + 1],
+                fixed[offset + 2],
+                fixed[offset + 3],
+            ]) as usize
+        };
+        let body_len = u32_at(4);
+        let fields_len = u32_at(12);
+        let header_len = (16 + fields_len + 7) / 8 * 8;
+        if header_len + body_len > MAX_MESSAGE {
+            return Err(invalid(format!(
+                "oversized D-Bus message of {} bytes",
+                header_len + body_len
+            )));
+        }
+        let mut rest = vec![0u8; header_len + body_len - 16];
+        self.read_exact(&mut rest)?;
+        let mut header = fixed.to_vec();
+        header.extend_from_slice(&rest[..header_len - 16]);
+
+        let mut message = Message {
+            kind: fixed[1],
+            reply_serial: None,
+            error_name: None,
+            signature: String::new(),
+            body: rest[header_len - 16..].to_vec(),
+            fds: mem::take(&mut self.fds),
+        };
+        let mut reader = Reader {
+            buf: &header,
+            pos: 16,
+        };
+        while reader.pos < 16 + fields_len {
+            reader.align(8);
+            let code = reader.byte()?;
+            let kind = reader.signature()?;
+            match (code, kind.as_str()) {
+                (FIELD_REPLY_SERIAL, "u") => message.reply_serial = Some(reader.u32()?),
+                (FIELD_ERROR_NAME, "s") => message.error_name = Some(reader.string()?),
+                (FIELD_SIGNATURE, "g") => message.signature = reader.signature()?,
+                (_, "s") | (_, "o") => drop(reader.string()?),
+                (_, "g") => drop(reader.signature()?),
+                (_, "u") => drop(reader.u32()?),
+                _ => return Err(invalid(format!("unexpected header field type {}", kind))),
+            }
+        }
+
+        Ok(message)
+    }
+
+    // Fill buf from the bus, keeping the fds that come along.
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let read = self.recv(&mut buf[done..])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            done += read;
+        }
+
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Room for MAX_FDS fds, u64s for the alignment cmsghdr needs.
+        // CMSG_SPACE() only computes a size.
+        let space = unsafe { libc::CMSG_SPACE((MAX_FDS * size_of::<libc::c_int>()) as u32) };
+        let mut control = vec![0u64; (space as usize + 7) / 8];
+        // msghdr is plain old data, for which all zeroes is a valid value.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        // recvmsg() writes no more than the lengths given of buf and control.
+        let read =
+            unsafe { libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The CMSG macros only walk the control buffer recvmsg() filled in.
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+                let data = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::c_int;
+                let len = header.cmsg_len as usize - (data as usize - cmsg as usize);
+                for index in 0..len / size_of::<libc::c_int>() {
+                    // The fds were just passed to us, each File is the sole
+                    // owner of its own.
+                    let fd = unsafe { data.add(index).read_unaligned() };
+                    self.fds.push(unsafe { File::from_raw_fd(fd) });
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+
+        Ok(read as usize)
+    }
+}
+
+// Read a line of the authentication, which has to start with prefix.
+fn expect_line(stream: &mut UnixStream, prefix: &str) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        line.push(byte[0]);
+        if line.len() > 512 {
+            return Err(invalid("overlong D-Bus authentication line".to_string()));
+        }
+    }
+    let line = String::from_utf8_lossy(&line);
+    if !line.starts_with(prefix) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("D-Bus authentication failed: {}", line.trim()),
+        ));
+    }
+
+    Ok(())
+}
@@ -4,32 +4,116 @@ extern crate vhost;
 extern crate vhost_user_backend;
 extern crate vm_memory;
 
+mod control;
+mod daemon;
+mod landlock;
+mod metrics;
+mod privileges;
+mod remote;
+mod report;
+mod script;
+mod sd_notify;
+mod seccomp;
+mod spans;
+mod websocket;
+mod x11;
+
+use std::mem::size_of;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
-use std::{convert, error, fmt, io, process, result};
+use std::thread;
+use std::time::Duration;
+use std::{env, error, fmt, fs, io, process};
 
-use clap::{crate_authors, crate_version, App, Arg};
+use clap::{crate_authors, crate_version, App, Arg, ArgGroup, ArgMatches};
+use control::{Answer, Command, ReplayStatus, Stats, Status};
+use daemon::Detached;
+use landlock::Sandbox;
 use libc::EFD_NONBLOCK;
 use log::*;
-use std::borrow::Borrow;
-use std::os::unix::io::AsRawFd;
-use vhost::vhost_user::message::*;
+use privileges::Credentials;
+use regex::Regex;
+use sd_notify::Notifier;
+use seccomp::SeccompAction;
+use serde::Serialize;
+use std::ffi::{CString, OsString};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use vhost::vhost_user::Listener;
-use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring, VringWorker};
-use virtio_bindings::bindings::virtio_blk::VIRTIO_F_VERSION_1;
-use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+use vhost_user_backend::VhostUserDaemon;
 use vmm_sys_util::eventfd::EventFd;
 
+use vhost_user_input::backend::Focus;
+use vhost_user_input::codes::{self, EV_ABS};
+use vhost_user_input::config::*;
+use vhost_user_input::description::{self, DeviceDescription};
+use vhost_user_input::evdev::{self, list_devices, EvdevDevice};
+use vhost_user_input::filter::{CodeMask, FilterRules};
+use vhost_user_input::hotplug::{Monitor, Selector};
+use vhost_user_input::inject::Injected;
+#[cfg(feature = "libei")]
+use vhost_user_input::libei;
+use vhost_user_input::logind::Session;
+use vhost_user_input::overflow::OverflowPolicy;
+use vhost_user_input::profiles;
+use vhost_user_input::recording::{Format, Recorder, Replay};
+use vhost_user_input::watchdog::Heartbeat;
+use vhost_user_input::{filter, held_keys, hotkey, inject, logger, recording, stats, watchdog};
+use vhost_user_input::{Source, VhostUserInputBackend, VhostUserInputBackendBuilder};
+
 type Result<T> = std::result::Result<T, Error>;
-type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
 
 #[derive(Debug)]
 enum Error {
-    /// Failed to create kill eventfd
-    CreateKillEventFd(io::Error),
-    /// Failed to handle event other than input event.
-    HandleEventNotEpollIn,
-    /// Failed to handle unknown event.
-    HandleEventUnknownEvent,
+    /// Failed to read the filter rules file.
+    ReadFilterRules(io::Error),
+    /// Failed to parse the filter rules file.
+    ParseFilterRules(filter::ParseError),
+    /// Failed to open the evdev device at the path.
+    OpenEvdev(String, io::Error),
+    /// Failed to query the evdev device at the path for its configuration,
+    /// outside of the backend.
+    DescribeEvdev(String, vhost_user_input::config::Error),
+    /// Failed to serialize the capabilities.
+    SerializeCapabilities(serde_json::Error),
+    /// The socket path is empty.
+    EmptySocketPath,
+    /// Something other than a socket is in the way of the socket path.
+    SocketPathInUse(String),
+    /// The directory of the socket path doesn't exist.
+    NoSocketDir(String),
+    /// Failed to bind or listen on the socket path.
+    CreateListener(vhost::vhost_user::Error),
+    /// Failed to give the socket at the path its mode or owner.
+    SetSocketPermissions(String, io::Error),
+    /// Failed to bind or listen on the abstract socket name.
+    ListenAbstract(String, io::Error),
+    /// Permissions were given for the abstract socket name, which has none.
+    AbstractSocketPermissions(String),
+    /// The inherited fd is not a listening unix socket.
+    InvalidListenerFd(RawFd),
+    /// Failed to duplicate the inherited fd for the next frontend.
+    DupListenerFd(io::Error),
+    /// The --evdev-name pattern is not a valid regex.
+    InvalidEvdevName(regex::Error),
+    /// Failed to list the host evdev devices.
+    ListEvdevs(io::Error),
+    /// The --evdev-vid-pid ids are not given as VENDOR:PRODUCT in hex.
+    InvalidEvdevIds(String),
+    /// No evdev device matches the --evdev-name pattern or --evdev-vid-pid ids.
+    NoMatchingEvdev(String),
+    /// Several evdev devices match the --evdev-name pattern or --evdev-vid-pid ids.
+    AmbiguousEvdev(Vec<String>),
+    /// Failed to read the recording to replay.
+    ReadRecording(io::Error),
+    /// Failed to parse the recording to replay.
+    ParseRecording(recording::ParseError),
+    /// Failed to create, arm or read the replay timer.
+    ReplayTimer(io::Error),
 }
 
 impl fmt::Display for Error {
@@ -40,238 +124,881 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
-impl convert::From<Error> for io::Error {
-    fn from(e: Error) -> Self {
-        io::Error::new(io::ErrorKind::Other, e)
-    }
+// How often --client --persistent tries to reach a restarting frontend.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// The first fd systemd passes with socket activation, after the standard ones.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// How often --report-battery looks at the battery level.
+const BATTERY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// The range of --speed, which keeps the replay timer's deadlines in range.
+const MIN_REPLAY_SPEED: f64 = 0.001;
+const MAX_REPLAY_SPEED: f64 = 1000.0;
+
+fn load_filter_rules(path: &str) -> Result<FilterRules> {
+    let text = fs::read_to_string(path).map_err(Error::ReadFilterRules)?;
+    FilterRules::parse(&text).map_err(Error::ParseFilterRules)
+}
+
+fn load_replay(path: &str, speed: f64, stepping: bool) -> Result<Replay> {
+    let data = fs::read(path).map_err(Error::ReadRecording)?;
+    let events = recording::parse(&data).map_err(Error::ParseRecording)?;
+
+    Replay::new(events, speed, stepping).map_err(Error::ReplayTimer)
 }
 
-// const VIRTIO_INPUT_CFG_UNSET: u32 = 0x00;
-const VIRTIO_INPUT_CFG_ID_NAME: u32 = 0x01;
-const VIRTIO_INPUT_CFG_ID_SERIAL: u32 = 0x02;
-const VIRTIO_INPUT_CFG_ID_DEVIDS: u32 = 0x03;
-const VIRTIO_INPUT_CFG_PROP_BITS: u32 = 0x10;
-const VIRTIO_INPUT_CFG_EV_BITS: u32 = 0x11;
-const VIRTIO_INPUT_CFG_ABS_INFO: u32 = 0x12;
+// The receiver of --libei-socket, connected to the EIS server named name.
+#[cfg(feature = "libei")]
+fn libei_source(name: &str) -> io::Result<Box<dyn Source>> {
+    let receiver = libei::Receiver::connect(&libei::socket_path(name))?;
+
+    Ok(Box::new(receiver))
+}
 
-#[derive(Copy, Clone, Debug, Default)]
-#[repr(C)]
-struct VirtioInputAbsInfo {
-    min: u32,
-    max: u32,
-    fuzz: u32,
-    flat: u32,
-    res: u32,
+#[cfg(not(feature = "libei"))]
+fn libei_source(_name: &str) -> io::Result<Box<dyn Source>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "built without the libei feature",
+    ))
 }
 
-#[derive(Copy, Clone, Debug, Default)]
-#[repr(C)]
-struct VirtioInputDevIDs {
-    bustype: u16,
-    vendor: u16,
-    product: u16,
-    version: u16,
+// Log the battery of each device that has one now and whenever it changes, for
+// operators watching a passthrough device run flat.
+fn report_batteries(paths: Vec<String>) {
+    let mut last: Vec<Option<evdev::Battery>> = paths.iter().map(|_| None).collect();
+    let mut check = move || {
+        for (path, last) in paths.iter().zip(last.iter_mut()) {
+            let battery = evdev::battery(path);
+            if battery != *last {
+                if let Some(battery) = battery.as_ref() {
+                    info!("battery of {}: {}", path, battery);
+                }
+                *last = battery;
+            }
+        }
+    };
+
+    check();
+    thread::spawn(move || loop {
+        thread::sleep(BATTERY_REPORT_INTERVAL);
+        check();
+    });
 }
 
-#[derive(Copy, Clone)]
-#[repr(C, packed)]
-struct VirtioInputConfig {
-    select: u8,
-    subsel: u8,
-    size: u8,
-    reserved: [u8; 5],
-    string: [char; 128],
-    bitmap: [u8; 128],
-    abs: VirtioInputAbsInfo,
-    ids: VirtioInputDevIDs,
+// The keys and taps of the hotkey given by the --<name> and --<name>-taps
+// arguments, or the defaults, exiting if they're invalid.
+fn hotkey_args(cmd_arguments: &ArgMatches, name: &str, keys: &str, taps: &str) -> (Vec<u16>, u32) {
+    let keys = match x11::parse_hotkey(cmd_arguments.value_of(name).unwrap_or(keys)) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Invalid --{}: {}", name, e);
+            process::exit(1);
+        }
+    };
+    let taps_name = format!("{}-taps", name);
+    match cmd_arguments.value_of(&taps_name).unwrap_or(taps).parse() {
+        Ok(0) | Err(_) => {
+            error!("Invalid --{}, expected a positive number", taps_name);
+            process::exit(1);
+        }
+        Ok(taps) => (keys, taps),
+    }
 }
 
-struct VirtioInputEvent {
-    event_type: u16,
-    code: u16,
-    value: u32,
+// Tell whoever handed us fd that we're ready for a frontend. Failing to do so
+// isn't fatal: the supervisor will see the fd close or time out.
+fn notify_ready(fd: RawFd) {
+    // Safe because the fd was handed to us for this purpose only, and we are
+    // its sole owner from here on.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    if let Err(e) = file.write_all(b"\n") {
+        warn!("Failed to write ready notification to fd {}: {}", fd, e);
+    }
 }
 
-struct VhostUserInputThread {
-    input_fd: EventFd,
-    vring_worker: Option<Arc<VringWorker>>,
-    event_idx: bool,
-    kill_evt: EventFd,
+// Tell the supervisor through ready_fd, the parent with --daemon and systemd
+// that we're ready for a frontend. systemd's watchdog is fed for as long as
+// none of the workers stamping heartbeats stall. Set up as we are, root is
+// given up, and the seccomp filter goes on last.
+fn ready(
+    ready_fd: Option<RawFd>,
+    detached: Option<Detached>,
+    notifier: Option<Notifier>,
+    heartbeats: Vec<Arc<Heartbeat>>,
+    credentials: Option<Credentials>,
+    seccomp: Option<SeccompAction>,
+) {
+    if let Some(credentials) = credentials {
+        if let Err(e) = privileges::give_up(&credentials) {
+            error!("Failed to drop privileges: {}", e);
+            process::exit(1);
+        }
+        info!("dropped privileges");
+    }
+    if let Some(fd) = ready_fd {
+        notify_ready(fd);
+    }
+    if let Some(notifier) = notifier {
+        notifier.ready(heartbeats);
+    }
+    if let Some(detached) = detached {
+        if let Err(e) = detached.ready() {
+            error!("Failed to detach from the terminal: {}", e);
+            process::exit(1);
+        }
+    }
+    if let Some(action) = seccomp {
+        if let Err(e) = seccomp::apply(action) {
+            error!("Failed to install the seccomp filter: {}", e);
+            process::exit(1);
+        }
+        info!("seccomp filter installed");
+    }
 }
 
-impl VhostUserInputThread {
-    // Create a new virtio input device
-    fn new(input_fd: EventFd) -> Result<Self> {
-        println!("new VhostUserInputThread");
+// What --landlock leaves reachable: the host devices, all of /dev/input when
+// they come and go, the directories of the sockets bound, the files given, and
+// /proc, where whoever else grabbed a device is looked for.
+fn sandbox(
+    cmd_arguments: &ArgMatches,
+    evdev_paths: &[String],
+    devices: &[(String, String)],
+    hotplug: bool,
+) -> Sandbox {
+    let mut sandbox = Sandbox::default();
+    let values = |name| cmd_arguments.values_of(name).into_iter().flatten();
 
-        Ok(VhostUserInputThread {
-            input_fd,
-            vring_worker: None,
-            event_idx: false,
-            kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
-        })
+    for path in evdev_paths
+        .iter()
+        .chain(devices.iter().map(|(path, _)| path))
+    {
+        sandbox.read_write(path);
+    }
+    if hotplug {
+        sandbox.devices(evdev::INPUT_DIR);
+    }
+    sandbox.read_tree("/proc");
+    if cmd_arguments.is_present("report-battery") {
+        sandbox.read_tree("/sys");
     }
 
-    fn process_queue(&mut self, vring: &mut Vring) -> bool {
-        let mut used_any: bool = false;
-        while let Some(_) = vring.mut_queue().iter().unwrap().next() {
-            println!("got an element in the queue!");
-            used_any = true;
+    // A client connects, which Landlock has no say in.
+    if !cmd_arguments.is_present("client") {
+        for name in &["socket-path", "switch-socket", "mirror-socket"] {
+            values(name).for_each(|path| sandbox.bind(path));
         }
+        for (_, path) in devices {
+            sandbox.bind(path);
+        }
+    }
+    for name in &["control-socket", "inject-socket"] {
+        values(name).for_each(|path| sandbox.bind(path));
+    }
 
-        used_any
+    for name in &[
+        "device-description",
+        "filter-rules",
+        "replay",
+        "script",
+        "remote-token-file",
+    ] {
+        values(name).for_each(|path| sandbox.read(path));
+    }
+    if cmd_arguments.is_present("x11-capture") || cmd_arguments.is_present("x11-window") {
+        if let Some(path) = x11::xauthority() {
+            sandbox.read(&path.to_string_lossy());
+        }
+    }
+    for name in &["record", "event-log", "log-file", "pidfile"] {
+        values(name).for_each(|path| sandbox.create(path));
     }
+    // Where the output goes once detached without a log.
+    if cmd_arguments.is_present("daemon") {
+        sandbox.read_write("/dev/null");
+    }
+
+    sandbox
 }
 
-struct VhostUserInputBackend {
-    thread: Mutex<VhostUserInputThread>,
-    config: VirtioInputConfig,
-    queues_per_thread: Vec<u64>,
-    num_queues: usize,
-    queue_size: usize,
+// What the sockets bound for frontends get, see --socket-mode, --socket-owner
+// and --socket-group. Whatever isn't given is left to the umask and to us.
+#[derive(Clone, Copy, Default, PartialEq)]
+struct SocketPermissions {
+    mode: Option<u32>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
 }
 
-impl VhostUserInputBackend {
-    fn new(input_fd: EventFd, num_queues: usize, queue_size: usize) -> Result<Self> {
-        let queues_per_thread = Vec::new();
+// Listen on path for a frontend. A stale socket left there by a previous run
+// is replaced, anything else is never touched.
+fn listen(path: &str, permissions: SocketPermissions) -> Result<Listener> {
+    if path.is_empty() || path == "@" {
+        return Err(Error::EmptySocketPath);
+    }
+    if path.starts_with('@') {
+        if permissions != SocketPermissions::default() {
+            return Err(Error::AbstractSocketPermissions(path.to_string()));
+        }
+        return listen_abstract(path).map_err(|e| Error::ListenAbstract(path.to_string(), e));
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(Error::SocketPathInUse(path.to_string()));
+        }
+        _ => {}
+    }
+    let dir = match Path::new(path).parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => Path::new("/"),
+    };
+    if !dir.is_dir() {
+        return Err(Error::NoSocketDir(dir.display().to_string()));
+    }
+    if permissions == SocketPermissions::default() {
+        return Listener::new(path, true).map_err(Error::CreateListener);
+    }
+
+    // Bound where only we can reach it, a directory of our own next to path,
+    // and only renamed into place once it has its mode and owner, so the
+    // frontend never sees it with others.
+    let private =
+        private_dir(dir, path).map_err(|e| Error::SetSocketPermissions(path.to_string(), e))?;
+    let bound = private.join("socket");
+    let result = Listener::new(&bound.to_string_lossy(), false)
+        .map_err(Error::CreateListener)
+        .and_then(|listener| {
+            set_socket_permissions(&bound, permissions)
+                .and_then(|_| fs::rename(&bound, path))
+                .map(|_| listener)
+                .map_err(|e| Error::SetSocketPermissions(path.to_string(), e))
+        });
+    // Both are gone or empty by now unless something failed.
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
 
-        let thread = Mutex::new(VhostUserInputThread::new(input_fd.try_clone().unwrap())?);
+    result
+}
 
-        let config = VirtioInputConfig {
-            select: 0,
-            subsel: 0,
-            size: 0,
-            reserved: [0; 5],
-            string: ['x'; 128],
-            bitmap: [0; 128],
-            abs: Default::default(),
-            ids: Default::default(),
-        };
+// Listen on the abstract name of path, past its @, as in ss and systemd. The
+// name goes away with the socket, so nothing is ever left behind to replace,
+// but neither are there permissions: any process in our network namespace
+// may connect.
+fn listen_abstract(path: &str) -> io::Result<Listener> {
+    // sockaddr_un is plain old data, for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let name = &path.as_bytes()[1..];
+    // Past the leading NUL that makes it abstract.
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "abstract socket name too long",
+        ));
+    }
+    for (to, &from) in addr.sun_path[1..].iter_mut().zip(name) {
+        *to = from as libc::c_char;
+    }
+    let path_offset = size_of::<libc::sockaddr_un>() - addr.sun_path.len();
+    let addr_len = (path_offset + 1 + name.len()) as libc::socklen_t;
 
-        Ok(VhostUserInputBackend {
-            thread,
-            config,
-            queues_per_thread,
-            num_queues,
-            queue_size,
-        })
+    // Creates a socket and nothing else.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fd was just created, the listener is its sole owner.
+    let socket = unsafe { UnixListener::from_raw_fd(fd) };
+    // bind() only reads the length given of addr.
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len,
+        )
+    };
+    if bound < 0 || unsafe { libc::listen(fd, 128) } < 0 {
+        return Err(io::Error::last_os_error());
     }
-}
 
-// Helper fn to convert VirtioInputConfig structs to &[u8]
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
+    // The listener gives up the fd, which the Listener owns from here on.
+    Ok(unsafe { Listener::from_raw_fd(socket.into_raw_fd()) })
 }
 
-impl VhostUserBackend for VhostUserInputBackend {
-    fn num_queues(&self) -> usize {
-        println!("num_queues");
+// A new directory in dir, only we have access to, for the socket at path.
+fn private_dir(dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let name = Path::new(path)
+        .file_name()
+        .map_or_else(|| "socket".into(), |name| name.to_string_lossy());
+    let template = dir.join(format!(".{}.XXXXXX", name));
+    let template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in the path"))?;
+    let mut template = template.into_bytes_with_nul();
+    // mkdtemp() only replaces the Xs of the NUL terminated template, with the
+    // mode 0700.
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
 
-        self.num_queues
+fn set_socket_permissions(path: &Path, permissions: SocketPermissions) -> io::Result<()> {
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in the path"))?;
+        // chown() only reads the NUL terminated path, -1 leaves an id as is.
+        let ret = unsafe {
+            libc::chown(
+                c_path.as_ptr(),
+                permissions.uid.unwrap_or(!0),
+                permissions.gid.unwrap_or(!0),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
     }
 
-    fn max_queue_size(&self) -> usize {
-        println!("max_queue_size");
+    Ok(())
+}
 
-        self.queue_size as usize
+// Parse a WIDTHxHEIGHT size, neither of them 0.
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let x = size.find('x')?;
+    match (size[..x].parse(), size[x + 1..].parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
     }
+}
+
+// Where a device gets its frontends from.
+enum Frontend {
+    // Listen on the socket path, see --socket-path, with the permissions given.
+    Socket(String, SocketPermissions),
+    // Listen on the inherited socket, see --fd.
+    Fd(RawFd),
+    // Connect to the frontend's socket, see --client.
+    Client(String),
+}
 
-    fn features(&self) -> u64 {
-        println!("features");
+impl Frontend {
+    // Where the frontend comes from, for the metrics.
+    fn name(&self) -> String {
+        match self {
+            Frontend::Socket(path, _) | Frontend::Client(path) => path.clone(),
+            Frontend::Fd(fd) => format!("fd:{}", fd),
+        }
+    }
+}
 
-        1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_INPUT_CFG_ID_NAME
-            | 1 << VIRTIO_INPUT_CFG_ID_SERIAL
-            | 1 << VIRTIO_INPUT_CFG_ID_DEVIDS
-            | 1 << VIRTIO_INPUT_CFG_PROP_BITS
-            | 1 << VIRTIO_INPUT_CFG_EV_BITS
-            | 1 << VIRTIO_INPUT_CFG_ABS_INFO
-            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+// The listener for the next frontend, None if we connect to it instead.
+fn next_listener(frontend: &Frontend, persistent: bool) -> Option<Listener> {
+    let (listener, source) = match frontend {
+        Frontend::Socket(path, permissions) => (listen(path, *permissions), path.clone()),
+        // start() closes the listener it's given, keep the inherited fd open
+        // for the frontends after this one.
+        Frontend::Fd(fd) if persistent => (
+            dup_fd(*fd).and_then(listen_fd),
+            format!("inherited fd {}", fd),
+        ),
+        Frontend::Fd(fd) => (listen_fd(*fd), format!("inherited fd {}", fd)),
+        Frontend::Client(_) => return None,
+    };
+    match listener {
+        Ok(listener) => {
+            info!("listening on {}", source);
+            Some(listener)
+        }
+        Err(e) => {
+            error!("Failed to listen on {}: {}", source, e);
+            process::exit(1);
+        }
     }
+}
 
-    fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        println!("protocol_features");
+// Listen for the next frontend on a copy of fd, which stays open for the
+// frontends after it.
+fn listen_dup(fd: RawFd) -> Option<Listener> {
+    match dup_fd(fd).and_then(listen_fd) {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            error!("Failed to listen again on fd {}: {}", fd, e);
+            process::exit(1);
+        }
+    }
+}
 
-        VhostUserProtocolFeatures::CONFIG
+// Serve the backend to frontends, starting with the one on listener, until
+// one disconnects. With persistent, until the process is killed.
+fn serve(
+    input_backend: Arc<RwLock<VhostUserInputBackend>>,
+    frontend: &Frontend,
+    mut listener: Option<Listener>,
+    persistent: bool,
+) {
+    // A persistent socket path is bound only once, and listened on through
+    // copies of its fd like an inherited one, as binding it again can fail
+    // once --user or --chroot took effect. The listener bound is never
+    // dropped, which would remove the socket file.
+    let mut bound = None;
+    if let (Frontend::Socket(..), true) = (frontend, persistent) {
+        if let Some(socket) = listener.take() {
+            bound = Some(socket.as_raw_fd());
+            std::mem::forget(socket);
+            listener = listen_dup(bound.unwrap());
+        }
     }
 
-    fn set_event_idx(&mut self, enabled: bool) {
-        println!("set_event_idx");
+    // One daemon per frontend connection. Without persistent there's only the
+    // one.
+    let mut reconnecting = false;
+    loop {
+        let mut daemon =
+            VhostUserDaemon::new("vhost-user-input".to_string(), input_backend.clone()).unwrap();
+        info!("VhostUserDaemon created...");
+
+        let started = match (listener.take(), frontend) {
+            (Some(listener), _) => daemon.start(listener),
+            (None, Frontend::Client(socket_path)) => {
+                info!("connecting to {}", socket_path);
+                let mut started = daemon.start_client(socket_path);
+                // A restarting VMM takes a moment to listen again.
+                while reconnecting && started.is_err() {
+                    thread::sleep(RECONNECT_INTERVAL);
+                    started = daemon.start_client(socket_path);
+                }
+                started
+            }
+            (None, _) => unreachable!(),
+        };
+        if let Err(e) = started {
+            error!("Failed to start daemon: {:?}", e);
+            process::exit(1);
+        }
+        info!("VhostUserDaemon started...");
+
+        let started = input_backend
+            .write()
+            .unwrap()
+            .started(daemon.get_vring_workers(), reconnecting);
+        if let Err(e) = started {
+            error!("Failed to register VringWorker: {:?}", e);
+            process::exit(1)
+        }
+
+        if let Err(e) = daemon.wait() {
+            error!("Waiting for daemon failed: {:?}", e);
+        }
+        info!("Waiting complete");
+        input_backend.write().unwrap().stopped();
+
+        if !persistent {
+            if let Err(e) = input_backend.read().unwrap().flush_recording() {
+                error!("Failed to write the recording: {}", e);
+            }
+            break;
+        }
+        if let Err(e) = input_backend.write().unwrap().disconnected() {
+            error!("Failed to reset backend for the next frontend: {}", e);
+            process::exit(1);
+        }
+        listener = match bound {
+            Some(fd) => {
+                info!("listening on {} again", frontend.name());
+                listen_dup(fd)
+            }
+            None => next_listener(frontend, persistent),
+        };
+        reconnecting = true;
+        info!("frontend disconnected, waiting for the next one");
+    }
+}
 
-        self.thread.lock().unwrap().event_idx = enabled;
+fn dup_fd(fd: RawFd) -> Result<RawFd> {
+    // Safe because F_DUPFD_CLOEXEC only creates a new fd, and fails on an fd
+    // that isn't open.
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(Error::DupListenerFd(io::Error::last_os_error())),
+        dup => Ok(dup),
     }
+}
 
-    fn update_memory(
-        &mut self,
-        _mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    ) -> VhostUserBackendResult<()> {
-        println!("update_memory");
+// The listening socket systemd passed us if it started us through a .socket
+// unit. The variables are cleared either way, for nothing we start to take
+// them for its own, as sd_listen_fds() does.
+fn activated_fd() -> Option<RawFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    // Meant for another process, e.g. the parent that passed the variables
+    // on by mistake.
+    if pid?.parse::<u32>().ok()? != process::id() {
+        return None;
+    }
+    match fds?.parse::<RawFd>().ok()? {
+        0 => None,
+        1 => Some(SD_LISTEN_FDS_START),
+        count => {
+            warn!("systemd passed {} sockets, listening on the first", count);
+            Some(SD_LISTEN_FDS_START)
+        }
+    }
+}
 
-        Ok(())
+// Listen on a unix socket the parent process bound and handed down to us as
+// fd, the way libvirt does.
+fn listen_fd(fd: RawFd) -> Result<Listener> {
+    let mut accepting: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe because sockaddr_storage is plain old data, for which all zeroes
+    // is a valid value.
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut addr_len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // Safe because both calls only write within the buffers we pass along
+    // with their sizes, and fail on an fd that isn't a socket.
+    let listening = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) == 0
+            && accepting != 0
+            && libc::getsockname(
+                fd,
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut addr_len,
+            ) == 0
+            && addr.ss_family as libc::c_int == libc::AF_UNIX
+    };
+    if !listening {
+        return Err(Error::InvalidListenerFd(fd));
     }
 
-    fn handle_event(
-        &self,
-        device_event: u16,
-        evset: epoll::Events,
-        vrings: &[Arc<RwLock<Vring>>],
-        thread_id: usize,
-    ) -> VhostUserBackendResult<bool> {
-        println!("handle event");
+    // Safe because the fd was handed to us for this purpose only, and we
+    // checked it is a listening unix socket.
+    Ok(unsafe { Listener::from_raw_fd(fd) })
+}
+
+// What --print-capabilities prints, in the format the vhost-user spec asks
+// backend programs for, plus the description of the evdev device if one was
+// given.
+#[derive(Serialize)]
+struct Capabilities {
+    #[serde(rename = "type")]
+    device_type: &'static str,
+    features: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<DeviceDescription>,
+}
 
-        if evset != epoll::Events::EPOLLIN {
-            return Err(Error::HandleEventNotEpollIn.into());
+fn print_capabilities(evdev_paths: &[&str]) -> Result<()> {
+    // Described the way build() merges them.
+    let mut merged: Option<DeviceConfig> = None;
+    for &path in evdev_paths {
+        let device = EvdevDevice::open(path).map_err(|e| Error::OpenEvdev(path.to_string(), e))?;
+        let config = DeviceConfig::from_evdev(&device)
+            .map_err(|e| Error::DescribeEvdev(path.to_string(), e))?;
+        match merged.as_mut() {
+            Some(merged) => merged.merge(config),
+            None => merged = Some(config),
         }
+    }
+    let device = merged.as_ref().map(DeviceDescription::from_device_config);
+    let capabilities = Capabilities {
+        device_type: "input",
+        features: vec!["evdev-path", "no-grab"],
+        device,
+    };
+    let json = serde_json::to_string_pretty(&capabilities).map_err(Error::SerializeCapabilities)?;
+    println!("{}", json);
 
-        println!("event received: {:#?}", device_event);
-        let mut thread = self.thread.lock().unwrap();
-        match device_event {
-            0 => {
-                let mut vring = vrings[0].write().unwrap();
-                if thread.event_idx {
-                    loop {
-                        vring.mut_queue();
-                        if !thread.process_queue(&mut vring) {
-                            break;
-                        }
-                    }
-                } else {
-                    thread.process_queue(&mut vring);
-                }
+    Ok(())
+}
 
-                Ok(false)
+// The paths of the evdev devices for which describe has a description, in
+// eventN order. More than one match is an error unless all is set, none is
+// one anyway, naming what was looked for.
+fn find_evdevs(
+    looked_for: String,
+    all: bool,
+    describe: impl Fn(&EvdevDevice) -> io::Result<Option<String>>,
+) -> Result<Vec<String>> {
+    let mut matches = Vec::new();
+    for path in list_devices().map_err(Error::ListEvdevs)? {
+        // Nodes we aren't allowed to open can't be what the user is after.
+        let description = match EvdevDevice::open(&path).and_then(|device| describe(&device)) {
+            Ok(description) => description,
+            Err(e) => {
+                warn!("Skipping {}: {}", path, e);
+                continue;
             }
-            _ => Err(Error::HandleEventUnknownEvent.into()),
+        };
+        if let Some(description) = description {
+            info!("{} matches: {}", path, description);
+            matches.push(path);
         }
     }
 
-    fn get_config(&self, _offset: u32, _size: u32) -> Vec<u8> {
-        println!("get config");
-
-        unsafe { any_as_u8_slice(self.config.borrow()).to_vec() }
+    match matches.len() {
+        0 => Err(Error::NoMatchingEvdev(looked_for)),
+        1 => Ok(matches),
+        _ if all => Ok(matches),
+        _ => Err(Error::AmbiguousEvdev(matches)),
     }
+}
+
+// Resolve --evdev-name to the paths of the evdev devices whose name matches
+// pattern.
+fn find_evdevs_by_name(pattern: &str, all: bool) -> Result<Vec<String>> {
+    let pattern = Regex::new(pattern).map_err(Error::InvalidEvdevName)?;
+    find_evdevs(pattern.to_string(), all, |device| {
+        let name = device.name()?;
+        Ok(Some(name).filter(|name| pattern.is_match(name)))
+    })
+}
 
-    fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> result::Result<(), io::Error> {
-        println!("set_config");
+// The vendor and product ids of --evdev-vid-pid, e.g. 046d:c52b as lsusb shows
+// them.
+fn parse_evdev_ids(ids: &str) -> Result<(u16, u16)> {
+    let parse = |id: &str| u16::from_str_radix(id, 16).ok();
+    match ids.split_once(':') {
+        Some((vendor, product)) => match (parse(vendor), parse(product)) {
+            (Some(vendor), Some(product)) => Ok((vendor, product)),
+            _ => Err(Error::InvalidEvdevIds(ids.to_string())),
+        },
+        None => Err(Error::InvalidEvdevIds(ids.to_string())),
+    }
+}
 
-        let mut config_slice = unsafe { any_as_u8_slice(self.config.borrow()) }.to_vec();
-        let data_len = _buf.len() as u32;
-        let config_len = config_slice.len() as u32;
-        if _offset + data_len > config_len {
-            error!("Failed to write config space");
-            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+// Resolve --evdev-vid-pid to the paths of the evdev devices with these vendor
+// and product ids.
+fn find_evdevs_by_ids(ids: &str, all: bool) -> Result<Vec<String>> {
+    let (vendor, product) = parse_evdev_ids(ids)?;
+    find_evdevs(ids.to_string(), all, |device| {
+        let id = device.ids()?;
+        if id.vendor != vendor || id.product != product {
+            return Ok(None);
         }
+        Ok(Some(device.name()?))
+    })
+}
 
-        let (_, right) = config_slice.split_at_mut(_offset as usize);
-        right.copy_from_slice(&_buf[..]);
+// Move the host devices between the guests in backends, from one to the other
+// as focus_rx says, starting out with the first. Each backend is locked in
+// turn, never both at once.
+fn switch_focus(backends: Vec<Arc<RwLock<VhostUserInputBackend>>>, focus_rx: Receiver<Focus>) {
+    let mut focused = 0;
+    for focus in focus_rx {
+        let next = match focus {
+            Focus::Next => (focused + 1) % backends.len(),
+            Focus::Guest(guest) => guest,
+        };
+        if next == focused {
+            continue;
+        }
 
-        Ok(())
+        let evdevs = backends[focused].read().unwrap().take_evdevs();
+        let backend = backends[next].read().unwrap();
+        if let Err(e) = backend.give_evdevs(evdevs) {
+            warn!("Failed to switch to guest {}: {}", next, e);
+        }
+        info!("switched to guest {}", next);
+        focused = next;
     }
+}
+
+// Carry out --control-socket commands on the device served by input_backend.
+// Events go through injected, like those of --inject-socket clients.
+// focus_tx switches between the guests of --switch-socket, if any. swap says
+// whether devices can still be opened once the privileges are given up.
+fn control_handler(
+    input_backend: Arc<RwLock<VhostUserInputBackend>>,
+    injected: Injected,
+    focus_tx: Option<(Sender<Focus>, usize)>,
+    swap: bool,
+) -> Arc<control::Handler> {
+    // Senders only became Sync in recent Rust.
+    let focus_tx = Mutex::new(focus_tx);
+    Arc::new(move |command: Command| {
+        if let Command::Focus { guest } = command {
+            return match focus_tx.lock().unwrap().as_ref() {
+                Some((focus_tx, guests)) if guest < *guests => {
+                    focus_tx
+                        .send(Focus::Guest(guest))
+                        .map_err(|_| "the switch between guests is gone".to_string())?;
+                    Ok(None)
+                }
+                Some((_, guests)) => Err(format!("there are only {} guests", guests)),
+                None => Err("no --switch-socket guests".to_string()),
+            };
+        }
+
+        if let Some(events) = command.events()? {
+            if !injected.push(&events) {
+                return Err("too many pending events".to_string());
+            }
+            return Ok(None);
+        }
+
+        let backend = input_backend.read().unwrap();
+        match command {
+            Command::Status => {
+                let state = backend.state();
+                Ok(Some(Answer::Status(Status {
+                    name: state.name,
+                    paused: state.paused,
+                    driver_ready: state.driver_ready,
+                    evdevs: state.evdevs,
+                    pending_events: state.pending_events,
+                    dropped_events: state.events_dropped,
+                    replay: state
+                        .replay
+                        .map(|(played, events)| ReplayStatus { played, events }),
+                })))
+            }
+            Command::QueryStats => {
+                let state = backend.state();
+                let counters = state.counters;
+                Ok(Some(Answer::Stats(Stats {
+                    events_forwarded: counters.events_forwarded,
+                    events_dropped: state.events_dropped,
+                    frames: counters.frames,
+                    used_ring_adds: counters.used_ring_adds,
+                    notifications_sent: counters.notifications_sent,
+                    notifications_suppressed: counters.notifications_suppressed,
+                    statusq_events: counters.statusq_events,
+                })))
+            }
+            Command::Step => {
+                backend.step_replay()?;
+                injected.wake();
+                Ok(None)
+            }
+            Command::Pause => {
+                backend.pause();
+                injected.wake();
+                Ok(None)
+            }
+            Command::Resume => {
+                backend.resume().map_err(|e| e.to_string())?;
+                injected.wake();
+                Ok(None)
+            }
+            Command::Swap { .. } if !swap => {
+                Err("swap takes --logind along with --user, --group or --chroot".to_string())
+            }
+            Command::Swap { path } => {
+                backend.swap_evdev(&path)?;
+                injected.wake();
+                Ok(None)
+            }
+            _ => unreachable!(),
+        }
+    })
+}
 
-    fn queues_per_thread(&self) -> Vec<u64> {
-        println!("queues_per_thread");
+// The state of the guests served by backends, each known by its frontend, for
+// --metrics-listen.
+fn metrics_collector(
+    backends: Vec<(String, Arc<RwLock<VhostUserInputBackend>>)>,
+) -> Arc<metrics::Collector> {
+    Arc::new(move || {
+        backends
+            .iter()
+            .map(|(socket, backend)| {
+                let state = backend.read().unwrap().state();
+                metrics::Guest {
+                    socket: socket.clone(),
+                    connected: state.connected,
+                    driver_ready: state.driver_ready,
+                    counters: state.counters,
+                    events_dropped: state.events_dropped,
+                    buffered_events: state.buffered_events,
+                    eventq_buffers: state.eventq_buffers,
+                }
+            })
+            .collect()
+    })
+}
+
+// Serve the metrics of the guests of backends, with --metrics-listen.
+fn serve_metrics(
+    address: Option<&str>,
+    backends: Vec<(String, Arc<RwLock<VhostUserInputBackend>>)>,
+) {
+    let address = match address {
+        Some(address) => address,
+        None => return,
+    };
+    match metrics::listen(address, metrics_collector(backends)) {
+        Ok(address) => info!("serving metrics on http://{}/metrics", address),
+        Err(e) => {
+            error!(
+                "Failed to listen for metrics scrapers on {}: {}",
+                address, e
+            );
+            process::exit(1);
+        }
+    }
+}
 
-        self.queues_per_thread.clone()
+// Log the state of the guests served by backends, each known by its frontend,
+// on SIGUSR1.
+fn report_state(backends: &[(String, Arc<RwLock<VhostUserInputBackend>>)]) {
+    info!("state of {} guests on SIGUSR1", backends.len());
+    for (socket, backend) in backends.iter() {
+        let state = backend.read().unwrap().state();
+        let connection = match (state.connected, state.driver_ready) {
+            (true, true) => "connected, driver ready",
+            (true, false) => "connected, driver not ready",
+            (false, _) => "not connected",
+        };
+        info!(
+            "{}: {}, features {:#x} of {:#x}, {} queues of up to {} entries",
+            socket,
+            connection,
+            state.acked_features,
+            state.features,
+            state.num_queues,
+            state.queue_size
+        );
+        info!(
+            "{}: {} events buffered, {} eventq buffers waiting, {} host devices {}{}",
+            socket,
+            state.buffered_events,
+            state.eventq_buffers,
+            state.evdevs,
+            if state.grabbed {
+                "grabbed"
+            } else {
+                "not grabbed"
+            },
+            if state.paused { ", paused" } else { "" }
+        );
+        let counters = state.counters;
+        info!(
+            "{}: {} events forwarded in {} frames, {} dropped, {} used buffers, {} notifications sent, {} suppressed, {} statusq events",
+            socket,
+            counters.events_forwarded,
+            counters.frames,
+            state.events_dropped,
+            counters.used_ring_adds,
+            counters.notifications_sent,
+            counters.notifications_suppressed,
+            counters.statusq_events
+        );
     }
 }
 
@@ -284,103 +1011,1596 @@ fn main() {
         .arg(
             Arg::with_name("print-capabilities")
                 .long("print-capabilities")
-                .help("Print capabilities")
-                .takes_value(true)
-                .min_values(1),
+                .help("Print capabilities as JSON, with the description of --evdev-path if given, and exit"),
         )
         .arg(
             Arg::with_name("no-grab")
                 .long("no-grab")
-                .help("Don't grab device")
+                .help("Don't grab device"),
+        )
+        .arg(
+            Arg::with_name("grab-toggle")
+                .long("grab-toggle")
+                .help("Switch the host devices between host and guest with a hotkey on them")
+                .conflicts_with("no-grab"),
+        )
+        .arg(
+            Arg::with_name("grab-hotkey")
+                .long("grab-hotkey")
+                .help("Keys toggling --grab-toggle, joined by + (KEY_LEFTCTRL+KEY_RIGHTCTRL by default)")
                 .takes_value(true)
-                .min_values(1),
+                .requires("grab-toggle"),
+        )
+        .arg(
+            Arg::with_name("grab-hotkey-taps")
+                .long("grab-hotkey-taps")
+                .help("Times in a row the --grab-hotkey keys are pressed together to toggle (2 by default)")
+                .takes_value(true)
+                .requires("grab-toggle"),
         )
         .arg(
             Arg::with_name("socket-path")
                 .long("socket-path")
                 .help("vhost-user socket path")
                 .takes_value(true)
-                .min_values(1)
-                .required(true),
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("switch-socket")
+                .long("switch-socket")
+                .help("Also serve a further guest on this vhost-user socket path, only one guest getting the host devices at a time (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["device", "hotplug"]),
+        )
+        .arg(
+            Arg::with_name("mirror-socket")
+                .long("mirror-socket")
+                .help("Also serve a further guest on this vhost-user socket path, getting the same input as the first (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["device", "switch-socket"]),
+        )
+        .arg(
+            Arg::with_name("socket-mode")
+                .long("socket-mode")
+                .help("Octal mode of the vhost-user sockets bound, e.g. 0660, set before the frontend can reach them")
+                .takes_value(true)
+                .conflicts_with("client"),
+        )
+        .arg(
+            Arg::with_name("socket-owner")
+                .long("socket-owner")
+                .help("User, a name or uid, owning the vhost-user sockets bound")
+                .takes_value(true)
+                .conflicts_with("client"),
+        )
+        .arg(
+            Arg::with_name("socket-group")
+                .long("socket-group")
+                .help("Group, a name or gid, owning the vhost-user sockets bound")
+                .takes_value(true)
+                .conflicts_with("client"),
+        )
+        .arg(
+            Arg::with_name("switch-hotkey")
+                .long("switch-hotkey")
+                .help("Keys moving the host devices on to the next --switch-socket guest, joined by + (KEY_SCROLLLOCK by default)")
+                .takes_value(true)
+                .requires("switch-socket"),
+        )
+        .arg(
+            Arg::with_name("switch-hotkey-taps")
+                .long("switch-hotkey-taps")
+                .help("Times in a row the --switch-hotkey keys are pressed together to switch (2 by default)")
+                .takes_value(true)
+                .requires("switch-socket"),
+        )
+        .arg(
+            Arg::with_name("client")
+                .long("client")
+                .help("Connect to the frontend's socket at --socket-path, or those of --device, instead of listening on it")
+                .conflicts_with("fd"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .help("Serve the evdev device on the socket path, given as EVDEV_PATH,SOCKET_PATH, once per device")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&[
+                    "socket-path",
+                    "fd",
+                    "evdev-path",
+                    "evdev-name",
+                    "evdev-by-id",
+                    "evdev-vid-pid",
+                    "evdev-group",
+                    "device-description",
+                    "name",
+                    "record",
+                    "replay",
+                    "event-log",
+                    "synthetic",
+                    "inject-socket",
+                    "control-socket",
+                    "websocket",
+                    "remote-listen",
+                    "vsock-port",
+                    "x11-capture",
+                    "x11-window",
+                    "libei-socket",
+                    "script",
+                    "hotplug",
+                ]),
+        )
+        .arg(
+            Arg::with_name("synthetic")
+                .long("synthetic")
+                .help("Present a built-in device with no host device behind it, fed through --inject-socket")
+                .takes_value(true)
+                .possible_values(profiles::NAMES)
+                .conflicts_with_all(&[
+                    "evdev-path",
+                    "evdev-name",
+                    "evdev-by-id",
+                    "evdev-vid-pid",
+                    "evdev-group",
+                    "device-description",
+                ]),
+        )
+        .arg(
+            Arg::with_name("touchscreen-size")
+                .long("touchscreen-size")
+                .help("Size of the synthetic touchscreen as WIDTHxHEIGHT points (32768x32768 by default)")
+                .takes_value(true)
+                .requires("synthetic"),
+        )
+        .arg(
+            Arg::with_name("touch-slots")
+                .long("touch-slots")
+                .help("Contacts the synthetic touchscreen tracks at once (10 by default)")
+                .takes_value(true)
+                .requires("synthetic"),
+        )
+        .arg(
+            Arg::with_name("inject-socket")
+                .long("inject-socket")
+                .help("Accept events to forward to the guest, one per line, on a unix socket at this path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("script")
+                .long("script")
+                .help("Forward the events scripted one per line, e.g. `key KEY_A press`, `rel X 5`, `sleep 100ms` or `syn`, in this file or named pipe, or on stdin for -")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("websocket")
+                .long("websocket")
+                .help("Accept key and pointer events from browsers on a WebSocket server at this address, e.g. 127.0.0.1:8080")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("websocket-origin")
+                .long("websocket-origin")
+                .help("Let in browsers from the pages of this origin, e.g. https://console.example (repeatable), no browser is let in otherwise")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("websocket"),
+        )
+        .arg(
+            Arg::with_name("remote-listen")
+                .long("remote-listen")
+                .help("Accept events from other machines on a TCP listener at this address, e.g. 0.0.0.0:5910")
+                .takes_value(true)
+                .requires("remote-token-file"),
+        )
+        .arg(
+            Arg::with_name("remote-token-file")
+                .long("remote-token-file")
+                .help("File holding the token --remote-listen clients have to send first")
+                .takes_value(true)
+                .requires("remote-listen"),
+        )
+        .arg(
+            Arg::with_name("vsock-port")
+                .long("vsock-port")
+                .help("Accept events from other VMs on this vsock port, as --remote-listen does but without a token")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vsock-cid")
+                .long("vsock-cid")
+                .help("Only accept --vsock-port clients from the VM with this context ID")
+                .takes_value(true)
+                .requires("vsock-port"),
+        )
+        .arg(
+            Arg::with_name("x11-capture")
+                .long("x11-capture")
+                .help("Forward the keyboard and pointer input of the X session on $DISPLAY while captured with the hotkey"),
+        )
+        .arg(
+            Arg::with_name("x11-hotkey")
+                .long("x11-hotkey")
+                .help("Keys toggling --x11-capture, joined by + (KEY_LEFTCTRL+KEY_LEFTALT+KEY_G by default)")
+                .takes_value(true)
+                .requires("x11-capture"),
+        )
+        .arg(
+            Arg::with_name("x11-window")
+                .long("x11-window")
+                .help("Open a window on $DISPLAY and forward the keyboard and pointer input it gets"),
+        )
+        .arg(
+            Arg::with_name("libei-socket")
+                .long("libei-socket")
+                .help("Forward the keyboard and pointer input of the Wayland session from the EIS server at this socket, e.g. $LIBEI_SOCKET (libei feature)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .long("control-socket")
+                .help("Accept JSON commands to inject events, query status and pause host input on a unix socket at this path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-listen")
+                .long("metrics-listen")
+                .help("Serve Prometheus metrics of every guest over HTTP at this address, e.g. 127.0.0.1:9930")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("persistent")
+                .long("persistent")
+                .help("Wait for a new frontend when the current one disconnects, instead of exiting"),
         )
         .arg(
             Arg::with_name("fd")
                 .long("fd")
                 .help("Use inherited fd socket")
                 .takes_value(true)
-                .min_values(1),
+                .min_values(1)
+                .conflicts_with("socket-path"),
         )
         .arg(
             Arg::with_name("evdev-path")
                 .long("evdev-path")
-                .help("evdev input device path")
+                .help("evdev input device path, once per device to merge into one")
                 .takes_value(true)
-                .min_values(1),
+                .multiple(true)
+                .number_of_values(1),
         )
-        .get_matches();
-
-    // Socket on which the vhost-user-input server listens on
-    let socket_path = match cmd_arguments.value_of("socket-path") {
-        None => {
-            panic!("no socket-path provided!")
-        }
-        Some(path) => path,
-    };
-
-    // Add a new listener on the socket-path to listen for events
-    let listener = Listener::new(socket_path, true).unwrap();
-    // TODO: Implement logging
-    println!("listening on {}", socket_path);
-
-    // EventFd for synthetic inputs to the VhostUserInputThread
-    let sim_inputs = EventFd::new(EFD_NONBLOCK).unwrap();
-
-    let input_backend = Arc::new(RwLock::new(
-        VhostUserInputBackend::new(sim_inputs.try_clone().unwrap(), 1, 1024).unwrap(),
-    ));
-    println!("VhostUserInputBackend created...");
-
-    let mut daemon =
-        VhostUserDaemon::new("vhost-user-input".to_string(), input_backend.clone()).unwrap();
-    println!("VhostUserDaemon created...");
-
-    if let Err(e) = daemon.start(listener) {
-        error!("Failed to start daemon: {:?}", e);
-        process::exit(1);
-    }
-    println!("VhostUserDaemon started...");
-
-    // Get vring_workers from the VhostUserInputThread, register listeners on each of them for
-    // synthetic inputs EventFd created earlier
-    let vring_workers = daemon.get_vring_workers();
-    for vring_worker in vring_workers {
-        // Send dummy data for now
-        if let Err(e) =
-            vring_worker.register_listener(sim_inputs.as_raw_fd(), epoll::Events::EPOLLIN, 0)
-        {
-            error!("Failed to register VringWorker: {:?}", e);
-            process::exit(1)
-        }
-    }
-
-    if let Err(e) = daemon.wait() {
-        error!("Waiting for daemon failed: {:?}", e);
-    }
-    println!("Waiting complete");
-
-    let kill_evt = input_backend
-        .read()
-        .unwrap()
-        .thread
-        .lock()
-        .unwrap()
-        .kill_evt
-        .try_clone()
-        .unwrap();
-    if let Err(e) = kill_evt.write(1) {
-        error!("Error shutting down worker thread: {:?}", e)
+        .arg(
+            Arg::with_name("evdev-name")
+                .long("evdev-name")
+                .visible_alias("device-by-name")
+                .help("Use the evdev device whose name matches this regex")
+                .takes_value(true)
+                .min_values(1)
+                .conflicts_with("evdev-path"),
+        )
+        .arg(
+            Arg::with_name("evdev-by-id")
+                .long("evdev-by-id")
+                .visible_alias("device-by-id")
+                .help("Use the evdev device of this /dev/input/by-id link, given by name or path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["evdev-path", "evdev-name"]),
+        )
+        .arg(
+            Arg::with_name("evdev-vid-pid")
+                .long("evdev-vid-pid")
+                .help("Use the evdev device with these vendor and product ids, as VENDOR:PRODUCT in hex, e.g. 046d:c52b")
+                .takes_value(true)
+                .conflicts_with_all(&["evdev-path", "evdev-name", "evdev-by-id"]),
+        )
+        .group(ArgGroup::with_name("evdev-match").args(&["evdev-name", "evdev-vid-pid"]))
+        .arg(
+            Arg::with_name("evdev-name-all")
+                .long("evdev-name-all")
+                .help("Accept several devices matching --evdev-name or --evdev-vid-pid")
+                .requires("evdev-match"),
+        )
+        .group(ArgGroup::with_name("evdev-select").args(&[
+            "evdev-path",
+            "evdev-name",
+            "evdev-by-id",
+            "evdev-vid-pid",
+        ]))
+        .arg(
+            Arg::with_name("hotplug")
+                .long("hotplug")
+                .help("Keep the device up while the host device selected by --evdev-path, --evdev-name, --evdev-by-id or --evdev-vid-pid is unplugged, and attach it again once plugged in")
+                .requires("evdev-select")
+                .conflicts_with_all(&["evdev-group", "evdev-name-all"]),
+        )
+        .arg(
+            Arg::with_name("evdev-group")
+                .long("evdev-group")
+                .help("Forward this evdev device together with its sibling nodes")
+                .takes_value(true)
+                .min_values(1)
+                .conflicts_with_all(&["evdev-path", "evdev-name", "evdev-by-id", "evdev-vid-pid"]),
+        )
+        .arg(
+            Arg::with_name("strace-ioctls")
+                .long("strace-ioctls")
+                .help("Log every ioctl issued to the evdev devices at debug level"),
+        )
+        .arg(
+            Arg::with_name("time64")
+                .long("time64")
+                .help("Read evdev events in the kernel's layout rather than libc's, for a 32-bit build whose libc has a 64-bit time_t"),
+        )
+        .arg(
+            Arg::with_name("filter-rules")
+                .long("filter-rules")
+                .help("File with per-event filter rules (see Filter rules in the README for the syntax)")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .help("Device name to present to the guest")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("name-suffix")
+                .long("name-suffix")
+                .help("Append this to the device name, e.g. [vm-left]")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("invert-abs")
+                .long("invert-abs")
+                .help("Invert an absolute axis, e.g. ABS_Y (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("device-description")
+                .long("device-description")
+                .help("JSON file describing the device to present (see src/description.rs)")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("syn-dropped")
+                .long("syn-dropped")
+                .help("What to do when the host drops events: resync, drop or forward")
+                .takes_value(true)
+                .possible_values(&["resync", "drop", "forward"])
+                .default_value("resync"),
+        )
+        .arg(
+            Arg::with_name("max-config-size")
+                .long("max-config-size")
+                .help("Largest config space read to answer, capped at the config struct size")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .help("Record the events read from the host to this file")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("record-format")
+                .long("record-format")
+                .help("Format of the --record file")
+                .takes_value(true)
+                .possible_values(&["binary", "text"])
+                .default_value("binary")
+                .requires("record"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .help("Replay a recording once the guest driver is up")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .alias("replay-speed")
+                .help("Replay speed factor, 2 plays twice as fast, from 0.001 to 1000")
+                .takes_value(true)
+                .default_value("1")
+                .requires("replay"),
+        )
+        .arg(
+            Arg::with_name("replay-step")
+                .long("replay-step")
+                .help("Replay a frame at a time, on each step command of --control-socket")
+                .requires_all(&["replay", "control-socket"]),
+        )
+        .arg(
+            Arg::with_name("report-battery")
+                .long("report-battery")
+                .help("Log the battery level of wireless evdev devices when it changes"),
+        )
+        .arg(
+            Arg::with_name("log-dropped-events")
+                .long("log-dropped-events")
+                .help("Log a summary of the events dropped, by cause, every 5 seconds"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .help("Once set up, become this user, a name or uid, and its primary group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .help("Once set up, become this group, a name or gid, instead of the --user's")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("chroot")
+                .long("chroot")
+                .help("Once set up, change root to this directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .help("Once ready, restrict the daemon to the system calls it needs: others kill it, or with log are only logged")
+                .takes_value(true)
+                .possible_values(&["kill", "log"]),
+        )
+        .arg(
+            Arg::with_name("logind")
+                .long("logind")
+                .help("Have systemd-logind open the evdev devices, as the controller of our session, rather than opening them ourselves"),
+        )
+        .arg(
+            Arg::with_name("landlock")
+                .long("landlock")
+                .help("Once the devices are found, restrict filesystem access to them, the directories of the sockets and the files given"),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Detach from the terminal once ready for a frontend, the parent exiting then"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .help("Append the log to this file, with --daemon once ready and along with the rest of the output, which is dropped otherwise")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("Log records up to this level: trace logs every event, debug the frontend's requests")
+                .takes_value(true)
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .arg(
+            Arg::with_name("log-target")
+                .long("log-target")
+                .help("Send the log to stderr, to journald with the device, queue and event type as fields, or to syslog")
+                .takes_value(true)
+                .possible_values(&["stderr", "journald", "syslog"])
+                .default_value("stderr"),
+        )
+        .arg(
+            Arg::with_name("pidfile")
+                .long("pidfile")
+                .help("Write the daemon's pid to this file, removed again on exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ready-fd")
+                .long("ready-fd")
+                .help("Write a newline to this inherited fd, then close it, once ready for a frontend")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("prefetch")
+                .long("prefetch")
+                .help("Take up to N eventq buffers from the guest at once")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            Arg::with_name("event-buffer-size")
+                .long("event-buffer-size")
+                .help("Hold up to N events for the guest while it takes none, e.g. during boot")
+                .takes_value(true)
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::with_name("overflow-policy")
+                .long("overflow-policy")
+                .help("What to do once the event buffer is full: drop-newest, drop-oldest or block-source")
+                .takes_value(true)
+                .possible_values(&["drop-newest", "drop-oldest", "block-source"])
+                .default_value("drop-newest"),
+        )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .help("Only pass EV_TYPE[:CODE,...] to the guest, masking everything else out (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("block")
+                .long("block")
+                .help("Never pass EV_TYPE[:CODE,...] to the guest, e.g. EV_KEY:KEY_POWER,KEY_SLEEP (repeatable)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("coalesce-rel")
+                .long("coalesce-rel")
+                .help("Experimental: sum consecutive relative motion on the same axis within a frame, and across frames waiting for the guest"),
+        )
+        .arg(
+            Arg::with_name("max-events-per-sec")
+                .long("max-events-per-sec")
+                .help("Forward at most N events per second, merging pointer motion beyond that")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("event-log")
+                .long("event-log")
+                .help("Append a record of every event delivered to the guest to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("event-log-max-size")
+                .long("event-log-max-size")
+                .help("Rotate the event log to <file>.1 once it reaches N bytes")
+                .takes_value(true)
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::with_name("worker-watchdog-ms")
+                .long("worker-watchdog-ms")
+                .help("Log an error when the worker thread makes no progress for N milliseconds (0 disables the watchdog)")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("worker-watchdog-exit")
+                .long("worker-watchdog-exit")
+                .help("Also shut down when the worker thread stalls"),
+        )
+        .arg(
+            Arg::with_name("slow-span-us")
+                .long("slow-span-us")
+                .help("Warn about queue processing, config requests and evdev reads taking longer than N microseconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Export the spans to this OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces (otlp feature)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poll-interval-ms")
+                .long("poll-interval-ms")
+                .help("Also poll the input device every N milliseconds (0 disables polling)")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .get_matches();
+
+    // Before anything is logged. With --daemon, the log goes where the rest of
+    // the output does once ready.
+    let level = cmd_arguments
+        .value_of("log-level")
+        .unwrap()
+        .parse()
+        .unwrap_or(LevelFilter::Info);
+    let log_file = cmd_arguments
+        .value_of("log-file")
+        .filter(|_| !cmd_arguments.is_present("daemon"));
+    let target = cmd_arguments
+        .value_of("log-target")
+        .unwrap()
+        .parse()
+        .unwrap_or(logger::Target::Stderr);
+    if let Err(e) = logger::init(level, target, log_file) {
+        eprintln!("Failed to set up logging: {}", e);
+        process::exit(1);
+    }
+    // Before any thread is started, for all of them to leave it to the report.
+    if let Err(e) = report::block() {
+        error!("Failed to block SIGUSR1: {}", e);
+        process::exit(1);
+    }
+
+    if cmd_arguments.is_present("print-capabilities") {
+        let evdev_paths: Vec<&str> = cmd_arguments
+            .values_of("evdev-path")
+            .into_iter()
+            .flatten()
+            .collect();
+        if let Err(e) = print_capabilities(&evdev_paths) {
+            error!("Failed to print capabilities: {}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    let persistent = cmd_arguments.is_present("persistent");
+    let mut socket_permissions = SocketPermissions::default();
+    if let Some(mode) = cmd_arguments.value_of("socket-mode") {
+        match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o777 => socket_permissions.mode = Some(mode),
+            _ => {
+                error!("Invalid --socket-mode {}, expected an octal mode", mode);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(user) = cmd_arguments.value_of("socket-owner") {
+        match privileges::user(user) {
+            Ok((uid, _)) => socket_permissions.uid = Some(uid),
+            Err(e) => {
+                error!("Invalid --socket-owner {}: {}", user, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(group) = cmd_arguments.value_of("socket-group") {
+        match privileges::group(group) {
+            Ok(gid) => socket_permissions.gid = Some(gid),
+            Err(e) => {
+                error!("Invalid --socket-group {}: {}", group, e);
+                process::exit(1);
+            }
+        }
+    }
+    let grab = !cmd_arguments.is_present("no-grab");
+    let grab_hotkey = if cmd_arguments.is_present("grab-toggle") {
+        Some(hotkey_args(
+            &cmd_arguments,
+            "grab-hotkey",
+            hotkey::GRAB_HOTKEY,
+            hotkey::GRAB_TAPS,
+        ))
+    } else {
+        None
+    };
+    let switch_hotkey = if cmd_arguments.is_present("switch-socket") {
+        Some(hotkey_args(
+            &cmd_arguments,
+            "switch-hotkey",
+            hotkey::SWITCH_HOTKEY,
+            hotkey::SWITCH_TAPS,
+        ))
+    } else {
+        None
+    };
+
+    let mut devices = Vec::new();
+    for device in cmd_arguments.values_of("device").into_iter().flatten() {
+        match device.find(',') {
+            Some(comma) if comma > 0 && comma + 1 < device.len() => {
+                devices.push((device[..comma].to_string(), device[comma + 1..].to_string()))
+            }
+            _ => {
+                error!(
+                    "Invalid --device {}: expected EVDEV_PATH,SOCKET_PATH",
+                    device
+                );
+                process::exit(1);
+            }
+        }
     }
+    if cmd_arguments.is_present("client") {
+        let abstract_name = ["socket-path", "switch-socket", "mirror-socket"]
+            .iter()
+            .flat_map(|name| cmd_arguments.values_of(name).into_iter().flatten())
+            .chain(devices.iter().map(|(_, path)| path.as_str()))
+            .find(|path| path.starts_with('@'));
+        if let Some(path) = abstract_name {
+            error!(
+                "--client can't connect to the abstract socket name {}",
+                path
+            );
+            process::exit(1);
+        }
+    }
+
+    let ready_fd = match cmd_arguments.value_of("ready-fd").map(str::parse::<RawFd>) {
+        None => None,
+        Some(Ok(fd)) if fd >= 0 => Some(fd),
+        Some(Ok(fd)) => {
+            error!("Invalid --ready-fd: {}", fd);
+            process::exit(1);
+        }
+        Some(Err(e)) => {
+            error!("Invalid --ready-fd: {}", e);
+            process::exit(1);
+        }
+    };
 
-    println!("Worked threads closed.");
-    process::exit(0);
+    let seccomp = match cmd_arguments.value_of("seccomp").map(str::parse) {
+        None => None,
+        Some(Ok(action)) => Some(action),
+        Some(Err(e)) => {
+            error!("Invalid --seccomp: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut credentials = Credentials::default();
+    if let Some(user) = cmd_arguments.value_of("user") {
+        match privileges::user(user) {
+            Ok((uid, gid)) => {
+                credentials.uid = Some(uid);
+                credentials.gid = Some(gid);
+            }
+            Err(e) => {
+                error!("Invalid --user {}: {}", user, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(group) = cmd_arguments.value_of("group") {
+        match privileges::group(group) {
+            Ok(gid) => credentials.gid = Some(gid),
+            Err(e) => {
+                error!("Invalid --group {}: {}", group, e);
+                process::exit(1);
+            }
+        }
+    }
+    credentials.root = cmd_arguments.value_of("chroot").map(str::to_string);
+    let credentials = Some(credentials).filter(|credentials| {
+        credentials.uid.is_some() || credentials.gid.is_some() || credentials.root.is_some()
+    });
+    // Devices opened once the privileges are given up are opened as the new
+    // user and group, within the new root, where /dev/input is rarely
+    // reachable. logind opens them for us instead.
+    let open_later = credentials.is_none() || cmd_arguments.is_present("logind");
+    if !open_later && cmd_arguments.is_present("hotplug") {
+        error!("--hotplug takes --logind along with --user, --group or --chroot");
+        process::exit(1);
+    }
+
+    // Before --daemon forks, as it's only for the pid systemd started.
+    let activated = activated_fd();
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier,
+        Err(e) => {
+            error!("Failed to set up systemd notifications: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Before any thread is started.
+    let detached = if cmd_arguments.is_present("daemon") {
+        match daemon::detach(cmd_arguments.value_of("log-file")) {
+            Ok(detached) => Some(detached),
+            Err(e) => {
+                error!("Failed to detach from the terminal: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let pidfile = cmd_arguments.value_of("pidfile");
+    if let Some(path) = pidfile {
+        if let Err(e) = daemon::write_pidfile(path) {
+            error!("Failed to write the pidfile {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    let max_config_size = match cmd_arguments.value_of("max-config-size").map(str::parse) {
+        None => size_of::<VirtioInputConfig>(),
+        Some(Ok(size)) => size,
+        Some(Err(e)) => {
+            error!("Invalid --max-config-size: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let max_events_per_sec = match cmd_arguments.value_of("max-events-per-sec").map(str::parse) {
+        None => None,
+        Some(Ok(0)) | Some(Err(_)) => {
+            error!("Invalid --max-events-per-sec, expected a positive number of events");
+            process::exit(1);
+        }
+        Some(Ok(rate)) => Some(rate),
+    };
+
+    let prefetch = match cmd_arguments.value_of("prefetch").unwrap().parse() {
+        Ok(0) | Err(_) => {
+            error!("Invalid --prefetch, expected a positive number of buffers");
+            process::exit(1);
+        }
+        Ok(n) => n,
+    };
+
+    let event_buffer_size = match cmd_arguments.value_of("event-buffer-size").unwrap().parse() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Invalid --event-buffer-size, expected a number of events");
+            process::exit(1);
+        }
+    };
+
+    let overflow_policy: OverflowPolicy =
+        match cmd_arguments.value_of("overflow-policy").unwrap().parse() {
+            Ok(policy) => policy,
+            Err(e) => {
+                error!("Invalid --overflow-policy: {}", e);
+                process::exit(1);
+            }
+        };
+
+    let watchdog_timeout = match cmd_arguments
+        .value_of("worker-watchdog-ms")
+        .unwrap()
+        .parse()
+    {
+        Ok(0) => None,
+        Ok(ms) => Some(Duration::from_millis(ms)),
+        Err(e) => {
+            error!("Invalid --worker-watchdog-ms: {}", e);
+            process::exit(1);
+        }
+    };
+    if watchdog_timeout.is_none() && cmd_arguments.is_present("worker-watchdog-exit") {
+        error!("--worker-watchdog-exit needs a non-zero --worker-watchdog-ms");
+        process::exit(1);
+    }
+
+    let slow_span = match cmd_arguments.value_of("slow-span-us").map(str::parse) {
+        None => None,
+        Some(Ok(us)) if us > 0 => Some(Duration::from_micros(us)),
+        Some(_) => {
+            error!("Invalid --slow-span-us, expected a positive number of microseconds");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = spans::init(slow_span, cmd_arguments.value_of("otlp-endpoint")) {
+        error!("Failed to set up spans: {}", e);
+        process::exit(1);
+    }
+
+    let poll_interval = match cmd_arguments.value_of("poll-interval-ms").unwrap().parse() {
+        Ok(0) => None,
+        Ok(ms) => Some(Duration::from_millis(ms)),
+        Err(e) => {
+            error!("Invalid --poll-interval-ms: {}", e);
+            process::exit(1);
+        }
+    };
+
+    evdev::trace_ioctls(cmd_arguments.is_present("strace-ioctls"));
+    evdev::force_kernel_layout(cmd_arguments.is_present("time64"));
+
+    let logind = if cmd_arguments.is_present("logind") {
+        match Session::take_control() {
+            Ok(session) => Some(Arc::new(Mutex::new(session))),
+            Err(e) => {
+                error!("Failed to take control of the logind session: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // udev's announcements are followed before looking for the device, so that
+    // it can't be plugged in unseen in between.
+    let mut hotplug = None;
+    let evdev_paths = if cmd_arguments.is_present("hotplug") {
+        let count = |arg| {
+            cmd_arguments
+                .values_of(arg)
+                .map_or(0, |values| values.count())
+        };
+        if count("evdev-path") + count("evdev-by-id") > 1 {
+            error!("--hotplug follows a single host device");
+            process::exit(1);
+        }
+        let selector = if let Some(pattern) = cmd_arguments.value_of("evdev-name") {
+            match Regex::new(pattern) {
+                Ok(pattern) => Selector::Name(pattern),
+                Err(e) => {
+                    error!("Invalid --evdev-name {}: {}", pattern, e);
+                    process::exit(1);
+                }
+            }
+        } else if let Some(ids) = cmd_arguments.value_of("evdev-vid-pid") {
+            match parse_evdev_ids(ids) {
+                Ok((vendor, product)) => Selector::Ids(vendor, product),
+                Err(e) => {
+                    error!("Invalid --evdev-vid-pid {}: {}", ids, e);
+                    process::exit(1);
+                }
+            }
+        } else if let Some(name) = cmd_arguments.value_of("evdev-by-id") {
+            Selector::Path(evdev::by_id_link(name))
+        } else {
+            Selector::Path(cmd_arguments.value_of("evdev-path").unwrap().to_string())
+        };
+        let mut monitor = match Monitor::open() {
+            Ok(monitor) => monitor,
+            Err(e) => {
+                error!("Failed to follow udev's device announcements: {}", e);
+                process::exit(1);
+            }
+        };
+
+        let paths = match selector.find() {
+            Ok(Some(path)) => vec![path],
+            Ok(None) if cmd_arguments.is_present("device-description") => {
+                info!("no host device plugged in yet, presenting the device description");
+                Vec::new()
+            }
+            Ok(None) => {
+                info!("waiting for the host device to be plugged in");
+                match monitor.wait_for(&selector) {
+                    Ok(path) => vec![path],
+                    Err(e) => {
+                        error!("Failed to wait for the host device: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to list the host evdev devices: {}", e);
+                process::exit(1);
+            }
+        };
+        for path in paths.iter() {
+            info!("host device at {}", path);
+        }
+        hotplug = Some((monitor, selector));
+        paths
+    } else if let Some(path) = cmd_arguments.value_of("evdev-group") {
+        match evdev::siblings(path) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Failed to find the siblings of {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(pattern) = cmd_arguments.value_of("evdev-name") {
+        match find_evdevs_by_name(pattern, cmd_arguments.is_present("evdev-name-all")) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("No usable evdev device for --evdev-name {}: {}", pattern, e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(ids) = cmd_arguments.value_of("evdev-vid-pid") {
+        match find_evdevs_by_ids(ids, cmd_arguments.is_present("evdev-name-all")) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("No usable evdev device for --evdev-vid-pid {}: {}", ids, e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(names) = cmd_arguments.values_of("evdev-by-id") {
+        let mut paths = Vec::new();
+        for name in names {
+            match evdev::by_id(name) {
+                Ok(path) => {
+                    info!("{} is {}", name, path);
+                    paths.push(path);
+                }
+                Err(e) => {
+                    error!("No usable evdev device for --evdev-by-id {}: {}", name, e);
+                    process::exit(1);
+                }
+            }
+        }
+        paths
+    } else {
+        cmd_arguments
+            .values_of("evdev-path")
+            .into_iter()
+            .flatten()
+            .map(str::to_string)
+            .collect()
+    };
+
+    // Before any thread is started, which it wouldn't cover.
+    if cmd_arguments.is_present("landlock") {
+        let sandbox = sandbox(&cmd_arguments, &evdev_paths, &devices, hotplug.is_some());
+        if let Err(e) = sandbox.apply() {
+            error!("Failed to apply the Landlock sandbox: {}", e);
+            process::exit(1);
+        }
+        info!("landlock sandbox applied");
+    }
+
+    if cmd_arguments.is_present("report-battery") {
+        let paths = evdev_paths
+            .iter()
+            .chain(devices.iter().map(|(path, _)| path));
+        report_batteries(paths.cloned().collect());
+    }
+
+    if !evdev_paths.is_empty() || !devices.is_empty() {
+        // A wrong size garbles every event, make it easy to check.
+        info!("reading {}-byte evdev events", evdev::event_size());
+    }
+
+    let touchscreen_size = match cmd_arguments.value_of("touchscreen-size") {
+        Some(size) => match parse_size(size) {
+            Some(size) => size,
+            None => {
+                error!("Invalid --touchscreen-size {}, expected WIDTHxHEIGHT", size);
+                process::exit(1);
+            }
+        },
+        None => (profiles::TOUCHSCREEN_WIDTH, profiles::TOUCHSCREEN_HEIGHT),
+    };
+    let touch_slots = match cmd_arguments.value_of("touch-slots").map(str::parse) {
+        None => profiles::TOUCH_SLOTS,
+        Some(Ok(slots)) if slots > 0 => slots,
+        _ => {
+            error!("Invalid --touch-slots, expected a positive number");
+            process::exit(1);
+        }
+    };
+
+    // A device description, when given, is presented to the guest as is and
+    // takes precedence over what the evdev device reports. A synthetic device
+    // is one of the built-in descriptions, the touchscreen in the size asked
+    // for.
+    let device_description = match cmd_arguments.value_of("device-description") {
+        Some(path) => match DeviceConfig::from_description(path) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!("Failed to load device description {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => match cmd_arguments.value_of("synthetic") {
+            Some("touchscreen") => Some(profiles::touchscreen(
+                touchscreen_size.0,
+                touchscreen_size.1,
+                touch_slots,
+            )),
+            _ => None,
+        },
+    };
+
+    let mut invert_abs = Vec::new();
+    for axis in cmd_arguments.values_of("invert-abs").into_iter().flatten() {
+        let code = match axis
+            .parse::<u16>()
+            .ok()
+            .or_else(|| match codes::code_by_name(axis) {
+                Some((EV_ABS, code)) => Some(code),
+                _ => None,
+            }) {
+            Some(code) => code,
+            None => {
+                error!("Invalid --invert-abs: {} is not an absolute axis", axis);
+                process::exit(1);
+            }
+        };
+        invert_abs.push(code);
+    }
+
+    let syn_dropped = match cmd_arguments.value_of("syn-dropped").unwrap().parse() {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("Invalid --syn-dropped: {}", e);
+            process::exit(1);
+        }
+    };
+    let record_format = cmd_arguments
+        .value_of("record-format")
+        .unwrap()
+        .parse::<Format>()
+        .unwrap();
+    let recorder = match cmd_arguments.value_of("record") {
+        Some(path) => match Recorder::create(path, record_format) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Failed to create recording {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let replay_speed = match cmd_arguments.value_of("speed").unwrap().parse::<f64>() {
+        Ok(speed) if (MIN_REPLAY_SPEED..=MAX_REPLAY_SPEED).contains(&speed) => speed,
+        _ => {
+            error!(
+                "Invalid --speed, expected a factor between {} and {}",
+                MIN_REPLAY_SPEED, MAX_REPLAY_SPEED
+            );
+            process::exit(1);
+        }
+    };
+
+    let replay = match cmd_arguments.value_of("replay") {
+        Some(path) => {
+            match load_replay(path, replay_speed, cmd_arguments.is_present("replay-step")) {
+                Ok(replay) => Some(replay),
+                Err(e) => {
+                    error!("Failed to load recording {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // A builder with the options every device shares, for input from
+    // input_fd on top of that.
+    let new_builder = |input_fd: EventFd| {
+        let mut builder = VhostUserInputBackendBuilder::new(input_fd)
+            .syn_dropped(syn_dropped)
+            .max_config_size(max_config_size)
+            .log_dropped(cmd_arguments.is_present("log-dropped-events"))
+            .grab(grab)
+            .prefetch(prefetch)
+            .event_buffer_size(event_buffer_size)
+            .overflow_policy(overflow_policy)
+            .coalesce_rel(cmd_arguments.is_present("coalesce-rel"));
+        if let Some(session) = logind.as_ref() {
+            builder = builder.logind(session.clone());
+        }
+        if let Some((keys, taps)) = grab_hotkey.clone() {
+            builder = builder.grab_hotkey(keys, taps);
+        }
+        if let Some(suffix) = cmd_arguments.value_of("name-suffix") {
+            builder = builder.name_suffix(suffix);
+        }
+        for &axis in invert_abs.iter() {
+            builder = builder.invert_abs(axis);
+        }
+        // Filters keep track of held keys, so each device needs its own.
+        if let Some(path) = cmd_arguments.value_of("filter-rules") {
+            match load_filter_rules(path) {
+                Ok(rules) => builder = builder.filter(rules),
+                Err(e) => {
+                    error!("Failed to load filter rules from {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        if cmd_arguments.is_present("only") || cmd_arguments.is_present("block") {
+            let mut mask = CodeMask::default();
+            for spec in cmd_arguments.values_of("only").into_iter().flatten() {
+                if let Err(e) = mask.allow(spec) {
+                    error!("Invalid --only {}: {}", spec, e);
+                    process::exit(1);
+                }
+            }
+            for spec in cmd_arguments.values_of("block").into_iter().flatten() {
+                if let Err(e) = mask.block(spec) {
+                    error!("Invalid --block {}: {}", spec, e);
+                    process::exit(1);
+                }
+            }
+            builder = builder.mask(mask);
+        }
+        if let Some(interval) = poll_interval {
+            builder = builder.poll_interval(interval);
+        }
+        builder
+    };
+    // Of every backend built, for systemd's watchdog.
+    let heartbeats = Mutex::new(Vec::new());
+    let build = |builder: VhostUserInputBackendBuilder| {
+        let input_backend = match builder.build() {
+            Ok(backend) => Arc::new(RwLock::new(backend)),
+            Err(e) => {
+                error!("Failed to create backend: {}", e);
+                process::exit(1);
+            }
+        };
+        info!("VhostUserInputBackend created...");
+        heartbeats
+            .lock()
+            .unwrap()
+            .push(input_backend.read().unwrap().heartbeat());
+
+        if let Some(timeout) = watchdog_timeout {
+            let backend = input_backend.read().unwrap();
+            let kill_evt = if cmd_arguments.is_present("worker-watchdog-exit") {
+                Some(backend.kill_evt().unwrap())
+            } else {
+                None
+            };
+            watchdog::spawn(backend.heartbeat(), timeout, kill_evt);
+        }
+        input_backend
+    };
+
+    if devices.is_empty() {
+        let frontend = if let Some(fd) = cmd_arguments.value_of("fd") {
+            match fd.parse::<RawFd>() {
+                Ok(fd) if fd >= 0 => Frontend::Fd(fd),
+                _ => {
+                    error!("Invalid --fd: {}", fd);
+                    process::exit(1);
+                }
+            }
+        } else if let Some(socket_path) = cmd_arguments.value_of("socket-path") {
+            if cmd_arguments.is_present("client") {
+                Frontend::Client(socket_path.to_string())
+            } else {
+                Frontend::Socket(socket_path.to_string(), socket_permissions)
+            }
+        } else {
+            match activated {
+                Some(fd) if !cmd_arguments.is_present("client") => Frontend::Fd(fd),
+                _ => {
+                    error!("One of --socket-path, --fd or --device is required, unless started through systemd socket activation");
+                    process::exit(1);
+                }
+            }
+        };
+        let listener = next_listener(&frontend, persistent);
+
+        // EventFd for synthetic inputs to the VhostUserInputThread
+        let sim_inputs = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut builder = new_builder(sim_inputs.try_clone().unwrap());
+        let focus = if switch_hotkey.is_some() {
+            Some(mpsc::channel())
+        } else {
+            None
+        };
+        if let (Some((keys, taps)), Some((focus_tx, _))) = (switch_hotkey.clone(), focus.as_ref()) {
+            builder = builder.switch_hotkey(keys, taps, focus_tx.clone());
+        }
+        // The guests of --mirror-socket, their frames taken from the queue
+        // the first guest hands them to.
+        let mut mirrors = Vec::new();
+        for path in cmd_arguments
+            .values_of("mirror-socket")
+            .into_iter()
+            .flatten()
+        {
+            let frontend = if cmd_arguments.is_present("client") {
+                Frontend::Client(path.to_string())
+            } else {
+                Frontend::Socket(path.to_string(), socket_permissions)
+            };
+            let listener = next_listener(&frontend, persistent);
+            let mirror_inputs = EventFd::new(EFD_NONBLOCK).unwrap();
+            let mirrored = Injected::new(mirror_inputs.try_clone().unwrap());
+            builder = builder.mirror(mirrored.clone());
+            mirrors.push((mirror_inputs, mirrored, frontend, listener));
+        }
+        let injected = Injected::new(sim_inputs);
+        if let Some(path) = cmd_arguments.value_of("inject-socket") {
+            if let Err(e) = inject::listen(path, injected.clone()) {
+                error!("Failed to listen for injected events on {}: {}", path, e);
+                process::exit(1);
+            }
+            info!("accepting injected events on {}", path);
+        }
+        if let Some(path) = cmd_arguments.value_of("script") {
+            if let Err(e) = script::spawn(path, injected.clone()) {
+                error!("Failed to open the script {}: {}", path, e);
+                process::exit(1);
+            }
+            info!("following the script on {}", path);
+        }
+        if let Some(address) = cmd_arguments.value_of("websocket") {
+            let origins = cmd_arguments
+                .values_of("websocket-origin")
+                .into_iter()
+                .flatten()
+                .map(str::to_string)
+                .collect();
+            match websocket::listen(address, origins, injected.clone()) {
+                Ok(address) => info!("accepting browser input on ws://{}/", address),
+                Err(e) => {
+                    error!(
+                        "Failed to listen for WebSocket clients on {}: {}",
+                        address, e
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(address) = cmd_arguments.value_of("remote-listen") {
+            let path = cmd_arguments.value_of("remote-token-file").unwrap();
+            let token = match remote::read_token(path) {
+                Ok(token) => token,
+                Err(e) => {
+                    error!("Failed to read the remote token from {}: {}", path, e);
+                    process::exit(1);
+                }
+            };
+            match remote::listen(address, token, injected.clone()) {
+                Ok(address) => info!("accepting remote input on {}", address),
+                Err(e) => {
+                    error!("Failed to listen for remote clients on {}: {}", address, e);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(port) = cmd_arguments.value_of("vsock-port") {
+            let port = match port.parse::<u32>() {
+                Ok(port) => port,
+                Err(_) => {
+                    error!("Invalid --vsock-port: {}", port);
+                    process::exit(1);
+                }
+            };
+            let cid = cmd_arguments
+                .value_of("vsock-cid")
+                .map(|cid| match cid.parse::<u32>() {
+                    Ok(cid) => cid,
+                    Err(_) => {
+                        error!("Invalid --vsock-cid: {}", cid);
+                        process::exit(1);
+                    }
+                });
+            if let Err(e) = remote::listen_vsock(port, cid, injected.clone()) {
+                error!("Failed to listen on vsock port {}: {}", port, e);
+                process::exit(1);
+            }
+            info!("accepting vsock input on port {}", port);
+        }
+        if cmd_arguments.is_present("x11-capture") {
+            let hotkey = cmd_arguments
+                .value_of("x11-hotkey")
+                .unwrap_or(x11::DEFAULT_HOTKEY);
+            let hotkey = match x11::parse_hotkey(hotkey) {
+                Ok(hotkey) => hotkey,
+                Err(e) => {
+                    error!("Invalid --x11-hotkey: {}", e);
+                    process::exit(1);
+                }
+            };
+            let display = match std::env::var("DISPLAY") {
+                Ok(display) => display,
+                Err(_) => {
+                    error!("--x11-capture needs DISPLAY to be set");
+                    process::exit(1);
+                }
+            };
+            if let Err(e) = x11::spawn_capture(&display, hotkey, injected.clone()) {
+                error!("Failed to connect to X display {}: {}", display, e);
+                process::exit(1);
+            }
+            info!("following X11 input on {}", display);
+        }
+        if cmd_arguments.is_present("x11-window") {
+            let display = match std::env::var("DISPLAY") {
+                Ok(display) => display,
+                Err(_) => {
+                    error!("--x11-window needs DISPLAY to be set");
+                    process::exit(1);
+                }
+            };
+            if let Err(e) = x11::spawn_window(&display, "vhost-user-input", injected.clone()) {
+                error!("Failed to open a window on X display {}: {}", display, e);
+                process::exit(1);
+            }
+            info!("forwarding input of the window on {}", display);
+        }
+        if let Some(name) = cmd_arguments.value_of("libei-socket") {
+            match libei_source(name) {
+                Ok(source) => builder = builder.source(source),
+                Err(e) => {
+                    error!("Failed to connect to the EIS server {}: {}", name, e);
+                    process::exit(1);
+                }
+            }
+            info!("receiving Wayland input from {}", name);
+        }
+        let control_socket = cmd_arguments.value_of("control-socket");
+        if cmd_arguments.is_present("inject-socket")
+            || cmd_arguments.is_present("script")
+            || cmd_arguments.is_present("websocket")
+            || cmd_arguments.is_present("remote-listen")
+            || cmd_arguments.is_present("vsock-port")
+            || cmd_arguments.is_present("x11-capture")
+            || cmd_arguments.is_present("x11-window")
+            || control_socket.is_some()
+        {
+            builder = builder.injected(injected.clone());
+        }
+        for path in evdev_paths.iter() {
+            builder = builder.evdev_path(path);
+        }
+        if let Some((monitor, selector)) = hotplug {
+            builder = builder.hotplug(monitor, selector);
+        }
+        match (device_description, cmd_arguments.value_of("synthetic")) {
+            (Some(device_config), _) => builder = builder.device_config(device_config),
+            (None, Some(name)) => builder = builder.device_type(name),
+            (None, None) => {}
+        }
+        if let Some(name) = cmd_arguments.value_of("name") {
+            builder = builder.name_override(name);
+        }
+        if let Some(recorder) = recorder {
+            builder = builder.recorder(recorder);
+        }
+        if let Some(replay) = replay {
+            builder = builder.replay(replay);
+        }
+        if let Some(rate) = max_events_per_sec {
+            builder = builder.max_events_per_sec(rate);
+        }
+        if let Some(path) = cmd_arguments.value_of("event-log") {
+            match cmd_arguments
+                .value_of("event-log-max-size")
+                .unwrap()
+                .parse()
+            {
+                Ok(max_size) if max_size > 0 => builder = builder.event_log(path, max_size),
+                _ => {
+                    error!("Invalid --event-log-max-size, expected a positive number of bytes");
+                    process::exit(1);
+                }
+            }
+        }
+        let input_backend = build(builder);
+        // Every guest, for the metrics.
+        let mut guests = vec![(frontend.name(), input_backend.clone())];
+
+        // The further guests are joined once the first one is done, so that
+        // they aren't cut off while they still have a frontend.
+        let mut threads = Vec::new();
+        if !mirrors.is_empty() {
+            let device_config = input_backend.read().unwrap().device_config().clone();
+            for (mirror_inputs, mirrored, frontend, listener) in mirrors {
+                let backend = build(
+                    new_builder(mirror_inputs)
+                        .same_device(device_config.clone())
+                        .mirrored(mirrored),
+                );
+                guests.push((frontend.name(), backend.clone()));
+                threads.push(thread::spawn(move || {
+                    serve(backend, &frontend, listener, persistent)
+                }));
+            }
+        }
+
+        // The further guests of --switch-socket, served from threads of their
+        // own, which get the host devices when switched to.
+        let mut focus_tx = None;
+        if let Some((tx, rx)) = focus {
+            let device_config = input_backend.read().unwrap().device_config().clone();
+            let mut backends = vec![input_backend.clone()];
+            for path in cmd_arguments
+                .values_of("switch-socket")
+                .into_iter()
+                .flatten()
+            {
+                let frontend = if cmd_arguments.is_present("client") {
+                    Frontend::Client(path.to_string())
+                } else {
+                    Frontend::Socket(path.to_string(), socket_permissions)
+                };
+                let listener = next_listener(&frontend, persistent);
+                let mut builder = new_builder(EventFd::new(EFD_NONBLOCK).unwrap())
+                    .same_device(device_config.clone());
+                if let Some((keys, taps)) = switch_hotkey.clone() {
+                    builder = builder.switch_hotkey(keys, taps, tx.clone());
+                }
+                let backend = build(builder);
+                backends.push(backend.clone());
+                guests.push((frontend.name(), backend.clone()));
+                threads.push(thread::spawn(move || {
+                    serve(backend, &frontend, listener, persistent)
+                }));
+            }
+            focus_tx = Some((tx, backends.len()));
+            thread::spawn(move || switch_focus(backends, rx));
+        }
+
+        if let Some(path) = control_socket {
+            let handler = control_handler(input_backend.clone(), injected, focus_tx, open_later);
+            if let Err(e) = control::listen(path, handler) {
+                error!("Failed to listen for control commands on {}: {}", path, e);
+                process::exit(1);
+            }
+            info!("accepting control commands on {}", path);
+        }
+        serve_metrics(cmd_arguments.value_of("metrics-listen"), guests.clone());
+        report::spawn(move || report_state(&guests));
+
+        // start() blocks until a frontend connects, so this is the last point
+        // at which we can tell a supervisor we're ready for one. In client mode
+        // the frontend is already there, and start_client() returns once
+        // connected.
+        ready(
+            ready_fd,
+            detached,
+            notifier,
+            heartbeats.into_inner().unwrap(),
+            credentials,
+            seccomp,
+        );
+
+        serve(input_backend, &frontend, listener, persistent);
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("Guest thread panicked");
+                process::exit(1);
+            }
+        }
+    } else {
+        // Each device gets a backend, worker threads and a thread serving its
+        // frontends of its own, so that one device's frontend coming and going
+        // doesn't concern the others.
+        let mut served = Vec::new();
+        for (evdev_path, socket_path) in devices {
+            let frontend = if cmd_arguments.is_present("client") {
+                Frontend::Client(socket_path)
+            } else {
+                Frontend::Socket(socket_path, socket_permissions)
+            };
+            let listener = next_listener(&frontend, persistent);
+            let sim_inputs = EventFd::new(EFD_NONBLOCK).unwrap();
+            let input_backend = build(new_builder(sim_inputs).evdev_path(&evdev_path));
+            served.push((input_backend, frontend, listener));
+        }
+        let guests: Vec<_> = served
+            .iter()
+            .map(|(input_backend, frontend, _)| (frontend.name(), input_backend.clone()))
+            .collect();
+        serve_metrics(cmd_arguments.value_of("metrics-listen"), guests.clone());
+        report::spawn(move || report_state(&guests));
+
+        // Every device listens by now.
+        ready(
+            ready_fd,
+            detached,
+            notifier,
+            heartbeats.into_inner().unwrap(),
+            credentials,
+            seccomp,
+        );
+
+        let threads: Vec<_> = served
+            .into_iter()
+            .map(|(input_backend, frontend, listener)| {
+                thread::spawn(move || serve(input_backend, &frontend, listener, persistent))
+            })
+            .collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("Device thread panicked");
+                process::exit(1);
+            }
+        }
+    }
+
+    info!("Worked threads closed.");
+    if let Some(path) = pidfile {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove the pidfile {}: {}", path, e);
+        }
+    }
 }
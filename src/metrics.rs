@@ -0,0 +1,227 @@
+// Prometheus metrics over HTTP, see --metrics-listen.
+//
+// GET /metrics answers in the text exposition format, with a series of each
+// metric per guest, labelled with the socket its frontend comes from: the
+// counters of query-stats, and gauges of the events buffered for the guest, the
+// eventq buffers waiting for events and whether a frontend is connected and
+// its driver ready. Each request gets a connection of its own, closed once
+// answered. There is neither TLS nor authentication.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::stats::Counters;
+
+// Largest request taken from a scraper, headers included.
+const MAX_REQUEST: usize = 8192;
+// How long a scraper gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// The state of one guest when scraped.
+pub struct Guest {
+    // Where its frontend comes from, the socket path for most.
+    pub socket: String,
+    pub connected: bool,
+    pub driver_ready: bool,
+    pub counters: Counters,
+    pub events_dropped: u64,
+    // Events waiting for the guest, in the event buffer or held back.
+    pub buffered_events: usize,
+    // eventq buffers the guest posted that wait for events.
+    pub eventq_buffers: usize,
+}
+
+// The guests as they are now, on every scrape.
+pub type Collector = dyn Fn() -> Vec<Guest> + Send + Sync;
+
+// A metric's help and type lines, and its value for every guest.
+fn metric<T: std::fmt::Display>(
+    text: &mut String,
+    guests: &[Guest],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&Guest) -> T,
+) {
+    writeln!(text, "# HELP vhost_user_input_{} {}", name, help).unwrap();
+    writeln!(text, "# TYPE vhost_user_input_{} {}", name, kind).unwrap();
+    for guest in guests {
+        writeln!(
+            text,
+            "vhost_user_input_{}{{socket=\"{}\"}} {}",
+            name,
+            escape(&guest.socket),
+            value(guest)
+        )
+        .unwrap();
+    }
+}
+
+// A label value, with backslashes, quotes and newlines escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(guests: &[Guest]) -> String {
+    let mut text = String::new();
+    metric(
+        &mut text,
+        guests,
+        "events_forwarded_total",
+        "counter",
+        "Events written into eventq buffers.",
+        |guest| guest.counters.events_forwarded,
+    );
+    metric(
+        &mut text,
+        guests,
+        "events_dropped_total",
+        "counter",
+        "Events that never made it to the guest, for any reason.",
+        |guest| guest.events_dropped,
+    );
+    metric(
+        &mut text,
+        guests,
+        "frames_total",
+        "counter",
+        "Complete frames handed on for the guest.",
+        |guest| guest.counters.frames,
+    );
+    metric(
+        &mut text,
+        guests,
+        "used_ring_adds_total",
+        "counter",
+        "Buffers put on the used rings.",
+        |guest| guest.counters.used_ring_adds,
+    );
+    metric(
+        &mut text,
+        guests,
+        "notifications_sent_total",
+        "counter",
+        "Interrupts sent to the driver for used buffers.",
+        |guest| guest.counters.notifications_sent,
+    );
+    metric(
+        &mut text,
+        guests,
+        "notifications_suppressed_total",
+        "counter",
+        "Interrupts skipped as the driver asked with VIRTIO_RING_F_EVENT_IDX.",
+        |guest| guest.counters.notifications_suppressed,
+    );
+    metric(
+        &mut text,
+        guests,
+        "statusq_events_total",
+        "counter",
+        "Events the driver sent on the statusq.",
+        |guest| guest.counters.statusq_events,
+    );
+    metric(
+        &mut text,
+        guests,
+        "buffered_events",
+        "gauge",
+        "Events waiting for the guest.",
+        |guest| guest.buffered_events,
+    );
+    metric(
+        &mut text,
+        guests,
+        "eventq_buffers",
+        "gauge",
+        "eventq buffers the guest posted that wait for events.",
+        |guest| guest.eventq_buffers,
+    );
+    metric(
+        &mut text,
+        guests,
+        "connected",
+        "gauge",
+        "Whether a frontend is connected.",
+        |guest| guest.connected as u8,
+    );
+    metric(
+        &mut text,
+        guests,
+        "driver_ready",
+        "gauge",
+        "Whether the guest driver negotiated features.",
+        |guest| guest.driver_ready as u8,
+    );
+
+    text
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )
+}
+
+// Answer the one request of a scraper.
+fn serve(mut stream: TcpStream, collector: Arc<Collector>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are of no interest, but are read so that closing the
+    // connection doesn't reset it under the reply.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &render(&collector())),
+        (Some("GET"), Some(_)) => respond(&mut stream, "404 Not Found", "not found\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "only GET /metrics\n"),
+    }
+}
+
+// Answer scrapers on address, each served by a thread of its own. Returns the
+// address bound, which for port 0 is where the port is learned.
+pub fn listen(address: &str, collector: Arc<Collector>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let collector = collector.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, collector) {
+                            debug!("Failed to answer metrics scraper: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept metrics scraper: {}", e),
+            }
+        }
+    });
+
+    Ok(address)
+}
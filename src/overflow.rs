@@ -0,0 +1,126 @@
+// What happens to input once the event buffer is full, see --overflow-policy.
+//
+// drop-newest drops the frames coming in until the guest has made room, and
+// then sends it a SYN_DROPPED and the state those frames changed, as evdev does
+// for a reader that falls behind. drop-oldest makes room for the frames coming
+// in by dropping the oldest frames waiting for the guest instead, which it
+// follows with the same, so that the guest always gets the latest input. With
+// block-source nothing is dropped here: the host devices are no longer read,
+// and injected events are left waiting, until the guest has made room, so that
+// it's the kernel's and the injecting clients' buffers that fill up instead.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use crate::codes::{ABS_MT_SLOT, EV_ABS, EV_KEY, EV_LED, EV_SW, EV_SYN, SYN_DROPPED, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    BlockSource,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "block-source" => Ok(OverflowPolicy::BlockSource),
+            _ => Err(format!("unknown overflow policy {}", s)),
+        }
+    }
+}
+
+// The last value of each key, switch, LED and absolute axis set by dropped
+// frames. Relative motion has no state, and multitouch axes depend on their
+// slot, so those are lost.
+pub type Missed = BTreeMap<(u16, u16), u32>;
+
+// Whether event sets state Missed keeps, and the value it sets.
+fn state(event: &VirtioInputEvent) -> Option<u32> {
+    match event.event_type {
+        // Autorepeat counts as down.
+        EV_KEY => Some((event.value != 0) as u32),
+        EV_SW | EV_LED => Some(event.value),
+        EV_ABS if event.code < ABS_MT_SLOT => Some(event.value),
+        _ => None,
+    }
+}
+
+pub fn miss(missed: &mut Missed, events: &[VirtioInputEvent]) {
+    for event in events {
+        if let Some(value) = state(event) {
+            missed.insert((event.event_type, event.code), value);
+        }
+    }
+}
+
+fn syn(code: u16) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type: EV_SYN,
+        code,
+        value: 0,
+    }
+}
+
+// A SYN_DROPPED, in a frame of its own so that readers discarding the rest of
+// its frame keep the next, followed by a frame setting changes, if any.
+pub fn resync_frames(changes: Vec<VirtioInputEvent>) -> Vec<VirtioInputEvent> {
+    let mut events = vec![syn(SYN_DROPPED), syn(SYN_REPORT)];
+    if !changes.is_empty() {
+        events.extend_from_slice(&changes);
+        events.push(syn(SYN_REPORT));
+    }
+
+    events
+}
+
+pub fn changes(missed: &Missed) -> Vec<VirtioInputEvent> {
+    missed
+        .iter()
+        .map(|(&(event_type, code), &value)| VirtioInputEvent {
+            event_type,
+            code,
+            value,
+        })
+        .collect()
+}
+
+// Drop whole frames off the front of queued, the events waiting for the guest,
+// until they fit into capacity together with the resync for them and incoming.
+// Returns the number of events dropped and the resync, which goes between the
+// frames kept and incoming. It leaves out state the frames kept set anyway, as
+// it would undo that.
+pub fn drop_oldest(
+    queued: &mut Vec<VirtioInputEvent>,
+    incoming: usize,
+    capacity: usize,
+) -> (usize, Vec<VirtioInputEvent>) {
+    let mut dropped = 0;
+    let mut missed = Missed::new();
+    loop {
+        let kept: BTreeSet<(u16, u16)> = queued
+            .iter()
+            .filter(|event| state(event).is_some())
+            .map(|event| (event.event_type, event.code))
+            .collect();
+        let mut resync = missed.clone();
+        resync.retain(|key, _| !kept.contains(key));
+        let resync = resync_frames(changes(&resync));
+        if queued.len() + resync.len() + incoming <= capacity || queued.is_empty() {
+            return (dropped, resync);
+        }
+
+        let end = queued
+            .iter()
+            .position(|event| event.event_type == EV_SYN && event.code == SYN_REPORT)
+            .map_or(queued.len(), |syn| syn + 1);
+        miss(&mut missed, &queued[..end]);
+        queued.drain(..end);
+        dropped += end;
+    }
+}
@@ -0,0 +1,141 @@
+// Giving up root once set up, see --user, --group and --chroot.
+//
+// The evdev devices are opened and grabbed, and the sockets bound, as whoever
+// started us. Then, before serving a guest, the process changes root and user
+// for good, groups first while it still may. glibc applies each change to
+// every thread, so those started during setup are covered too. Names are
+// looked up at startup, while /etc is still there to look them up in.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::ptr;
+
+// Large enough for any sane passwd or group entry.
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+// What to become once set up.
+#[derive(Default)]
+pub struct Credentials {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+    // The directory to change root to.
+    pub root: Option<String>,
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no {} named {}", kind, name),
+    )
+}
+
+// The uid and primary gid of user, a name or a number.
+pub fn user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|_| not_found("user", user))?;
+    let by_id = user.parse::<libc::uid_t>().ok();
+    // passwd is plain old data, for which all zeroes is a valid value.
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found: *mut libc::passwd = ptr::null_mut();
+    // Both only write entry, buffer within its length and found, which points
+    // at entry or is null.
+    let ret = unsafe {
+        match by_id {
+            Some(uid) => libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+            None => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if found.is_null() {
+        // A number without an entry is still a uid, in a group of its own.
+        return match by_id {
+            Some(uid) => Ok((uid, uid)),
+            None => Err(not_found("user", user)),
+        };
+    }
+
+    Ok((entry.pw_uid, entry.pw_gid))
+}
+
+// The gid of group, a name or a number.
+pub fn group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| not_found("group", group))?;
+    // group is plain old data, for which all zeroes is a valid value.
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found: *mut libc::group = ptr::null_mut();
+    // Only writes entry, buffer within its length and found, which points at
+    // entry or is null.
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if found.is_null() {
+        return Err(not_found("group", group));
+    }
+
+    Ok(entry.gr_gid)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Change root, and become credentials, for good.
+pub fn give_up(credentials: &Credentials) -> io::Result<()> {
+    if let Some(dir) = credentials.root.as_deref() {
+        let dir = CString::new(dir)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in the path"))?;
+        let root = CStr::from_bytes_with_nul(b"/\0").unwrap();
+        // Both only read the NUL terminated paths they are given.
+        check(unsafe { libc::chroot(dir.as_ptr()) })?;
+        check(unsafe { libc::chdir(root.as_ptr()) })?;
+    }
+    if let Some(gid) = credentials.gid {
+        // setgroups() reads the one gid it is told about.
+        check(unsafe { libc::setgroups(1, &gid) })?;
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some(uid) = credentials.uid {
+        check(unsafe { libc::setuid(uid) })?;
+        // Had it been possible, root would be a setuid(0) away.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "still able to become root",
+            ));
+        }
+    }
+
+    Ok(())
+}
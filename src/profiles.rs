@@ -0,0 +1,172 @@
+// Built-in devices for --synthetic, presented to the guest without any host
+// device behind them. Their input comes from --inject-socket, --replay and the
+// other event sources.
+
+use crate::codes::{
+    self, ABS_HAT0X, ABS_HAT0Y, ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT,
+    ABS_MT_TRACKING_ID, ABS_RX, ABS_RY, ABS_RZ, ABS_X, ABS_Y, ABS_Z, BTN_EAST, BTN_EXTRA, BTN_LEFT,
+    BTN_MIDDLE, BTN_MODE, BTN_NORTH, BTN_RIGHT, BTN_SELECT, BTN_SIDE, BTN_SOUTH, BTN_START,
+    BTN_THUMBL, BTN_THUMBR, BTN_TL, BTN_TL2, BTN_TOUCH, BTN_TR, BTN_TR2, BTN_WEST, EV_ABS, EV_FF,
+    EV_KEY, EV_LED, EV_REL, EV_REP, FF_RUMBLE, INPUT_PROP_DIRECT, INPUT_PROP_POINTER, LED_CAPSL,
+    LED_NUML, LED_SCROLLL, REL_HWHEEL, REL_WHEEL, REL_X, REL_Y, REP_DELAY, REP_PERIOD,
+};
+use crate::config::{DeviceConfig, VirtioInputAbsInfo, VirtioInputDevIDs};
+use crate::evdev::set_bit;
+
+// BUS_VIRTUAL from linux/input.h.
+const BUS_VIRTUAL: u16 = 0x06;
+// Vendor of QEMU's own virtio input devices, which guests already know.
+const VENDOR: u16 = 0x0627;
+
+// Range of the tablet's axes, the one of QEMU's usb-tablet and virtio tablet.
+pub const TABLET_MAX: i32 = 32767;
+
+// Touchscreen defaults, see --touchscreen-size and --touch-slots.
+pub const TOUCHSCREEN_WIDTH: u32 = 32768;
+pub const TOUCHSCREEN_HEIGHT: u32 = 32768;
+pub const TOUCH_SLOTS: u32 = 10;
+// Highest tracking ID, they wrap around like those of real touchscreens.
+pub const MAX_TRACKING_ID: u32 = 65535;
+
+pub const NAMES: &[&str] = &["keyboard", "tablet", "touchscreen", "gamepad", "desktop"];
+
+// The profile with the given name, one of NAMES.
+pub fn by_name(name: &str) -> Option<DeviceConfig> {
+    match name {
+        "keyboard" => Some(keyboard()),
+        "tablet" => Some(tablet()),
+        "touchscreen" => Some(touchscreen(
+            TOUCHSCREEN_WIDTH,
+            TOUCHSCREEN_HEIGHT,
+            TOUCH_SLOTS,
+        )),
+        "gamepad" => Some(gamepad()),
+        "desktop" => Some(desktop()),
+        _ => None,
+    }
+}
+
+fn device(name: &str, product: u16) -> DeviceConfig {
+    DeviceConfig {
+        name: name.to_string(),
+        ids: VirtioInputDevIDs {
+            bustype: BUS_VIRTUAL,
+            vendor: VENDOR,
+            product,
+            version: 1,
+        },
+        ..Default::default()
+    }
+}
+
+fn set_codes(config: &mut DeviceConfig, ev_type: u16, codes: &[u16]) {
+    let bits = config.ev_bits.entry(ev_type as u8).or_default();
+    for &code in codes {
+        set_bit(bits, code);
+    }
+}
+
+fn set_axis(config: &mut DeviceConfig, axis: u16, min: i32, max: i32) {
+    set_codes(config, EV_ABS, &[axis]);
+    config.abs_info.insert(
+        axis,
+        VirtioInputAbsInfo {
+            min: min as u32,
+            max: max as u32,
+            ..Default::default()
+        },
+    );
+}
+
+// A keyboard with every key, the usual LEDs and autorepeat.
+pub fn keyboard() -> DeviceConfig {
+    let mut config = device("vhost-user-input keyboard", 0x0001);
+
+    // Buttons are for pointers and gamepads, and make guests take a keyboard
+    // for one of those.
+    let mut keys = Vec::new();
+    for &(name, code) in codes::code_table(EV_KEY) {
+        if name.starts_with("KEY_") && code != 0 {
+            set_bit(&mut keys, code);
+        }
+    }
+    config.ev_bits.insert(EV_KEY as u8, keys);
+
+    set_codes(&mut config, EV_LED, &[LED_NUML, LED_CAPSL, LED_SCROLLL]);
+    // The guest only turns on autorepeat for a non-empty EV_REP bitmap.
+    set_codes(&mut config, EV_REP, &[REP_DELAY, REP_PERIOD]);
+
+    config
+}
+
+// An absolute pointer with three buttons, which guests move their cursor to
+// wherever the host's is, without grabbing it.
+pub fn tablet() -> DeviceConfig {
+    let mut config = device("vhost-user-input tablet", 0x0003);
+    set_bit(&mut config.props, INPUT_PROP_POINTER);
+    set_codes(&mut config, EV_KEY, &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]);
+    set_axis(&mut config, ABS_X, 0, TABLET_MAX);
+    set_axis(&mut config, ABS_Y, 0, TABLET_MAX);
+
+    config
+}
+
+// A multitouch screen of width by height points taking up to slots contacts
+// at a time, with the single touch axes guests fall back on. width, height and
+// slots must not be 0.
+pub fn touchscreen(width: u32, height: u32, slots: u32) -> DeviceConfig {
+    let mut config = device("vhost-user-input touchscreen", 0x0005);
+    set_bit(&mut config.props, INPUT_PROP_DIRECT);
+    set_codes(&mut config, EV_KEY, &[BTN_TOUCH]);
+    let (max_x, max_y) = ((width - 1) as i32, (height - 1) as i32);
+    set_axis(&mut config, ABS_X, 0, max_x);
+    set_axis(&mut config, ABS_Y, 0, max_y);
+    set_axis(&mut config, ABS_MT_SLOT, 0, (slots - 1) as i32);
+    set_axis(&mut config, ABS_MT_TRACKING_ID, 0, MAX_TRACKING_ID as i32);
+    set_axis(&mut config, ABS_MT_POSITION_X, 0, max_x);
+    set_axis(&mut config, ABS_MT_POSITION_Y, 0, max_y);
+
+    config
+}
+
+// A gamepad laid out like an Xbox controller: face, shoulder and stick
+// buttons, two analog sticks, analog triggers and a d-pad hat, and rumble.
+pub fn gamepad() -> DeviceConfig {
+    let mut config = device("vhost-user-input gamepad", 0x0006);
+    set_codes(
+        &mut config,
+        EV_KEY,
+        &[
+            BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_TL2, BTN_TR2, BTN_SELECT,
+            BTN_START, BTN_MODE, BTN_THUMBL, BTN_THUMBR,
+        ],
+    );
+    for &stick in &[ABS_X, ABS_Y, ABS_RX, ABS_RY] {
+        set_axis(&mut config, stick, i16::MIN.into(), i16::MAX.into());
+    }
+    for &trigger in &[ABS_Z, ABS_RZ] {
+        set_axis(&mut config, trigger, 0, 255);
+    }
+    for &hat in &[ABS_HAT0X, ABS_HAT0Y] {
+        set_axis(&mut config, hat, -1, 1);
+    }
+    set_codes(&mut config, EV_FF, &[FF_RUMBLE]);
+
+    config
+}
+
+// A keyboard and a wheel mouse in one, for sources capturing all the input of
+// a desktop session.
+pub fn desktop() -> DeviceConfig {
+    let mut config = keyboard();
+    config.name = "vhost-user-input desktop".to_string();
+    config.ids.product = 0x0007;
+    set_codes(
+        &mut config,
+        EV_KEY,
+        &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, BTN_SIDE, BTN_EXTRA],
+    );
+    set_codes(&mut config, EV_REL, &[REL_X, REL_Y, REL_HWHEEL, REL_WHEEL]);
+
+    config
+}
@@ -0,0 +1,153 @@
+// Throttling of the events forwarded to the guest, see --max-events-per-sec.
+//
+// A token bucket, refilled at the rate given and holding up to BURST of it,
+// pays for every event handed to the guest. A motion frame, one of nothing but
+// relative motion and absolute axes, that finds the bucket short is held back,
+// and the motion frames following it are merged into it, relative motion
+// summed and absolute axes at their latest value, until there are tokens for
+// it. The pointer ends up in the same place, just in fewer steps. Any other
+// frame, keys and multitouch included, is never held back nor dropped: it
+// takes the motion held back along, ahead of itself, and the bucket goes into
+// debt for them.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::codes::{ABS_MT_SLOT, EV_ABS, EV_REL, EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+// How much of the rate the bucket holds, for the odd burst.
+const BURST: Duration = Duration::from_millis(20);
+// Fewest events the bucket holds at low rates, enough for any motion frame.
+const MIN_BURST: f64 = 32.0;
+
+pub struct RateLimiter {
+    // Events per second.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    // Motion frames merged while the bucket was short.
+    held: Option<Vec<VirtioInputEvent>>,
+    // Fires once there are tokens for held.
+    timer: TimerFd,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> io::Result<Self> {
+        let rate = f64::from(rate);
+        let burst = (rate * BURST.as_secs_f64()).max(MIN_BURST);
+        Ok(RateLimiter {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+            held: None,
+            timer: TimerFd::new().map_err(|e| io::Error::from_raw_os_error(e.errno()))?,
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+
+    fn spend(&mut self, events: usize) {
+        // Never so deep in debt that motion waits for more than a burst.
+        self.tokens = (self.tokens - events as f64).max(-self.burst);
+    }
+
+    // The frames to hand to the guest now for frame, in order.
+    pub fn admit(
+        &mut self,
+        frame: Vec<VirtioInputEvent>,
+    ) -> io::Result<Vec<Vec<VirtioInputEvent>>> {
+        self.refill();
+        if is_motion(&frame) {
+            match self.held.as_mut() {
+                Some(held) => merge(held, &frame),
+                None => self.held = Some(frame),
+            }
+            return Ok(self.release()?.into_iter().collect());
+        }
+
+        let mut frames: Vec<_> = self.held.take().into_iter().collect();
+        frames.push(frame);
+        self.spend(frames.iter().map(Vec::len).sum());
+        Ok(frames)
+    }
+
+    // The motion held back, if there are tokens for it by now, or else arm
+    // the timer for when there will be.
+    fn release(&mut self) -> io::Result<Option<Vec<VirtioInputEvent>>> {
+        let needed = match self.held.as_ref() {
+            Some(held) => held.len(),
+            None => return Ok(None),
+        };
+        let short = (needed as f64).min(self.burst) - self.tokens;
+        if short <= 0.0 {
+            self.spend(needed);
+            return Ok(self.held.take());
+        }
+
+        // A zero timeout would disarm the timer instead of firing it.
+        let wait = Duration::from_secs_f64(short / self.rate).max(Duration::from_micros(1));
+        self.timer
+            .reset(wait, None)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        Ok(None)
+    }
+
+    // Called when the timer fires.
+    pub fn take_due(&mut self) -> io::Result<Option<Vec<VirtioInputEvent>>> {
+        self.timer
+            .wait()
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        self.refill();
+        self.release()
+    }
+
+    // Forget the motion held back, returning the number of its events.
+    pub fn clear(&mut self) -> usize {
+        self.held.take().map_or(0, |held| held.len())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+fn is_motion(frame: &[VirtioInputEvent]) -> bool {
+    frame.iter().all(|event| match event.event_type {
+        EV_REL => true,
+        EV_ABS => event.code < ABS_MT_SLOT,
+        EV_SYN => event.code == SYN_REPORT,
+        _ => false,
+    })
+}
+
+// Merge the motion frame into held, which ends with its SYN_REPORT too.
+fn merge(held: &mut Vec<VirtioInputEvent>, frame: &[VirtioInputEvent]) {
+    for event in frame.iter().filter(|event| event.event_type != EV_SYN) {
+        let same = held
+            .iter_mut()
+            .find(|other| other.event_type == event.event_type && other.code == event.code);
+        match same {
+            Some(other) if event.event_type == EV_REL => {
+                other.value = (other.value as i32).saturating_add(event.value as i32) as u32;
+            }
+            Some(other) => other.value = event.value,
+            None => {
+                let end = held.len().saturating_sub(1);
+                held.insert(end, *event);
+            }
+        }
+    }
+}
@@ -0,0 +1,421 @@
+// Recordings of host input, written with --record and played back with
+// --replay.
+//
+// A recording is binary by default, see below. With --record-format text it's
+// a text file instead, whose first line names the format and its version, and
+// every other line holds one event:
+//
+//     vhost-user-input-recording 1
+//     <delta_us> <type> <code> <value>
+//
+// delta_us is the time since the previous event in microseconds, so the first
+// event is always at 0 and nothing in a recording depends on the wall clock or
+// the host's uptime. Replays deliver the events at the same relative offsets,
+// scaled by --speed, which makes recordings usable as fixtures.
+//
+// In the binary form the same events take a few bytes each, for long
+// captures. The file starts with BINARY_MAGIC and the version as a
+// little-endian u32, then every event is delta_us as an unsigned LEB128, type
+// and code as little-endian u16 and value as a little-endian i32. Replays tell
+// the two apart by the first bytes.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
+
+use log::info;
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::codes::{EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+
+const MAGIC: &str = "vhost-user-input-recording";
+const VERSION: u32 = 1;
+const BINARY_MAGIC: &[u8] = b"VUIR";
+const BINARY_VERSION: u32 = 1;
+// Bytes of an event after its delta.
+const BINARY_EVENT_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "binary" => Ok(Format::Binary),
+            _ => Err(format!("unknown recording format {}", s)),
+        }
+    }
+}
+
+// Where parsing failed: a line of a text recording, a byte offset into a
+// binary one.
+#[derive(Debug)]
+pub enum Position {
+    Line(usize),
+    Byte(usize),
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    position: Position,
+    reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Position::Line(line) => write!(f, "line {}: {}", line, self.reason),
+            Position::Byte(offset) => write!(f, "byte {}: {}", offset, self.reason),
+        }
+    }
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    format: Format,
+    // Host timestamp of the previous event.
+    last: Option<Duration>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, format: Format) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            Format::Text => writeln!(out, "{} {}", MAGIC, VERSION)?,
+            Format::Binary => {
+                out.write_all(BINARY_MAGIC)?;
+                out.write_all(&BINARY_VERSION.to_le_bytes())?;
+            }
+        }
+
+        Ok(Recorder {
+            out,
+            format,
+            last: None,
+        })
+    }
+
+    fn write_binary(&mut self, delta: Duration, event: &VirtioInputEvent) -> io::Result<()> {
+        let mut delta = delta.as_micros() as u64;
+        loop {
+            let byte = (delta & 0x7f) as u8;
+            delta >>= 7;
+            if delta == 0 {
+                self.out.write_all(&[byte])?;
+                break;
+            }
+            self.out.write_all(&[byte | 0x80])?;
+        }
+        self.out.write_all(&event.event_type.to_le_bytes())?;
+        self.out.write_all(&event.code.to_le_bytes())?;
+        self.out.write_all(&(event.value as i32).to_le_bytes())
+    }
+
+    // Write out what is buffered, as a recorder still shared at exit is never
+    // dropped.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // Append an event read from the host at time, on any monotonic clock.
+    pub fn record(&mut self, time: Duration, event: &VirtioInputEvent) -> io::Result<()> {
+        // Host timestamps can step back (e.g. across devices), never go negative.
+        let delta = self.last.map_or(Duration::default(), |last| {
+            time.checked_sub(last).unwrap_or_default()
+        });
+        self.last = Some(time.max(self.last.unwrap_or_default()));
+        match self.format {
+            Format::Text => writeln!(
+                self.out,
+                "{} {} {} {}",
+                delta.as_micros(),
+                event.event_type,
+                event.code,
+                event.value as i32
+            )?,
+            Format::Binary => self.write_binary(delta, event)?,
+        }
+
+        // Keep the file usable if we get killed, a frame at a time.
+        if event.event_type == EV_SYN && event.code == SYN_REPORT {
+            self.out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_event(line: &str) -> Result<(Duration, VirtioInputEvent), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 {
+        return Err(format!("expected 4 fields, got {}", fields.len()));
+    }
+    let field = |i: usize| -> Result<i64, String> {
+        fields[i]
+            .parse()
+            .map_err(|_| format!("invalid number {}", fields[i]))
+    };
+
+    let delta = field(0)?;
+    if delta < 0 {
+        return Err(format!("negative delta {}", delta));
+    }
+
+    Ok((
+        Duration::from_micros(delta as u64),
+        VirtioInputEvent {
+            event_type: field(1)? as u16,
+            code: field(2)? as u16,
+            value: field(3)? as i32 as u32,
+        },
+    ))
+}
+
+// The events of a binary recording, past the magic, with their offset from the
+// first one.
+fn parse_binary(data: &[u8]) -> Result<Vec<(Duration, VirtioInputEvent)>, ParseError> {
+    let error = |offset: usize, reason: &str| ParseError {
+        position: Position::Byte(offset),
+        reason: reason.to_string(),
+    };
+    let start = BINARY_MAGIC.len();
+    let version = match data.get(start..start + 4) {
+        Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => return Err(error(start, "truncated header")),
+    };
+    if version != BINARY_VERSION {
+        return Err(error(
+            start,
+            &format!("unsupported recording version {}", version),
+        ));
+    }
+
+    let mut events = Vec::new();
+    let mut offset = Duration::default();
+    let mut pos = start + 4;
+    while pos < data.len() {
+        let event_start = pos;
+        let mut delta: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = match data.get(pos) {
+                Some(&byte) => byte,
+                None => return Err(error(event_start, "truncated event")),
+            };
+            if shift > 63 {
+                return Err(error(event_start, "delta out of range"));
+            }
+            delta |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            pos += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let bytes = match data.get(pos..pos + BINARY_EVENT_SIZE) {
+            Some(bytes) => bytes,
+            None => return Err(error(event_start, "truncated event")),
+        };
+        pos += BINARY_EVENT_SIZE;
+        offset = match offset.checked_add(Duration::from_micros(delta)) {
+            Some(offset) => offset,
+            None => return Err(error(event_start, "offset out of range")),
+        };
+        events.push((
+            offset,
+            VirtioInputEvent {
+                event_type: u16::from_le_bytes([bytes[0], bytes[1]]),
+                code: u16::from_le_bytes([bytes[2], bytes[3]]),
+                value: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            },
+        ));
+    }
+
+    Ok(events)
+}
+
+// The events of a recording, in either format, with their offset from the
+// first one.
+pub fn parse(data: &[u8]) -> Result<Vec<(Duration, VirtioInputEvent)>, ParseError> {
+    if data.starts_with(BINARY_MAGIC) {
+        return parse_binary(data);
+    }
+    let text = str::from_utf8(data).map_err(|_| ParseError {
+        position: Position::Line(1),
+        reason: "not a vhost-user-input recording".to_string(),
+    })?;
+
+    let mut lines = text.lines().enumerate();
+    match lines
+        .next()
+        .map(|(_, line)| line.split_whitespace().collect::<Vec<_>>())
+    {
+        Some(header) if header.len() == 2 && header[0] == MAGIC => {
+            if header[1] != VERSION.to_string() {
+                return Err(ParseError {
+                    position: Position::Line(1),
+                    reason: format!("unsupported recording version {}", header[1]),
+                });
+            }
+        }
+        _ => {
+            return Err(ParseError {
+                position: Position::Line(1),
+                reason: "not a vhost-user-input recording".to_string(),
+            })
+        }
+    }
+
+    let mut events = Vec::new();
+    let mut offset = Duration::default();
+    for (index, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |reason| ParseError {
+            position: Position::Line(index + 1),
+            reason,
+        };
+        let (delta, event) = parse_event(line).map_err(error)?;
+        offset = offset
+            .checked_add(delta)
+            .ok_or_else(|| error("offset out of range".to_string()))?;
+        events.push((offset, event));
+    }
+
+    Ok(events)
+}
+
+// Playback of a recording, paced by a timer registered with the worker, or a
+// frame at a time by step() when stepping.
+pub struct Replay {
+    // Events with their offset from the first one.
+    events: Vec<(Duration, VirtioInputEvent)>,
+    next: usize,
+    speed: f64,
+    stepping: bool,
+    start: Option<Instant>,
+    timer: TimerFd,
+}
+
+impl Replay {
+    pub fn new(
+        events: Vec<(Duration, VirtioInputEvent)>,
+        speed: f64,
+        stepping: bool,
+    ) -> io::Result<Self> {
+        Ok(Replay {
+            events,
+            next: 0,
+            speed,
+            stepping,
+            start: None,
+            timer: TimerFd::new().map_err(|e| io::Error::from_raw_os_error(e.errno()))?,
+        })
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.start.is_some()
+    }
+
+    // Start playing, the first event is due right away. When stepping, nothing
+    // is due until step() is called.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.start = Some(Instant::now());
+        if self.stepping {
+            return Ok(());
+        }
+        self.arm()
+    }
+
+    pub fn is_stepping(&self) -> bool {
+        self.stepping
+    }
+
+    // Events played so far, and in all.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.next, self.events.len())
+    }
+
+    // Hand out the next frame, up to and including its SYN_REPORT, when
+    // stepping. Empty once the recording is over.
+    pub fn step(&mut self) -> Vec<VirtioInputEvent> {
+        let mut events = Vec::new();
+        while let Some((_, event)) = self.events.get(self.next) {
+            events.push(*event);
+            self.next += 1;
+            if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                break;
+            }
+        }
+        if self.next == self.events.len() && !events.is_empty() {
+            info!("replay finished");
+        }
+
+        events
+    }
+
+    // Saturates rather than panics for offsets too far out at a low speed.
+    fn due(&self, offset: Duration) -> Duration {
+        Duration::try_from_secs_f64(offset.as_secs_f64() / self.speed).unwrap_or(Duration::MAX)
+    }
+
+    // Arm the timer for the next event, if there is one.
+    fn arm(&mut self) -> io::Result<()> {
+        let (start, (offset, _)) = match (self.start, self.events.get(self.next)) {
+            (Some(start), Some(next)) => (start, next),
+            _ => return Ok(()),
+        };
+        let wait = self
+            .due(*offset)
+            .checked_sub(start.elapsed())
+            .unwrap_or_default();
+        // A zero timeout would disarm the timer instead of firing it.
+        self.timer
+            .reset(wait.max(Duration::from_nanos(1)), None)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    // Called when the timer fires: hand out the events that are due and wait
+    // for the next one.
+    pub fn take_due(&mut self) -> io::Result<Vec<VirtioInputEvent>> {
+        self.timer
+            .wait()
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        let elapsed = match self.start {
+            Some(start) => start.elapsed(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut events = Vec::new();
+        while let Some((offset, event)) = self.events.get(self.next) {
+            if self.due(*offset) > elapsed {
+                break;
+            }
+            events.push(*event);
+            self.next += 1;
+        }
+        if self.next == self.events.len() && !events.is_empty() {
+            info!("replay finished");
+        }
+        self.arm()?;
+
+        Ok(events)
+    }
+}
+
+impl AsRawFd for Replay {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
@@ -0,0 +1,289 @@
+// Input from another machine over TCP, see --remote-listen, or from another
+// VM over vsock, see --vsock-port.
+//
+// Clients send messages, each a big-endian u32 length followed by that many
+// bytes. The first message is the token of --remote-token-file, answered with
+// a single byte, 0 if it matches and 1 before hanging up if it doesn't. Every
+// message after it holds events in the virtio_input_event layout the guest
+// sees, 8 bytes each:
+//
+//     type: u16 LE, code: u16 LE, value: i32 LE
+//
+// As with the other sources, the guest sees nothing of a frame until its
+// SYN_REPORT, which may come in a later message. A malformed message ends the
+// connection, and the keys a client leaves down are released when it goes.
+//
+// The token keeps strangers out, but travels in the clear, as does the input:
+// use a VPN or an SSH tunnel across untrusted networks.
+//
+// vsock clients skip the token: only VMs on the same host can reach the port
+// at all, and --vsock-cid narrows that down to one of them.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::{self, size_of};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::codes::{EV_SYN, SYN_REPORT};
+use crate::held_keys::HeldKeys;
+use crate::inject::Injected;
+use crate::VirtioInputEvent;
+
+const EVENT_SIZE: usize = 8;
+// Largest message taken from a client.
+const MAX_MESSAGE: usize = 512 * EVENT_SIZE;
+// How long a client has to send its token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const AUTH_OK: u8 = 0;
+const AUTH_DENIED: u8 = 1;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Read a message, None if the client hung up between messages.
+fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(invalid("message too large"));
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+
+    Ok(Some(message))
+}
+
+// Compare without returning early, so that the time taken tells nothing about
+// how much of the token a client got right.
+fn token_matches(token: &[u8], given: &[u8]) -> bool {
+    let mut diff = token.len() ^ given.len();
+    for (i, &byte) in token.iter().enumerate() {
+        diff |= (byte ^ given.get(i).copied().unwrap_or(!byte)) as usize;
+    }
+    diff == 0
+}
+
+fn authenticate(stream: &mut TcpStream, token: &[u8]) -> io::Result<()> {
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let given = read_message(stream)?.ok_or_else(|| invalid("no token"))?;
+    if !token_matches(token, &given) {
+        stream.write_all(&[AUTH_DENIED])?;
+        return Err(invalid("wrong token"));
+    }
+    stream.write_all(&[AUTH_OK])?;
+    stream.set_read_timeout(None)
+}
+
+fn parse_events(message: &[u8]) -> io::Result<Vec<VirtioInputEvent>> {
+    let events = message.chunks_exact(EVENT_SIZE);
+    if !events.remainder().is_empty() {
+        return Err(invalid("message isn't a whole number of events"));
+    }
+
+    Ok(events
+        .map(|bytes| VirtioInputEvent {
+            event_type: u16::from_le_bytes([bytes[0], bytes[1]]),
+            code: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        })
+        .collect())
+}
+
+fn is_syn_report(event: &VirtioInputEvent) -> bool {
+    event.event_type == EV_SYN && event.code == SYN_REPORT
+}
+
+// The keys a client holds down, and whether it left a frame without its
+// SYN_REPORT.
+#[derive(Default)]
+struct Client {
+    held: HeldKeys,
+    open_frame: bool,
+}
+
+impl Client {
+    // Forward the events of the authenticated client until it goes away.
+    fn forward(&mut self, stream: &mut impl Read, injected: &Injected) -> io::Result<()> {
+        while let Some(message) = read_message(stream)? {
+            let events = parse_events(&message)?;
+            if !injected.push(&events) {
+                warn!(
+                    "Too many pending events, dropping {} remote events",
+                    events.len()
+                );
+                continue;
+            }
+            for event in events.iter() {
+                self.held.update(event);
+            }
+            if let Some(last) = events.last() {
+                self.open_frame = !is_syn_report(last);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Events ending the client's frame and releasing its keys, if needed.
+    fn cleanup(&self) -> Vec<VirtioInputEvent> {
+        let mut events = Vec::new();
+        if self.open_frame {
+            events.push(VirtioInputEvent {
+                event_type: EV_SYN,
+                code: SYN_REPORT,
+                value: 0,
+            });
+        }
+        events.extend(self.held.releases());
+        events
+    }
+}
+
+// Forward the events of a client let in until it goes away. peer names it in
+// the log.
+fn serve(mut stream: impl Read, peer: &str, injected: &Injected) {
+    info!("{} connected", peer);
+
+    let mut client = Client::default();
+    let result = client.forward(&mut stream, injected);
+
+    let cleanup = client.cleanup();
+    if !cleanup.is_empty() && !injected.push(&cleanup) {
+        warn!(
+            "Too many pending events, can't release the keys of {}",
+            peer
+        );
+    }
+    match result {
+        Ok(()) => info!("{} disconnected", peer),
+        Err(e) => info!("{} disconnected: {}", peer, e),
+    }
+}
+
+fn serve_tcp(mut stream: TcpStream, token: Arc<Vec<u8>>, injected: Injected) {
+    let peer = match stream.peer_addr() {
+        Ok(addr) => format!("remote client {}", addr),
+        Err(_) => "remote client".to_string(),
+    };
+    if let Err(e) = authenticate(&mut stream, &token) {
+        warn!("Rejected {}: {}", peer, e);
+        return;
+    }
+    serve(stream, &peer, &injected);
+}
+
+// Read the token clients have to send from path, without its trailing newline.
+pub fn read_token(path: &str) -> io::Result<Vec<u8>> {
+    let mut token = fs::read(path)?;
+    while token
+        .last()
+        .map_or(false, |byte| byte.is_ascii_whitespace())
+    {
+        token.pop();
+    }
+    if token.is_empty() {
+        return Err(invalid("the token is empty"));
+    }
+
+    Ok(token)
+}
+
+// Accept clients on address, each served by a thread of its own. Returns the
+// address listened on, with the port picked if address had 0.
+pub fn listen(address: &str, token: Vec<u8>, injected: Injected) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let token = Arc::new(token);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let token = token.clone();
+                    let injected = injected.clone();
+                    thread::spawn(move || serve_tcp(stream, token, injected));
+                }
+                Err(e) => warn!("Failed to accept remote client: {}", e),
+            }
+        }
+    });
+
+    Ok(address)
+}
+
+// Accept vsock clients on port, from the VM with context ID cid only if
+// given, each served by a thread of its own.
+pub fn listen_vsock(port: u32, cid: Option<u32>, injected: Injected) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The socket was just created, listener is its sole owner from here on.
+    let listener = unsafe { File::from_raw_fd(fd) };
+
+    // sockaddr_vm is plain old data, for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_port = port;
+    addr.svm_cid = libc::VMADDR_CID_ANY;
+    // bind() reads no more than the size given of addr.
+    let ret = unsafe {
+        libc::bind(
+            listener.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(listener.as_raw_fd(), libc::SOMAXCONN) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    thread::spawn(move || loop {
+        let mut peer: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        // accept4() writes no more than len bytes of the peer's address.
+        let fd = unsafe {
+            libc::accept4(
+                listener.as_raw_fd(),
+                &mut peer as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+                &mut len,
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            warn!(
+                "Failed to accept vsock client: {}",
+                io::Error::last_os_error()
+            );
+            continue;
+        }
+        // accept4() returned a new fd, stream is its sole owner.
+        let stream = unsafe { File::from_raw_fd(fd) };
+        let allowed = cid.map_or(true, |cid| cid == peer.svm_cid);
+        let peer = format!("vsock client {}:{}", peer.svm_cid, peer.svm_port);
+        if !allowed {
+            warn!("Rejected {}: not from CID {}", peer, cid.unwrap());
+            continue;
+        }
+        let injected = injected.clone();
+        thread::spawn(move || serve(stream, &peer, &injected));
+    });
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// A report of the guests' state to the log on SIGUSR1, for a look at a daemon
+// in the field without a control client.
+//
+// SIGUSR1 is blocked at startup, before the first thread is started, so that
+// every thread inherits the mask and none is interrupted by it. A thread of
+// its own takes it with sigwait() and writes the report, free of what a signal
+// handler may not do.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::thread;
+
+use log::*;
+
+// The set of SIGUSR1 alone.
+fn sigusr1() -> libc::sigset_t {
+    // sigset_t is plain old data, for which all zeroes is a valid value.
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    // Both only write set.
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+    }
+    set
+}
+
+// Keep SIGUSR1 from the calling thread and the threads it starts from now on.
+pub fn block() -> io::Result<()> {
+    let set = sigusr1();
+    // pthread_sigmask() only reads set.
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+
+    Ok(())
+}
+
+// Call report on every SIGUSR1, from a thread of its own.
+pub fn spawn(report: impl Fn() + Send + 'static) {
+    thread::spawn(move || {
+        let set = sigusr1();
+        loop {
+            let mut signal = 0;
+            // sigwait() only reads set and writes signal.
+            let ret = unsafe { libc::sigwait(&set, &mut signal) };
+            if ret != 0 {
+                warn!(
+                    "Failed to wait for SIGUSR1: {}",
+                    io::Error::from_raw_os_error(ret)
+                );
+                return;
+            }
+            report();
+        }
+    });
+}
@@ -0,0 +1,148 @@
+// Recovery from SYN_DROPPED, emitted by evdev when its buffer overflowed and
+// events were lost.
+//
+// We keep the state of the host device as implied by the events read from it.
+// After a drop, the events up to the next SYN_REPORT are discarded (the kernel
+// makes no promise about them), the real state is queried with EVIOCGKEY,
+// EVIOCGLED, EVIOCGSW and EVIOCGABS, and the differences are sent as one
+// corrective frame. Multitouch slot state can't be recovered this way and is
+// left to the next touch.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::str::FromStr;
+
+use crate::codes::{ABS_MT_SLOT, EV_ABS, EV_KEY, EV_LED, EV_SW, EV_SYN, SYN_REPORT};
+use crate::config::VirtioInputEvent;
+use crate::evdev::{set_bit, test_bit, EvdevDevice};
+
+// Last multitouch axis, ABS_MT_TOOL_Y.
+const ABS_MT_LAST: u16 = 0x3d;
+
+// What to do with a SYN_DROPPED from the host, see --syn-dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SynDropped {
+    // Discard the broken frame and send the guest the state it missed.
+    Resync,
+    // Only discard the broken frame.
+    Drop,
+    // Hand SYN_DROPPED to the guest as is.
+    Forward,
+}
+
+impl FromStr for SynDropped {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resync" => Ok(SynDropped::Resync),
+            "drop" => Ok(SynDropped::Drop),
+            "forward" => Ok(SynDropped::Forward),
+            _ => Err(format!("unknown SYN_DROPPED policy {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceState {
+    keys: Vec<u8>,
+    leds: Vec<u8>,
+    switches: Vec<u8>,
+    abs: BTreeMap<u16, i32>,
+}
+
+fn set_state(bits: &mut Vec<u8>, code: u16, on: bool) {
+    if on {
+        set_bit(bits, code);
+    } else if let Some(byte) = bits.get_mut(code as usize / 8) {
+        *byte &= !(1 << (code % 8));
+    }
+}
+
+// Events turning the bits of old into those of new.
+fn diff_bits(ev_type: u16, old: &[u8], new: &[u8], out: &mut Vec<VirtioInputEvent>) {
+    let bits = old.len().max(new.len()) * 8;
+    for code in 0..bits as u16 {
+        let on = test_bit(new, code);
+        if test_bit(old, code) != on {
+            out.push(VirtioInputEvent {
+                event_type: ev_type,
+                code,
+                value: on as u32,
+            });
+        }
+    }
+}
+
+impl DeviceState {
+    pub fn query(device: &EvdevDevice) -> io::Result<Self> {
+        let mut abs = BTreeMap::new();
+        let abs_bits = device.ev_bits(EV_ABS)?;
+        for axis in 0..(abs_bits.len() * 8) as u16 {
+            if test_bit(&abs_bits, axis) && !(ABS_MT_SLOT..=ABS_MT_LAST).contains(&axis) {
+                abs.insert(axis, device.abs_info(axis)?.value);
+            }
+        }
+
+        Ok(DeviceState {
+            keys: device.key_state()?,
+            leds: device.led_state()?,
+            switches: device.switch_state()?,
+            abs,
+        })
+    }
+
+    pub fn update(&mut self, event: &VirtioInputEvent) {
+        match event.event_type {
+            EV_KEY => set_state(&mut self.keys, event.code, event.value != 0),
+            EV_LED => set_state(&mut self.leds, event.code, event.value != 0),
+            EV_SW => set_state(&mut self.switches, event.code, event.value != 0),
+            EV_ABS if !(ABS_MT_SLOT..=ABS_MT_LAST).contains(&event.code) => {
+                self.abs.insert(event.code, event.value as i32);
+            }
+            _ => {}
+        }
+    }
+
+    // The frame releasing the keys down in self, empty if there are none.
+    pub fn key_releases(&self) -> Vec<VirtioInputEvent> {
+        let mut events = Vec::new();
+        diff_bits(EV_KEY, &self.keys, &[], &mut events);
+        if !events.is_empty() {
+            events.push(VirtioInputEvent {
+                event_type: EV_SYN,
+                code: SYN_REPORT,
+                value: 0,
+            });
+        }
+
+        events
+    }
+
+    // The frame taking a reader that saw self to current, empty if they agree.
+    pub fn diff(&self, current: &DeviceState) -> Vec<VirtioInputEvent> {
+        let mut events = Vec::new();
+        diff_bits(EV_KEY, &self.keys, &current.keys, &mut events);
+        diff_bits(EV_LED, &self.leds, &current.leds, &mut events);
+        diff_bits(EV_SW, &self.switches, &current.switches, &mut events);
+        for (&axis, &value) in current.abs.iter() {
+            if self.abs.get(&axis) != Some(&value) {
+                events.push(VirtioInputEvent {
+                    event_type: EV_ABS,
+                    code: axis,
+                    value: value as u32,
+                });
+            }
+        }
+
+        if !events.is_empty() {
+            events.push(VirtioInputEvent {
+                event_type: EV_SYN,
+                code: SYN_REPORT,
+                value: 0,
+            });
+        }
+
+        events
+    }
+}
@@ -0,0 +1,191 @@
+// Events scripted on stdin or in a named pipe, see --script, for shell scripts
+// to drive the guest's input without a client of their own:
+//
+//     key KEY_A press
+//     key KEY_A release
+//     rel X 5
+//     abs Y 16384
+//     syn
+//     sleep 100ms
+//
+// Codes are names, with or without the prefix of their type, or numbers. Key
+// values are press, release, repeat or a number. Sleeps are in us, ms or s.
+// As with host input, the guest sees nothing of a frame until its syn. Blank
+// lines and those starting with # are skipped, and a line that doesn't parse
+// is logged with the reason and skipped too.
+//
+// A named pipe is opened again whenever its writer closes it, so that scripts
+// can be run against it one after the other. Stdin and regular files are read
+// once.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::FileTypeExt;
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::codes::{self, EV_ABS, EV_KEY, EV_REL, EV_SYN, SYN_REPORT};
+use crate::description::{resolve, Code};
+use crate::inject::Injected;
+use crate::VirtioInputEvent;
+
+// How long to wait for the worker before trying a frame again while too many
+// events are pending.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+enum Line {
+    Event(VirtioInputEvent),
+    Sleep(Duration),
+}
+
+// A code of ev_type by number, full name or name without prefix, e.g. 0,
+// REL_X or X for REL_X.
+fn code(ev_type: u16, prefix: &str, code: &str) -> Result<u16, String> {
+    if let Ok(number) = code.parse::<u16>() {
+        return Ok(number);
+    }
+    let name = if code.starts_with(prefix) || codes::code_by_name(code).is_some() {
+        code.to_string()
+    } else {
+        format!("{}{}", prefix, code.to_uppercase())
+    };
+
+    resolve(ev_type, &Code::Name(name))
+}
+
+fn key_value(value: &str) -> Result<i32, String> {
+    match value {
+        "press" => Ok(1),
+        "release" => Ok(0),
+        "repeat" => Ok(2),
+        _ => value
+            .parse()
+            .map_err(|_| format!("invalid key value {}", value)),
+    }
+}
+
+fn duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {}, e.g. 100ms", duration);
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (number, unit) = duration.split_at(split);
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => Err(invalid()),
+    }
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type,
+        code,
+        value: value as u32,
+    }
+}
+
+// The line, None if it's blank or a comment.
+fn parse(line: &str) -> Result<Option<Line>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let line = match words.as_slice() {
+        [] => return Ok(None),
+        [first, ..] if first.starts_with('#') => return Ok(None),
+        ["key", name, value] => Line::Event(event(
+            EV_KEY,
+            code(EV_KEY, "KEY_", name)?,
+            key_value(value)?,
+        )),
+        ["rel", name, value] => Line::Event(event(
+            EV_REL,
+            code(EV_REL, "REL_", name)?,
+            value.parse().map_err(|_| format!("invalid value {}", value))?,
+        )),
+        ["abs", name, value] => Line::Event(event(
+            EV_ABS,
+            code(EV_ABS, "ABS_", name)?,
+            value.parse().map_err(|_| format!("invalid value {}", value))?,
+        )),
+        ["syn"] => Line::Event(event(EV_SYN, SYN_REPORT, 0)),
+        ["sleep", time] => Line::Sleep(duration(time)?),
+        _ => {
+            return Err(
+                "expected key <code> <value>, rel <code> <value>, abs <code> <value>, syn or sleep <duration>"
+                    .to_string(),
+            )
+        }
+    };
+
+    Ok(Some(line))
+}
+
+// Queue a frame for the worker, waiting for it while too many events are
+// pending rather than dropping the script's input.
+fn push(injected: &Injected, frame: &[VirtioInputEvent]) {
+    while !injected.push(frame) {
+        thread::sleep(RETRY_DELAY);
+    }
+}
+
+fn run(input: impl BufRead, name: &str, injected: &Injected) -> io::Result<()> {
+    let mut frame = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        match parse(&line?) {
+            Ok(Some(Line::Event(event))) => {
+                frame.push(event);
+                if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                    push(injected, &frame);
+                    frame.clear();
+                }
+            }
+            Ok(Some(Line::Sleep(duration))) => thread::sleep(duration),
+            Ok(None) => {}
+            Err(e) => warn!("{}:{}: {}", name, number + 1, e),
+        }
+    }
+    if !frame.is_empty() {
+        warn!("{} ended without a syn, dropping its last frame", name);
+    }
+
+    Ok(())
+}
+
+// Follow the script at path, or stdin for "-", from a thread of its own.
+pub fn spawn(path: &str, injected: Injected) -> io::Result<()> {
+    let path = path.to_string();
+    if path == "-" {
+        thread::spawn(move || {
+            if let Err(e) = run(io::stdin().lock(), "stdin", &injected) {
+                error!("Failed to read the script on stdin: {}", e);
+            }
+        });
+        return Ok(());
+    }
+
+    let fifo = fs::metadata(&path)?.file_type().is_fifo();
+    // Opening a named pipe waits for a writer, so a regular file is opened
+    // here for its errors to be reported right away.
+    let mut file = if fifo { None } else { Some(File::open(&path)?) };
+    thread::spawn(move || loop {
+        let file = match file.take().map_or_else(|| File::open(&path), Ok) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to open the script {}: {}", path, e);
+                return;
+            }
+        };
+        if let Err(e) = run(BufReader::new(file), &path, &injected) {
+            error!("Failed to read the script {}: {}", path, e);
+            return;
+        }
+        if !fifo {
+            return;
+        }
+    });
+
+    Ok(())
+}
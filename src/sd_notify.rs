@@ -0,0 +1,133 @@
+// systemd's notification protocol, for Type=notify units: READY=1 once ready
+// for a frontend, and with WatchdogSec= WATCHDOG=1 keepalives for as long as
+// the workers are making progress.
+//
+// Messages are datagrams to the unix socket named by NOTIFY_SOCKET, a path or,
+// starting with @, an abstract name. A worker waits in epoll for as long as
+// nothing happens, so it can't send keepalives itself: a thread of its own
+// sends them on behalf of the workers, unless one of them has been stuck in
+// handle_event for half the watchdog interval, in which case systemd gets none
+// and restarts us once the interval is over.
+
+use std::env;
+use std::io;
+use std::mem::{self, size_of};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::watchdog::Heartbeat;
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: libc::sockaddr_un,
+    addr_len: libc::socklen_t,
+    // Half of WatchdogSec=, if the watchdog is meant for us.
+    keepalive: Option<Duration>,
+}
+
+impl Notifier {
+    // The notifier systemd asks for, if any. The variables are cleared, for
+    // nothing we start to take them for its own, which also makes this the
+    // place to look before --daemon forks and WATCHDOG_PID no longer matches.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = env::var_os("NOTIFY_SOCKET");
+        let usec = env::var("WATCHDOG_USEC").ok();
+        let pid = env::var("WATCHDOG_PID").ok();
+        env::remove_var("NOTIFY_SOCKET");
+        env::remove_var("WATCHDOG_USEC");
+        env::remove_var("WATCHDOG_PID");
+        let path = match path {
+            Some(path) => path.into_string().map_err(|_| invalid("NOTIFY_SOCKET"))?,
+            None => return Ok(None),
+        };
+
+        // sockaddr_un is plain old data, for which all zeroes is a valid value.
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let bytes = path.as_bytes();
+        // An abstract name has no NUL terminator to leave room for.
+        let room = addr.sun_path.len() - !bytes.starts_with(b"@") as usize;
+        if bytes.is_empty() || bytes.len() > room {
+            return Err(invalid("NOTIFY_SOCKET"));
+        }
+        for (to, &from) in addr.sun_path.iter_mut().zip(bytes) {
+            *to = from as libc::c_char;
+        }
+        if bytes[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let path_offset = size_of::<libc::sockaddr_un>() - addr.sun_path.len();
+        let addr_len = (path_offset + bytes.len()) as libc::socklen_t;
+
+        let ours = pid.map_or(true, |pid| pid.parse::<u32>().ok() == Some(process::id()));
+        let keepalive = match usec {
+            Some(usec) if ours => {
+                let usec = usec.parse::<u64>().map_err(|_| invalid("WATCHDOG_USEC"))?;
+                Some(Duration::from_micros(usec / 2)).filter(|half| *half > Duration::default())
+            }
+            _ => None,
+        };
+
+        Ok(Some(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            addr_len,
+            keepalive,
+        }))
+    }
+
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        // sendto() reads no more than the lengths given of state and addr.
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                state.as_ptr() as *const libc::c_void,
+                state.len(),
+                libc::MSG_NOSIGNAL,
+                &self.addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                self.addr_len,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // Tell systemd we're ready, and keep its watchdog fed from a thread of
+    // its own for as long as none of the heartbeats stall.
+    pub fn ready(self, heartbeats: Vec<Arc<Heartbeat>>) {
+        if let Err(e) = self.notify("READY=1") {
+            warn!("Failed to notify systemd of readiness: {}", e);
+        }
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return,
+        };
+        thread::spawn(move || loop {
+            thread::sleep(keepalive);
+            let stalled = heartbeats.iter().any(|heartbeat| {
+                heartbeat
+                    .stalled_for()
+                    .map_or(false, |busy| busy >= keepalive)
+            });
+            if stalled {
+                continue;
+            }
+            if let Err(e) = self.notify("WATCHDOG=1") {
+                warn!("Failed to send systemd watchdog keepalive: {}", e);
+            }
+        });
+    }
+}
+
+fn invalid(variable: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {}", variable))
+}
@@ -0,0 +1,359 @@
+// A seccomp allow-list of the system calls the daemon makes once set up, see
+// --seccomp, in the spirit of cloud-hypervisor's backends.
+//
+// The filter is installed on every thread at once, those already serving
+// sockets included, once the daemon is ready for a frontend, and threads
+// started later inherit it. A call not on the list kills the process, or with
+// log is only logged by the kernel, which is the way to find what a setup
+// needs beyond the list. ioctl() is limited to evdev's and the few std uses
+// on sockets. Opening files stays allowed, for the control socket's swap, the
+// event log's rotation and the like, so it's no substitute for a sandbox of
+// the filesystem.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeccompAction {
+    Kill,
+    Log,
+}
+
+impl FromStr for SeccompAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kill" => Ok(SeccompAction::Kill),
+            "log" => Ok(SeccompAction::Log),
+            _ => Err(format!("unknown seccomp action {}", s)),
+        }
+    }
+}
+
+// The filter is only kept for these architectures, as the system call numbers
+// and even the calls there are differ on others.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod filter {
+    use std::convert::TryFrom;
+    use std::io;
+
+    use super::SeccompAction;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // From linux/seccomp.h and linux/filter.h.
+    const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_ALU_AND_K: u16 = 0x54;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+
+    // Offsets into struct seccomp_data.
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    // The low half of the second argument, on little-endian hosts.
+    const ARG1: u32 = 24;
+
+    // The ioctl() type of evdev's requests, 'E', the second byte of the request.
+    const EVDEV_IOCTL_TYPE: u32 = (b'E' as u32) << 8;
+
+    #[cfg(target_arch = "x86_64")]
+    const ARCH_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_open,
+        libc::SYS_poll,
+        libc::SYS_epoll_wait,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_mkdir,
+        libc::SYS_rmdir,
+        libc::SYS_chmod,
+        libc::SYS_chown,
+        libc::SYS_readlink,
+        libc::SYS_access,
+        libc::SYS_dup2,
+    ];
+    #[cfg(target_arch = "aarch64")]
+    const ARCH_SYSCALLS: &[libc::c_long] = &[];
+
+    const SYSCALLS: &[libc::c_long] = &[
+        // Input, sockets and the vrings.
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_close,
+        libc::SYS_ioctl,
+        libc::SYS_fcntl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_ppoll,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        libc::SYS_timerfd_create,
+        libc::SYS_timerfd_settime,
+        libc::SYS_timerfd_gettime,
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_shutdown,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        // Files: recordings, the event log, swapped and plugged in devices.
+        libc::SYS_openat,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_readlinkat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        // Binding sockets again with --socket-mode and the like.
+        libc::SYS_mkdirat,
+        libc::SYS_fchmodat,
+        libc::SYS_fchownat,
+        libc::SYS_getdents64,
+        libc::SYS_fsync,
+        libc::SYS_ftruncate,
+        // Guest memory, and the allocator.
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        // Threads, time and the process.
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        // The SIGUSR1 report.
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_restart_syscall,
+        libc::SYS_sigaltstack,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_prctl,
+        // Panics abort through a signal to the thread itself.
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    fn statement(code: u16, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code, jt, jf, k }
+    }
+
+    // A jump over skip statements, which BPF can't take past 255.
+    fn skip(skip: usize) -> u8 {
+        u8::try_from(skip).expect("seccomp allow-list too long for a BPF jump")
+    }
+
+    fn program(action: SeccompAction) -> Vec<libc::sock_filter> {
+        let deny = match action {
+            SeccompAction::Kill => SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Log => SECCOMP_RET_LOG,
+        };
+        let allowed: Vec<u32> = SYSCALLS
+            .iter()
+            .chain(ARCH_SYSCALLS)
+            .filter(|&&nr| nr != libc::SYS_ioctl)
+            .map(|&nr| nr as u32)
+            .collect();
+        // Besides evdev's, for the sockets std sets non-blocking and close-on-exec.
+        let requests = [libc::FIONBIO as u32, libc::FIOCLEX as u32];
+
+        // Jumps are relative to the next statement, and only go forward.
+        let mut program = vec![
+            // Another architecture's numbers mean other calls.
+            statement(BPF_LD_W_ABS, ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, NR),
+            // Past the list, its deny and allow, to the ioctl() requests.
+            jump(
+                BPF_JMP_JEQ_K,
+                libc::SYS_ioctl as u32,
+                skip(allowed.len() + 2),
+                0,
+            ),
+        ];
+        for (index, &nr) in allowed.iter().enumerate() {
+            // Past the rest of the list and the deny.
+            program.push(jump(BPF_JMP_JEQ_K, nr, skip(allowed.len() - index), 0));
+        }
+        program.push(statement(BPF_RET_K, deny));
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+        program.push(statement(BPF_LD_W_ABS, ARG1));
+        for (index, &request) in requests.iter().enumerate() {
+            // Past the rest of the requests, the type check and the deny.
+            let past = requests.len() - index + 2;
+            program.push(jump(BPF_JMP_JEQ_K, request, skip(past), 0));
+        }
+        program.push(statement(BPF_ALU_AND_K, 0xff00));
+        program.push(jump(BPF_JMP_JEQ_K, EVDEV_IOCTL_TYPE, 1, 0));
+        program.push(statement(BPF_RET_K, deny));
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+        program
+    }
+
+    // Install the filter on every thread of the process.
+    pub fn apply(action: SeccompAction) -> io::Result<()> {
+        let mut program = program(action);
+        let fprog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_mut_ptr(),
+        };
+
+        // Unprivileged processes may only install filters once they can't gain
+        // privileges, e.g. through a setuid binary, that the filter would stay on.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // seccomp() only reads the program fprog points at, which outlives it.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            // A thread that couldn't be synchronized, as it has a filter of its own.
+            tid if tid > 0 => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("thread {} can't take the filter", tid),
+            )),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // What program returns for a call, run the way the kernel does.
+        fn run(program: &[libc::sock_filter], arch: u32, nr: libc::c_long, arg1: u32) -> u32 {
+            let (mut pc, mut a) = (0, 0);
+            loop {
+                let statement = program[pc];
+                pc += 1;
+                match statement.code {
+                    BPF_LD_W_ABS => {
+                        a = match statement.k {
+                            NR => nr as u32,
+                            ARCH => arch,
+                            ARG1 => arg1,
+                            k => panic!("load of seccomp_data offset {}", k),
+                        }
+                    }
+                    BPF_ALU_AND_K => a &= statement.k,
+                    BPF_JMP_JEQ_K if a == statement.k => pc += statement.jt as usize,
+                    BPF_JMP_JEQ_K => pc += statement.jf as usize,
+                    BPF_RET_K => return statement.k,
+                    code => panic!("unexpected statement {:#x}", code),
+                }
+            }
+        }
+
+        #[test]
+        fn layout() {
+            let program = program(SeccompAction::Log);
+            // Every jump lands on a statement, and every path ends in a return.
+            for (pc, statement) in program.iter().enumerate() {
+                let next = pc + 1 + statement.jt.max(statement.jf) as usize;
+                assert!(next < program.len() || statement.code == BPF_RET_K);
+            }
+            assert_eq!(program.last().unwrap().code, BPF_RET_K);
+            assert!(program.len() <= libc::c_ushort::MAX as usize);
+
+            let eviocgrab = (1 << 30) | (4 << 16) | EVDEV_IOCTL_TYPE | 0x90;
+            let allow = SECCOMP_RET_ALLOW;
+            for &nr in SYSCALLS.iter().chain(ARCH_SYSCALLS) {
+                if nr != libc::SYS_ioctl {
+                    assert_eq!(run(&program, AUDIT_ARCH, nr, 0), allow, "syscall {}", nr);
+                }
+            }
+            assert_eq!(
+                run(&program, AUDIT_ARCH, libc::SYS_ptrace, 0),
+                SECCOMP_RET_LOG
+            );
+            assert_eq!(
+                run(&program, AUDIT_ARCH + 1, libc::SYS_read, 0),
+                SECCOMP_RET_KILL_PROCESS
+            );
+
+            let ioctl = |request: u32| run(&program, AUDIT_ARCH, libc::SYS_ioctl, request);
+            assert_eq!(ioctl(eviocgrab), allow);
+            assert_eq!(ioctl(libc::FIONBIO as u32), allow);
+            assert_eq!(ioctl(libc::FIOCLEX as u32), allow);
+            assert_eq!(ioctl(libc::TIOCSTI as u32), SECCOMP_RET_LOG);
+
+            let program = super::program(SeccompAction::Kill);
+            assert_eq!(
+                run(&program, AUDIT_ARCH, libc::SYS_ptrace, 0),
+                SECCOMP_RET_KILL_PROCESS
+            );
+        }
+
+        #[test]
+        fn skips() {
+            assert_eq!(skip(255), 255);
+            assert!(std::panic::catch_unwind(|| skip(256)).is_err());
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use filter::apply;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn apply(_action: SeccompAction) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "--seccomp isn't supported on this architecture",
+    ))
+}
@@ -0,0 +1,136 @@
+// How long the worker spends on each unit of work, see --slow-span-us and
+// --otlp-endpoint.
+//
+// Processing a queue, answering a config request and reading an evdev device
+// are tracing spans, with the queue or device they're for as a field. While
+// one is open its name is a SPAN field of the records logged on the thread,
+// next to the DEVICE or QUEUE it's for, and once it's over it logs how long it
+// took: at trace level always, as a warning past the threshold, so that a
+// latency spike in a large fleet shows up tied to the device and queue it
+// happened on. Built with the otlp feature, the spans can also be exported to
+// an OTLP collector such as Jaeger. Unless any of that is asked for, no
+// subscriber is installed and a span costs a check of its callsite.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::*;
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::logger::{self, Field};
+
+// In microseconds, u64::MAX while there's no threshold.
+static SLOW: AtomicU64 = AtomicU64::new(u64::MAX);
+
+thread_local! {
+    // The SPAN fields of the spans entered on this thread.
+    static ENTERED: RefCell<Vec<Field>> = const { RefCell::new(Vec::new()) };
+}
+
+// When a span was opened.
+struct Start(Instant);
+
+// Times the spans and logs how long they took.
+struct Timing;
+
+impl<S> Layer<S> for Timing
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Start(Instant::now()));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let field = logger::field("SPAN", span.name());
+            ENTERED.with(|entered| entered.borrow_mut().push(field));
+        }
+    }
+
+    fn on_exit(&self, _id: &Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| entered.borrow_mut().pop());
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let elapsed = match span.extensions().get::<Start>() {
+            Some(start) => start.0.elapsed(),
+            None => return,
+        };
+        if elapsed.as_micros() as u64 >= SLOW.load(Ordering::Relaxed) {
+            warn!("{} took {:?}", span.name(), elapsed);
+        } else {
+            trace!("{} took {:?}", span.name(), elapsed);
+        }
+    }
+}
+
+// Install the subscriber for the spans, if they're timed, which takes a
+// threshold or the trace level, or exported to otlp_endpoint.
+pub fn init(threshold: Option<Duration>, otlp_endpoint: Option<&str>) -> Result<(), String> {
+    if let Some(threshold) = threshold {
+        SLOW.store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+    let timed = threshold.is_some() || log_enabled!(Level::Trace);
+    if !timed && otlp_endpoint.is_none() {
+        return Ok(());
+    }
+
+    let subscriber = tracing_subscriber::registry()
+        .with(if timed { Some(Timing) } else { None })
+        .with(otlp(otlp_endpoint)?);
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())
+}
+
+// Export the spans to the OTLP/HTTP collector at endpoint, e.g.
+// http://localhost:4318/v1/traces for Jaeger, as they close.
+#[cfg(feature = "otlp")]
+fn otlp<S>(endpoint: Option<&str>) -> Result<Option<impl Layer<S>>, String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "vhost-user-input",
+            )])),
+        )
+        .install_simple()
+        .map_err(|e| e.to_string())?;
+    info!("exporting spans to {}", endpoint);
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp(endpoint: Option<&str>) -> Result<tracing_subscriber::layer::Identity, String> {
+    match endpoint {
+        Some(_) => Err("built without the otlp feature".to_string()),
+        None => Ok(tracing_subscriber::layer::Identity::new()),
+    }
+}
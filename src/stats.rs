@@ -0,0 +1,91 @@
+// Counters of what the device did for the guest, and of the events that never
+// made it there, by cause.
+
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DropCause {
+    // The event buffer was full, the guest isn't taking events fast enough.
+    Overflow,
+    // A filter rule dropped the event.
+    Filtered,
+    // Part of a frame the host broke with SYN_DROPPED.
+    HostOverflow,
+    // Host input while paused from the control socket.
+    Paused,
+}
+
+const CAUSES: [DropCause; 4] = [
+    DropCause::Overflow,
+    DropCause::Filtered,
+    DropCause::HostOverflow,
+    DropCause::Paused,
+];
+
+impl DropCause {
+    pub fn name(self) -> &'static str {
+        match self {
+            DropCause::Overflow => "overflow",
+            DropCause::Filtered => "filtered",
+            DropCause::HostOverflow => "host overflow",
+            DropCause::Paused => "paused",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DropCounters {
+    // Totals since startup, indexed by cause.
+    counts: [u64; CAUSES.len()],
+    // Totals at the last summary.
+    summarized: [u64; CAUSES.len()],
+}
+
+impl DropCounters {
+    pub fn add(&mut self, cause: DropCause, events: usize) {
+        self.counts[cause as usize] += events as u64;
+    }
+
+    // Events dropped since startup, for any cause.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // One line on what was dropped since the previous summary, None if nothing.
+    pub fn summary(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let mut total = 0;
+        for cause in CAUSES.iter() {
+            let i = *cause as usize;
+            let dropped = self.counts[i] - self.summarized[i];
+            if dropped > 0 {
+                let sep = if line.is_empty() { "" } else { ", " };
+                write!(line, "{}{} {}", sep, dropped, cause.name()).unwrap();
+                total += dropped;
+            }
+        }
+        self.summarized = self.counts;
+
+        if total == 0 {
+            return None;
+        }
+        Some(format!("dropped {} events: {}", total, line))
+    }
+}
+
+// Totals since startup of the work done for the guest, see query-stats.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counters {
+    // Events written into eventq buffers.
+    pub events_forwarded: u64,
+    // Complete frames handed on for the guest.
+    pub frames: u64,
+    // Buffers put on the used rings, eventq and statusq alike.
+    pub used_ring_adds: u64,
+    // Interrupts sent to the driver for used buffers.
+    pub notifications_sent: u64,
+    // Interrupts VIRTIO_RING_F_EVENT_IDX let us skip.
+    pub notifications_suppressed: u64,
+    // Events the driver sent on the statusq, e.g. LED changes.
+    pub statusq_events: u64,
+}
@@ -0,0 +1,103 @@
+// Detection of a worker stuck in handle_event, e.g. deadlocked on the thread
+// lock or blocked on a host read, which otherwise just looks like the guest
+// stopped getting input.
+//
+// The worker only stamps atomics, and the watchdog only reads them, so neither
+// can block the other.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use vmm_sys_util::eventfd::EventFd;
+
+// Stamped by the worker around each epoll cycle.
+pub struct Heartbeat {
+    epoch: Instant,
+    // When the cycle in progress started, in microseconds since epoch plus
+    // one, or 0 while the worker is waiting in epoll.
+    busy_since: AtomicU64,
+}
+
+// Marks the worker idle again when dropped, whichever way the cycle returns.
+pub struct Beat<'a>(&'a Heartbeat);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new()
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            epoch: Instant::now(),
+            busy_since: AtomicU64::new(0),
+        }
+    }
+
+    pub fn beat(&self) -> Beat<'_> {
+        let now = self.epoch.elapsed().as_micros() as u64 + 1;
+        self.busy_since.store(now, Ordering::Release);
+        Beat(self)
+    }
+
+    // How long the worker has been in the current cycle, None if it's idle.
+    pub fn stalled_for(&self) -> Option<Duration> {
+        match self.busy_since.load(Ordering::Acquire) {
+            0 => None,
+            since => Some(
+                self.epoch
+                    .elapsed()
+                    .checked_sub(Duration::from_micros(since - 1))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+impl Drop for Beat<'_> {
+    fn drop(&mut self) {
+        self.0.busy_since.store(0, Ordering::Release);
+    }
+}
+
+// Watch heartbeat from a thread of its own, logging an error when a cycle
+// takes longer than timeout. With kill_evt the worker is also told to exit,
+// and if it's too stuck to notice within another timeout, the whole process
+// exits so a supervisor can restart it.
+pub fn spawn(heartbeat: Arc<Heartbeat>, timeout: Duration, kill_evt: Option<EventFd>) {
+    let interval = (timeout / 4).max(Duration::from_millis(1));
+    thread::spawn(move || {
+        let mut stalled = false;
+        loop {
+            thread::sleep(interval);
+            match heartbeat.stalled_for() {
+                Some(busy) if busy >= timeout => {
+                    if !stalled {
+                        error!(
+                            "Worker thread stalled: no progress for {} ms",
+                            busy.as_millis()
+                        );
+                        stalled = true;
+                        if let Some(kill_evt) = kill_evt.as_ref() {
+                            if let Err(e) = kill_evt.write(1) {
+                                error!("Failed to signal worker thread to exit: {}", e);
+                            }
+                        }
+                    } else if kill_evt.is_some() && busy >= timeout * 2 {
+                        error!("Worker thread did not exit, shutting down");
+                        std::process::exit(1);
+                    }
+                }
+                _ if stalled => {
+                    warn!("Worker thread resumed");
+                    stalled = false;
+                }
+                _ => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,771 @@
+// Browser input over WebSocket, see --websocket.
+//
+// A page connects to ws://<address>/ and sends a JSON message per input event,
+// named after the DOM events it comes from:
+//
+//     {"type": "keydown", "code": "KeyA"}
+//     {"type": "keyup", "code": "KeyA"}
+//     {"type": "mousemove", "dx": 4, "dy": -2}
+//     {"type": "mousemove", "x": 0.5, "y": 0.25}
+//     {"type": "mousedown", "button": 0}
+//     {"type": "mouseup", "button": 0}
+//     {"type": "wheel", "dx": 0, "dy": 120}
+//
+// Keys are KeyboardEvent.code values, which name the physical key whatever
+// the layout, buttons MouseEvent.button values. mousemove is relative with
+// dx/dy, and absolute with x/y from 0 to 1 across the guest's screen, scaled
+// to the range of --synthetic tablet. Wheel deltas only count by their sign.
+// Every message is a frame of its own, and the keys and buttons a client
+// leaves down are released when it goes away.
+//
+// Only the part of RFC 6455 browsers need is implemented: the handshake,
+// masked text frames, fragmentation, ping and close. There is neither TLS nor
+// authentication. Browsers send the Origin of the page with the handshake, and
+// only the pages of --websocket-origin are let in, so that any other page the
+// user has open can't type into the guest. Clients that send no Origin are not
+// browsers, and get in.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::codes::{
+    self, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_ABS, EV_KEY, EV_REL, EV_SYN,
+    REL_HWHEEL, REL_WHEEL, REL_X, REL_Y, SYN_REPORT,
+};
+use crate::inject::Injected;
+use crate::profiles::TABLET_MAX;
+use crate::VirtioInputEvent;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest handshake request and message taken from a client.
+const MAX_REQUEST: usize = 8192;
+const MAX_MESSAGE: usize = 65536;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Message {
+    Keydown {
+        code: String,
+    },
+    Keyup {
+        code: String,
+    },
+    Mousemove {
+        #[serde(default)]
+        dx: i32,
+        #[serde(default)]
+        dy: i32,
+        x: Option<f64>,
+        y: Option<f64>,
+    },
+    Mousedown {
+        button: u8,
+    },
+    Mouseup {
+        button: u8,
+    },
+    Wheel {
+        #[serde(default)]
+        dx: f64,
+        #[serde(default)]
+        dy: f64,
+    },
+}
+
+// Keys whose KeyboardEvent.code doesn't follow from their evdev name.
+const KEYS: &[(&str, &str)] = &[
+    ("Enter", "KEY_ENTER"),
+    ("Escape", "KEY_ESC"),
+    ("Backspace", "KEY_BACKSPACE"),
+    ("Tab", "KEY_TAB"),
+    ("Space", "KEY_SPACE"),
+    ("Minus", "KEY_MINUS"),
+    ("Equal", "KEY_EQUAL"),
+    ("BracketLeft", "KEY_LEFTBRACE"),
+    ("BracketRight", "KEY_RIGHTBRACE"),
+    ("Backslash", "KEY_BACKSLASH"),
+    ("IntlBackslash", "KEY_102ND"),
+    ("Semicolon", "KEY_SEMICOLON"),
+    ("Quote", "KEY_APOSTROPHE"),
+    ("Backquote", "KEY_GRAVE"),
+    ("Comma", "KEY_COMMA"),
+    ("Period", "KEY_DOT"),
+    ("Slash", "KEY_SLASH"),
+    ("CapsLock", "KEY_CAPSLOCK"),
+    ("ShiftLeft", "KEY_LEFTSHIFT"),
+    ("ShiftRight", "KEY_RIGHTSHIFT"),
+    ("ControlLeft", "KEY_LEFTCTRL"),
+    ("ControlRight", "KEY_RIGHTCTRL"),
+    ("AltLeft", "KEY_LEFTALT"),
+    ("AltRight", "KEY_RIGHTALT"),
+    ("MetaLeft", "KEY_LEFTMETA"),
+    ("MetaRight", "KEY_RIGHTMETA"),
+    ("ContextMenu", "KEY_COMPOSE"),
+    ("ArrowUp", "KEY_UP"),
+    ("ArrowDown", "KEY_DOWN"),
+    ("ArrowLeft", "KEY_LEFT"),
+    ("ArrowRight", "KEY_RIGHT"),
+    ("Insert", "KEY_INSERT"),
+    ("Delete", "KEY_DELETE"),
+    ("Home", "KEY_HOME"),
+    ("End", "KEY_END"),
+    ("PageUp", "KEY_PAGEUP"),
+    ("PageDown", "KEY_PAGEDOWN"),
+    ("PrintScreen", "KEY_SYSRQ"),
+    ("ScrollLock", "KEY_SCROLLLOCK"),
+    ("Pause", "KEY_PAUSE"),
+    ("NumLock", "KEY_NUMLOCK"),
+    ("NumpadEnter", "KEY_KPENTER"),
+    ("NumpadAdd", "KEY_KPPLUS"),
+    ("NumpadSubtract", "KEY_KPMINUS"),
+    ("NumpadMultiply", "KEY_KPASTERISK"),
+    ("NumpadDivide", "KEY_KPSLASH"),
+    ("NumpadDecimal", "KEY_KPDOT"),
+];
+
+// The evdev key of a KeyboardEvent.code.
+fn key_code(code: &str) -> Option<u16> {
+    let name = if let Some(letter) = code.strip_prefix("Key") {
+        format!("KEY_{}", letter)
+    } else if let Some(digit) = code.strip_prefix("Digit") {
+        format!("KEY_{}", digit)
+    } else if let Some(digit) = code.strip_prefix("Numpad").filter(|d| d.len() == 1) {
+        format!("KEY_KP{}", digit)
+    } else if code.starts_with('F') && code[1..].chars().all(|c| c.is_ascii_digit()) {
+        format!("KEY_{}", code)
+    } else {
+        KEYS.iter().find(|(dom, _)| *dom == code)?.1.to_string()
+    };
+
+    match codes::code_by_name(&name) {
+        Some((EV_KEY, code)) => Some(code),
+        _ => None,
+    }
+}
+
+fn button_code(button: u8) -> Option<u16> {
+    match button {
+        0 => Some(BTN_LEFT),
+        1 => Some(BTN_MIDDLE),
+        2 => Some(BTN_RIGHT),
+        _ => None,
+    }
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type,
+        code,
+        value: value as u32,
+    }
+}
+
+fn scale(position: f64) -> i32 {
+    (position.clamp(0.0, 1.0) * TABLET_MAX as f64).round() as i32
+}
+
+// Keys and buttons a client holds down.
+#[derive(Default)]
+struct Client {
+    held: BTreeSet<u16>,
+}
+
+impl Client {
+    fn press(&mut self, code: u16, down: bool) -> VirtioInputEvent {
+        let value = if !down {
+            self.held.remove(&code);
+            0
+        } else if self.held.insert(code) {
+            1
+        } else {
+            // Browsers repeat keydown while a key is held.
+            2
+        };
+
+        event(EV_KEY, code, value)
+    }
+
+    // The frame for a message, without its SYN_REPORT.
+    fn translate(&mut self, message: Message) -> Result<Vec<VirtioInputEvent>, String> {
+        let events = match message {
+            Message::Keydown { code } | Message::Keyup { code } if key_code(&code).is_none() => {
+                return Err(format!("unknown key {}", code))
+            }
+            Message::Keydown { code } => vec![self.press(key_code(&code).unwrap(), true)],
+            Message::Keyup { code } => vec![self.press(key_code(&code).unwrap(), false)],
+            Message::Mousedown { button } | Message::Mouseup { button }
+                if button_code(button).is_none() =>
+            {
+                return Err(format!("unknown button {}", button))
+            }
+            Message::Mousedown { button } => vec![self.press(button_code(button).unwrap(), true)],
+            Message::Mouseup { button } => vec![self.press(button_code(button).unwrap(), false)],
+            Message::Mousemove { dx, dy, x, y } => {
+                let mut events = Vec::new();
+                if let Some(x) = x {
+                    events.push(event(EV_ABS, ABS_X, scale(x)));
+                }
+                if let Some(y) = y {
+                    events.push(event(EV_ABS, ABS_Y, scale(y)));
+                }
+                if dx != 0 {
+                    events.push(event(EV_REL, REL_X, dx));
+                }
+                if dy != 0 {
+                    events.push(event(EV_REL, REL_Y, dy));
+                }
+                events
+            }
+            Message::Wheel { dx, dy } => {
+                let mut events = Vec::new();
+                if dx != 0.0 {
+                    events.push(event(EV_REL, REL_HWHEEL, dx.signum() as i32));
+                }
+                // The DOM scrolls down for positive deltas, evdev up.
+                if dy != 0.0 {
+                    events.push(event(EV_REL, REL_WHEEL, -dy.signum() as i32));
+                }
+                events
+            }
+        };
+
+        Ok(events)
+    }
+
+    // The frame releasing whatever is still held.
+    fn releases(&mut self) -> Vec<VirtioInputEvent> {
+        let held = std::mem::take(&mut self.held);
+        held.into_iter()
+            .map(|code| event(EV_KEY, code, 0))
+            .collect()
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                block[i * 4],
+                block[i * 4 + 1],
+                block[i * 4 + 2],
+                block[i * 4 + 3],
+            ]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Read the client's upgrade request, and accept it or answer with an error.
+// Requests from browsers are only accepted from the pages of origins.
+fn handshake(
+    reader: &mut impl BufRead,
+    out: &mut impl Write,
+    origins: &[String],
+) -> io::Result<()> {
+    let mut key = None;
+    let mut upgrade = false;
+    let mut origin = None;
+    let mut size = 0;
+    let mut first = true;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("connection closed during the handshake"));
+        }
+        size += line.len();
+        if size > MAX_REQUEST {
+            return Err(invalid("handshake request too large"));
+        }
+        let line = line.trim_end();
+        if first {
+            first = false;
+            if !line.starts_with("GET ") {
+                key = None;
+                break;
+            }
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_string());
+            }
+        }
+    }
+
+    if let Some(origin) = origin {
+        if !origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&origin))
+        {
+            write!(
+                out,
+                "HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+            )?;
+            return Err(invalid(&format!("origin {} not allowed", origin)));
+        }
+    }
+
+    match key {
+        Some(key) if upgrade => {
+            let accept = base64(&sha1(format!("{}{}", key, GUID).as_bytes()));
+            write!(
+                out,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )
+        }
+        _ => {
+            write!(
+                out,
+                "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+            )?;
+            Err(invalid("not a WebSocket upgrade request"))
+        }
+    }
+}
+
+// Read a frame, returning whether it ends its message, its opcode and its
+// unmasked payload.
+fn read_frame(reader: &mut impl Read) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    if header[1] & 0x80 == 0 {
+        return Err(invalid("unmasked client frame"));
+    }
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err(invalid("frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+fn write_frame(out: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)
+}
+
+#[derive(Serialize)]
+struct Reply {
+    error: String,
+}
+
+fn send_error(out: &mut impl Write, reason: &str) -> io::Result<()> {
+    let reply = serde_json::to_string(&Reply {
+        error: reason.to_string(),
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    write_frame(out, OP_TEXT, reply.as_bytes())
+}
+
+// Handle the messages of one client until it goes away.
+fn serve_messages(
+    reader: &mut impl Read,
+    out: &mut impl Write,
+    client: &mut Client,
+    injected: &Injected,
+) -> io::Result<()> {
+    // The message being reassembled from fragments, with its opcode.
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let (fin, opcode, payload) = read_frame(reader)?;
+        match opcode {
+            OP_CLOSE => {
+                // Echo the status code, if any, to complete the close.
+                let status = payload.get(..2).unwrap_or(&[]);
+                return write_frame(out, OP_CLOSE, status);
+            }
+            OP_PING => {
+                write_frame(out, OP_PONG, &payload)?;
+                continue;
+            }
+            OP_PONG => continue,
+            OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+            OP_CONTINUATION if message.is_some() => {
+                let (_, data) = message.as_mut().unwrap();
+                if data.len() + payload.len() > MAX_MESSAGE {
+                    return Err(invalid("message too large"));
+                }
+                data.extend_from_slice(&payload);
+            }
+            _ => return Err(invalid("unexpected frame")),
+        }
+        if !fin {
+            continue;
+        }
+
+        let (opcode, data) = message.take().unwrap();
+        if opcode == OP_BINARY {
+            send_error(out, "expected a text message")?;
+            continue;
+        }
+        let result = serde_json::from_slice(&data)
+            .map_err(|e| format!("invalid message: {}", e))
+            .and_then(|message| client.translate(message));
+        let mut events = match result {
+            Ok(events) => events,
+            Err(reason) => {
+                send_error(out, &reason)?;
+                continue;
+            }
+        };
+        if events.is_empty() {
+            continue;
+        }
+        events.push(event(EV_SYN, SYN_REPORT, 0));
+        if !injected.push(&events) {
+            send_error(out, "too many pending events")?;
+        }
+    }
+}
+
+fn serve(stream: TcpStream, origins: Arc<Vec<String>>, injected: Injected) {
+    let peer = stream
+        .peer_addr()
+        .map_or("unknown peer".to_string(), |addr| addr.to_string());
+    let mut out = match stream.try_clone() {
+        Ok(out) => out,
+        Err(e) => {
+            warn!("Failed to set up WebSocket client {}: {}", peer, e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    if let Err(e) = handshake(&mut reader, &mut out, &origins) {
+        warn!("Rejected WebSocket client {}: {}", peer, e);
+        return;
+    }
+    info!("WebSocket client {} connected", peer);
+
+    let mut client = Client::default();
+    let result = serve_messages(&mut reader, &mut out, &mut client, &injected);
+
+    let mut releases = client.releases();
+    if !releases.is_empty() {
+        releases.push(event(EV_SYN, SYN_REPORT, 0));
+        if !injected.push(&releases) {
+            warn!(
+                "Too many pending events, can't release the keys of {}",
+                peer
+            );
+        }
+    }
+    match result {
+        Ok(()) => info!("WebSocket client {} disconnected", peer),
+        Err(e) => info!("WebSocket client {} disconnected: {}", peer, e),
+    }
+}
+
+// Accept WebSocket clients on address, each served by a thread of its own,
+// and browsers only from the pages of origins, e.g. https://console.example.
+// Returns the address listened on, with the port picked if address had 0.
+pub fn listen(address: &str, origins: Vec<String>, injected: Injected) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let origins = Arc::new(origins);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let origins = origins.clone();
+                    let injected = injected.clone();
+                    thread::spawn(move || serve(stream, origins, injected));
+                }
+                Err(e) => warn!("Failed to accept WebSocket client: {}", e),
+            }
+        }
+    });
+
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libc::EFD_NONBLOCK;
+    use vmm_sys_util::eventfd::EventFd;
+
+    const KEY_A: u16 = 30;
+
+    // A client frame, masked as browsers send them.
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn request(headers: &str) -> String {
+        format!(
+            "GET / HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{}\r\n",
+            headers
+        )
+    }
+
+    fn shake(request: &str, origins: &[&str]) -> (io::Result<()>, String) {
+        let origins: Vec<String> = origins.iter().map(|o| o.to_string()).collect();
+        let mut out = Vec::new();
+        let result = handshake(&mut request.as_bytes(), &mut out, &origins);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn digests() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Spans two blocks once padded.
+        let hex: String = sha1(&[b'a'; 56])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "c2db330f6083854c99d4b5bfb6e8f29f201be699");
+
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn handshake_accept() {
+        // The example of RFC 6455, section 1.3.
+        let (result, reply) = shake(&request(""), &[]);
+        assert!(result.is_ok());
+        assert!(reply.starts_with("HTTP/1.1 101 "));
+        assert!(reply.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let origin = "Origin: https://console.example\r\n";
+        let (result, reply) = shake(&request(origin), &["https://console.example"]);
+        assert!(result.is_ok());
+        assert!(reply.starts_with("HTTP/1.1 101 "));
+    }
+
+    #[test]
+    fn handshake_reject() {
+        let origin = "Origin: https://evil.example\r\n";
+        let (result, reply) = shake(&request(origin), &["https://console.example"]);
+        assert!(result.is_err());
+        assert!(reply.starts_with("HTTP/1.1 403 "));
+        let (result, reply) = shake(&request(origin), &[]);
+        assert!(result.is_err());
+        assert!(reply.starts_with("HTTP/1.1 403 "));
+
+        let (result, reply) = shake("GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", &[]);
+        assert!(result.is_err());
+        assert!(reply.starts_with("HTTP/1.1 400 "));
+        let (result, reply) = shake("POST / HTTP/1.1\r\n\r\n", &[]);
+        assert!(result.is_err());
+        assert!(reply.starts_with("HTTP/1.1 400 "));
+
+        let (result, _) = shake(&request(&"X-Padding: a\r\n".repeat(1000)), &[]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn frames() {
+        // The masked "Hello" of RFC 6455, section 5.7.
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (fin, opcode, payload) = read_frame(&mut &frame[..]).unwrap();
+        assert_eq!(
+            (fin, opcode, payload.as_slice()),
+            (true, OP_TEXT, &b"Hello"[..])
+        );
+
+        let mut data = masked(false, OP_TEXT, b"Hel");
+        data.extend(masked(true, OP_CONTINUATION, b"lo"));
+        let payload = vec![b'x'; 300];
+        data.extend(masked(true, OP_BINARY, &payload));
+        let mut reader = &data[..];
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            (false, OP_TEXT, b"Hel".to_vec())
+        );
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            (true, OP_CONTINUATION, b"lo".to_vec())
+        );
+        assert_eq!(read_frame(&mut reader).unwrap(), (true, OP_BINARY, payload));
+        assert!(read_frame(&mut reader).is_err());
+
+        // Unmasked, then too large for MAX_MESSAGE, then truncated.
+        let frame = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(read_frame(&mut &frame[..]).is_err());
+        let mut frame = vec![0x82, 0x80 | 127];
+        frame.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        let e = read_frame(&mut &frame[..]).unwrap_err();
+        assert_eq!(e.to_string(), "frame too large");
+        let frame = masked(true, OP_TEXT, b"Hello");
+        assert!(read_frame(&mut &frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn messages() {
+        let injected = Injected::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let mut client = Client::default();
+
+        let mut data = masked(false, OP_TEXT, br#"{"type":"key"#);
+        data.extend(masked(true, OP_PING, b"ping"));
+        data.extend(masked(true, OP_CONTINUATION, br#"down","code":"KeyA"}"#));
+        data.extend(masked(true, OP_CLOSE, &1000u16.to_be_bytes()));
+        let mut out = Vec::new();
+        serve_messages(&mut &data[..], &mut out, &mut client, &injected).unwrap();
+        let mut reply = vec![0x80 | OP_PONG, 4];
+        reply.extend_from_slice(b"ping");
+        reply.extend_from_slice(&[0x80 | OP_CLOSE, 2, 0x03, 0xe8]);
+        assert_eq!(out, reply);
+        let events: Vec<_> = injected
+            .take()
+            .iter()
+            .map(|e| (e.event_type, e.code, e.value))
+            .collect();
+        assert_eq!(events, vec![(EV_KEY, KEY_A, 1), (EV_SYN, SYN_REPORT, 0)]);
+        assert_eq!(client.releases().len(), 1);
+
+        // Fragments adding up to more than MAX_MESSAGE.
+        let chunk = vec![b' '; MAX_MESSAGE / 2 + 1];
+        let mut data = masked(false, OP_TEXT, &chunk);
+        data.extend(masked(true, OP_CONTINUATION, &chunk));
+        let e =
+            serve_messages(&mut &data[..], &mut Vec::new(), &mut client, &injected).unwrap_err();
+        assert_eq!(e.to_string(), "message too large");
+        assert!(injected.take().is_empty());
+    }
+}
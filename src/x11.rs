@@ -0,0 +1,1171 @@
+// Keyboard and pointer input from the host's X session, captured with a
+// hotkey, see --x11-capture, or typed into a window, see --x11-window.
+//
+// The daemon follows the session's input through XInput2 raw events, which the
+// X server sends whoever selected them whatever window has the focus or a grab.
+// Pressing the hotkey grabs the keyboard and pointer, so that the host's
+// applications stop seeing the input, and forwards it to the guest until the
+// hotkey is pressed again, which ungrabs both and releases whatever is still
+// held in the guest.
+//
+// The window is a plain X11 window instead: its input goes to the guest while
+// it has the focus and the pointer, and the guest's keys and buttons are
+// released when it loses the focus.
+//
+// Only the bits of the X11 protocol needed for this are spoken, over the local
+// socket of $DISPLAY with its MIT-MAGIC-COOKIE-1 from $XAUTHORITY. X keycodes
+// are evdev's plus 8, as with the evdev and libinput drivers X servers use on
+// Linux. Pointer motion is forwarded relative and unaccelerated, for the guest
+// to accelerate it.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+
+use log::*;
+
+use crate::codes::{
+    self, BTN_EXTRA, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, BTN_SIDE, EV_KEY, EV_REL, EV_SYN, REL_HWHEEL,
+    REL_WHEEL, REL_X, REL_Y, SYN_REPORT,
+};
+use crate::held_keys::HeldKeys;
+use crate::inject::Injected;
+use crate::VirtioInputEvent;
+
+// Toggles capture unless --x11-hotkey says otherwise, as in QEMU's SDL window.
+pub const DEFAULT_HOTKEY: &str = "KEY_LEFTCTRL+KEY_LEFTALT+KEY_G";
+
+const X_CREATE_WINDOW: u8 = 1;
+const X_MAP_WINDOW: u8 = 8;
+const X_CHANGE_PROPERTY: u8 = 18;
+const X_GRAB_POINTER: u8 = 26;
+const X_UNGRAB_POINTER: u8 = 27;
+const X_GRAB_KEYBOARD: u8 = 31;
+const X_UNGRAB_KEYBOARD: u8 = 32;
+const X_QUERY_EXTENSION: u8 = 98;
+const XI_SELECT_EVENTS: u8 = 46;
+const XI_QUERY_VERSION: u8 = 47;
+
+const X_ERROR: u8 = 0;
+const X_REPLY: u8 = 1;
+const KEY_PRESS: u8 = 2;
+const KEY_RELEASE: u8 = 3;
+const BUTTON_PRESS: u8 = 4;
+const BUTTON_RELEASE: u8 = 5;
+const MOTION_NOTIFY: u8 = 6;
+const ENTER_NOTIFY: u8 = 7;
+const LEAVE_NOTIFY: u8 = 8;
+const FOCUS_OUT: u8 = 10;
+const GENERIC_EVENT: u8 = 35;
+// Largest reply or event taken from the server.
+const MAX_PACKET: usize = 1 << 20;
+
+const XI_ALL_MASTER_DEVICES: u16 = 1;
+const XI_RAW_KEY_PRESS: u16 = 13;
+const XI_RAW_KEY_RELEASE: u16 = 14;
+const XI_RAW_BUTTON_PRESS: u16 = 15;
+const XI_RAW_BUTTON_RELEASE: u16 = 16;
+const XI_RAW_MOTION: u16 = 17;
+// Flag of key presses the server generated for autorepeat.
+const XI_KEY_REPEAT: u32 = 1 << 16;
+
+const GRAB_MODE_ASYNC: u8 = 1;
+const WINDOW_CLASS_INPUT_OUTPUT: u16 = 1;
+// CreateWindow values given: the background pixel and the event mask.
+const CW_BACK_PIXEL: u32 = 1 << 1;
+const CW_EVENT_MASK: u32 = 1 << 11;
+const WINDOW_EVENTS: u32 = KEY_PRESS_MASK
+    | KEY_RELEASE_MASK
+    | BUTTON_PRESS_MASK
+    | BUTTON_RELEASE_MASK
+    | ENTER_WINDOW_MASK
+    | LEAVE_WINDOW_MASK
+    | POINTER_MOTION_MASK
+    | FOCUS_CHANGE_MASK;
+const KEY_PRESS_MASK: u32 = 1 << 0;
+const KEY_RELEASE_MASK: u32 = 1 << 1;
+const BUTTON_PRESS_MASK: u32 = 1 << 2;
+const BUTTON_RELEASE_MASK: u32 = 1 << 3;
+const ENTER_WINDOW_MASK: u32 = 1 << 4;
+const LEAVE_WINDOW_MASK: u32 = 1 << 5;
+const POINTER_MOTION_MASK: u32 = 1 << 6;
+const FOCUS_CHANGE_MASK: u32 = 1 << 21;
+// Predefined atoms.
+const ATOM_STRING: u32 = 31;
+const ATOM_WM_NAME: u32 = 39;
+const WINDOW_WIDTH: u16 = 640;
+const WINDOW_HEIGHT: u16 = 480;
+const KEYCODE_OFFSET: u32 = 8;
+
+const XAUTH_FAMILY_LOCAL: u16 = 256;
+const XAUTH_FAMILY_WILD: u16 = 65535;
+const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn push_padded(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len() + pad(data.len()), 0);
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn i16_at(data: &[u8], offset: usize) -> i16 {
+    u16_at(data, offset) as i16
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+// A request with its length filled in. minor is the second byte, which core
+// requests use for a parameter of their own.
+fn request(major: u8, minor: u8, body: &[u8]) -> Vec<u8> {
+    let mut request = vec![major, minor, 0, 0];
+    push_padded(&mut request, body);
+    let len = (request.len() / 4) as u16;
+    request[2..4].copy_from_slice(&len.to_le_bytes());
+    request
+}
+
+// The display number of a local DISPLAY, e.g. 0 for ":0" or ":0.0".
+fn display_number(display: &str) -> io::Result<String> {
+    let number = display
+        .strip_prefix("unix:")
+        .or_else(|| display.strip_prefix(':'))
+        .ok_or_else(|| invalid(format!("{} isn't a local display", display)))?;
+    let number = number.split('.').next().unwrap_or_default();
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid(format!("invalid display {}", display)));
+    }
+
+    Ok(number.to_string())
+}
+
+// The next length-prefixed field of an Xauthority entry.
+fn xauth_field<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes([*data.get(*pos)?, *data.get(*pos + 1)?]) as usize;
+    let field = data.get(*pos + 2..*pos + 2 + len)?;
+    *pos += 2 + len;
+    Some(field)
+}
+
+// The Xauthority file the cookie is read from, if there's one to look in.
+pub fn xauthority() -> Option<PathBuf> {
+    match env::var_os("XAUTHORITY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(env::var_os("HOME")?).join(".Xauthority")),
+    }
+}
+
+// The cookie of the local display number in the Xauthority file, if any.
+fn read_cookie(number: &str) -> Option<Vec<u8>> {
+    find_cookie(&fs::read(xauthority()?).ok()?, number)
+}
+
+// The cookie of the local display number in the entries of an Xauthority file.
+fn find_cookie(data: &[u8], number: &str) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let family = u16::from_be_bytes([data[pos], *data.get(pos + 1)?]);
+        pos += 2;
+        let _address = xauth_field(data, &mut pos)?;
+        let display = xauth_field(data, &mut pos)?;
+        let name = xauth_field(data, &mut pos)?;
+        let cookie = xauth_field(data, &mut pos)?;
+        if (family == XAUTH_FAMILY_LOCAL || family == XAUTH_FAMILY_WILD)
+            && display == number.as_bytes()
+            && name == MIT_MAGIC_COOKIE
+        {
+            return Some(cookie.to_vec());
+        }
+    }
+
+    None
+}
+
+fn grab_status(status: u8) -> &'static str {
+    match status {
+        1 => "already grabbed by another client",
+        2 => "invalid time",
+        3 => "root window not viewable",
+        4 => "frozen by another client's grab",
+        _ => "unknown status",
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    // Events read while waiting for a reply, to be handled next.
+    events: VecDeque<Vec<u8>>,
+    root: u32,
+    // Black on the root window's screen.
+    black_pixel: u32,
+    // First of the IDs we may give the resources we create.
+    resource_base: u32,
+    // Major opcode of XInputExtension, 0 until init_xinput().
+    xi_opcode: u8,
+}
+
+impl Connection {
+    fn connect(display: &str) -> io::Result<Self> {
+        let number = display_number(display)?;
+        let stream = UnixStream::connect(format!("/tmp/.X11-unix/X{}", number))?;
+        Connection::new(stream, display, read_cookie(&number))
+    }
+
+    // Set up the connection to the server of display on stream, with the
+    // cookie if there's one.
+    fn new(mut stream: UnixStream, display: &str, cookie: Option<Vec<u8>>) -> io::Result<Self> {
+        let (auth_name, auth_data) = match cookie.as_ref() {
+            Some(cookie) => (MIT_MAGIC_COOKIE, cookie.as_slice()),
+            None => (&b""[..], &b""[..]),
+        };
+        // Little-endian, protocol 11.0.
+        let mut setup = vec![b'l', 0, 11, 0, 0, 0];
+        setup.extend_from_slice(&(auth_name.len() as u16).to_le_bytes());
+        setup.extend_from_slice(&(auth_data.len() as u16).to_le_bytes());
+        setup.extend_from_slice(&[0, 0]);
+        push_padded(&mut setup, auth_name);
+        push_padded(&mut setup, auth_data);
+        stream.write_all(&setup)?;
+
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header)?;
+        let mut info = vec![0u8; u16_at(&header, 6) as usize * 4];
+        stream.read_exact(&mut info)?;
+        if header[0] != 1 {
+            // Failed gives the length of its reason, Authenticate doesn't.
+            let len = if header[0] == 0 {
+                (header[1] as usize).min(info.len())
+            } else {
+                info.len()
+            };
+            let reason = String::from_utf8_lossy(&info[..len]);
+            return Err(invalid(format!(
+                "{} refused the connection: {}",
+                display,
+                reason.trim_end_matches('\0').trim()
+            )));
+        }
+
+        // The root window of the first screen, past the vendor string and the
+        // pixmap formats.
+        if info.len() < 32 {
+            return Err(invalid("truncated connection setup".to_string()));
+        }
+        let vendor_len = u16_at(&info, 16) as usize;
+        let formats = info[21] as usize;
+        let screen = 32 + vendor_len + pad(vendor_len) + 8 * formats;
+        if info.len() < screen + 16 {
+            return Err(invalid("truncated connection setup".to_string()));
+        }
+
+        Ok(Connection {
+            stream,
+            events: VecDeque::new(),
+            root: u32_at(&info, screen),
+            black_pixel: u32_at(&info, screen + 12),
+            resource_base: u32_at(&info, 4),
+            xi_opcode: 0,
+        })
+    }
+
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut packet = vec![0u8; 32];
+        self.stream.read_exact(&mut packet)?;
+        // Replies and generic events go on past the 32 bytes of the others.
+        if packet[0] == X_REPLY || packet[0] & 0x7f == GENERIC_EVENT {
+            let extra = u32_at(&packet, 4) as usize * 4;
+            if extra > MAX_PACKET {
+                return Err(invalid(format!("oversized X packet of {} bytes", extra)));
+            }
+            packet.resize(32 + extra, 0);
+            self.stream.read_exact(&mut packet[32..])?;
+        }
+
+        Ok(packet)
+    }
+
+    // Send a request and wait for its reply.
+    fn call(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream.write_all(request)?;
+        loop {
+            let packet = self.read_packet()?;
+            match packet[0] {
+                X_ERROR => {
+                    return Err(invalid(format!(
+                        "X error {} for request {}",
+                        packet[1], packet[10]
+                    )))
+                }
+                X_REPLY => return Ok(packet),
+                _ => self.events.push_back(packet),
+            }
+        }
+    }
+
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        self.stream.write_all(request)
+    }
+
+    fn next_event(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let packet = match self.events.pop_front() {
+                Some(packet) => packet,
+                None => self.read_packet()?,
+            };
+            if packet[0] == X_ERROR {
+                warn!("X error {} for request {}", packet[1], packet[10]);
+                continue;
+            }
+            return Ok(packet);
+        }
+    }
+
+    // Check for XInput 2.1, from which on raw events are sent regardless of
+    // grabs, and select them on the root window.
+    fn init_xinput(&mut self) -> io::Result<()> {
+        let name = b"XInputExtension";
+        let mut body = (name.len() as u16).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(name);
+        let reply = self.call(&request(X_QUERY_EXTENSION, 0, &body))?;
+        if reply[8] == 0 {
+            return Err(invalid("the X server has no XInput extension".to_string()));
+        }
+        self.xi_opcode = reply[9];
+
+        let mut body = 2u16.to_le_bytes().to_vec();
+        body.extend_from_slice(&2u16.to_le_bytes());
+        let reply = self.call(&request(self.xi_opcode, XI_QUERY_VERSION, &body))?;
+        let version = (u16_at(&reply, 8), u16_at(&reply, 10));
+        if version < (2, 1) {
+            return Err(invalid(format!(
+                "the X server has XInput {}.{}, raw events need 2.1",
+                version.0, version.1
+            )));
+        }
+
+        let mask: u32 = [
+            XI_RAW_KEY_PRESS,
+            XI_RAW_KEY_RELEASE,
+            XI_RAW_BUTTON_PRESS,
+            XI_RAW_BUTTON_RELEASE,
+            XI_RAW_MOTION,
+        ]
+        .iter()
+        .fold(0, |mask, &event| mask | 1 << event);
+        let mut body = self.root.to_le_bytes().to_vec();
+        // One mask, of one 4 byte unit.
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&XI_ALL_MASTER_DEVICES.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&mask.to_le_bytes());
+        let request = request(self.xi_opcode, XI_SELECT_EVENTS, &body);
+        self.send(&request)
+    }
+
+    // Take the keyboard and pointer from the host's applications.
+    fn grab(&mut self) -> io::Result<()> {
+        let mut body = self.root.to_le_bytes().to_vec();
+        // CurrentTime.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&[GRAB_MODE_ASYNC, GRAB_MODE_ASYNC, 0, 0]);
+        let reply = self.call(&request(X_GRAB_KEYBOARD, 0, &body))?;
+        if reply[1] != 0 {
+            return Err(invalid(format!(
+                "can't grab the keyboard: {}",
+                grab_status(reply[1])
+            )));
+        }
+
+        let mut body = self.root.to_le_bytes().to_vec();
+        // No event mask, the raw events come anyway.
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&[GRAB_MODE_ASYNC, GRAB_MODE_ASYNC]);
+        // No confine_to window nor cursor, at CurrentTime.
+        body.extend_from_slice(&[0; 12]);
+        let reply = self.call(&request(X_GRAB_POINTER, 0, &body))?;
+        if reply[1] != 0 {
+            self.send(&request(X_UNGRAB_KEYBOARD, 0, &0u32.to_le_bytes()))?;
+            return Err(invalid(format!(
+                "can't grab the pointer: {}",
+                grab_status(reply[1])
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn ungrab(&mut self) -> io::Result<()> {
+        self.send(&request(X_UNGRAB_POINTER, 0, &0u32.to_le_bytes()))?;
+        self.send(&request(X_UNGRAB_KEYBOARD, 0, &0u32.to_le_bytes()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RawEvent {
+    // evdev code, and whether it's an autorepeat press.
+    KeyPress(u16, bool),
+    KeyRelease(u16),
+    ButtonPress(u32),
+    ButtonRelease(u32),
+    // Unaccelerated motion along x and y.
+    Motion(f64, f64),
+}
+
+impl RawEvent {
+    // The raw event in packet, if it is one of those selected.
+    fn parse(packet: &[u8], xi_opcode: u8) -> Option<RawEvent> {
+        if packet[0] & 0x7f != GENERIC_EVENT || packet[1] != xi_opcode {
+            return None;
+        }
+        let detail = u32_at(packet, 16);
+        let key = || detail.checked_sub(KEYCODE_OFFSET).map(|code| code as u16);
+        match u16_at(packet, 8) {
+            XI_RAW_KEY_PRESS => Some(RawEvent::KeyPress(
+                key()?,
+                u32_at(packet, 24) & XI_KEY_REPEAT != 0,
+            )),
+            XI_RAW_KEY_RELEASE => Some(RawEvent::KeyRelease(key()?)),
+            XI_RAW_BUTTON_PRESS => Some(RawEvent::ButtonPress(detail)),
+            XI_RAW_BUTTON_RELEASE => Some(RawEvent::ButtonRelease(detail)),
+            XI_RAW_MOTION => {
+                // The valuator mask, then a FP3232 value for each valuator set
+                // in it, accelerated, then the same raw.
+                let mask_len = u16_at(packet, 22) as usize * 4;
+                let mask = packet.get(32..32 + mask_len)?;
+                let set: Vec<usize> = (0..mask_len * 8)
+                    .filter(|&bit| mask[bit / 8] & 1 << (bit % 8) != 0)
+                    .collect();
+                let raw = 32 + mask_len + set.len() * 8;
+                let value = |axis: usize| -> Option<f64> {
+                    let index = match set.iter().position(|&bit| bit == axis) {
+                        Some(index) => index,
+                        None => return Some(0.0),
+                    };
+                    let offset = raw + index * 8;
+                    packet.get(offset..offset + 8)?;
+                    let integral = u32_at(packet, offset) as i32;
+                    let frac = u32_at(packet, offset + 4);
+                    Some(integral as f64 + frac as f64 / 4_294_967_296.0)
+                };
+                Some(RawEvent::Motion(value(0)?, value(1)?))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn event(event_type: u16, code: u16, value: i32) -> VirtioInputEvent {
+    VirtioInputEvent {
+        event_type,
+        code,
+        value: value as u32,
+    }
+}
+
+// The events of a pointer button, as evdev buttons or wheel clicks.
+fn button(button: u32, pressed: bool) -> Vec<VirtioInputEvent> {
+    let code = match button {
+        1 => BTN_LEFT,
+        2 => BTN_MIDDLE,
+        3 => BTN_RIGHT,
+        8 => BTN_SIDE,
+        9 => BTN_EXTRA,
+        // Wheel buttons only click, on press.
+        4..=7 if !pressed => return Vec::new(),
+        4 => return vec![event(EV_REL, REL_WHEEL, 1)],
+        5 => return vec![event(EV_REL, REL_WHEEL, -1)],
+        6 => return vec![event(EV_REL, REL_HWHEEL, -1)],
+        7 => return vec![event(EV_REL, REL_HWHEEL, 1)],
+        _ => return Vec::new(),
+    };
+
+    vec![event(EV_KEY, code, pressed as i32)]
+}
+
+// Where the input goes, and what of it the guest thinks is held.
+struct Output {
+    injected: Injected,
+    held: HeldKeys,
+}
+
+impl Output {
+    // Send the guest events as a frame.
+    fn forward(&mut self, mut events: Vec<VirtioInputEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        events.push(event(EV_SYN, SYN_REPORT, 0));
+        if !self.injected.push(&events) {
+            warn!("Too many pending events, dropping X11 input");
+            return;
+        }
+        for event in events.iter() {
+            self.held.update(event);
+        }
+    }
+
+    // Release whatever the guest thinks is held, once the input stops.
+    fn release_all(&mut self) {
+        let releases = self.held.releases();
+        if !releases.is_empty() && !self.injected.push(&releases) {
+            warn!("Too many pending events, can't release {:?}", self.held);
+        }
+        self.held.clear();
+    }
+}
+
+struct Capture {
+    connection: Connection,
+    hotkey: Vec<u16>,
+    output: Output,
+    // Keys down on the host, to spot the hotkey.
+    down: BTreeSet<u16>,
+    captured: bool,
+    // Motion not forwarded yet, less than a unit.
+    remainder: (f64, f64),
+}
+
+impl Capture {
+    fn toggle(&mut self) -> io::Result<()> {
+        if self.captured {
+            self.connection.ungrab()?;
+            self.output.release_all();
+            self.captured = false;
+            info!("released X11 input");
+            return Ok(());
+        }
+
+        match self.connection.grab() {
+            Ok(()) => {
+                self.captured = true;
+                self.remainder = (0.0, 0.0);
+                info!("capturing X11 input");
+            }
+            Err(e) => warn!("Failed to capture X11 input: {}", e),
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, raw: RawEvent) -> io::Result<()> {
+        match raw {
+            RawEvent::KeyPress(code, repeat) => {
+                self.down.insert(code);
+                if !repeat
+                    && self.hotkey.contains(&code)
+                    && self.hotkey.iter().all(|key| self.down.contains(key))
+                {
+                    return self.toggle();
+                }
+                if self.captured {
+                    let value = if repeat { 2 } else { 1 };
+                    self.output.forward(vec![event(EV_KEY, code, value)]);
+                }
+            }
+            RawEvent::KeyRelease(code) => {
+                self.down.remove(&code);
+                if self.captured {
+                    self.output.forward(vec![event(EV_KEY, code, 0)]);
+                }
+            }
+            RawEvent::ButtonPress(number) if self.captured => {
+                self.output.forward(button(number, true))
+            }
+            RawEvent::ButtonRelease(number) if self.captured => {
+                self.output.forward(button(number, false))
+            }
+            RawEvent::Motion(dx, dy) if self.captured => {
+                let x = self.remainder.0 + dx;
+                let y = self.remainder.1 + dy;
+                self.remainder = (x.fract(), y.fract());
+                self.output
+                    .forward(motion(x.trunc() as i32, y.trunc() as i32));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = self.connection.next_event()?;
+            if let Some(raw) = RawEvent::parse(&packet, self.connection.xi_opcode) {
+                self.handle(raw)?;
+            }
+        }
+    }
+}
+
+fn motion(dx: i32, dy: i32) -> Vec<VirtioInputEvent> {
+    let mut events = Vec::new();
+    if dx != 0 {
+        events.push(event(EV_REL, REL_X, dx));
+    }
+    if dy != 0 {
+        events.push(event(EV_REL, REL_Y, dy));
+    }
+    events
+}
+
+struct Window {
+    connection: Connection,
+    output: Output,
+    // Where the pointer was in the window, None while it's outside.
+    pointer: Option<(i16, i16)>,
+}
+
+impl Window {
+    // Create the window and show it.
+    fn open(connection: Connection, title: &str, output: Output) -> io::Result<Self> {
+        let mut connection = connection;
+        let window = connection.resource_base | 1;
+
+        let mut body = window.to_le_bytes().to_vec();
+        body.extend_from_slice(&connection.root.to_le_bytes());
+        // At 0,0, for the window manager to place.
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(&WINDOW_WIDTH.to_le_bytes());
+        body.extend_from_slice(&WINDOW_HEIGHT.to_le_bytes());
+        // No border.
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&WINDOW_CLASS_INPUT_OUTPUT.to_le_bytes());
+        // The root window's visual.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&(CW_BACK_PIXEL | CW_EVENT_MASK).to_le_bytes());
+        body.extend_from_slice(&connection.black_pixel.to_le_bytes());
+        body.extend_from_slice(&WINDOW_EVENTS.to_le_bytes());
+        // The depth, in the second byte, is the root window's.
+        connection.send(&request(X_CREATE_WINDOW, 0, &body))?;
+
+        let mut body = window.to_le_bytes().to_vec();
+        body.extend_from_slice(&ATOM_WM_NAME.to_le_bytes());
+        body.extend_from_slice(&ATOM_STRING.to_le_bytes());
+        // 8 bit format.
+        body.extend_from_slice(&[8, 0, 0, 0]);
+        body.extend_from_slice(&(title.len() as u32).to_le_bytes());
+        body.extend_from_slice(title.as_bytes());
+        // Replace the property.
+        connection.send(&request(X_CHANGE_PROPERTY, 0, &body))?;
+
+        connection.send(&request(X_MAP_WINDOW, 0, &window.to_le_bytes()))?;
+
+        Ok(Window {
+            connection,
+            output,
+            pointer: None,
+        })
+    }
+
+    fn handle(&mut self, packet: &[u8]) {
+        let detail = packet[1];
+        let position = (i16_at(packet, 24), i16_at(packet, 26));
+        match packet[0] & 0x7f {
+            KEY_PRESS | KEY_RELEASE if detail as u32 >= KEYCODE_OFFSET => {
+                let code = (detail as u32 - KEYCODE_OFFSET) as u16;
+                let pressed = packet[0] & 0x7f == KEY_PRESS;
+                self.output
+                    .forward(vec![event(EV_KEY, code, pressed as i32)]);
+            }
+            BUTTON_PRESS => self.output.forward(button(detail as u32, true)),
+            BUTTON_RELEASE => self.output.forward(button(detail as u32, false)),
+            MOTION_NOTIFY => {
+                if let Some((x, y)) = self.pointer {
+                    let dx = position.0 as i32 - x as i32;
+                    let dy = position.1 as i32 - y as i32;
+                    self.output.forward(motion(dx, dy));
+                }
+                self.pointer = Some(position);
+            }
+            ENTER_NOTIFY => self.pointer = Some(position),
+            LEAVE_NOTIFY => self.pointer = None,
+            FOCUS_OUT => self.output.release_all(),
+            _ => {}
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = self.connection.next_event()?;
+            self.handle(&packet);
+        }
+    }
+}
+
+// The keys of a hotkey given as KEY_A+KEY_B names.
+pub fn parse_hotkey(hotkey: &str) -> Result<Vec<u16>, String> {
+    hotkey
+        .split('+')
+        .map(|name| match codes::code_by_name(name.trim()) {
+            Some((EV_KEY, code)) => Ok(code),
+            _ => Err(format!("unknown key {}", name)),
+        })
+        .collect()
+}
+
+// Connect to the X server of display and follow its input from a thread of
+// its own, forwarding it through injected while captured.
+pub fn spawn_capture(display: &str, hotkey: Vec<u16>, injected: Injected) -> io::Result<()> {
+    let mut connection = Connection::connect(display)?;
+    connection.init_xinput()?;
+    let mut capture = Capture {
+        connection,
+        hotkey,
+        output: Output {
+            injected,
+            held: HeldKeys::default(),
+        },
+        down: BTreeSet::new(),
+        captured: false,
+        remainder: (0.0, 0.0),
+    };
+
+    thread::spawn(move || {
+        if let Err(e) = capture.run() {
+            error!("Lost the X11 connection, no more X11 input: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+// Open a window titled title on display, and forward what is typed into it
+// through injected from a thread of its own.
+pub fn spawn_window(display: &str, title: &str, injected: Injected) -> io::Result<()> {
+    let connection = Connection::connect(display)?;
+    let output = Output {
+        injected,
+        held: HeldKeys::default(),
+    };
+    let mut window = Window::open(connection, title, output)?;
+
+    thread::spawn(move || {
+        // Closing the window makes the window manager drop the connection.
+        if let Err(e) = window.run() {
+            info!("input window closed: {}", e);
+        }
+        window.output.release_all();
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libc::EFD_NONBLOCK;
+    use vmm_sys_util::eventfd::EventFd;
+
+    const KEY_A: u16 = 30;
+    const KEY_LEFTCTRL: u16 = 29;
+    const KEY_G: u16 = 34;
+    const XI_OPCODE: u8 = 131;
+    const ROOT: u32 = 0x1e3;
+    const BLACK_PIXEL: u32 = 0x00ff_0000;
+    const RESOURCE_BASE: u32 = 0x0040_0000;
+
+    // The setup the server accepts the connection with: a vendor string, a
+    // pixmap format and one screen.
+    fn accepted() -> Vec<u8> {
+        let mut info = vec![0u8; 32];
+        info[4..8].copy_from_slice(&RESOURCE_BASE.to_le_bytes());
+        info[16..18].copy_from_slice(&4u16.to_le_bytes());
+        info[20] = 1;
+        info[21] = 1;
+        info.extend_from_slice(b"Test");
+        info.extend_from_slice(&[24, 32, 32, 0, 0, 0, 0, 0]);
+        let mut screen = vec![0u8; 40];
+        screen[0..4].copy_from_slice(&ROOT.to_le_bytes());
+        screen[12..16].copy_from_slice(&BLACK_PIXEL.to_le_bytes());
+        info.extend_from_slice(&screen);
+
+        let mut setup = vec![1, 0, 11, 0, 0, 0];
+        setup.extend_from_slice(&((info.len() / 4) as u16).to_le_bytes());
+        setup.extend_from_slice(&info);
+        setup
+    }
+
+    fn reply(fill: &[(usize, u8)]) -> Vec<u8> {
+        let mut reply = vec![0u8; 32];
+        reply[0] = X_REPLY;
+        for &(offset, byte) in fill {
+            reply[offset] = byte;
+        }
+        reply
+    }
+
+    // A core event, at x, y in the window.
+    fn core_event(code: u8, detail: u8, x: i16, y: i16) -> Vec<u8> {
+        let mut event = vec![0u8; 32];
+        event[0] = code;
+        event[1] = detail;
+        event[24..26].copy_from_slice(&x.to_le_bytes());
+        event[26..28].copy_from_slice(&y.to_le_bytes());
+        event
+    }
+
+    // An XInput raw event with the valuators of values, each given
+    // accelerated, then raw.
+    fn raw_event(evtype: u16, detail: u32, flags: u32, values: &[(usize, f64)]) -> Vec<u8> {
+        let mut event = vec![0u8; 32];
+        event[0] = GENERIC_EVENT;
+        event[1] = XI_OPCODE;
+        event[8..10].copy_from_slice(&evtype.to_le_bytes());
+        event[16..20].copy_from_slice(&detail.to_le_bytes());
+        event[22..24].copy_from_slice(&1u16.to_le_bytes());
+        event[24..28].copy_from_slice(&flags.to_le_bytes());
+        let mask = values
+            .iter()
+            .fold(0u32, |mask, &(axis, _)| mask | 1 << axis);
+        event.extend_from_slice(&mask.to_le_bytes());
+        for &scale in [2.0, 1.0].iter() {
+            for &(_, value) in values {
+                let value = value * scale;
+                let integral = value.floor();
+                event.extend_from_slice(&(integral as i32).to_le_bytes());
+                let frac = ((value - integral) * 4_294_967_296.0) as u32;
+                event.extend_from_slice(&frac.to_le_bytes());
+            }
+        }
+        let len = ((event.len() - 32) / 4) as u32;
+        event[4..8].copy_from_slice(&len.to_le_bytes());
+        event
+    }
+
+    // What the client sent so far.
+    fn sent(server: &mut UnixStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        server.set_nonblocking(true).unwrap();
+        let _ = server.read_to_end(&mut data);
+        data
+    }
+
+    fn connection(server: &mut UnixStream, client: UnixStream) -> Connection {
+        server.write_all(&accepted()).unwrap();
+        let connection = Connection::new(client, ":0", None).unwrap();
+        sent(server);
+        connection
+    }
+
+    fn taken(injected: &Injected) -> Vec<(u16, u16, i32)> {
+        injected
+            .take()
+            .iter()
+            .map(|e| (e.event_type, e.code, e.value as i32))
+            .collect()
+    }
+
+    #[test]
+    fn displays() {
+        assert_eq!(display_number(":0").unwrap(), "0");
+        assert_eq!(display_number(":12.1").unwrap(), "12");
+        assert_eq!(display_number("unix:1").unwrap(), "1");
+        assert!(display_number("localhost:0").is_err());
+        assert!(display_number(":").is_err());
+        assert!(display_number(":x").is_err());
+    }
+
+    #[test]
+    fn cookies() {
+        let entry = |family: u16, display: &str, name: &[u8], cookie: &[u8]| {
+            let mut entry = family.to_be_bytes().to_vec();
+            for field in [&b"host"[..], display.as_bytes(), name, cookie].iter() {
+                entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                entry.extend_from_slice(field);
+            }
+            entry
+        };
+        let mut data = entry(XAUTH_FAMILY_LOCAL, "1", MIT_MAGIC_COOKIE, &[1; 16]);
+        data.extend(entry(
+            XAUTH_FAMILY_LOCAL,
+            "0",
+            b"XDM-AUTHORIZATION-1",
+            &[2; 16],
+        ));
+        data.extend(entry(0, "0", MIT_MAGIC_COOKIE, &[3; 16]));
+        data.extend(entry(XAUTH_FAMILY_WILD, "0", MIT_MAGIC_COOKIE, &[4; 16]));
+        assert_eq!(find_cookie(&data, "1"), Some(vec![1; 16]));
+        assert_eq!(find_cookie(&data, "0"), Some(vec![4; 16]));
+        assert_eq!(find_cookie(&data, "2"), None);
+        assert_eq!(find_cookie(&data[..data.len() - 1], "0"), None);
+    }
+
+    #[test]
+    fn setup() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        server.write_all(&accepted()).unwrap();
+        let connection = Connection::new(client, ":0", Some(vec![0xab; 16])).unwrap();
+        assert_eq!(connection.root, ROOT);
+        assert_eq!(connection.black_pixel, BLACK_PIXEL);
+        assert_eq!(connection.resource_base, RESOURCE_BASE);
+
+        let mut expected = vec![b'l', 0, 11, 0, 0, 0, 18, 0, 16, 0, 0, 0];
+        expected.extend_from_slice(b"MIT-MAGIC-COOKIE-1\0\0");
+        expected.extend_from_slice(&[0xab; 16]);
+        assert_eq!(sent(&mut server), expected);
+
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut refused = vec![0, 9, 11, 0, 0, 0, 3, 0];
+        refused.extend_from_slice(b"No cookie\0\0\0");
+        server.write_all(&refused).unwrap();
+        let e = Connection::new(client, ":0", None).err().unwrap();
+        assert_eq!(e.to_string(), ":0 refused the connection: No cookie");
+        assert_eq!(sent(&mut server), [b'l', 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn requests() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut connection = connection(&mut server, client);
+
+        // An event that comes in while waiting for a reply is kept for later.
+        let key_press = core_event(KEY_PRESS, 38, 0, 0);
+        server.write_all(&key_press).unwrap();
+        server.write_all(&reply(&[(8, 1), (9, XI_OPCODE)])).unwrap();
+        server.write_all(&reply(&[(8, 2), (10, 2)])).unwrap();
+        connection.init_xinput().unwrap();
+        assert_eq!(connection.xi_opcode, XI_OPCODE);
+        let mut expected = vec![X_QUERY_EXTENSION, 0, 6, 0, 15, 0, 0, 0];
+        expected.extend_from_slice(b"XInputExtension\0");
+        expected.extend_from_slice(&[XI_OPCODE, XI_QUERY_VERSION, 2, 0, 2, 0, 2, 0]);
+        expected.extend_from_slice(&[XI_OPCODE, XI_SELECT_EVENTS, 5, 0]);
+        expected.extend_from_slice(&ROOT.to_le_bytes());
+        expected.extend_from_slice(&[1, 0, 0, 0, 1, 0, 1, 0]);
+        expected.extend_from_slice(&0x3e000u32.to_le_bytes());
+        assert_eq!(sent(&mut server), expected);
+        assert_eq!(connection.next_event().unwrap(), key_press);
+
+        server.write_all(&reply(&[])).unwrap();
+        server.write_all(&reply(&[(1, 1)])).unwrap();
+        let e = connection.grab().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "can't grab the pointer: already grabbed by another client"
+        );
+        let mut expected = vec![X_GRAB_KEYBOARD, 0, 4, 0];
+        expected.extend_from_slice(&ROOT.to_le_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 0, 1, 1, 0, 0]);
+        expected.extend_from_slice(&[X_GRAB_POINTER, 0, 6, 0]);
+        expected.extend_from_slice(&ROOT.to_le_bytes());
+        expected.extend_from_slice(&[0, 0, 1, 1]);
+        expected.extend_from_slice(&[0; 12]);
+        expected.extend_from_slice(&[X_UNGRAB_KEYBOARD, 0, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(sent(&mut server), expected);
+
+        // Errors are logged and skipped.
+        let mut error = vec![0u8; 32];
+        error[1] = 3;
+        error[10] = X_GRAB_POINTER;
+        server.write_all(&error).unwrap();
+        let motion = raw_event(XI_RAW_MOTION, 0, 0, &[(0, 1.0)]);
+        server.write_all(&motion).unwrap();
+        assert_eq!(connection.next_event().unwrap(), motion);
+
+        // The server doesn't send more than MAX_PACKET past the 32 bytes.
+        let mut oversized = reply(&[]);
+        oversized[4..8].copy_from_slice(&(MAX_PACKET as u32 / 4 + 1).to_le_bytes());
+        server.write_all(&oversized).unwrap();
+        assert!(connection.next_event().is_err());
+    }
+
+    #[test]
+    fn raw_events() {
+        let parse = |packet: Vec<u8>| RawEvent::parse(&packet, XI_OPCODE);
+        assert_eq!(
+            parse(raw_event(XI_RAW_KEY_PRESS, 38, 0, &[])),
+            Some(RawEvent::KeyPress(KEY_A, false))
+        );
+        assert_eq!(
+            parse(raw_event(XI_RAW_KEY_PRESS, 38, XI_KEY_REPEAT, &[])),
+            Some(RawEvent::KeyPress(KEY_A, true))
+        );
+        assert_eq!(
+            parse(raw_event(XI_RAW_KEY_RELEASE, 38, 0, &[])),
+            Some(RawEvent::KeyRelease(KEY_A))
+        );
+        // Below the keycode offset.
+        assert_eq!(parse(raw_event(XI_RAW_KEY_PRESS, 7, 0, &[])), None);
+        assert_eq!(
+            parse(raw_event(XI_RAW_BUTTON_PRESS, 3, 0, &[])),
+            Some(RawEvent::ButtonPress(3))
+        );
+        assert_eq!(
+            parse(raw_event(XI_RAW_BUTTON_RELEASE, 3, 0, &[])),
+            Some(RawEvent::ButtonRelease(3))
+        );
+
+        // The raw values, not the accelerated ones, and 0 for a valuator
+        // that isn't set.
+        assert_eq!(
+            parse(raw_event(XI_RAW_MOTION, 0, 0, &[(0, 3.5), (1, -2.25)])),
+            Some(RawEvent::Motion(3.5, -2.25))
+        );
+        assert_eq!(
+            parse(raw_event(XI_RAW_MOTION, 0, 0, &[(1, 1.0), (3, 120.0)])),
+            Some(RawEvent::Motion(0.0, 1.0))
+        );
+        let mut truncated = raw_event(XI_RAW_MOTION, 0, 0, &[(0, 1.0)]);
+        truncated.truncate(truncated.len() - 4);
+        assert_eq!(parse(truncated), None);
+
+        // Another extension's generic events, and core events.
+        let mut other = raw_event(XI_RAW_KEY_PRESS, 38, 0, &[]);
+        other[1] = XI_OPCODE + 1;
+        assert_eq!(parse(other), None);
+        assert_eq!(parse(core_event(KEY_PRESS, 38, 0, 0)), None);
+    }
+
+    #[test]
+    fn capture() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let injected = Injected::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let mut capture = Capture {
+            connection: connection(&mut server, client),
+            hotkey: vec![KEY_LEFTCTRL, KEY_G],
+            output: Output {
+                injected: injected.clone(),
+                held: HeldKeys::default(),
+            },
+            down: BTreeSet::new(),
+            captured: false,
+            remainder: (0.0, 0.0),
+        };
+
+        capture.handle(RawEvent::KeyPress(KEY_A, false)).unwrap();
+        capture.handle(RawEvent::KeyRelease(KEY_A)).unwrap();
+        capture.handle(RawEvent::Motion(4.0, 4.0)).unwrap();
+        assert!(taken(&injected).is_empty());
+
+        server.write_all(&reply(&[])).unwrap();
+        server.write_all(&reply(&[])).unwrap();
+        capture
+            .handle(RawEvent::KeyPress(KEY_LEFTCTRL, false))
+            .unwrap();
+        capture.handle(RawEvent::KeyPress(KEY_G, false)).unwrap();
+        assert!(capture.captured);
+        assert_eq!(sent(&mut server)[0], X_GRAB_KEYBOARD);
+
+        capture.handle(RawEvent::KeyPress(KEY_A, false)).unwrap();
+        capture.handle(RawEvent::KeyPress(KEY_A, true)).unwrap();
+        capture.handle(RawEvent::ButtonPress(1)).unwrap();
+        capture.handle(RawEvent::ButtonPress(5)).unwrap();
+        capture.handle(RawEvent::ButtonRelease(5)).unwrap();
+        // Fractions add up across events.
+        capture.handle(RawEvent::Motion(1.5, 0.25)).unwrap();
+        capture.handle(RawEvent::Motion(0.75, 0.75)).unwrap();
+        assert_eq!(
+            taken(&injected),
+            vec![
+                (EV_KEY, KEY_A, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 2),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, BTN_LEFT, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_WHEEL, -1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_X, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_X, 1),
+                (EV_REL, REL_Y, 1),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        // Pressing the hotkey again ungrabs and releases what is held.
+        capture.handle(RawEvent::KeyPress(KEY_G, false)).unwrap();
+        assert!(!capture.captured);
+        let mut expected = vec![X_UNGRAB_POINTER, 0, 2, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&[X_UNGRAB_KEYBOARD, 0, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(sent(&mut server), expected);
+        assert_eq!(
+            taken(&injected),
+            vec![
+                (EV_KEY, KEY_A, 0),
+                (EV_KEY, BTN_LEFT, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn window() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let injected = Injected::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        let output = Output {
+            injected: injected.clone(),
+            held: HeldKeys::default(),
+        };
+        let mut window = Window::open(connection(&mut server, client), "guest", output).unwrap();
+
+        let id = (RESOURCE_BASE | 1).to_le_bytes();
+        let mut expected = vec![X_CREATE_WINDOW, 0, 10, 0];
+        expected.extend_from_slice(&id);
+        expected.extend_from_slice(&ROOT.to_le_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 0, 0x80, 2, 0xe0, 1, 0, 0, 1, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&(CW_BACK_PIXEL | CW_EVENT_MASK).to_le_bytes());
+        expected.extend_from_slice(&BLACK_PIXEL.to_le_bytes());
+        expected.extend_from_slice(&0x20007fu32.to_le_bytes());
+        expected.extend_from_slice(&[X_CHANGE_PROPERTY, 0, 8, 0]);
+        expected.extend_from_slice(&id);
+        expected.extend_from_slice(&[39, 0, 0, 0, 31, 0, 0, 0, 8, 0, 0, 0, 5, 0, 0, 0]);
+        expected.extend_from_slice(b"guest\0\0\0");
+        expected.extend_from_slice(&[X_MAP_WINDOW, 0, 2, 0]);
+        expected.extend_from_slice(&id);
+        assert_eq!(sent(&mut server), expected);
+
+        // Motion only counts from where the pointer entered.
+        window.handle(&core_event(MOTION_NOTIFY, 0, 5, 5));
+        window.handle(&core_event(LEAVE_NOTIFY, 0, 5, 5));
+        window.handle(&core_event(ENTER_NOTIFY, 0, 10, 10));
+        window.handle(&core_event(MOTION_NOTIFY, 0, 13, 8));
+        window.handle(&core_event(KEY_PRESS, 38, 13, 8));
+        window.handle(&core_event(BUTTON_PRESS, 4, 13, 8));
+        window.handle(&core_event(BUTTON_PRESS, 3, 13, 8));
+        window.handle(&core_event(BUTTON_RELEASE, 3, 13, 8));
+        assert_eq!(
+            taken(&injected),
+            vec![
+                (EV_REL, REL_X, 3),
+                (EV_REL, REL_Y, -2),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, KEY_A, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_REL, REL_WHEEL, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, BTN_RIGHT, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, BTN_RIGHT, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        window.handle(&core_event(FOCUS_OUT, 0, 0, 0));
+        assert_eq!(
+            taken(&injected),
+            vec![(EV_KEY, KEY_A, 0), (EV_SYN, SYN_REPORT, 0)]
+        );
+    }
+}